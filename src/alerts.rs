@@ -0,0 +1,226 @@
+//! Encrypted nostr DMs to the operator when the mint needs attention
+//!
+//! Conditions are polled in the background, nothing on the request path waits
+//! on an alert being sent.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bip39::Mnemonic;
+use nostr_sdk::{Client, PublicKey};
+
+use crate::config;
+use crate::nostr::{self, Error};
+use crate::price::PriceCache;
+use crate::search_provider::kagi::KagiProvider;
+use crate::search_route_handlers::LightningBackend;
+
+/// How often conditions are checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Least time between two alerts for the same condition
+const ALERT_REPEAT: Duration = Duration::from_secs(60 * 60);
+
+/// Condition the operator is alerted about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Alert {
+    /// Kagi api balance is below the threshold
+    KagiBalanceLow,
+    /// Lightning node has not answered for too long
+    LightningUnreachable,
+    /// No BTC price could be fetched for too long
+    PriceUnavailable,
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Alert::KagiBalanceLow => write!(f, "kagi balance low"),
+            Alert::LightningUnreachable => write!(f, "lightning unreachable"),
+            Alert::PriceUnavailable => write!(f, "price unavailable"),
+        }
+    }
+}
+
+/// Client sending alerts to the operator, at most one per condition an hour
+pub struct Alerter {
+    client: Client,
+    operator: PublicKey,
+    mint_name: String,
+    last_sent: HashMap<Alert, Instant>,
+}
+
+impl Alerter {
+    /// Create new [`Alerter`] connected to the alert relays, signing with the
+    /// same key as the mint announcement
+    pub async fn new(
+        settings: &config::Settings,
+        mnemonic: &Mnemonic,
+        operator: PublicKey,
+    ) -> Result<Self, Error> {
+        let client = Client::new(nostr::signing_keys(&settings.nostr, mnemonic)?);
+
+        for relay in &settings.alerts.relays {
+            client.add_relay(relay.as_str()).await?;
+        }
+
+        client.connect().await;
+
+        Ok(Self {
+            client,
+            operator,
+            mint_name: settings.mint_info.name.clone(),
+            last_sent: HashMap::new(),
+        })
+    }
+
+    /// DM `message` to the operator unless `alert` was sent within the last
+    /// hour
+    pub async fn send(&mut self, alert: Alert, message: &str) {
+        if self
+            .last_sent
+            .get(&alert)
+            .is_some_and(|sent_at| sent_at.elapsed() < ALERT_REPEAT)
+        {
+            return;
+        }
+
+        let message = format!("{}: {}", self.mint_name, message);
+
+        match self
+            .client
+            .send_private_msg(self.operator, message, [])
+            .await
+        {
+            Ok(_) => {
+                tracing::info!("Sent {} alert to operator", alert);
+                self.last_sent.insert(alert, Instant::now());
+            }
+            Err(err) => tracing::warn!("Could not send {} alert: {}", alert, err),
+        }
+    }
+}
+
+/// When a condition started failing
+#[derive(Debug, Default)]
+struct Outage {
+    since: Option<Instant>,
+}
+
+impl Outage {
+    /// Record the latest check, returning how long it has been failing
+    fn update(&mut self, ok: bool) -> Option<Duration> {
+        if ok {
+            self.since = None;
+            return None;
+        }
+
+        Some(self.since.get_or_insert_with(Instant::now).elapsed())
+    }
+}
+
+/// Check the configured conditions every minute and alert the operator
+///
+/// Conditions without a threshold configured are not checked.
+pub async fn monitor(
+    settings: config::Settings,
+    mnemonic: Mnemonic,
+    kagi: Arc<KagiProvider>,
+    lightning: LightningBackend,
+    price_cache: PriceCache,
+) {
+    let Some(operator) = settings
+        .mint_info
+        .operator_nostr_pubkey()
+        .and_then(|pubkey| PublicKey::parse(&pubkey).ok())
+    else {
+        tracing::warn!("Alerts are unavailable: no valid nostr contact");
+        return;
+    };
+
+    let mut alerter = match Alerter::new(&settings, &mnemonic, operator).await {
+        Ok(alerter) => alerter,
+        Err(err) => {
+            tracing::warn!("Alerts are unavailable: {}", err);
+            return;
+        }
+    };
+
+    let lightning_limit = settings
+        .alerts
+        .lightning_unreachable_mins
+        .map(|mins| Duration::from_secs(mins * 60));
+    let price_limit = settings
+        .alerts
+        .price_unavailable_mins
+        .map(|mins| Duration::from_secs(mins * 60));
+
+    let mut lightning_outage = Outage::default();
+    let mut price_outage = Outage::default();
+
+    let mut check_interval = tokio::time::interval(CHECK_INTERVAL);
+
+    loop {
+        check_interval.tick().await;
+
+        if let (Some(threshold), Some(balance)) =
+            (settings.alerts.kagi_balance_threshold, kagi.api_balance())
+        {
+            if balance < threshold {
+                alerter
+                    .send(
+                        Alert::KagiBalanceLow,
+                        &format!(
+                            "Kagi api balance is ${:.2}, below ${:.2}",
+                            balance, threshold
+                        ),
+                    )
+                    .await;
+            }
+        }
+
+        if let Some(limit) = lightning_limit {
+            let reachable = match &lightning {
+                LightningBackend::Cln(cln) => cln.check_connection().await.is_ok(),
+                LightningBackend::Phoenixd(phoenixd) => phoenixd.check_connection().await.is_ok(),
+                LightningBackend::Dev(_) => true,
+            };
+
+            if let Some(down_for) = lightning_outage.update(reachable) {
+                if down_for >= limit {
+                    alerter
+                        .send(
+                            Alert::LightningUnreachable,
+                            &format!(
+                                "Lightning node unreachable for {} minutes",
+                                down_for.as_secs() / 60
+                            ),
+                        )
+                        .await;
+                }
+            }
+        }
+
+        if let Some(limit) = price_limit {
+            // Starts a refresh when the cached price is stale
+            let _ = price_cache.get_price().await;
+
+            if let Some(down_for) = price_outage.update(price_cache.is_fresh(CHECK_INTERVAL).await)
+            {
+                if down_for >= limit {
+                    alerter
+                        .send(
+                            Alert::PriceUnavailable,
+                            &format!(
+                                "No BTC price could be fetched for {} minutes",
+                                down_for.as_secs() / 60
+                            ),
+                        )
+                        .await;
+                }
+            }
+        }
+    }
+}
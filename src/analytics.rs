@@ -0,0 +1,108 @@
+//! Opt-in counts of what is searched for, without keeping any query
+//!
+//! Queries are normalized and hashed with a key only this deployment has, so
+//! the stored counts can not be matched against a list of guessed queries
+//! elsewhere. Only the hash and coarse length and result count buckets are
+//! written.
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Result};
+use bitcoin::secp256k1::rand::{thread_rng, Rng};
+
+/// File in the work dir the hashing key is kept in
+const KEY_FILE: &str = "analytics.key";
+
+/// Upper bounds of the query length buckets, in characters
+const LENGTH_BUCKETS: [usize; 4] = [10, 20, 40, 80];
+
+/// Upper bounds of the result count buckets
+const RESULT_BUCKETS: [usize; 3] = [0, 5, 10];
+
+/// Hashes queries for the analytics counters
+#[derive(Clone)]
+pub struct QueryAnalytics {
+    key: [u8; 32],
+}
+
+impl QueryAnalytics {
+    /// Load the key from the work dir, creating it on first use
+    pub fn load_or_create(work_dir: &Path) -> Result<Self> {
+        let path = work_dir.join(KEY_FILE);
+
+        match fs::read(&path) {
+            Ok(bytes) => {
+                let key = bytes
+                    .try_into()
+                    .map_err(|_| anyhow!("{} is not a 32 byte key", path.display()))?;
+
+                return Ok(Self { key });
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+            Err(err) => bail!("Could not read {}: {}", path.display(), err),
+        }
+
+        let mut key = [0u8; 32];
+        thread_rng().fill(&mut key);
+
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .and_then(|mut file| file.write_all(&key))
+            .map_err(|err| anyhow!("Could not write {}: {}", path.display(), err))?;
+
+        tracing::info!("Created query analytics key {}", path.display());
+
+        Ok(Self { key })
+    }
+
+    /// Keyed hash of the normalized `query`, as hex
+    pub fn query_hash(&self, query: &str) -> String {
+        blake3::keyed_hash(&self.key, normalize(query).as_bytes())
+            .to_hex()
+            .to_string()
+    }
+}
+
+/// Lowercase with runs of whitespace collapsed, so trivially different
+/// spellings count together
+fn normalize(query: &str) -> String {
+    query
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// Bucket of the normalized length of `query`, such as `11-20`
+pub fn length_bucket(query: &str) -> String {
+    bucket(normalize(query).chars().count(), &LENGTH_BUCKETS, 1)
+}
+
+/// Bucket of `results` returned, such as `6-10`
+pub fn result_bucket(results: usize) -> String {
+    bucket(results, &RESULT_BUCKETS, 0)
+}
+
+fn bucket(value: usize, upper_bounds: &[usize], min: usize) -> String {
+    let mut lower = min;
+
+    for &upper in upper_bounds {
+        if value <= upper {
+            if lower == upper {
+                return upper.to_string();
+            }
+
+            return format!("{}-{}", lower, upper);
+        }
+
+        lower = upper + 1;
+    }
+
+    format!("{}+", lower)
+}
@@ -1,6 +1,6 @@
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 #[derive(Parser)]
 #[command(about = "A cashu mint written in rust", author = env!("CARGO_PKG_AUTHORS"), version = env!("CARGO_PKG_VERSION"))]
@@ -8,17 +8,113 @@ pub struct CLIArgs {
     #[arg(
         short,
         long,
-        help = "Use the <directory> as the location of the database",
+        help = "Use the <directory> as the location of the database, overrides ATHENUT_WORK_DIR",
         required = false
     )]
     pub work_dir: Option<PathBuf>,
     #[arg(
         short,
         long,
-        help = "Use the <file name> as the location of the config file",
+        help = "Use the <file name> as the location of the config file, .toml, .yaml, .yml or .json",
         required = false
     )]
     pub config: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Run without lightning, every mint quote is paid without a payment",
+        required = false
+    )]
+    pub dev: bool,
+    #[arg(
+        long,
+        help = "Start without checking the lightning node, search provider and databases first",
+        required = false
+    )]
+    pub skip_checks: bool,
+    #[arg(
+        long,
+        help = "Publish the nostr mint announcement and status note once and exit",
+        required = false
+    )]
+    pub announce_now: bool,
     #[arg(short, long, help = "Recover Greenlight from seed", required = false)]
     pub recover: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Commands run instead of starting the mint
+#[derive(Subcommand)]
+pub enum Command {
+    /// Load and validate the config, print it with secrets masked and exit
+    Check {
+        #[arg(
+            long,
+            help = "Also connect to the lightning node and check the kagi token"
+        )]
+        probe: bool,
+    },
+    /// Write a commented example config with a new mnemonic to the work dir
+    GenerateConfig {
+        #[arg(long, help = "Print the config instead of writing it")]
+        stdout: bool,
+        #[arg(long, help = "Overwrite an existing config")]
+        force: bool,
+    },
+    /// Make a new active keyset, earlier keysets stay valid for spending
+    ///
+    /// Run while the mint is stopped, it uses the new keyset once started.
+    RotateKeyset {
+        #[arg(
+            long,
+            default_value = "xsr",
+            help = "Unit to rotate, only xsr is supported"
+        )]
+        unit: String,
+    },
+    /// Generate or verify a mnemonic, without network or database access
+    #[command(subcommand)]
+    Mnemonic(MnemonicCommand),
+    /// Export or import the search api database, run while the mint is stopped
+    #[command(subcommand)]
+    Db(DbCommand),
+    /// Compare the amounts issued and redeemed per keyset with the mint db,
+    /// run while the mint is stopped
+    ///
+    /// Exits with an error when any keyset does not match.
+    Audit,
+}
+
+#[derive(Subcommand)]
+pub enum DbCommand {
+    /// Write every table of the search api database to a json file
+    Export {
+        #[arg(long, help = "File to write the dump to")]
+        out: PathBuf,
+    },
+    /// Restore a dump written by export
+    Import {
+        #[arg(long = "in", help = "Dump to read")]
+        input: PathBuf,
+        #[arg(long, help = "Import into a database that already has data")]
+        merge: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum MnemonicCommand {
+    /// Print a new mnemonic
+    Generate {
+        #[arg(
+            long,
+            default_value_t = 24,
+            help = "Number of words, 12, 15, 18, 21 or 24"
+        )]
+        words: usize,
+    },
+    /// Check a mnemonic and print the fingerprint of its XSR key
+    Verify {
+        #[arg(required = true, num_args = 1.., help = "Words of the mnemonic")]
+        phrase: Vec<String>,
+    },
 }
@@ -6,12 +6,12 @@
 use std::path::PathBuf;
 use std::pin::Pin;
 use std::str::FromStr;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
-use bitcoin::hashes::serde::Deserialize;
+use bitcoin::secp256k1::rand;
 use cdk::amount::{to_unit, Amount};
 use cdk::cdk_lightning::{
     self, CreateInvoiceResponse, MintLightning, PayInvoiceResponse, PaymentQuoteResponse, Settings,
@@ -24,7 +24,8 @@ use cdk::nuts::{
 use cdk::util::{hex, unix_time};
 use cdk::{mint, Bolt11Invoice};
 use cln_rpc::model::requests::{
-    InvoiceRequest, ListinvoicesRequest, ListpaysRequest, PayRequest, WaitanyinvoiceRequest,
+    GetinfoRequest, InvoiceRequest, ListinvoicesRequest, ListpaysRequest, PayRequest,
+    WaitanyinvoiceRequest,
 };
 use cln_rpc::model::responses::{
     ListinvoicesInvoices, ListinvoicesInvoicesStatus, ListpaysPaysStatus, PayStatus,
@@ -38,6 +39,25 @@ use tokio::sync::Mutex;
 use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
+use crate::db::{Db, SearchStore};
+use crate::price::{self, cents_to_msats, PriceCache};
+use crate::runtime_settings::SharedRuntimeSettings;
+
+/// Delay before the first attempt to re-dial the CLN socket
+const RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+/// Longest delay between attempts to re-dial the CLN socket
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Reconnect attempts an RPC call makes before giving up
+const RECONNECT_CALL_ATTEMPTS: u32 = 4;
+/// Consecutive failed reconnects after which CLN is reported as down
+const RECONNECT_CIRCUIT_BREAKER_THRESHOLD: u32 = 5;
+/// Delay after the first failed wait for invoices
+const WAIT_INVOICE_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest delay between failed waits for invoices
+const WAIT_INVOICE_MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Failed waits for invoices between error level reports
+const WAIT_INVOICE_FAILURE_REPORT_INTERVAL: u32 = 10;
+
 /// CLN Error
 #[derive(Debug, Error)]
 pub enum Error {
@@ -50,15 +70,27 @@ pub enum Error {
     /// Unknown invoice
     #[error("Unknown invoice")]
     UnknownInvoice,
+    /// Invoice expired without being paid
+    #[error("Invoice expired")]
+    InvoiceExpired,
     /// Invalid payment hash
     #[error("Invalid hash")]
     InvalidHash,
+    /// CLN did not answer an rpc call in time
+    #[error("CLN timed out")]
+    Timeout,
+    /// BTC price could not be fetched to price the invoice
+    #[error("Price unavailable")]
+    PriceUnavailable,
     /// Cln Error
     #[error(transparent)]
     Cln(#[from] cln_rpc::Error),
     /// Cln Rpc Error
     #[error(transparent)]
     ClnRpc(#[from] cln_rpc::RpcError),
+    /// Price Error
+    #[error(transparent)]
+    Price(#[from] price::Error),
     /// Amount Error
     #[error(transparent)]
     Amount(#[from] cdk::amount::Error),
@@ -70,37 +102,136 @@ impl From<Error> for cdk::cdk_lightning::Error {
     }
 }
 
+/// Operator settings for the [`Cln`] backend
+#[derive(Clone)]
+pub struct ClnSettings {
+    /// Path to the lightningd rpc socket
+    pub rpc_socket: PathBuf,
+    pub fee_reserve: FeeReserve,
+    /// Holds the price of one XSR, reloadable without a restart
+    pub runtime_settings: SharedRuntimeSettings,
+    /// Invoice description used when the quote does not give one
+    pub mint_name: String,
+    /// Longest a pay call may take before it is reported as pending
+    pub pay_timeout: Duration,
+    /// Longest any other rpc call may take
+    pub rpc_timeout: Duration,
+    /// Fee cap for pays as a percentage of the amount paid
+    pub max_fee_percent: Option<f64>,
+    /// Seconds CLN keeps retrying a pay for
+    pub retry_for_seconds: Option<u16>,
+    /// Most blocks a pay's funds may be locked up for
+    pub max_delay_blocks: Option<u16>,
+}
+
+/// Connection CLN rpc requests are sent over
+///
+/// Implemented by [`SocketTransport`] for lightningd and by in memory mocks so
+/// the backend can be exercised without a node.
+#[async_trait]
+pub trait ClnTransport: Send + Sync {
+    /// Send `request` and wait for its response
+    async fn call(&mut self, request: Request) -> Result<cln_rpc::Response, cln_rpc::RpcError>;
+
+    /// Drop the current connection and dial a new one
+    async fn reconnect(&mut self) -> Result<(), Error>;
+
+    /// Open another connection to the same node
+    async fn connect(&self) -> Result<Box<dyn ClnTransport>, Error>;
+}
+
+/// [`ClnTransport`] over the lightningd unix socket
+pub struct SocketTransport {
+    rpc_socket: PathBuf,
+    cln_client: cln_rpc::ClnRpc,
+}
+
+impl SocketTransport {
+    /// Connect to the lightningd rpc socket at `rpc_socket`
+    pub async fn new(rpc_socket: PathBuf) -> Result<Self, Error> {
+        let cln_client = cln_rpc::ClnRpc::new(&rpc_socket).await?;
+
+        Ok(Self {
+            rpc_socket,
+            cln_client,
+        })
+    }
+}
+
+#[async_trait]
+impl ClnTransport for SocketTransport {
+    async fn call(&mut self, request: Request) -> Result<cln_rpc::Response, cln_rpc::RpcError> {
+        self.cln_client.call(request).await
+    }
+
+    async fn reconnect(&mut self) -> Result<(), Error> {
+        self.cln_client = cln_rpc::ClnRpc::new(&self.rpc_socket).await?;
+        Ok(())
+    }
+
+    async fn connect(&self) -> Result<Box<dyn ClnTransport>, Error> {
+        Ok(Box::new(Self::new(self.rpc_socket.clone()).await?))
+    }
+}
+
 /// CLN mint backend
 #[derive(Clone)]
 pub struct Cln {
-    rpc_socket: PathBuf,
-    cln_client: Arc<Mutex<cln_rpc::ClnRpc>>,
-    fee_reserve: FeeReserve,
+    settings: ClnSettings,
+    cln_client: Arc<Mutex<Box<dyn ClnTransport>>>,
     mint_settings: MintMethodSettings,
     melt_settings: MeltMethodSettings,
     wait_invoice_cancel_token: CancellationToken,
     wait_invoice_is_active: Arc<AtomicBool>,
+    price_cache: PriceCache,
+    reconnect_failures: Arc<AtomicU32>,
+    db: Db,
 }
 
 impl Cln {
     /// Create new [`Cln`]
+    ///
+    /// `db` persists the last processed pay index across restarts,
+    /// `price_cache` prices invoices for the XSR unit.
     pub async fn new(
-        rpc_socket: PathBuf,
-        fee_reserve: FeeReserve,
+        settings: ClnSettings,
         mint_settings: MintMethodSettings,
         melt_settings: MeltMethodSettings,
+        price_cache: PriceCache,
+        db: Db,
     ) -> Result<Self, Error> {
-        let cln_client = cln_rpc::ClnRpc::new(&rpc_socket).await?;
+        let transport = SocketTransport::new(settings.rpc_socket.clone()).await?;
 
-        Ok(Self {
-            rpc_socket,
-            cln_client: Arc::new(Mutex::new(cln_client)),
-            fee_reserve,
+        Ok(Self::with_transport(
+            settings,
+            Box::new(transport),
+            mint_settings,
+            melt_settings,
+            price_cache,
+            db,
+        ))
+    }
+
+    /// Create new [`Cln`] sending its rpc calls over `transport`
+    pub fn with_transport(
+        settings: ClnSettings,
+        transport: Box<dyn ClnTransport>,
+        mint_settings: MintMethodSettings,
+        melt_settings: MeltMethodSettings,
+        price_cache: PriceCache,
+        db: Db,
+    ) -> Self {
+        Self {
+            settings,
+            cln_client: Arc::new(Mutex::new(transport)),
             mint_settings,
             melt_settings,
             wait_invoice_cancel_token: CancellationToken::new(),
             wait_invoice_is_active: Arc::new(AtomicBool::new(false)),
-        })
+            price_cache,
+            reconnect_failures: Arc::new(AtomicU32::new(0)),
+            db,
+        }
     }
 }
 
@@ -131,8 +262,20 @@ impl MintLightning for Cln {
     async fn wait_any_invoice(
         &self,
     ) -> Result<Pin<Box<dyn Stream<Item = String> + Send>>, Self::Err> {
-        let last_pay_index = self.get_last_pay_index().await?;
-        let cln_client = cln_rpc::ClnRpc::new(&self.rpc_socket).await?;
+        // The persisted index is preferred since the last invoice CLN knows
+        // about is not necessarily the last one processed
+        let last_pay_index = match self.db.get_last_pay_index() {
+            Ok(Some(last_pay_index)) => Some(last_pay_index),
+            Ok(None) => self.get_last_pay_index().await?,
+            Err(err) => {
+                tracing::warn!("Could not read persisted last pay index: {}", err);
+                self.get_last_pay_index().await?
+            }
+        };
+
+        tracing::info!("Waiting for invoices from pay index {:?}", last_pay_index);
+
+        let cln_client = self.cln_client.lock().await.connect().await?;
 
         let stream = futures::stream::unfold(
             (
@@ -140,11 +283,27 @@ impl MintLightning for Cln {
                 last_pay_index,
                 self.wait_invoice_cancel_token.clone(),
                 Arc::clone(&self.wait_invoice_is_active),
+                self.db.clone(),
+                self.settings.rpc_timeout,
+                Arc::clone(&self.reconnect_failures),
             ),
-            |(mut cln_client, mut last_pay_idx, cancel_token, is_active)| async move {
+            |(
+                mut cln_client,
+                mut last_pay_idx,
+                cancel_token,
+                is_active,
+                db,
+                rpc_timeout,
+                reconnect_failures,
+            )| async move {
                 // Set the stream as active
                 is_active.store(true, Ordering::SeqCst);
 
+                // Reset every time an invoice is yielded
+                let mut backoff = WAIT_INVOICE_INITIAL_BACKOFF;
+                let mut failures: u32 = 0;
+                let mut failing_since: Option<Instant> = None;
+
                 loop {
                     tokio::select! {
                         _ = cancel_token.cancelled() => {
@@ -180,7 +339,13 @@ impl MintLightning for Cln {
                             // We only want to yield invoices that have been paid
                             match wait_any_response.status {
                                 WaitanyinvoiceStatus::PAID => (),
-                                WaitanyinvoiceStatus::EXPIRED => continue,
+                                WaitanyinvoiceStatus::EXPIRED => {
+                                    tracing::debug!(
+                                        "Invoice {} expired unpaid",
+                                        wait_any_response.payment_hash
+                                    );
+                                    continue;
+                                }
                             }
 
                             last_pay_idx = wait_any_response.pay_index;
@@ -192,12 +357,14 @@ impl MintLightning for Cln {
                                 // Since this is not returned in the wait any response,
                                 // we need to do a second query for it.
                                 Some(_) => {
-                                    match fetch_invoice_by_payment_hash(
-                                        &mut cln_client,
-                                        &payment_hash,
+                                    let fetched = tokio::time::timeout(
+                                        rpc_timeout,
+                                        fetch_invoice_by_payment_hash(cln_client.as_mut(), &payment_hash),
                                     )
                                     .await
-                                    {
+                                    .unwrap_or(Err(Error::Timeout));
+
+                                    match fetched {
                                         Ok(Some(invoice)) => {
                                             if let Some(local_offer_id) = invoice.local_offer_id {
                                                 local_offer_id.to_string()
@@ -206,6 +373,16 @@ impl MintLightning for Cln {
                                             }
                                         }
                                         Ok(None) => continue,
+                                        Err(Error::Timeout) => {
+                                            tracing::warn!("Timed out fetching invoice by payment hash");
+                                            // A late answer would be read as the
+                                            // response to the next call
+                                            if let Err(err) = cln_client.reconnect().await {
+                                                tracing::warn!("Could not reconnect to CLN: {err}");
+                                            }
+                                            tokio::time::sleep(Duration::from_secs(1)).await;
+                                            continue;
+                                        }
                                         Err(e) => {
                                             tracing::warn!(
                                                 "Error fetching invoice by payment hash: {e}"
@@ -217,11 +394,62 @@ impl MintLightning for Cln {
                                 None => payment_hash,
                             };
 
-                            return Some((request_look_up, (cln_client, last_pay_idx, cancel_token, is_active)));
+                            if let Some(pay_index) = last_pay_idx {
+                                if let Err(err) = db.set_last_pay_index(pay_index) {
+                                    tracing::error!("Could not persist last pay index {}: {}", pay_index, err);
+                                }
+                            }
+
+                            if let Some(amount) = wait_any_response.amount_received_msat {
+                                if let Err(err) = db.add_revenue(&wait_any_response.payment_hash.to_string(), amount.msat()) {
+                                    tracing::error!("Could not record revenue for {}: {}", wait_any_response.payment_hash, err);
+                                }
+                            }
+
+                            return Some((request_look_up, (cln_client, last_pay_idx, cancel_token, is_active, db, rpc_timeout, reconnect_failures)));
+                                }
+                                Err(e) if is_connection_error(&e) => {
+                                    tracing::warn!("Lost connection to CLN while waiting for invoices: {e}");
+
+                                    // The stream holds its own connection so it
+                                    // has to be re-dialed here, until CLN is back
+                                    // or the wait is cancelled
+                                    tokio::select! {
+                                        _ = cancel_token.cancelled() => {
+                                            is_active.store(false, Ordering::SeqCst);
+                                            return None;
+                                        }
+                                        reconnected = reconnect(cln_client.as_mut(), None, &reconnect_failures) => {
+                                            if let Err(err) = reconnected {
+                                                tracing::error!("Could not reconnect to CLN: {err}");
+                                            }
+                                        }
+                                    }
+                                    continue;
                                 }
                                 Err(e) => {
-                                    tracing::warn!("Error fetching invoice: {e}");
-                                    tokio::time::sleep(Duration::from_secs(1)).await;
+                                    failures += 1;
+                                    let failing_since = *failing_since.get_or_insert_with(Instant::now);
+
+                                    if failures % WAIT_INVOICE_FAILURE_REPORT_INTERVAL == 0 {
+                                        tracing::error!(
+                                            "Waiting for invoices still failing after {} attempts over {} minutes: {e}",
+                                            failures,
+                                            failing_since.elapsed().as_secs() / 60
+                                        );
+                                    } else {
+                                        tracing::warn!("Error fetching invoice: {e}");
+                                    }
+
+                                    tokio::select! {
+                                        _ = cancel_token.cancelled() => {
+                                            is_active.store(false, Ordering::SeqCst);
+                                            return None;
+                                        }
+                                        _ = tokio::time::sleep(with_jitter(backoff)) => {}
+                                    }
+
+                                    backoff = (backoff * 2).min(WAIT_INVOICE_MAX_BACKOFF);
                                     continue;
                                 }
                             }
@@ -251,9 +479,9 @@ impl MintLightning for Cln {
         )?;
 
         let relative_fee_reserve =
-            (self.fee_reserve.percent_fee_reserve * u64::from(amount) as f32) as u64;
+            (self.settings.fee_reserve.percent_fee_reserve * u64::from(amount) as f32) as u64;
 
-        let absolute_fee_reserve: u64 = self.fee_reserve.min_fee_reserve.into();
+        let absolute_fee_reserve: u64 = self.settings.fee_reserve.min_fee_reserve.into();
 
         let fee = match relative_fee_reserve > absolute_fee_reserve {
             true => relative_fee_reserve,
@@ -291,38 +519,45 @@ impl MintLightning for Cln {
             }
         }
 
-        let mut cln_client = self.cln_client.lock().await;
-        let cln_response = cln_client
-            .call(Request::Pay(PayRequest {
-                bolt11: melt_quote.request.to_string(),
-                amount_msat: None,
-                label: None,
-                riskfactor: None,
-                maxfeepercent: None,
-                retry_for: None,
-                maxdelay: None,
-                exemptfee: None,
-                localinvreqid: None,
-                exclude: None,
-                maxfee: max_fee
-                    .map(|a| {
-                        let msat = to_unit(a, &melt_quote.unit, &CurrencyUnit::Msat)?;
-                        Ok::<cln_rpc::primitives::Amount, Self::Err>(CLN_Amount::from_msat(
-                            msat.into(),
-                        ))
-                    })
-                    .transpose()?,
-                description: None,
-                partial_msat: partial_amount
-                    .map(|a| {
-                        let msat = to_unit(a, &melt_quote.unit, &CurrencyUnit::Msat)?;
-
-                        Ok::<cln_rpc::primitives::Amount, Self::Err>(CLN_Amount::from_msat(
-                            msat.into(),
-                        ))
-                    })
-                    .transpose()?,
-            }))
+        let partial_msat: Option<u64> = partial_amount
+            .map(|a| to_unit(a, &melt_quote.unit, &CurrencyUnit::Msat).map(u64::from))
+            .transpose()?;
+
+        let quote_max_fee_msat: Option<u64> = max_fee
+            .map(|a| to_unit(a, &melt_quote.unit, &CurrencyUnit::Msat).map(u64::from))
+            .transpose()?;
+
+        let pay_amount_msat = partial_msat
+            .or(bolt11.amount_milli_satoshis())
+            .ok_or(Error::UnknownInvoiceAmount)?;
+
+        let maxfee = max_pay_fee_msat(
+            pay_amount_msat,
+            quote_max_fee_msat,
+            self.settings.max_fee_percent,
+        );
+
+        let cln_response = self
+            .call_dedicated(
+                Request::Pay(PayRequest {
+                    bolt11: melt_quote.request.to_string(),
+                    amount_msat: None,
+                    label: None,
+                    riskfactor: None,
+                    // CLN rejects maxfeepercent alongside maxfee, the operator
+                    // percentage is folded into maxfee instead
+                    maxfeepercent: None,
+                    retry_for: self.settings.retry_for_seconds,
+                    maxdelay: self.settings.max_delay_blocks,
+                    exemptfee: None,
+                    localinvreqid: None,
+                    exclude: None,
+                    maxfee: maxfee.map(CLN_Amount::from_msat),
+                    description: None,
+                    partial_msat: partial_msat.map(CLN_Amount::from_msat),
+                }),
+                self.settings.pay_timeout,
+            )
             .await;
 
         let response = match cln_response {
@@ -344,6 +579,15 @@ impl MintLightning for Cln {
                     unit: melt_quote.unit,
                 }
             }
+            Err(Error::Timeout) => {
+                // The payment may still complete, the mint has to check on it
+                // rather than treat it as failed
+                tracing::warn!(
+                    "Timed out paying invoice: {}",
+                    bolt11.payment_hash().to_string()
+                );
+                return Err(Self::Err::InvoicePaymentPending);
+            }
             _ => {
                 tracing::error!(
                     "Error attempting to pay invoice: {}",
@@ -366,14 +610,31 @@ impl MintLightning for Cln {
         let time_now = unix_time();
         assert!(unix_expiry > time_now);
 
-        let mut cln_client = self.cln_client.lock().await;
-
-        let label = Uuid::new_v4().to_string();
+        // The payment hash is only known once CLN has created the invoice, so
+        // the label carries the unit and creation time with a random suffix to
+        // keep it unique
+        let label = format!(
+            "athenut:{}:{}:{}",
+            unit.to_string().to_lowercase(),
+            time_now,
+            &Uuid::new_v4().simple().to_string()[..8]
+        );
+
+        let description = match description.is_empty() {
+            true => self.settings.mint_name.clone(),
+            false => description,
+        };
 
         let amount =
             if unit == &CurrencyUnit::from_str("XSR").map_err(|_| Error::UnknownInvoiceAmount)? {
-                let usd_price = get_usd_price().await.unwrap();
-                let msats = cents_to_msats(3 * u64::from(amount), usd_price)?;
+                let btc_price = self.price_cache.get_price().await.map_err(|err| {
+                    tracing::error!("Could not price invoice: {}", err);
+                    Error::PriceUnavailable
+                })?;
+                let msats = cents_to_msats(
+                    self.settings.runtime_settings.load().cost_per_search_cents * u64::from(amount),
+                    btc_price,
+                )?;
                 msats.into()
             } else {
                 to_unit(amount, unit, &CurrencyUnit::Msat)?
@@ -381,20 +642,22 @@ impl MintLightning for Cln {
 
         let amount_msat = AmountOrAny::Amount(CLN_Amount::from_msat(amount.into()));
 
-        let cln_response = cln_client
-            .call(cln_rpc::Request::Invoice(InvoiceRequest {
-                amount_msat,
-                description,
-                label: label.clone(),
-                expiry: Some(unix_expiry - time_now),
-                fallbacks: None,
-                preimage: None,
-                cltv: None,
-                deschashonly: None,
-                exposeprivatechannels: None,
-            }))
-            .await
-            .map_err(Error::from)?;
+        let cln_response = self
+            .call(
+                cln_rpc::Request::Invoice(InvoiceRequest {
+                    amount_msat,
+                    description,
+                    label: label.clone(),
+                    expiry: Some(unix_expiry - time_now),
+                    fallbacks: None,
+                    preimage: None,
+                    cltv: None,
+                    deschashonly: None,
+                    exposeprivatechannels: None,
+                }),
+                self.settings.rpc_timeout,
+            )
+            .await?;
 
         match cln_response {
             cln_rpc::Response::Invoice(invoice_res) => {
@@ -402,6 +665,12 @@ impl MintLightning for Cln {
                 let expiry = request.expires_at().map(|t| t.as_secs());
                 let payment_hash = request.payment_hash();
 
+                tracing::debug!(
+                    "Created invoice {} for request lookup id {}",
+                    label,
+                    payment_hash
+                );
+
                 Ok(CreateInvoiceResponse {
                     request_lookup_id: payment_hash.to_string(),
                     request,
@@ -419,26 +688,26 @@ impl MintLightning for Cln {
         &self,
         payment_hash: &str,
     ) -> Result<MintQuoteState, Self::Err> {
-        let mut cln_client = self.cln_client.lock().await;
-
-        let cln_response = cln_client
-            .call(Request::ListInvoices(ListinvoicesRequest {
-                payment_hash: Some(payment_hash.to_string()),
-                label: None,
-                invstring: None,
-                offer_id: None,
-                index: None,
-                limit: None,
-                start: None,
-            }))
-            .await
-            .map_err(Error::from)?;
+        let cln_response = self
+            .call(
+                Request::ListInvoices(ListinvoicesRequest {
+                    payment_hash: Some(payment_hash.to_string()),
+                    label: None,
+                    invstring: None,
+                    offer_id: None,
+                    index: None,
+                    limit: None,
+                    start: None,
+                }),
+                self.settings.rpc_timeout,
+            )
+            .await?;
 
         let status = match cln_response {
             cln_rpc::Response::ListInvoices(invoice_response) => {
                 match invoice_response.invoices.first() {
                     Some(invoice_response) => {
-                        cln_invoice_status_to_mint_state(invoice_response.status)
+                        cln_invoice_status_to_mint_state(invoice_response.status)?
                     }
                     None => {
                         tracing::info!(
@@ -462,16 +731,16 @@ impl MintLightning for Cln {
         &self,
         payment_hash: &str,
     ) -> Result<PayInvoiceResponse, Self::Err> {
-        let mut cln_client = self.cln_client.lock().await;
-
-        let cln_response = cln_client
-            .call(Request::ListPays(ListpaysRequest {
-                payment_hash: Some(payment_hash.parse().map_err(|_| Error::InvalidHash)?),
-                bolt11: None,
-                status: None,
-            }))
-            .await
-            .map_err(Error::from)?;
+        let cln_response = self
+            .call(
+                Request::ListPays(ListpaysRequest {
+                    payment_hash: Some(payment_hash.parse().map_err(|_| Error::InvalidHash)?),
+                    bolt11: None,
+                    status: None,
+                }),
+                self.settings.rpc_timeout,
+            )
+            .await?;
 
         match cln_response {
             cln_rpc::Response::ListPays(pays_response) => match pays_response.pays.first() {
@@ -504,56 +773,110 @@ impl MintLightning for Cln {
     }
 }
 
-#[derive(Debug, Deserialize)]
-struct PriceResponse {
-    #[serde(rename = "USD")]
-    usd: u64,
-}
+impl Cln {
+    /// Make a CLN RPC call that must answer within `timeout`, re-dialing the
+    /// socket and retrying the call once if the connection to lightningd has
+    /// gone stale
+    async fn call(&self, request: Request, timeout: Duration) -> Result<cln_rpc::Response, Error> {
+        let mut cln_client = self.cln_client.lock().await;
 
-async fn get_usd_price() -> Result<u64, Box<dyn std::error::Error>> {
-    let client = reqwest::Client::new();
-    let response = client
-        .get("https://mempool.space/api/v1/prices")
-        .send()
-        .await?
-        .json::<PriceResponse>()
-        .await?;
+        match self
+            .call_with_timeout(cln_client.as_mut(), request.clone(), timeout)
+            .await
+        {
+            Err(Error::ClnRpc(err)) if is_connection_error(&err) => {
+                tracing::warn!("Lost connection to CLN, reconnecting: {}", err);
+
+                reconnect(
+                    cln_client.as_mut(),
+                    Some(RECONNECT_CALL_ATTEMPTS),
+                    &self.reconnect_failures,
+                )
+                .await?;
+
+                self.call_with_timeout(cln_client.as_mut(), request, timeout)
+                    .await
+            }
+            response => response,
+        }
+    }
 
-    Ok(response.usd)
-}
+    /// Make a long running CLN RPC call on a connection of its own so it
+    /// does not hold up calls on the shared connection
+    async fn call_dedicated(
+        &self,
+        request: Request,
+        timeout: Duration,
+    ) -> Result<cln_rpc::Response, Error> {
+        let connection = self.cln_client.lock().await.connect().await;
+
+        let mut cln_client = match connection {
+            Ok(cln_client) => cln_client,
+            Err(err) => {
+                tracing::warn!("Could not open CLN connection, using shared one: {}", err);
+                return self.call(request, timeout).await;
+            }
+        };
 
-fn cents_to_msats(cents: u64, btc_price_dollars: u64) -> Result<u64, Error> {
-    // price_data.USD is price in cents
-    // 1 BTC = 100_000_000_000 msats
-    // 1 BTC = price_data.USD cents
+        self.call_with_timeout(cln_client.as_mut(), request, timeout)
+            .await
+    }
 
-    let bitcoin_price_cents = btc_price_dollars * 100;
+    async fn call_with_timeout(
+        &self,
+        cln_client: &mut dyn ClnTransport,
+        request: Request,
+        timeout: Duration,
+    ) -> Result<cln_rpc::Response, Error> {
+        match tokio::time::timeout(timeout, cln_client.call(request)).await {
+            Ok(response) => Ok(response?),
+            Err(_) => {
+                tracing::warn!("CLN did not answer within {:?}", timeout);
+
+                // A late answer would be read as the response to the next call
+                if let Err(err) = cln_client.reconnect().await {
+                    tracing::warn!("Could not reconnect to CLN: {}", err);
+                }
 
-    // Formula: (cents * 100_000_000_000) / price_data.USD
-    let msats = (cents as u128 * 100_000_000_000u128) / bitcoin_price_cents as u128;
+                Err(Error::Timeout)
+            }
+        }
+    }
 
-    let rounded_sats = (msats + 999) / 1000;
-    let rounded_msats = rounded_sats * 1000;
+    /// Check that the CLN RPC socket answers a getinfo call
+    pub async fn check_connection(&self) -> Result<(), Error> {
+        let cln_response = self
+            .call(
+                cln_rpc::Request::Getinfo(GetinfoRequest {}),
+                self.settings.rpc_timeout,
+            )
+            .await?;
 
-    Ok(rounded_msats as u64)
-}
+        match cln_response {
+            cln_rpc::Response::Getinfo(_) => Ok(()),
+            _ => {
+                tracing::warn!("CLN returned wrong response kind");
+                Err(Error::WrongClnResponse)
+            }
+        }
+    }
 
-impl Cln {
     /// Get last pay index for cln
     async fn get_last_pay_index(&self) -> Result<Option<u64>, Error> {
-        let mut cln_client = self.cln_client.lock().await;
-        let cln_response = cln_client
-            .call(cln_rpc::Request::ListInvoices(ListinvoicesRequest {
-                index: None,
-                invstring: None,
-                label: None,
-                limit: None,
-                offer_id: None,
-                payment_hash: None,
-                start: None,
-            }))
-            .await
-            .map_err(Error::from)?;
+        let cln_response = self
+            .call(
+                cln_rpc::Request::ListInvoices(ListinvoicesRequest {
+                    index: None,
+                    invstring: None,
+                    label: None,
+                    limit: None,
+                    offer_id: None,
+                    payment_hash: None,
+                    start: None,
+                }),
+                self.settings.rpc_timeout,
+            )
+            .await?;
 
         match cln_response {
             cln_rpc::Response::ListInvoices(invoice_res) => match invoice_res.invoices.last() {
@@ -568,11 +891,94 @@ impl Cln {
     }
 }
 
-fn cln_invoice_status_to_mint_state(status: ListinvoicesInvoicesStatus) -> MintQuoteState {
+/// Most fee in msats a payment of `amount_msat` may pay
+///
+/// Both the melt quote's max fee and the operator's `max_fee_percent` cap the
+/// fee, whichever is lower wins. `None` leaves the fee to lightningd's default.
+fn max_pay_fee_msat(
+    amount_msat: u64,
+    quote_max_fee_msat: Option<u64>,
+    max_fee_percent: Option<f64>,
+) -> Option<u64> {
+    let operator_max_fee_msat =
+        max_fee_percent.map(|percent| (amount_msat as f64 * percent / 100.0) as u64);
+
+    match (quote_max_fee_msat, operator_max_fee_msat) {
+        (Some(quote), Some(operator)) => Some(quote.min(operator)),
+        (quote, operator) => quote.or(operator),
+    }
+}
+
+/// Add up to a quarter of `delay` at random so retries spread out
+fn with_jitter(delay: Duration) -> Duration {
+    delay.mul_f64(1.0 + rand::random::<f64>() / 4.0)
+}
+
+/// Whether an RPC error came from the socket rather than from lightningd
+///
+/// Errors returned by lightningd always carry a code, failing to write to or
+/// read from the socket does not.
+fn is_connection_error(err: &cln_rpc::RpcError) -> bool {
+    err.code.is_none()
+}
+
+/// Re-dial the CLN connection with exponential backoff
+///
+/// Gives up after `max_attempts` if set, otherwise keeps trying until CLN is
+/// reachable again. `failures` counts consecutive failed attempts across all
+/// callers so that a CLN that stays down is reported once.
+async fn reconnect(
+    cln_client: &mut dyn ClnTransport,
+    max_attempts: Option<u32>,
+    failures: &AtomicU32,
+) -> Result<(), Error> {
+    let mut backoff = RECONNECT_INITIAL_BACKOFF;
+    let mut attempt = 0;
+
+    loop {
+        attempt += 1;
+
+        match cln_client.reconnect().await {
+            Ok(()) => {
+                if failures.swap(0, Ordering::SeqCst) >= RECONNECT_CIRCUIT_BREAKER_THRESHOLD {
+                    tracing::info!("Connection to CLN restored");
+                } else {
+                    tracing::debug!("Reconnected to CLN");
+                }
+                return Ok(());
+            }
+            Err(err) => {
+                let failed = failures.fetch_add(1, Ordering::SeqCst) + 1;
+
+                if failed == RECONNECT_CIRCUIT_BREAKER_THRESHOLD {
+                    tracing::error!(
+                        "CLN unreachable after {} attempts, lightning operations will fail until it is back",
+                        failed
+                    );
+                } else {
+                    tracing::debug!("Could not reconnect to CLN: {}", err);
+                }
+
+                if max_attempts.is_some_and(|max| attempt >= max) {
+                    return Err(err);
+                }
+            }
+        }
+
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
+    }
+}
+
+fn cln_invoice_status_to_mint_state(
+    status: ListinvoicesInvoicesStatus,
+) -> Result<MintQuoteState, Error> {
     match status {
-        ListinvoicesInvoicesStatus::UNPAID => MintQuoteState::Unpaid,
-        ListinvoicesInvoicesStatus::PAID => MintQuoteState::Paid,
-        ListinvoicesInvoicesStatus::EXPIRED => MintQuoteState::Unpaid,
+        ListinvoicesInvoicesStatus::UNPAID => Ok(MintQuoteState::Unpaid),
+        ListinvoicesInvoicesStatus::PAID => Ok(MintQuoteState::Paid),
+        // The mint has no expired quote state, reporting it as unpaid would
+        // leave wallets polling a quote that can never be paid
+        ListinvoicesInvoicesStatus::EXPIRED => Err(Error::InvoiceExpired),
     }
 }
 
@@ -585,7 +991,7 @@ fn cln_pays_status_to_mint_state(status: ListpaysPaysStatus) -> MeltQuoteState {
 }
 
 async fn fetch_invoice_by_payment_hash(
-    cln_client: &mut cln_rpc::ClnRpc,
+    cln_client: &mut dyn ClnTransport,
     payment_hash: &str,
 ) -> Result<Option<ListinvoicesInvoices>, Error> {
     match cln_client
@@ -613,3 +1019,766 @@ async fn fetch_invoice_by_payment_hash(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::Mutex as StdMutex;
+
+    use bitcoin::hashes::{sha256, Hash};
+    use bitcoin::secp256k1::{Secp256k1, SecretKey};
+    use cdk::lightning_invoice::{Currency, InvoiceBuilder, PaymentSecret};
+    use cln_rpc::model::responses::{
+        ListinvoicesResponse, ListpaysPays, ListpaysResponse, PayResponse,
+    };
+
+    use super::*;
+    use crate::price::{FiatCurrency, PriceSource};
+    use crate::runtime_settings::RuntimeSettings;
+    use crate::test_utils::{temp_dir, FakePriceFetcher};
+
+    /// Node the fake transport talks to
+    #[derive(Default)]
+    struct FakeNode {
+        /// Whether the socket is open, calls fail as a closed socket when not
+        connected: bool,
+        /// Reconnects that fail before the node accepts connections again
+        failing_reconnects: u32,
+        reconnects: u32,
+        calls: u32,
+        /// Answered to listinvoices, filtered by payment hash
+        invoices: Vec<ListinvoicesInvoices>,
+        /// Answered to listpays
+        pays: Vec<ListpaysPays>,
+        /// Answered to pay, after `pay_delay`
+        pay: Option<PayResponse>,
+        pay_delay: Duration,
+        /// Pay calls made
+        pays_made: u32,
+        /// Max fee in msats the last pay was capped at
+        pay_max_fee_msat: Option<u64>,
+        /// Handed out to waitanyinvoice in turn, it waits forever once empty
+        waits: VecDeque<Result<cln_rpc::Response, cln_rpc::RpcError>>,
+        /// Pay index each waitanyinvoice was made from
+        wait_indexes: Vec<Option<u64>>,
+        /// Never answer, like a wedged lightningd
+        unresponsive: bool,
+    }
+
+    struct FakeTransport(Arc<StdMutex<FakeNode>>);
+
+    #[async_trait]
+    impl ClnTransport for FakeTransport {
+        async fn call(&mut self, request: Request) -> Result<cln_rpc::Response, cln_rpc::RpcError> {
+            let (response, delay) = {
+                let mut node = self.0.lock().unwrap();
+                node.calls += 1;
+
+                if !node.connected {
+                    return Err(closed_socket());
+                }
+
+                match request {
+                    _ if node.unresponsive => (None, Duration::ZERO),
+                    Request::ListInvoices(request) => {
+                        let invoices =
+                            node.invoices
+                                .iter()
+                                .filter(|invoice| {
+                                    request.payment_hash.as_ref().map_or(true, |hash| {
+                                        invoice.payment_hash.to_string() == *hash
+                                    })
+                                })
+                                .cloned()
+                                .collect();
+
+                        (
+                            Some(Ok(cln_rpc::Response::ListInvoices(ListinvoicesResponse {
+                                invoices,
+                            }))),
+                            Duration::ZERO,
+                        )
+                    }
+                    Request::ListPays(_) => (
+                        Some(Ok(cln_rpc::Response::ListPays(ListpaysResponse {
+                            pays: node.pays.clone(),
+                        }))),
+                        Duration::ZERO,
+                    ),
+                    Request::Pay(request) => {
+                        node.pays_made += 1;
+                        node.pay_max_fee_msat = request.maxfee.map(|fee| fee.msat());
+
+                        (
+                            node.pay.clone().map(|pay| Ok(cln_rpc::Response::Pay(pay))),
+                            node.pay_delay,
+                        )
+                    }
+                    Request::WaitAnyInvoice(request) => {
+                        node.wait_indexes.push(request.lastpay_index);
+
+                        (node.waits.pop_front(), Duration::ZERO)
+                    }
+                    _ => (
+                        Some(Err(cln_rpc::RpcError {
+                            code: Some(-32601),
+                            message: "Unknown command".to_string(),
+                            data: None,
+                        })),
+                        Duration::ZERO,
+                    ),
+                }
+            };
+
+            tokio::time::sleep(delay).await;
+
+            match response {
+                Some(response) => response,
+                None => std::future::pending().await,
+            }
+        }
+
+        async fn reconnect(&mut self) -> Result<(), Error> {
+            let mut node = self.0.lock().unwrap();
+            node.reconnects += 1;
+
+            if node.failing_reconnects > 0 {
+                node.failing_reconnects -= 1;
+                return Err(Error::Timeout);
+            }
+
+            node.connected = true;
+            Ok(())
+        }
+
+        async fn connect(&self) -> Result<Box<dyn ClnTransport>, Error> {
+            Ok(Box::new(FakeTransport(Arc::clone(&self.0))))
+        }
+    }
+
+    fn closed_socket() -> cln_rpc::RpcError {
+        cln_rpc::RpcError {
+            code: None,
+            message: "socket closed".to_string(),
+            data: None,
+        }
+    }
+
+    /// Signed regtest invoice for `amount_msat`
+    fn test_invoice(amount_msat: u64) -> Bolt11Invoice {
+        let private_key = SecretKey::new(&mut rand::thread_rng());
+        let payment_hash = sha256::Hash::hash(&rand::random::<[u8; 32]>());
+
+        InvoiceBuilder::new(Currency::Regtest)
+            .description("test".to_string())
+            .payment_hash(payment_hash)
+            .payment_secret(PaymentSecret(rand::random()))
+            .amount_milli_satoshis(amount_msat)
+            .current_timestamp()
+            .min_final_cltv_expiry_delta(144)
+            .build_signed(|hash| Secp256k1::new().sign_ecdsa_recoverable(hash, &private_key))
+            .unwrap()
+    }
+
+    /// Sat melt quote paying `bolt11`
+    fn melt_quote(bolt11: &Bolt11Invoice) -> mint::MeltQuote {
+        mint::MeltQuote::new(
+            bolt11.to_string(),
+            CurrencyUnit::Sat,
+            Amount::from(bolt11.amount_milli_satoshis().unwrap() / 1_000),
+            Amount::from(4),
+            unix_time() + 600,
+            bolt11.payment_hash().to_string(),
+        )
+    }
+
+    /// Invoice as listinvoices reports it
+    fn listed_invoice(
+        payment_hash: &str,
+        status: &str,
+        pay_index: Option<u64>,
+    ) -> ListinvoicesInvoices {
+        serde_json::from_value(serde_json::json!({
+            "label": format!("athenut:xsr:0:{}", &payment_hash[..8]),
+            "payment_hash": payment_hash,
+            "status": status,
+            "expires_at": unix_time() + 600,
+            "pay_index": pay_index,
+        }))
+        .unwrap()
+    }
+
+    /// Pay as listpays reports it
+    fn listed_pay(payment_hash: &str, status: &str) -> ListpaysPays {
+        serde_json::from_value(serde_json::json!({
+            "payment_hash": payment_hash,
+            "status": status,
+            "created_at": unix_time(),
+        }))
+        .unwrap()
+    }
+
+    /// Answer to a pay of `amount_msat` ending in `status`
+    fn pay_response(payment_hash: &str, status: &str, amount_msat: u64) -> PayResponse {
+        serde_json::from_value(serde_json::json!({
+            "payment_preimage": "00".repeat(32),
+            "payment_hash": payment_hash,
+            "created_at": unix_time() as f64,
+            "parts": 1,
+            "amount_msat": amount_msat,
+            "amount_sent_msat": amount_msat,
+            "status": status,
+        }))
+        .unwrap()
+    }
+
+    /// Answer to waitanyinvoice for an invoice that ended in `status`
+    fn waited_invoice(payment_hash: &str, status: &str, pay_index: u64) -> cln_rpc::Response {
+        let paid = status == "paid";
+
+        cln_rpc::Response::WaitAnyInvoice(
+            serde_json::from_value(serde_json::json!({
+                "label": format!("athenut:xsr:0:{}", &payment_hash[..8]),
+                "payment_hash": payment_hash,
+                "status": status,
+                "expires_at": unix_time() + 600,
+                "pay_index": paid.then_some(pay_index),
+                "amount_received_msat": paid.then_some(1_000),
+            }))
+            .unwrap(),
+        )
+    }
+
+    fn fake_cln(node: &Arc<StdMutex<FakeNode>>, price_cache: PriceCache) -> Cln {
+        fake_cln_with_db(
+            node,
+            price_cache,
+            Db::new(&temp_dir().join("search.redb"), 400).unwrap(),
+        )
+    }
+
+    /// [`fake_cln`] keeping its pay index in `db`
+    fn fake_cln_with_db(node: &Arc<StdMutex<FakeNode>>, price_cache: PriceCache, db: Db) -> Cln {
+        let settings = ClnSettings {
+            rpc_socket: PathBuf::from("/nonexistent/lightning-rpc"),
+            fee_reserve: FeeReserve {
+                min_fee_reserve: Amount::from(4),
+                percent_fee_reserve: 0.04,
+            },
+            runtime_settings: SharedRuntimeSettings::new(RuntimeSettings::default()),
+            mint_name: "Test Mint".to_string(),
+            pay_timeout: Duration::from_secs(60),
+            rpc_timeout: Duration::from_secs(10),
+            max_fee_percent: None,
+            retry_for_seconds: None,
+            max_delay_blocks: None,
+        };
+
+        Cln::with_transport(
+            settings,
+            Box::new(FakeTransport(Arc::clone(node))),
+            MintMethodSettings::default(),
+            MeltMethodSettings::default(),
+            price_cache,
+            db,
+        )
+    }
+
+    fn fake_price_cache(fetcher: Arc<FakePriceFetcher>) -> PriceCache {
+        PriceCache::with_fetcher(
+            Duration::from_secs(60),
+            Duration::from_secs(600),
+            FiatCurrency::Usd,
+            PriceSource::DEFAULT.to_vec(),
+            1_000,
+            10_000_000,
+            fetcher,
+        )
+    }
+
+    fn connected_node() -> Arc<StdMutex<FakeNode>> {
+        Arc::new(StdMutex::new(FakeNode {
+            connected: true,
+            ..Default::default()
+        }))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn call_recovers_when_the_socket_closes_mid_run() {
+        let node = connected_node();
+        let cln = fake_cln(
+            &node,
+            fake_price_cache(Arc::new(FakePriceFetcher::new(200, ""))),
+        );
+
+        assert_eq!(cln.get_last_pay_index().await.unwrap(), None);
+
+        // lightningd restarts, closing the socket under the open connection
+        {
+            let mut node = node.lock().unwrap();
+            node.connected = false;
+            node.failing_reconnects = 2;
+        }
+
+        assert_eq!(cln.get_last_pay_index().await.unwrap(), None);
+
+        let node = node.lock().unwrap();
+        assert_eq!(node.reconnects, 3);
+        // The failed call and its retry, after the first one
+        assert_eq!(node.calls, 3);
+        assert_eq!(cln.reconnect_failures.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn call_fails_when_cln_stays_down() {
+        let node = Arc::new(StdMutex::new(FakeNode {
+            connected: false,
+            failing_reconnects: u32::MAX,
+            ..Default::default()
+        }));
+        let cln = fake_cln(
+            &node,
+            fake_price_cache(Arc::new(FakePriceFetcher::new(200, ""))),
+        );
+
+        assert!(cln.get_last_pay_index().await.is_err());
+
+        assert_eq!(node.lock().unwrap().reconnects, RECONNECT_CALL_ATTEMPTS);
+        assert_eq!(
+            cln.reconnect_failures.load(Ordering::SeqCst),
+            RECONNECT_CALL_ATTEMPTS
+        );
+    }
+
+    #[tokio::test]
+    async fn xsr_invoice_fails_cleanly_when_price_endpoints_return_500() {
+        let node = connected_node();
+        let fetcher = Arc::new(FakePriceFetcher::new(500, "Internal Server Error"));
+        let cln = fake_cln(&node, fake_price_cache(fetcher.clone()));
+
+        let result = cln
+            .create_invoice(
+                Amount::from(1),
+                &CurrencyUnit::from_str("XSR").unwrap(),
+                String::new(),
+                unix_time() + 3600,
+            )
+            .await;
+
+        match result {
+            Err(cdk_lightning::Error::Lightning(err)) => {
+                assert_eq!(err.to_string(), Error::PriceUnavailable.to_string())
+            }
+            Err(err) => panic!("Expected price unavailable, got {}", err),
+            Ok(_) => panic!("Invoice created without a price"),
+        }
+
+        // Every source was tried and CLN was never asked for an invoice
+        assert_eq!(fetcher.calls(), PriceSource::DEFAULT.len() as u32);
+        assert_eq!(node.lock().unwrap().calls, 0);
+    }
+
+    #[tokio::test]
+    async fn pay_is_not_attempted_for_an_invoice_already_paid_or_pending() {
+        for (status, already) in [("complete", "paid"), ("pending", "pending")] {
+            let node = connected_node();
+            let cln = fake_cln(
+                &node,
+                fake_price_cache(Arc::new(FakePriceFetcher::new(200, ""))),
+            );
+
+            let bolt11 = test_invoice(10_000);
+            let payment_hash = bolt11.payment_hash().to_string();
+            {
+                let mut node = node.lock().unwrap();
+                node.pays = vec![listed_pay(&payment_hash, status)];
+                node.pay = Some(pay_response(&payment_hash, "complete", 10_000));
+            }
+
+            let result = cln.pay_invoice(melt_quote(&bolt11), None, None).await;
+
+            match (already, result) {
+                ("paid", Err(cdk_lightning::Error::InvoiceAlreadyPaid)) => (),
+                ("pending", Err(cdk_lightning::Error::InvoicePaymentPending)) => (),
+                (_, Err(err)) => panic!("Expected invoice already {}, got {}", already, err),
+                (_, Ok(_)) => panic!("Invoice already {} was paid again", already),
+            }
+
+            assert_eq!(node.lock().unwrap().pays_made, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn incoming_status_of_an_unknown_payment_hash_is_an_error() {
+        let node = connected_node();
+        node.lock().unwrap().invoices = vec![listed_invoice(&"ab".repeat(32), "paid", Some(1))];
+        let cln = fake_cln(
+            &node,
+            fake_price_cache(Arc::new(FakePriceFetcher::new(200, ""))),
+        );
+
+        assert!(cln
+            .check_incoming_invoice_status(&"cd".repeat(32))
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn listed_pay_statuses_map_to_melt_states() {
+        assert_eq!(
+            cln_pays_status_to_mint_state(ListpaysPaysStatus::PENDING),
+            MeltQuoteState::Pending
+        );
+        assert_eq!(
+            cln_pays_status_to_mint_state(ListpaysPaysStatus::COMPLETE),
+            MeltQuoteState::Paid
+        );
+        assert_eq!(
+            cln_pays_status_to_mint_state(ListpaysPaysStatus::FAILED),
+            MeltQuoteState::Failed
+        );
+    }
+
+    #[tokio::test]
+    async fn pay_statuses_map_to_melt_states() {
+        for (status, state) in [
+            ("complete", MeltQuoteState::Paid),
+            ("pending", MeltQuoteState::Pending),
+            ("failed", MeltQuoteState::Failed),
+        ] {
+            let node = connected_node();
+            let cln = fake_cln(
+                &node,
+                fake_price_cache(Arc::new(FakePriceFetcher::new(200, ""))),
+            );
+
+            let bolt11 = test_invoice(10_000);
+            node.lock().unwrap().pay = Some(pay_response(
+                &bolt11.payment_hash().to_string(),
+                status,
+                10_000,
+            ));
+
+            let response = cln
+                .pay_invoice(melt_quote(&bolt11), None, None)
+                .await
+                .unwrap();
+
+            assert_eq!(response.status, state);
+            assert_eq!(response.total_spent, Amount::from(10));
+            assert_eq!(node.lock().unwrap().pays_made, 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn outgoing_status_of_an_unknown_payment_hash_is_unknown() {
+        let node = connected_node();
+        let cln = fake_cln(
+            &node,
+            fake_price_cache(Arc::new(FakePriceFetcher::new(200, ""))),
+        );
+
+        let response = cln.check_outgoing_payment(&"ab".repeat(32)).await.unwrap();
+
+        assert_eq!(response.status, MeltQuoteState::Unknown);
+        assert_eq!(response.total_spent, Amount::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_resumes_from_the_persisted_pay_index() {
+        let node = connected_node();
+        let first = "ab".repeat(32);
+        {
+            let mut node = node.lock().unwrap();
+            // The last invoice CLN lists is not the last one processed
+            node.invoices = vec![listed_invoice(&"ef".repeat(32), "paid", Some(9))];
+            node.waits.push_back(Ok(waited_invoice(&first, "paid", 5)));
+        }
+        let cln = fake_cln(
+            &node,
+            fake_price_cache(Arc::new(FakePriceFetcher::new(200, ""))),
+        );
+
+        let mut stream = cln.wait_any_invoice().await.unwrap();
+        assert_eq!(stream.next().await, Some(first));
+        drop(stream);
+
+        assert_eq!(cln.db.get_last_pay_index().unwrap(), Some(5));
+
+        // The mint restarts over the same db
+        let second = "cd".repeat(32);
+        node.lock()
+            .unwrap()
+            .waits
+            .push_back(Ok(waited_invoice(&second, "paid", 6)));
+        let restarted = fake_cln_with_db(
+            &node,
+            fake_price_cache(Arc::new(FakePriceFetcher::new(200, ""))),
+            cln.db.clone(),
+        );
+
+        let mut stream = restarted.wait_any_invoice().await.unwrap();
+        assert_eq!(stream.next().await, Some(second));
+
+        assert_eq!(node.lock().unwrap().wait_indexes, vec![Some(9), Some(5)]);
+        assert_eq!(restarted.db.get_last_pay_index().unwrap(), Some(6));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_carries_on_after_the_connection_drops() {
+        let node = connected_node();
+        let payment_hash = "ab".repeat(32);
+        {
+            let mut node = node.lock().unwrap();
+            node.waits.push_back(Err(closed_socket()));
+            node.waits.push_back(Err(cln_rpc::RpcError {
+                code: Some(-1),
+                message: "internal error".to_string(),
+                data: None,
+            }));
+            node.waits
+                .push_back(Ok(waited_invoice(&payment_hash, "paid", 1)));
+        }
+        let cln = fake_cln(
+            &node,
+            fake_price_cache(Arc::new(FakePriceFetcher::new(200, ""))),
+        );
+
+        let mut stream = cln.wait_any_invoice().await.unwrap();
+        assert_eq!(stream.next().await, Some(payment_hash));
+        assert!(cln.is_wait_invoice_active());
+
+        {
+            let node = node.lock().unwrap();
+            assert_eq!(node.reconnects, 1);
+            assert_eq!(node.wait_indexes, vec![None, None, None]);
+        }
+
+        cln.cancel_wait_invoice();
+        assert_eq!(stream.next().await, None);
+        assert!(!cln.is_wait_invoice_active());
+    }
+
+    #[test]
+    fn listed_invoice_statuses_map_to_mint_states() {
+        assert_eq!(
+            cln_invoice_status_to_mint_state(ListinvoicesInvoicesStatus::UNPAID).unwrap(),
+            MintQuoteState::Unpaid
+        );
+        assert_eq!(
+            cln_invoice_status_to_mint_state(ListinvoicesInvoicesStatus::PAID).unwrap(),
+            MintQuoteState::Paid
+        );
+        assert!(matches!(
+            cln_invoice_status_to_mint_state(ListinvoicesInvoicesStatus::EXPIRED),
+            Err(Error::InvoiceExpired)
+        ));
+    }
+
+    #[tokio::test]
+    async fn quote_polled_until_its_invoice_expires() {
+        let node = connected_node();
+        let payment_hash = "ab".repeat(32);
+        node.lock().unwrap().invoices = vec![listed_invoice(&payment_hash, "unpaid", None)];
+        let cln = fake_cln(
+            &node,
+            fake_price_cache(Arc::new(FakePriceFetcher::new(200, ""))),
+        );
+
+        assert_eq!(
+            cln.check_incoming_invoice_status(&payment_hash)
+                .await
+                .unwrap(),
+            MintQuoteState::Unpaid
+        );
+
+        node.lock().unwrap().invoices = vec![listed_invoice(&payment_hash, "expired", None)];
+
+        match cln.check_incoming_invoice_status(&payment_hash).await {
+            Err(cdk_lightning::Error::Lightning(err)) => {
+                assert_eq!(err.to_string(), Error::InvoiceExpired.to_string())
+            }
+            Err(err) => panic!("Expected invoice expired, got {}", err),
+            Ok(state) => panic!("Expired invoice reported as {:?}", state),
+        }
+    }
+
+    #[tokio::test]
+    async fn wait_passes_over_expired_invoices() {
+        let node = connected_node();
+        let expired = "ab".repeat(32);
+        let paid = "cd".repeat(32);
+        {
+            let mut node = node.lock().unwrap();
+            node.waits
+                .push_back(Ok(waited_invoice(&expired, "expired", 1)));
+            node.waits.push_back(Ok(waited_invoice(&paid, "paid", 1)));
+        }
+        let cln = fake_cln(
+            &node,
+            fake_price_cache(Arc::new(FakePriceFetcher::new(200, ""))),
+        );
+
+        let mut stream = cln.wait_any_invoice().await.unwrap();
+        assert_eq!(stream.next().await, Some(paid));
+
+        // Expired invoices carry no pay index to move past
+        assert_eq!(node.lock().unwrap().wait_indexes, vec![None, None]);
+        assert_eq!(cln.db.get_last_pay_index().unwrap(), Some(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn calls_to_a_wedged_node_time_out() {
+        let node = connected_node();
+        node.lock().unwrap().unresponsive = true;
+        let cln = fake_cln(
+            &node,
+            fake_price_cache(Arc::new(FakePriceFetcher::new(200, ""))),
+        );
+
+        let started = tokio::time::Instant::now();
+        assert!(matches!(cln.check_connection().await, Err(Error::Timeout)));
+        assert!(started.elapsed() >= cln.settings.rpc_timeout);
+        // The connection is re-dialed so a late answer is never read
+        assert_eq!(node.lock().unwrap().reconnects, 1);
+
+        // A pay that never ends may still complete, so it is reported
+        // pending once it times out
+        node.lock().unwrap().unresponsive = false;
+        let started = tokio::time::Instant::now();
+        match cln
+            .pay_invoice(melt_quote(&test_invoice(10_000)), None, None)
+            .await
+        {
+            Err(cdk_lightning::Error::InvoicePaymentPending) => (),
+            Err(err) => panic!("Expected payment pending, got {}", err),
+            Ok(_) => panic!("Pay answered by a wedged node"),
+        }
+        assert!(started.elapsed() >= cln.settings.pay_timeout);
+        assert_eq!(node.lock().unwrap().pays_made, 1);
+    }
+
+    #[test]
+    fn stricter_of_quote_and_operator_max_fee_wins() {
+        for (quote, percent, expected) in [
+            (None, None, None),
+            (Some(50), None, Some(50)),
+            (None, Some(2.0), Some(200)),
+            (Some(50), Some(2.0), Some(50)),
+            (Some(500), Some(2.0), Some(200)),
+            (Some(0), Some(2.0), Some(0)),
+        ] {
+            assert_eq!(
+                max_pay_fee_msat(10_000, quote, percent),
+                expected,
+                "quote {:?} percent {:?}",
+                quote,
+                percent
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn pay_is_capped_at_the_stricter_max_fee() {
+        let node = connected_node();
+        let mut cln = fake_cln(
+            &node,
+            fake_price_cache(Arc::new(FakePriceFetcher::new(200, ""))),
+        );
+        cln.settings.max_fee_percent = Some(1.0);
+
+        let bolt11 = test_invoice(1_000_000);
+        node.lock().unwrap().pay = Some(pay_response(
+            &bolt11.payment_hash().to_string(),
+            "complete",
+            1_000_000,
+        ));
+
+        // 1% of 1000 sats is below the quote's 40 sat reserve
+        cln.pay_invoice(melt_quote(&bolt11), None, Some(Amount::from(40)))
+            .await
+            .unwrap();
+        assert_eq!(node.lock().unwrap().pay_max_fee_msat, Some(10_000));
+
+        let bolt11 = test_invoice(1_000_000);
+        node.lock().unwrap().pay = Some(pay_response(
+            &bolt11.payment_hash().to_string(),
+            "complete",
+            1_000_000,
+        ));
+
+        cln.pay_invoice(melt_quote(&bolt11), None, Some(Amount::from(4)))
+            .await
+            .unwrap();
+        assert_eq!(node.lock().unwrap().pay_max_fee_msat, Some(4_000));
+    }
+
+    #[tokio::test]
+    async fn fee_reserve_is_the_larger_of_percent_and_minimum() {
+        let node = connected_node();
+        let cln = fake_cln(
+            &node,
+            fake_price_cache(Arc::new(FakePriceFetcher::new(200, ""))),
+        );
+
+        // 4% with a 4 sat minimum
+        for (amount_msat, fee) in [(1_000_000, 40), (10_000, 4), (100_000, 4)] {
+            let request: MeltQuoteBolt11Request = serde_json::from_value(serde_json::json!({
+                "request": test_invoice(amount_msat).to_string(),
+                "unit": "sat",
+            }))
+            .unwrap();
+
+            let quote = cln.get_payment_quote(&request).await.unwrap();
+
+            assert_eq!(quote.amount, Amount::from(amount_msat / 1_000));
+            assert_eq!(quote.fee, Amount::from(fee), "{} msats", amount_msat);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn status_check_is_not_held_up_by_a_slow_pay() {
+        let node = connected_node();
+        let cln = fake_cln(
+            &node,
+            fake_price_cache(Arc::new(FakePriceFetcher::new(200, ""))),
+        );
+
+        let bolt11 = test_invoice(10_000);
+        {
+            let mut node = node.lock().unwrap();
+            node.pay = Some(pay_response(
+                &bolt11.payment_hash().to_string(),
+                "complete",
+                10_000,
+            ));
+            node.pay_delay = Duration::from_secs(30);
+        }
+
+        let pay = tokio::spawn({
+            let cln = cln.clone();
+            async move { cln.pay_invoice(melt_quote(&bolt11), None, None).await }
+        });
+
+        // Let the pay get under way
+        while node.lock().unwrap().pays_made == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let started = tokio::time::Instant::now();
+        let status = tokio::time::timeout(
+            Duration::from_secs(1),
+            cln.check_outgoing_payment(&"ab".repeat(32)),
+        )
+        .await
+        .expect("status check is not blocked by the pay")
+        .unwrap();
+
+        assert_eq!(status.status, MeltQuoteState::Unknown);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(!pay.is_finished());
+
+        let paid = pay.await.unwrap().unwrap();
+        assert_eq!(paid.status, MeltQuoteState::Paid);
+    }
+}
@@ -1,24 +1,86 @@
-use std::path::PathBuf;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
+use bip39::Mnemonic;
+use cdk::mint_url::MintUrl;
 use cdk::nuts::PublicKey;
 use cdk::Amount;
-use config::{Config, ConfigError, File};
+use config::{Config, ConfigError, File, FileFormat};
+use reqwest::Url;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::dev_lightning::is_dev_url;
+use crate::expand_path;
+use crate::price::{FiatCurrency, PriceSource};
+use crate::search_provider::SafeSearch;
+
+/// Environment variable the mnemonic may be read from instead of the config
+pub const MNEMONIC_ENV_VAR: &str = "ATHENUT_MNEMONIC";
+
+/// Shown in place of secrets by [`Settings::redacted`]
+const REDACTED: &str = "********";
+
+/// Largest keyset max order, denominations past 2^63 do not fit in an amount
+pub const MAX_KEYSET_MAX_ORDER: u8 = 64;
+
+/// Problems found by [`Settings::validate`], one per invalid field
+#[derive(Debug, Error)]
+#[error("Invalid config:\n  {}", .0.join("\n  "))]
+pub struct ValidationError(pub Vec<String>);
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Info {
     pub url: String,
     pub listen_host: String,
     pub listen_port: u16,
+    /// Host the search api gets a listener of its own on, together with
+    /// `search_listen_port`. Served on `listen_host` with the mint api when unset.
+    pub search_listen_host: Option<String>,
+    pub search_listen_port: Option<u16>,
     pub mnemonic: String,
+    /// File the mnemonic is read from instead of `mnemonic`
+    pub mnemonic_path: Option<PathBuf>,
     pub seconds_quote_is_valid_for: Option<u64>,
     pub seconds_to_cache_requests_for: Option<u64>,
     pub seconds_to_extend_cache_by: Option<u64>,
     pub input_fee_ppk: Option<u64>,
+    /// Number of power of two denominations in the XSR keyset, 1 to 64
+    ///
+    /// Changing it, like changing `input_fee_ppk`, makes the mint generate a
+    /// new active keyset on the next start. Earlier keysets stay in the
+    /// database so tokens issued under them can still be spent, cdk does not
+    /// mark them inactive, use `rotate-keyset` for that.
+    pub keyset_max_order: Option<u8>,
+    /// PEM certificate chain to serve TLS with, plain http when unset
+    pub tls_cert_path: Option<PathBuf>,
+    /// PEM private key for `tls_cert_path`
+    pub tls_key_path: Option<PathBuf>,
+    /// Mint database, `cdk-mintd.redb` in the work dir when unset
+    pub mint_db_path: Option<PathBuf>,
+    /// Search api database, `athenmint_search_api.redb` in the work dir when
+    /// unset
+    pub search_db_path: Option<PathBuf>,
+}
+
+/// Lightning backend the mint is paid through
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LnBackend {
+    #[default]
+    Cln,
+    Phoenixd,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Ln {
+    /// Connect to `ln_backend`, on by default. When off every mint quote is
+    /// reported paid without a payment, for development only.
+    pub enable_ln: Option<bool>,
+    #[serde(default)]
+    pub ln_backend: LnBackend,
     pub fee_percent: f32,
     pub reserve_fee_min: Amount,
 }
@@ -26,11 +88,181 @@ pub struct Ln {
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Cln {
     pub rpc_path: PathBuf,
+    /// Seconds a pay call may take before the payment is reported as pending
+    pub pay_timeout_secs: Option<u64>,
+    /// Seconds any other rpc call may take
+    pub rpc_timeout_secs: Option<u64>,
+    /// Most fee a melt may pay as a percentage of the amount paid
+    ///
+    /// A melt quote's own max fee still applies, the lower of the two caps
+    /// the payment. Unset leaves the cap to lightningd's default.
+    pub max_fee_percent: Option<f64>,
+    /// Seconds lightningd keeps retrying a payment for
+    pub retry_for_seconds: Option<u16>,
+    /// Most blocks a payment's funds may be locked up for
+    pub max_delay_blocks: Option<u16>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Price {
+    /// Seconds a fetched BTC price is used for before it is refreshed
+    pub cache_ttl_secs: Option<u64>,
+    /// Seconds past the ttl a stale price is still served while it can not
+    /// be refreshed, quotes fail after that
+    pub max_stale_secs: Option<u64>,
+    /// Sources the BTC price is fetched from, tried in order
+    pub sources: Option<Vec<PriceSource>>,
+    /// Lowest BTC price in whole units of the price currency accepted from a source
+    pub min_price: Option<u64>,
+    /// Highest BTC price in whole units of the price currency accepted from a source
+    pub max_price: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Phoenixd {
+    pub url: String,
+    pub api_password: String,
+}
+
+/// Format log lines are written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Logging {
+    /// Tracing filter such as `info` or `info,athenut_mint=debug`, `RUST_LOG`
+    /// takes precedence when set
+    pub level: Option<String>,
+    #[serde(default)]
+    pub format: LogFormat,
+    /// File logs are also written to, rotated daily with the date appended
+    pub file_path: Option<PathBuf>,
+}
+
+/// Publishing the mint on nostr
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Nostr {
+    /// Relays events are published to
+    #[serde(default)]
+    pub relays: Vec<String>,
+    /// Publish a mint announcement on startup and a status note periodically
+    pub announce: Option<bool>,
+    /// Hex or nsec key events are signed with, derived from the mnemonic when
+    /// unset
+    pub secret_key: Option<String>,
+    /// Seconds between status notes
+    pub status_interval_secs: Option<u64>,
+}
+
+/// Nostr DMs to the operator when the mint needs attention
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Alerts {
+    pub enabled: Option<bool>,
+    /// Relays DMs are sent through
+    #[serde(default)]
+    pub relays: Vec<String>,
+    /// Alert when the Kagi api balance in USD drops below this
+    pub kagi_balance_threshold: Option<f64>,
+    /// Alert when the lightning node has been unreachable this long
+    pub lightning_unreachable_mins: Option<u64>,
+    /// Alert when no BTC price could be fetched for this long
+    pub price_unavailable_mins: Option<u64>,
+}
+
+/// Upstream search provider
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchProviderKind {
+    #[default]
+    Kagi,
+    Brave,
+}
+
+/// What is recorded about searches beyond the count
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Analytics {
+    /// Nothing
+    #[default]
+    Off,
+    /// Counts by keyed hash of the query and by length and result count
+    Hashed,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct SearchSettings {
+    #[serde(default)]
+    pub provider: SearchProviderKind,
     pub kagi_auth_token: String,
+    /// File the kagi token is read from instead of `kagi_auth_token`
+    pub kagi_auth_token_path: Option<PathBuf>,
+    /// Kagi api url, `https://kagi.com/api/v0` when unset
+    pub kagi_base_url: Option<String>,
+    pub kagi_timeout_secs: Option<u64>,
+    pub kagi_max_retries: Option<u32>,
+    /// Warn when the kagi api balance in USD drops below this
+    pub kagi_low_balance: Option<f64>,
+    /// Price of one XSR, a single search, in the minor unit of `price_currency`
+    pub cost_per_search_cents: Option<u64>,
+    /// Fiat currency searches are priced in, USD by default
+    pub price_currency: Option<FiatCurrency>,
+    /// Price of an `/answer` request in XSR
+    pub answer_price: Option<u64>,
+    /// Price of a `/summarize` request in XSR
+    pub summarize_price: Option<u64>,
+    /// Most results returned for a search
+    pub max_results: Option<u64>,
+    pub brave_auth_token: Option<String>,
+    pub brave_timeout_secs: Option<u64>,
+    pub cors_allowed_origins: Option<Vec<String>>,
+    /// Paid requests a client ip may make per minute
+    pub rate_limit_per_minute: Option<u32>,
+    /// Requests with a missing or invalid token a client ip may make per minute
+    pub invalid_rate_limit_per_minute: Option<u32>,
+    /// Proxies whose `X-Forwarded-For` header is used for the client ip,
+    /// otherwise the client is the connecting address
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Seconds search results are cached for
+    pub search_cache_ttl_secs: Option<u64>,
+    /// Most queries kept in the search result cache, 0 disables caching
+    pub search_cache_size: Option<usize>,
+    /// Region used for searches that do not ask for one
+    pub default_region: Option<String>,
+    /// Minimum safe search level, clients may only ask for stricter filtering
+    pub safesearch: Option<SafeSearch>,
+    /// Serve the OpenAPI spec at `/openapi.json` and Swagger UI at `/docs`
+    pub openapi: Option<bool>,
+    /// Return result titles and descriptions as plain text instead of html
+    /// limited to `<b>` and `<i>`
+    pub strip_html: Option<bool>,
+    /// Drop results that point at the same page as an earlier result, on by default
+    pub dedup_results: Option<bool>,
+    /// Seconds a prepaid session may go unused before it expires
+    pub session_idle_secs: Option<u64>,
+    /// Bearer token for the `/operator` endpoints, they are not served when unset
+    pub operator_token: Option<String>,
+    /// Days daily search counts are kept for
+    pub search_count_retention_days: Option<u64>,
+    /// Seconds between checks whether the search db needs compacting, never
+    /// when 0
+    pub db_compaction_interval_secs: Option<u64>,
+    /// Compact the search db once this percent of the file is fragmented
+    pub db_compaction_fragmented_percent: Option<u64>,
+    /// Count searches by hashed query, queries are never stored
+    #[serde(default)]
+    pub analytics: Analytics,
+    /// Domains whose results are dropped, subdomains included
+    #[serde(default)]
+    pub blocked_domains: Vec<String>,
+    /// Domains whose results are moved to the top, subdomains included
+    #[serde(default)]
+    pub boosted_domains: Vec<String>,
 }
 
 /// CDK settings, derived from `config.toml`
@@ -39,8 +271,18 @@ pub struct Settings {
     pub info: Info,
     pub mint_info: MintInfo,
     pub cln: Cln,
+    #[serde(default)]
+    pub phoenixd: Phoenixd,
     pub ln: Ln,
+    #[serde(default)]
+    pub price: Price,
     pub search_settings: SearchSettings,
+    #[serde(default)]
+    pub logging: Logging,
+    #[serde(default)]
+    pub nostr: Nostr,
+    #[serde(default)]
+    pub alerts: Alerts,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -57,49 +299,945 @@ pub struct MintInfo {
     pub icon_url: Option<String>,
     /// message of the day that the wallet must display to the user
     pub motd: Option<String>,
-    /// Nostr publickey
+    /// Nostr publickey, kept for older configs, prefer `contacts`
     pub contact_nostr_public_key: Option<String>,
-    /// Contact email
+    /// Contact email, kept for older configs, prefer `contacts`
     pub contact_email: Option<String>,
+    /// Ways to contact the operator, any NUT-06 method
+    #[serde(default)]
+    pub contacts: Vec<Contact>,
+    /// Url of the terms of service
+    pub tos_url: Option<String>,
+    /// Url of the privacy policy
+    pub privacy_policy_url: Option<String>,
+    /// Serve a page about the mint at `/`, true when unset
+    pub serve_landing_page: Option<bool>,
 }
 
-impl Settings {
-    #[must_use]
-    pub fn new(config_file_name: &Option<PathBuf>) -> Self {
-        let default_settings = Self::default();
-        // attempt to construct settings with file
-        let from_file = Self::new_from_default(&default_settings, config_file_name);
-        match from_file {
-            Ok(f) => f,
-            Err(e) => {
-                tracing::warn!("Error reading config file ({:?})", e);
-                default_settings
+/// Contact method of the operator, such as `email`, `nostr`, `twitter`,
+/// `telegram` or `website`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Contact {
+    pub method: String,
+    pub info: String,
+}
+
+impl MintInfo {
+    /// Nostr pubkey of the operator, from the legacy field or `contacts`
+    pub fn operator_nostr_pubkey(&self) -> Option<String> {
+        self.all_contacts()
+            .into_iter()
+            .find(|contact| contact.method == "nostr")
+            .map(|contact| contact.info)
+    }
+
+    /// Legacy contact fields followed by `contacts`, without repeats
+    pub fn all_contacts(&self) -> Vec<Contact> {
+        let legacy = [
+            ("nostr", &self.contact_nostr_public_key),
+            ("email", &self.contact_email),
+        ]
+        .into_iter()
+        .filter_map(|(method, info)| {
+            info.as_ref().map(|info| Contact {
+                method: method.to_string(),
+                info: info.clone(),
+            })
+        });
+
+        let mut contacts: Vec<Contact> = Vec::new();
+
+        for contact in legacy.chain(self.contacts.iter().cloned()) {
+            let contact = Contact {
+                method: contact.method.trim().to_lowercase(),
+                info: contact.info.trim().to_string(),
+            };
+
+            if !contact.info.is_empty() && !contacts.contains(&contact) {
+                contacts.push(contact);
             }
         }
+
+        contacts
     }
+}
 
-    fn new_from_default(
-        default: &Settings,
-        config_file_name: &Option<PathBuf>,
+impl Settings {
+    /// Load settings from `config_file_name`
+    ///
+    /// A config file passed explicitly must exist and parse. Without one
+    /// `default_config_file` is read if it exists, otherwise the defaults are
+    /// used and left for [`Settings::validate`] to reject.
+    ///
+    /// Secrets configured as files or environment variables are read in
+    /// before returning.
+    pub fn new(
+        config_file_name: Option<&Path>,
+        default_config_file: &Path,
     ) -> Result<Self, ConfigError> {
-        let mut default_config_file_name = home::home_dir()
-            .ok_or(ConfigError::NotFound("Config Path".to_string()))?
-            .join("cashu-rs-mint");
-
-        default_config_file_name.push("config.toml");
-        let config: String = match config_file_name {
-            Some(value) => value.clone().to_string_lossy().to_string(),
-            None => default_config_file_name.to_string_lossy().to_string(),
+        let default_settings = Self::default();
+
+        let config_file_name = match config_file_name {
+            Some(config_file_name) => Some(config_file_name),
+            None if default_config_file.exists() => Some(default_config_file),
+            None => {
+                tracing::warn!(
+                    "No config file at {}, using defaults",
+                    default_config_file.display()
+                );
+                None
+            }
         };
+
+        let mut settings = match config_file_name {
+            Some(config_file_name) => Self::new_from_default(&default_settings, config_file_name)?,
+            None => default_settings,
+        };
+
+        settings.load_secrets()?;
+
+        Ok(settings)
+    }
+
+    /// Fill in the mnemonic and kagi token from their file or environment
+    /// variable alternatives, only one source may be set for each
+    ///
+    /// Errors name the field and file but never include what was read.
+    fn load_secrets(&mut self) -> Result<(), ConfigError> {
+        let mnemonic_env = std::env::var(MNEMONIC_ENV_VAR).ok();
+
+        let mnemonic_sources = [
+            !self.info.mnemonic.is_empty(),
+            self.info.mnemonic_path.is_some(),
+            mnemonic_env.is_some(),
+        ];
+
+        if mnemonic_sources.iter().filter(|set| **set).count() > 1 {
+            return Err(ConfigError::Message(format!(
+                "Only one of info.mnemonic, info.mnemonic_path and {} may be set",
+                MNEMONIC_ENV_VAR
+            )));
+        }
+
+        if let Some(mnemonic_path) = &self.info.mnemonic_path {
+            self.info.mnemonic = read_secret("info.mnemonic_path", mnemonic_path)?;
+        }
+
+        if let Some(mnemonic) = mnemonic_env {
+            self.info.mnemonic = mnemonic.trim().to_string();
+        }
+
+        if let Some(kagi_auth_token_path) = &self.search_settings.kagi_auth_token_path {
+            if !self.search_settings.kagi_auth_token.is_empty() {
+                return Err(ConfigError::Message(
+                    "Only one of search_settings.kagi_auth_token and search_settings.kagi_auth_token_path may be set"
+                        .to_string(),
+                ));
+            }
+
+            self.search_settings.kagi_auth_token =
+                read_secret("search_settings.kagi_auth_token_path", kagi_auth_token_path)?;
+        }
+
+        Ok(())
+    }
+
+    fn new_from_default(default: &Settings, config_file_name: &Path) -> Result<Self, ConfigError> {
+        let (format, format_name) = config_format(config_file_name)?;
+
         let builder = Config::builder();
         let config: Config = builder
             // use defaults
             .add_source(Config::try_from(default)?)
             // override with file contents
-            .add_source(File::with_name(&config))
-            .build()?;
+            .add_source(File::from(config_file_name).format(format))
+            .build()
+            .map_err(|err| {
+                ConfigError::Message(format!(
+                    "Could not parse {} as {}: {}",
+                    config_file_name.display(),
+                    format_name,
+                    err
+                ))
+            })?;
         let settings: Settings = config.try_deserialize()?;
 
         Ok(settings)
     }
+
+    /// The settings as TOML with a comment above every field, fields that are
+    /// unset are written commented out
+    ///
+    /// Fields come from serializing the settings, so a new field shows up
+    /// here even before [`FIELD_COMMENTS`] describes it.
+    pub fn to_commented_toml(&self) -> Result<String, serde_json::Error> {
+        let serde_json::Value::Object(sections) = serde_json::to_value(self)? else {
+            return Ok(String::new());
+        };
+
+        let mut toml = String::new();
+
+        for (section, fields) in sections {
+            let serde_json::Value::Object(fields) = fields else {
+                continue;
+            };
+
+            toml.push_str(&format!("[{}]\n", section));
+
+            for (field, value) in fields {
+                if let Some(comment) = field_comment(&section, &field) {
+                    for line in comment.lines() {
+                        toml.push_str(&format!("# {}\n", line));
+                    }
+                }
+
+                match toml_value(&value) {
+                    Some(value) => toml.push_str(&format!("{} = {}\n", field, value)),
+                    None => toml.push_str(&format!("# {} =\n", field)),
+                }
+            }
+
+            toml.push('\n');
+        }
+
+        Ok(toml)
+    }
+
+    /// Copy of the settings with every secret masked, for printing
+    pub fn redacted(&self) -> Self {
+        let mask = |secret: &mut String| {
+            if !secret.is_empty() {
+                *secret = REDACTED.to_string();
+            }
+        };
+
+        let mut settings = self.clone();
+
+        mask(&mut settings.info.mnemonic);
+        mask(&mut settings.phoenixd.api_password);
+        mask(&mut settings.search_settings.kagi_auth_token);
+
+        if let Some(brave_auth_token) = settings.search_settings.brave_auth_token.as_mut() {
+            mask(brave_auth_token);
+        }
+
+        if let Some(operator_token) = settings.search_settings.operator_token.as_mut() {
+            mask(operator_token);
+        }
+
+        if let Some(secret_key) = settings.nostr.secret_key.as_mut() {
+            mask(secret_key);
+        }
+
+        settings
+    }
+
+    /// Check the settings the mint cannot start without, reporting every
+    /// problem rather than the first
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let mut problems = Vec::new();
+
+        if let Err(err) = Mnemonic::from_str(&self.info.mnemonic) {
+            problems.push(format!(
+                "info.mnemonic: not a valid BIP-39 mnemonic ({})",
+                err
+            ));
+        }
+
+        if let Err(err) = MintUrl::from_str(&self.info.url) {
+            problems.push(format!("info.url: not a valid url ({})", err));
+        }
+
+        if IpAddr::from_str(&self.info.listen_host).is_err() {
+            problems.push(format!(
+                "info.listen_host: {:?} is not an ip address",
+                self.info.listen_host
+            ));
+        }
+
+        if let Some(max_order) = self.info.keyset_max_order {
+            if !(1..=MAX_KEYSET_MAX_ORDER).contains(&max_order) {
+                problems.push(format!(
+                    "info.keyset_max_order: {} is not between 1 and {}",
+                    max_order, MAX_KEYSET_MAX_ORDER
+                ));
+            }
+        }
+
+        match (&self.info.tls_cert_path, &self.info.tls_key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                for (field, path) in [
+                    ("info.tls_cert_path", cert_path),
+                    ("info.tls_key_path", key_path),
+                ] {
+                    match path.to_str().and_then(expand_path) {
+                        Some(path) if path.exists() => (),
+                        _ => problems.push(format!("{}: {} does not exist", field, path.display())),
+                    }
+                }
+            }
+            (Some(_), None) => {
+                problems.push("info.tls_key_path: required when tls_cert_path is set".to_string())
+            }
+            (None, Some(_)) => {
+                problems.push("info.tls_cert_path: required when tls_key_path is set".to_string())
+            }
+            (None, None) => (),
+        }
+
+        if self.info.listen_port == 0 {
+            problems.push("info.listen_port: must be set to a non zero port".to_string());
+        }
+
+        match (&self.info.search_listen_host, self.info.search_listen_port) {
+            (Some(host), Some(port)) => {
+                if IpAddr::from_str(host).is_err() {
+                    problems.push(format!(
+                        "info.search_listen_host: {:?} is not an ip address",
+                        host
+                    ));
+                }
+
+                if port == 0 {
+                    problems.push(
+                        "info.search_listen_port: must be set to a non zero port".to_string(),
+                    );
+                } else if host == &self.info.listen_host && port == self.info.listen_port {
+                    problems.push(
+                        "info.search_listen_port: must differ from listen_port on the same host"
+                            .to_string(),
+                    );
+                }
+            }
+            (Some(_), None) => problems.push(
+                "info.search_listen_port: required when search_listen_host is set".to_string(),
+            ),
+            (None, Some(_)) => problems.push(
+                "info.search_listen_host: required when search_listen_port is set".to_string(),
+            ),
+            (None, None) => (),
+        }
+
+        match self.ln.ln_backend {
+            _ if !self.ln.enable_ln.unwrap_or(true) => {
+                if !is_dev_url(&self.info.url) {
+                    problems.push(format!(
+                        "ln.enable_ln: lightning can only be disabled for a development url, {} is not one",
+                        self.info.url
+                    ));
+                }
+            }
+            LnBackend::Cln => {
+                let rpc_path = self.cln.rpc_path.to_str().and_then(expand_path);
+
+                match rpc_path {
+                    Some(rpc_path) if rpc_path.exists() => (),
+                    _ => problems.push(format!(
+                        "cln.rpc_path: {} does not exist",
+                        self.cln.rpc_path.display()
+                    )),
+                }
+            }
+            LnBackend::Phoenixd => {
+                if self.phoenixd.url.is_empty() {
+                    problems.push("phoenixd.url: required for the phoenixd backend".to_string());
+                }
+
+                if self.phoenixd.api_password.is_empty() {
+                    problems.push(
+                        "phoenixd.api_password: required for the phoenixd backend".to_string(),
+                    );
+                }
+            }
+        }
+
+        // The kagi client backs /answer and /summarize whichever provider
+        // serves searches
+        if self.search_settings.kagi_auth_token.trim().is_empty() {
+            problems.push("search_settings.kagi_auth_token: must not be empty".to_string());
+        }
+
+        if self.search_settings.provider == SearchProviderKind::Brave
+            && self
+                .search_settings
+                .brave_auth_token
+                .as_ref()
+                .map_or(true, |token| token.trim().is_empty())
+        {
+            problems.push(
+                "search_settings.brave_auth_token: required for the brave provider".to_string(),
+            );
+        }
+
+        if let Some(kagi_base_url) = &self.search_settings.kagi_base_url {
+            match Url::parse(kagi_base_url) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") => (),
+                _ => problems.push(format!(
+                    "search_settings.kagi_base_url: {} is not an http(s) url",
+                    kagi_base_url
+                )),
+            }
+        }
+
+        if self.nostr.announce.unwrap_or(false) && self.nostr.relays.is_empty() {
+            problems.push("nostr.relays: at least one relay is required to announce".to_string());
+        }
+
+        if self.alerts.enabled.unwrap_or(false) {
+            if self.alerts.relays.is_empty() {
+                problems.push("alerts.relays: at least one relay is required".to_string());
+            }
+
+            match self.mint_info.operator_nostr_pubkey() {
+                Some(pubkey) if nostr_sdk::PublicKey::parse(&pubkey).is_err() => problems.push(
+                    format!("mint_info: nostr contact {} is not a valid pubkey", pubkey),
+                ),
+                Some(_) => (),
+                None => problems.push(
+                    "mint_info.contact_nostr_public_key: required to send alerts to".to_string(),
+                ),
+            }
+        }
+
+        for (field, relays) in [
+            ("nostr.relays", &self.nostr.relays),
+            ("alerts.relays", &self.alerts.relays),
+        ] {
+            for relay in relays {
+                match Url::parse(relay) {
+                    Ok(url) if matches!(url.scheme(), "ws" | "wss") => (),
+                    _ => problems.push(format!("{}: {} is not a websocket url", field, relay)),
+                }
+            }
+        }
+
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(ValidationError(problems)),
+        }
+    }
+}
+
+/// Comments written above fields by [`Settings::to_commented_toml`], keyed
+/// by `section.field`
+const FIELD_COMMENTS: &[(&str, &str)] = &[
+    ("info.url", "Public url of the mint"),
+    ("info.listen_host", "Ip address to listen on"),
+    ("info.listen_port", "Port to listen on"),
+    (
+        "info.search_listen_host",
+        "Serve the search api on a listener of its own, together with search_listen_port",
+    ),
+    ("info.search_listen_port", "Port of the search api listener"),
+    (
+        "info.mnemonic",
+        "BIP-39 mnemonic the mint keys are derived from, keep it secret and backed up",
+    ),
+    (
+        "info.mnemonic_path",
+        "Read the mnemonic from this file instead, or set ATHENUT_MNEMONIC",
+    ),
+    (
+        "info.seconds_quote_is_valid_for",
+        "Seconds a mint or melt quote is valid for",
+    ),
+    (
+        "info.seconds_to_cache_requests_for",
+        "Seconds mint api responses are cached for",
+    ),
+    (
+        "info.seconds_to_extend_cache_by",
+        "Seconds a cached response is extended by when it is used",
+    ),
+    ("info.input_fee_ppk", "Fee per thousand proofs spent"),
+    (
+        "info.keyset_max_order",
+        "Denominations in the XSR keyset, changing it rotates to a new keyset",
+    ),
+    ("info.tls_cert_path", "PEM certificate chain to serve https with"),
+    ("info.tls_key_path", "PEM private key of tls_cert_path"),
+    (
+        "info.mint_db_path",
+        "Mint database, cdk-mintd.redb in the work dir when unset",
+    ),
+    (
+        "info.search_db_path",
+        "Search api database, athenmint_search_api.redb in the work dir when unset",
+    ),
+    ("mint_info.name", "Name of the mint shown to wallets"),
+    ("mint_info.pubkey", "Hex pubkey of the mint"),
+    ("mint_info.description", "Short description of the mint"),
+    ("mint_info.description_long", "Long description of the mint"),
+    ("mint_info.icon_url", "Url of the mint icon"),
+    ("mint_info.motd", "Message of the day wallets show"),
+    (
+        "mint_info.contact_nostr_public_key",
+        "Nostr pubkey to contact the operator, prefer contacts",
+    ),
+    (
+        "mint_info.contact_email",
+        "Email to contact the operator, prefer contacts",
+    ),
+    (
+        "mint_info.contacts",
+        "Ways to contact the operator, e.g. [{ method = \"email\", info = \"hello@example.com\" }]",
+    ),
+    ("mint_info.tos_url", "Url of the terms of service"),
+    ("mint_info.privacy_policy_url", "Url of the privacy policy"),
+    (
+        "mint_info.serve_landing_page",
+        "Serve a page about the mint at /, true when unset",
+    ),
+    (
+        "ln.enable_ln",
+        "Connect to the lightning backend, when false quotes are paid without payment (development only)",
+    ),
+    ("ln.ln_backend", "Lightning backend, \"cln\" or \"phoenixd\""),
+    ("ln.fee_percent", "Fee reserve for melts as a fraction of the amount"),
+    ("ln.reserve_fee_min", "Smallest fee reserve for melts"),
+    ("cln.rpc_path", "Path to the lightningd rpc socket"),
+    (
+        "cln.pay_timeout_secs",
+        "Seconds a payment may take before it is reported as pending",
+    ),
+    ("cln.rpc_timeout_secs", "Seconds any other rpc call may take"),
+    (
+        "cln.max_fee_percent",
+        "Most fee a melt may pay as a percentage of the amount",
+    ),
+    (
+        "cln.retry_for_seconds",
+        "Seconds lightningd keeps retrying a payment for",
+    ),
+    (
+        "cln.max_delay_blocks",
+        "Most blocks a payment's funds may be locked up for",
+    ),
+    ("phoenixd.url", "Url of the phoenixd http api"),
+    ("phoenixd.api_password", "Password of the phoenixd http api"),
+    (
+        "price.cache_ttl_secs",
+        "Seconds a fetched BTC price is used for",
+    ),
+    (
+        "price.max_stale_secs",
+        "Seconds past the ttl a stale BTC price is served when sources are down",
+    ),
+    (
+        "price.sources",
+        "BTC price sources tried in order, \"mempool\", \"coinbase\" or \"kraken\"",
+    ),
+    ("price.min_price", "Lowest BTC price accepted from a source"),
+    ("price.max_price", "Highest BTC price accepted from a source"),
+    (
+        "search_settings.provider",
+        "Upstream search provider, \"kagi\" or \"brave\"",
+    ),
+    ("search_settings.kagi_auth_token", "Kagi api token"),
+    (
+        "search_settings.kagi_auth_token_path",
+        "Read the kagi token from this file instead",
+    ),
+    (
+        "search_settings.kagi_base_url",
+        "Kagi api url, for a proxy or a mock server",
+    ),
+    (
+        "search_settings.kagi_timeout_secs",
+        "Seconds a kagi request may take including retries",
+    ),
+    (
+        "search_settings.kagi_max_retries",
+        "Retries of a kagi request after a 5xx or connection error",
+    ),
+    (
+        "search_settings.kagi_low_balance",
+        "Log a warning when the kagi api balance in USD drops below this",
+    ),
+    (
+        "search_settings.cost_per_search_cents",
+        "Price of one search in the minor unit of price_currency",
+    ),
+    (
+        "search_settings.price_currency",
+        "Fiat currency searches are priced in",
+    ),
+    ("search_settings.answer_price", "Price of an /answer request in XSR"),
+    (
+        "search_settings.summarize_price",
+        "Price of a /summarize request in XSR",
+    ),
+    ("search_settings.max_results", "Most results returned for a search"),
+    (
+        "search_settings.brave_auth_token",
+        "Brave api token, required when provider is \"brave\"",
+    ),
+    (
+        "search_settings.brave_timeout_secs",
+        "Seconds a brave request may take",
+    ),
+    (
+        "search_settings.cors_allowed_origins",
+        "Origins allowed to call the search api, any origin when unset",
+    ),
+    (
+        "search_settings.rate_limit_per_minute",
+        "Paid requests a client ip may make per minute",
+    ),
+    (
+        "search_settings.invalid_rate_limit_per_minute",
+        "Requests with a bad token a client ip may make per minute",
+    ),
+    (
+        "search_settings.trusted_proxies",
+        "Proxies whose X-Forwarded-For header is used for the client ip",
+    ),
+    (
+        "search_settings.search_cache_ttl_secs",
+        "Seconds search results are cached for",
+    ),
+    (
+        "search_settings.search_cache_size",
+        "Most queries cached, 0 disables caching",
+    ),
+    (
+        "search_settings.default_region",
+        "Region used for searches that do not ask for one",
+    ),
+    (
+        "search_settings.safesearch",
+        "Minimum safe search level, \"off\", \"moderate\" or \"strict\"",
+    ),
+    (
+        "search_settings.openapi",
+        "Serve the OpenAPI spec at /openapi.json and Swagger UI at /docs",
+    ),
+    (
+        "search_settings.strip_html",
+        "Return result titles and descriptions as plain text",
+    ),
+    (
+        "search_settings.dedup_results",
+        "Drop results pointing at the same page as an earlier result",
+    ),
+    (
+        "search_settings.session_idle_secs",
+        "Seconds a prepaid session may go unused before it expires",
+    ),
+    (
+        "search_settings.operator_token",
+        "Bearer token for the /operator endpoints, not served when unset",
+    ),
+    (
+        "search_settings.search_count_retention_days",
+        "Days daily search counts are kept for",
+    ),
+    (
+        "search_settings.db_compaction_interval_secs",
+        "Seconds between search db compaction checks, first at 04:00 UTC, 0 disables",
+    ),
+    (
+        "search_settings.db_compaction_fragmented_percent",
+        "Compact the search db once this percent of the file is fragmented",
+    ),
+    (
+        "search_settings.analytics",
+        "\"off\" or \"hashed\" to count searches by keyed hash of the query, never the query",
+    ),
+    (
+        "search_settings.blocked_domains",
+        "Domains whose results are dropped, subdomains included",
+    ),
+    (
+        "search_settings.boosted_domains",
+        "Domains whose results are moved to the top, subdomains included",
+    ),
+    (
+        "logging.level",
+        "Tracing filter such as \"info\", RUST_LOG takes precedence",
+    ),
+    ("logging.format", "\"text\" or \"json\""),
+    (
+        "logging.file_path",
+        "Also write logs to this file, rotated daily",
+    ),
+    ("nostr.relays", "Relays to publish to, ws:// or wss:// urls"),
+    (
+        "nostr.announce",
+        "Publish a mint announcement on startup and a status note periodically",
+    ),
+    (
+        "nostr.secret_key",
+        "Hex or nsec key to sign with, derived from the mnemonic when unset",
+    ),
+    (
+        "nostr.status_interval_secs",
+        "Seconds between status notes, a day when unset",
+    ),
+    (
+        "alerts.enabled",
+        "DM the nostr contact of the mint when it needs attention",
+    ),
+    ("alerts.relays", "Relays to send alerts through"),
+    (
+        "alerts.kagi_balance_threshold",
+        "Alert when the Kagi api balance in USD drops below this",
+    ),
+    (
+        "alerts.lightning_unreachable_mins",
+        "Alert when the lightning node has been unreachable this long",
+    ),
+    (
+        "alerts.price_unavailable_mins",
+        "Alert when no BTC price could be fetched for this long",
+    ),
+];
+
+fn field_comment(section: &str, field: &str) -> Option<&'static str> {
+    FIELD_COMMENTS
+        .iter()
+        .find(|(key, _)| key.split_once('.') == Some((section, field)))
+        .map(|(_, comment)| *comment)
+}
+
+/// `value` written as a TOML value, `None` when it is unset
+fn toml_value(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::Null => None,
+        serde_json::Value::Array(values) => {
+            let values: Vec<String> = values.iter().filter_map(toml_value).collect();
+            Some(format!("[{}]", values.join(", ")))
+        }
+        serde_json::Value::Object(fields) => {
+            let fields: Vec<String> = fields
+                .iter()
+                .filter_map(|(field, value)| {
+                    toml_value(value).map(|value| format!("{} = {}", field, value))
+                })
+                .collect();
+            Some(format!("{{ {} }}", fields.join(", ")))
+        }
+        // Json strings, numbers and booleans are written the same in TOML
+        value => Some(value.to_string()),
+    }
+}
+
+/// Format of the config file at `path` and its name, from the extension
+fn config_format(path: &Path) -> Result<(FileFormat, &'static str), ConfigError> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase());
+
+    match extension.as_deref() {
+        Some("toml") => Ok((FileFormat::Toml, "TOML")),
+        Some("yaml") | Some("yml") => Ok((FileFormat::Yaml, "YAML")),
+        Some("json") => Ok((FileFormat::Json, "JSON")),
+        _ => Err(ConfigError::Message(format!(
+            "{}: unknown config format, use a .toml, .yaml, .yml or .json file",
+            path.display()
+        ))),
+    }
+}
+
+/// Read the secret in the file at `path`, `~` expanded, with surrounding
+/// whitespace trimmed
+fn read_secret(field: &str, path: &Path) -> Result<String, ConfigError> {
+    let expanded_path = path
+        .to_str()
+        .and_then(expand_path)
+        .ok_or_else(|| ConfigError::Message(format!("{}: invalid path", field)))?;
+
+    let secret = fs::read_to_string(&expanded_path).map_err(|err| {
+        ConfigError::Message(format!(
+            "{}: could not read {}: {}",
+            field,
+            expanded_path.display(),
+            err
+        ))
+    })?;
+
+    let secret = secret.trim();
+
+    if secret.is_empty() {
+        return Err(ConfigError::Message(format!(
+            "{}: {} is empty",
+            field,
+            expanded_path.display()
+        )));
+    }
+
+    Ok(secret.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_dir;
+
+    /// Settings loaded from `contents` written to a file named `file_name`
+    fn load(file_name: &str, contents: &str) -> Settings {
+        let path = temp_dir().join(file_name);
+        fs::write(&path, contents).unwrap();
+
+        Settings::new(Some(&path), &path).unwrap()
+    }
+
+    fn contact(method: &str, info: &str) -> Contact {
+        Contact {
+            method: method.to_string(),
+            info: info.to_string(),
+        }
+    }
+
+    #[test]
+    fn legacy_contacts_are_read() {
+        let settings = load(
+            "config.toml",
+            r#"
+[mint_info]
+name = "Test Mint"
+description = "Searches"
+contact_email = "ops@example.com"
+"#,
+        );
+
+        assert_eq!(
+            settings.mint_info.all_contacts(),
+            [contact("email", "ops@example.com")]
+        );
+        assert_eq!(settings.mint_info.operator_nostr_pubkey(), None);
+    }
+
+    #[test]
+    fn contacts_are_merged_with_legacy_fields_without_repeats() {
+        let settings = load(
+            "config.toml",
+            r#"
+[mint_info]
+name = "Test Mint"
+description = "Searches"
+contact_nostr_public_key = "npub1operator"
+contact_email = "ops@example.com"
+
+[[mint_info.contacts]]
+method = "Email"
+info = " ops@example.com "
+
+[[mint_info.contacts]]
+method = "twitter"
+info = "@athenut"
+
+[[mint_info.contacts]]
+method = "telegram"
+info = "   "
+
+[[mint_info.contacts]]
+method = "twitter"
+info = "@athenut"
+
+[[mint_info.contacts]]
+method = "website"
+info = "https://athenut.com"
+"#,
+        );
+
+        assert_eq!(settings.mint_info.contacts.len(), 5);
+        assert_eq!(
+            settings.mint_info.all_contacts(),
+            [
+                contact("nostr", "npub1operator"),
+                contact("email", "ops@example.com"),
+                contact("twitter", "@athenut"),
+                contact("website", "https://athenut.com"),
+            ]
+        );
+        assert_eq!(
+            settings.mint_info.operator_nostr_pubkey().as_deref(),
+            Some("npub1operator")
+        );
+    }
+
+    #[test]
+    fn contacts_round_trip_through_the_commented_toml() {
+        let mut settings = Settings::default();
+        settings.mint_info.contact_email = Some("ops@example.com".to_string());
+        settings.mint_info.contacts = vec![
+            contact("nostr", "npub1operator"),
+            contact("telegram", "@athenut"),
+        ];
+        settings.mint_info.tos_url = Some("https://athenut.com/tos".to_string());
+
+        let loaded = load("config.toml", &settings.to_commented_toml().unwrap());
+
+        assert_eq!(loaded.mint_info.contacts, settings.mint_info.contacts);
+        assert_eq!(
+            loaded.mint_info.contact_email,
+            settings.mint_info.contact_email
+        );
+        assert_eq!(loaded.mint_info.contact_nostr_public_key, None);
+        assert_eq!(loaded.mint_info.tos_url, settings.mint_info.tos_url);
+        assert_eq!(
+            loaded.mint_info.all_contacts(),
+            [
+                contact("email", "ops@example.com"),
+                contact("nostr", "npub1operator"),
+                contact("telegram", "@athenut"),
+            ]
+        );
+    }
+
+    #[test]
+    fn config_formats_deserialize_to_the_same_settings() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+
+        let [toml, yaml, json] = ["config.toml", "config.yaml", "config.json"].map(|file_name| {
+            let path = fixtures.join(file_name);
+            let settings = Settings::new(Some(&path), &path).unwrap();
+
+            serde_json::to_value(settings).unwrap()
+        });
+
+        assert_eq!(toml, yaml);
+        assert_eq!(toml, json);
+
+        // Values from every section made it in, not just the defaults
+        assert_eq!(toml["info"]["keyset_max_order"], 16);
+        assert_eq!(toml["mint_info"]["contacts"][0]["info"], "@example");
+        assert_eq!(toml["cln"]["max_fee_percent"], 1.5);
+        assert_eq!(toml["price"]["sources"][0], "kraken");
+        assert_eq!(toml["search_settings"]["price_currency"], "EUR");
+        assert_eq!(toml["search_settings"]["analytics"], "hashed");
+        assert_eq!(toml["logging"]["format"], "json");
+        assert_eq!(toml["nostr"]["announce"], true);
+    }
+
+    #[test]
+    fn yml_extension_is_read_as_yaml() {
+        let yaml = include_str!("../fixtures/config.yaml");
+
+        let settings = load("config.yml", yaml);
+
+        assert_eq!(settings.info.url, "https://mint.example.com");
+    }
+
+    #[test]
+    fn unknown_config_extension_is_rejected() {
+        let path = temp_dir().join("config.ini");
+        fs::write(&path, "").unwrap();
+
+        let err = Settings::new(Some(&path), &path).unwrap_err();
+
+        assert!(err.to_string().contains("unknown config format"), "{}", err);
+    }
+
+    #[test]
+    fn parse_errors_name_the_format() {
+        let path = temp_dir().join("config.yaml");
+        fs::write(&path, "info: [unclosed").unwrap();
+
+        let err = Settings::new(Some(&path), &path).unwrap_err();
+
+        assert!(err.to_string().contains("as YAML"), "{}", err);
+    }
 }
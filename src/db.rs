@@ -1,34 +1,455 @@
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
+use cdk::nuts::{Id, PublicKey};
+use cdk::util::unix_time;
 use serde::{Deserialize, Serialize};
-use std::{path::PathBuf, sync::Arc};
+use std::collections::{BTreeMap, HashMap};
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, TryLockError},
+};
+use utoipa::ToSchema;
 
-use redb::{Database, ReadableTable, TableDefinition};
+use redb::{
+    CompactionError, Database, ReadableTable, ReadableTableMetadata, TableDefinition, TableHandle,
+};
 
 const SEARCH_COUNTS_TABLE: TableDefinition<&str, u64> = TableDefinition::new("search_counts_table");
+// Payment id to json serialized `SearchPayment`
+const SEARCH_PAYMENTS_TABLE: TableDefinition<&str, &str> =
+    TableDefinition::new("search_payments_table");
+// CLN invoice processing state
+const CLN_STATE_TABLE: TableDefinition<&str, u64> = TableDefinition::new("cln_state_table");
+// Session id to json serialized `Session`
+const SESSIONS_TABLE: TableDefinition<&str, &str> = TableDefinition::new("sessions_table");
+// Payment hash to json serialized `ReceivedPayment`
+const RECEIVED_PAYMENTS_TABLE: TableDefinition<&str, &str> =
+    TableDefinition::new("received_payments_table");
+// Msats received, keyed like the search counts
+const REVENUE_TABLE: TableDefinition<&str, u64> = TableDefinition::new("revenue_table");
+// Hex of the Y of each proof paid for a request to the unix time it was used
+const USED_YS_TABLE: TableDefinition<&str, u64> = TableDefinition::new("used_ys_table");
+// Search counts by `query:<hash>`, `length:<bucket>` and `results:<bucket>`
+const ANALYTICS_TABLE: TableDefinition<&str, u64> = TableDefinition::new("query_analytics_table");
+// Amounts per keyset keyed `<flow>:<keyset id>`, see `KeysetFlow`
+const KEYSET_AMOUNTS_TABLE: TableDefinition<&str, u64> =
+    TableDefinition::new("keyset_amounts_table");
+// Melt quote id to json serialized `PendingMelt`
+const PENDING_MELTS_TABLE: TableDefinition<&str, &str> =
+    TableDefinition::new("pending_melts_table");
+// Small json serialized values that need no table of their own
+const META_TABLE: TableDefinition<&str, &str> = TableDefinition::new("meta_table");
 
 const ALL_TIME_KEY: &str = "all_time_count";
+const ALL_TIME_REVENUE_KEY: &str = "all_time_msats";
+const LAST_PAY_INDEX_KEY: &str = "last_pay_index";
+const KAGI_BALANCE_KEY: &str = "kagi_balance";
+
+// Tables by value type, every table is listed so dumps cover all of them
+const U64_TABLES: [TableDefinition<&str, u64>; 6] = [
+    SEARCH_COUNTS_TABLE,
+    CLN_STATE_TABLE,
+    REVENUE_TABLE,
+    USED_YS_TABLE,
+    ANALYTICS_TABLE,
+    KEYSET_AMOUNTS_TABLE,
+];
+const JSON_TABLES: [TableDefinition<&str, &str>; 5] = [
+    SEARCH_PAYMENTS_TABLE,
+    SESSIONS_TABLE,
+    RECEIVED_PAYMENTS_TABLE,
+    META_TABLE,
+    PENDING_MELTS_TABLE,
+];
+// Tables whose values are added together when merging a dump
+const COUNTER_TABLES: [TableDefinition<&str, u64>; 4] = [
+    SEARCH_COUNTS_TABLE,
+    REVENUE_TABLE,
+    ANALYTICS_TABLE,
+    KEYSET_AMOUNTS_TABLE,
+];
+
+/// Format version of [`DbDump`]
+pub const DUMP_VERSION: u32 = 1;
+
+// Daily counts are keyed `day:YYYY-MM-DD` and monthly counts `month:YYYY-MM`
+const DAY_KEY_PREFIX: &str = "day:";
+const MONTH_KEY_PREFIX: &str = "month:";
+const QUERY_KEY_PREFIX: &str = "query:";
+const LENGTH_KEY_PREFIX: &str = "length:";
+const RESULTS_KEY_PREFIX: &str = "results:";
 
 #[derive(Clone)]
 pub struct Db {
-    inner: Arc<Database>,
+    /// Write locked only to compact, when no transaction may be open
+    inner: Arc<RwLock<Database>>,
+    path: PathBuf,
+    /// Days daily search counts are kept for
+    count_retention_days: u64,
+}
+
+/// Storage of the search api
+pub trait SearchStore: Send + Sync {
+    /// Increment the all time, daily and monthly search counts
+    ///
+    /// Daily counts older than the retention are pruned on the first search
+    /// of each day.
+    fn increment_search_count(&self) -> Result<()>;
+
+    /// Check that the store can be read
+    fn check(&self) -> Result<()>;
+
+    /// Size on disk and number of rows in each table
+    fn stats(&self) -> Result<DbStats>;
+
+    fn get_search_count(&self) -> Result<SearchCount>;
+
+    /// Daily search counts from `from` to `to` inclusive, both `YYYY-MM-DD`
+    ///
+    /// Days without searches are left out.
+    fn get_search_counts_range(&self, from: &str, to: &str) -> Result<Vec<(String, u64)>>;
+
+    /// Record a spent search payment before the upstream request is made
+    fn add_search_payment(&self, id: &str, payment: &SearchPayment) -> Result<()>;
+
+    /// Remove a search payment once the search has been served
+    fn remove_search_payment(&self, id: &str) -> Result<()>;
+
+    /// Store the refund token issued for a failed search
+    fn set_search_payment_refund(&self, id: &str, refund: &str) -> Result<()>;
+
+    /// Search payments that were spent but neither served nor refunded
+    fn get_unrefunded_search_payments(&self) -> Result<Vec<(String, SearchPayment)>>;
+
+    /// Pay index of the last CLN invoice processed
+    fn get_last_pay_index(&self) -> Result<Option<u64>>;
+
+    fn set_last_pay_index(&self, pay_index: u64) -> Result<()>;
+
+    /// Record `msats` received for the invoice with `payment_hash`
+    ///
+    /// Returns `false` without touching the totals if the payment was already
+    /// recorded, so a payment seen twice is only counted once.
+    fn add_revenue(&self, payment_hash: &str, msats: u64) -> Result<bool>;
+
+    /// Msats received today, this month and all time
+    fn get_revenue_summary(&self) -> Result<RevenueSummary>;
+
+    /// Whether any of `ys` was already used to pay for a request
+    fn any_used_ys(&self, ys: &[PublicKey]) -> Result<bool>;
+
+    /// Record `ys` as used to pay for a request
+    fn add_used_ys(&self, ys: &[PublicKey]) -> Result<()>;
+
+    /// Remove the ys used before `before`, returning how many were removed
+    ///
+    /// Pruned ys are still rejected by the mint, which keeps every spent proof.
+    fn prune_used_ys(&self, before: u64) -> Result<u64>;
+
+    /// Record the kagi api balance reported by a response, returning the
+    /// balance recorded before it
+    fn set_kagi_balance(&self, balance_usd: f64) -> Result<Option<KagiBalance>>;
+
+    /// Most recent kagi api balance, `None` before any was reported
+    fn get_kagi_balance(&self) -> Result<Option<KagiBalance>>;
+
+    /// Count a search by its query hash and length and result count buckets
+    fn record_search_analytics(
+        &self,
+        query_hash: &str,
+        length_bucket: &str,
+        results_bucket: &str,
+    ) -> Result<()>;
+
+    /// The `top` most searched query hashes and the bucket counts
+    fn get_search_analytics(&self, top: usize) -> Result<SearchAnalytics>;
+
+    /// Add `amounts` to the per keyset totals of `flow`
+    fn add_keyset_amounts(&self, flow: KeysetFlow, amounts: &[(Id, u64)]) -> Result<()>;
+
+    /// Issued, redeemed and outstanding amounts of every keyset seen
+    fn get_issuance_summary(&self) -> Result<Vec<KeysetIssuance>>;
+
+    /// Keep the inputs of a melt that was still pending when answered
+    fn add_pending_melt(&self, quote_id: &str, melt: &PendingMelt) -> Result<()>;
+
+    /// Remove and return the pending melt of `quote_id`
+    ///
+    /// Only one caller is handed the melt, so its inputs are recorded once.
+    fn take_pending_melt(&self, quote_id: &str) -> Result<Option<PendingMelt>>;
+
+    /// Store a new prepaid session
+    fn add_session(&self, id: &str, session: &Session) -> Result<()>;
+
+    fn get_session(&self, id: &str) -> Result<Option<Session>>;
+
+    /// Debit `amount` from a session that has not been idle for `idle_secs`
+    ///
+    /// The balance is read and updated atomically, so concurrent debits can
+    /// never take the balance below zero.
+    fn debit_session(
+        &self,
+        id: &str,
+        amount: u64,
+        now: u64,
+        idle_secs: u64,
+    ) -> Result<SessionDebit>;
+
+    /// Credit `amount` back to a session for a request that was not served
+    fn credit_session(&self, id: &str, amount: u64) -> Result<()>;
+
+    /// Close a session so no more requests can be paid from it
+    ///
+    /// The balance is zeroed atomically so only one caller can ever be handed
+    /// the balance to refund.
+    fn close_session(&self, id: &str) -> Result<SessionClose>;
+
+    /// Store the token refunding a closed session
+    fn set_session_refund(&self, id: &str, refund: &str) -> Result<()>;
+
+    /// Reopen a session that was closed but could not be refunded
+    fn reopen_session(&self, id: &str, balance: u64) -> Result<()>;
 }
 
 impl Db {
-    pub fn new(path: &PathBuf) -> Result<Self> {
-        let db = Arc::new(Database::create(path)?);
+    pub fn new(path: &PathBuf, count_retention_days: u64) -> Result<Self> {
+        let db = Database::create(path)?;
 
         let write_txn = db.begin_write()?;
         {
             let _table = write_txn.open_table(SEARCH_COUNTS_TABLE)?;
+            let _table = write_txn.open_table(SEARCH_PAYMENTS_TABLE)?;
+            let _table = write_txn.open_table(SESSIONS_TABLE)?;
+            let _table = write_txn.open_table(CLN_STATE_TABLE)?;
+            let _table = write_txn.open_table(RECEIVED_PAYMENTS_TABLE)?;
+            let _table = write_txn.open_table(REVENUE_TABLE)?;
+            let _table = write_txn.open_table(META_TABLE)?;
+            let _table = write_txn.open_table(USED_YS_TABLE)?;
+            let _table = write_txn.open_table(ANALYTICS_TABLE)?;
+            let _table = write_txn.open_table(KEYSET_AMOUNTS_TABLE)?;
+            let _table = write_txn.open_table(PENDING_MELTS_TABLE)?;
+        }
+
+        write_txn.commit()?;
+
+        Ok(Self {
+            inner: Arc::new(RwLock::new(db)),
+            path: path.clone(),
+            count_retention_days,
+        })
+    }
+
+    /// Compact the file if at least `min_fragmented_percent` of it is
+    /// fragmented
+    ///
+    /// Compaction needs every transaction closed, so it is skipped with
+    /// [`Compaction::Busy`] while any other db call is running.
+    pub fn compact_if_fragmented(&self, min_fragmented_percent: u64) -> Result<Compaction> {
+        let mut db = match self.inner.try_write() {
+            Ok(db) => db,
+            Err(TryLockError::Poisoned(err)) => err.into_inner(),
+            Err(TryLockError::WouldBlock) => return Ok(Compaction::Busy),
+        };
+
+        let before_bytes = std::fs::metadata(&self.path)?.len();
+
+        let write_txn = db.begin_write()?;
+        let fragmented_bytes = write_txn.stats()?.fragmented_bytes();
+        write_txn.abort()?;
+
+        let fragmented_percent = fragmented_bytes * 100 / before_bytes.max(1);
+
+        if fragmented_percent < min_fragmented_percent {
+            return Ok(Compaction::NotNeeded { fragmented_percent });
+        }
+
+        match db.compact() {
+            Ok(_) => (),
+            Err(CompactionError::TransactionInProgress) => return Ok(Compaction::Busy),
+            Err(err) => return Err(err.into()),
+        }
+
+        Ok(Compaction::Compacted {
+            before_bytes,
+            after_bytes: std::fs::metadata(&self.path)?.len(),
+        })
+    }
+
+    fn database(&self) -> RwLockReadGuard<'_, Database> {
+        self.inner.read().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Every table, read in one transaction so the dump is consistent
+    pub fn export(&self) -> Result<DbDump> {
+        let db = self.database();
+
+        let read_txn = db.begin_read()?;
+
+        let mut tables = BTreeMap::new();
+
+        for definition in U64_TABLES {
+            let table = read_txn.open_table(definition)?;
+
+            let mut rows = BTreeMap::new();
+
+            for entry in table.iter()? {
+                let (key, value) = entry?;
+                rows.insert(
+                    key.value().to_string(),
+                    serde_json::Value::from(value.value()),
+                );
+            }
+
+            tables.insert(definition.name().to_string(), rows);
+        }
+
+        for definition in JSON_TABLES {
+            let table = read_txn.open_table(definition)?;
+
+            let mut rows = BTreeMap::new();
+
+            for entry in table.iter()? {
+                let (key, value) = entry?;
+                rows.insert(
+                    key.value().to_string(),
+                    serde_json::from_str(value.value())?,
+                );
+            }
+
+            tables.insert(definition.name().to_string(), rows);
+        }
+
+        Ok(DbDump {
+            version: DUMP_VERSION,
+            tables,
+        })
+    }
+
+    /// Whether every table is empty
+    pub fn is_empty(&self) -> Result<bool> {
+        let db = self.database();
+
+        let read_txn = db.begin_read()?;
+
+        for definition in U64_TABLES {
+            if read_txn.open_table(definition)?.iter()?.next().is_some() {
+                return Ok(false);
+            }
+        }
+
+        for definition in JSON_TABLES {
+            if read_txn.open_table(definition)?.iter()?.next().is_some() {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Write the rows of `dump` in one transaction
+    ///
+    /// Without `merge` the database must be empty. With `merge` search counts
+    /// and revenue are added to the existing totals and any other row that
+    /// already exists is kept.
+    pub fn import(&self, dump: &DbDump, merge: bool) -> Result<()> {
+        if dump.version != DUMP_VERSION {
+            bail!(
+                "Dump version {} is not supported, expected {}",
+                dump.version,
+                DUMP_VERSION
+            );
+        }
+
+        let known = |name: &str| {
+            U64_TABLES.iter().any(|table| table.name() == name)
+                || JSON_TABLES.iter().any(|table| table.name() == name)
+        };
+
+        if let Some(name) = dump.tables.keys().find(|name| !known(name)) {
+            bail!("Dump has unknown table {}", name);
+        }
+
+        if !merge && !self.is_empty()? {
+            bail!("The database is not empty, use --merge to import into it");
+        }
+
+        let db = self.database();
+
+        let write_txn = db.begin_write()?;
+
+        {
+            for definition in U64_TABLES {
+                let mut table = write_txn.open_table(definition)?;
+
+                let is_counter = COUNTER_TABLES
+                    .iter()
+                    .any(|counter| counter.name() == definition.name());
+
+                for (key, value) in dump.tables.get(definition.name()).into_iter().flatten() {
+                    let value = value
+                        .as_u64()
+                        .ok_or_else(|| anyhow!("{} {} is not a number", definition.name(), key))?;
+
+                    let current = table.get(key.as_str())?.map(|v| v.value());
+
+                    match current {
+                        Some(current) if is_counter => {
+                            table.insert(key.as_str(), current + value)?;
+                        }
+                        Some(_) => (),
+                        None => {
+                            table.insert(key.as_str(), value)?;
+                        }
+                    }
+                }
+            }
+
+            for definition in JSON_TABLES {
+                let mut table = write_txn.open_table(definition)?;
+
+                for (key, value) in dump.tables.get(definition.name()).into_iter().flatten() {
+                    if table.get(key.as_str())?.is_none() {
+                        table.insert(key.as_str(), value.to_string().as_str())?;
+                    }
+                }
+            }
         }
 
         write_txn.commit()?;
 
-        Ok(Self { inner: db })
+        Ok(())
+    }
+}
+
+impl SearchStore for Db {
+    fn stats(&self) -> Result<DbStats> {
+        let db = self.database();
+
+        let read_txn = db.begin_read()?;
+
+        let mut tables = BTreeMap::new();
+
+        for definition in U64_TABLES {
+            let rows = read_txn.open_table(definition)?.len()?;
+            tables.insert(definition.name().to_string(), rows);
+        }
+
+        for definition in JSON_TABLES {
+            let rows = read_txn.open_table(definition)?.len()?;
+            tables.insert(definition.name().to_string(), rows);
+        }
+
+        Ok(DbStats {
+            file_size_bytes: Some(std::fs::metadata(&self.path)?.len()),
+            tables,
+        })
     }
 
-    pub fn increment_search_count(&self) -> Result<()> {
-        let db = &self.inner;
+    fn increment_search_count(&self) -> Result<()> {
+        let db = self.database();
+
+        let now = unix_time();
+        let today_key = day_key(now);
+        let this_month_key = month_key(now);
 
         let write_txn = db.begin_write()?;
 
@@ -38,6 +459,30 @@ impl Db {
             let current_all_time = table.get(ALL_TIME_KEY)?.map(|v| v.value()).unwrap_or(0);
             let new_all_time = current_all_time + 1;
             table.insert(ALL_TIME_KEY, new_all_time)?;
+
+            let current_day = table.get(today_key.as_str())?.map(|v| v.value());
+            table.insert(today_key.as_str(), current_day.unwrap_or(0) + 1)?;
+
+            let current_month = table
+                .get(this_month_key.as_str())?
+                .map(|v| v.value())
+                .unwrap_or(0);
+            table.insert(this_month_key.as_str(), current_month + 1)?;
+
+            if current_day.is_none() {
+                let cutoff = day_key(
+                    now.saturating_sub(self.count_retention_days.saturating_mul(SECS_PER_DAY)),
+                );
+
+                let expired: Vec<String> = table
+                    .range(DAY_KEY_PREFIX..cutoff.as_str())?
+                    .map(|entry| entry.map(|(key, _)| key.value().to_string()))
+                    .collect::<Result<_, _>>()?;
+
+                for key in expired {
+                    table.remove(key.as_str())?;
+                }
+            }
         }
 
         write_txn.commit()?;
@@ -45,8 +490,17 @@ impl Db {
         Ok(())
     }
 
-    pub fn get_search_count(&self) -> Result<SearchCount> {
-        let db = &self.inner;
+    fn check(&self) -> Result<()> {
+        let db = self.database();
+
+        let read_txn = db.begin_read()?;
+        read_txn.open_table(SEARCH_COUNTS_TABLE)?;
+
+        Ok(())
+    }
+
+    fn get_search_count(&self) -> Result<SearchCount> {
+        let db = self.database();
 
         let read_txn = db.begin_read()?;
 
@@ -54,13 +508,1872 @@ impl Db {
 
         let current_all_time = table.get(ALL_TIME_KEY)?.map(|v| v.value()).unwrap_or(0);
 
+        let now = unix_time();
+
+        let today = table
+            .get(day_key(now).as_str())?
+            .map(|v| v.value())
+            .unwrap_or(0);
+
+        let month = table
+            .get(month_key(now).as_str())?
+            .map(|v| v.value())
+            .unwrap_or(0);
+
         Ok(SearchCount {
             all_time_search_count: current_all_time,
+            today_search_count: today,
+            month_search_count: month,
         })
     }
-}
 
-#[derive(Debug, Clone, Copy, Hash, Serialize, Deserialize)]
-pub struct SearchCount {
-    pub all_time_search_count: u64,
+    fn get_search_counts_range(&self, from: &str, to: &str) -> Result<Vec<(String, u64)>> {
+        let db = self.database();
+
+        let read_txn = db.begin_read()?;
+
+        let table = read_txn.open_table(SEARCH_COUNTS_TABLE)?;
+
+        let from = format!("{}{}", DAY_KEY_PREFIX, from);
+        let to = format!("{}{}", DAY_KEY_PREFIX, to);
+
+        if from > to {
+            return Ok(Vec::new());
+        }
+
+        let mut counts = Vec::new();
+
+        for entry in table.range(from.as_str()..=to.as_str())? {
+            let (key, count) = entry?;
+
+            if let Some(day) = key.value().strip_prefix(DAY_KEY_PREFIX) {
+                counts.push((day.to_string(), count.value()));
+            }
+        }
+
+        Ok(counts)
+    }
+
+    fn add_search_payment(&self, id: &str, payment: &SearchPayment) -> Result<()> {
+        let db = self.database();
+
+        let write_txn = db.begin_write()?;
+
+        {
+            let mut table = write_txn.open_table(SEARCH_PAYMENTS_TABLE)?;
+            table.insert(id, serde_json::to_string(payment)?.as_str())?;
+        }
+
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    fn remove_search_payment(&self, id: &str) -> Result<()> {
+        let db = self.database();
+
+        let write_txn = db.begin_write()?;
+
+        {
+            let mut table = write_txn.open_table(SEARCH_PAYMENTS_TABLE)?;
+            table.remove(id)?;
+        }
+
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    fn set_search_payment_refund(&self, id: &str, refund: &str) -> Result<()> {
+        let db = self.database();
+
+        let write_txn = db.begin_write()?;
+
+        {
+            let mut table = write_txn.open_table(SEARCH_PAYMENTS_TABLE)?;
+
+            let payment = table
+                .get(id)?
+                .map(|v| serde_json::from_str::<SearchPayment>(v.value()))
+                .transpose()?;
+
+            if let Some(mut payment) = payment {
+                payment.refund = Some(refund.to_string());
+                table.insert(id, serde_json::to_string(&payment)?.as_str())?;
+            }
+        }
+
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    fn get_unrefunded_search_payments(&self) -> Result<Vec<(String, SearchPayment)>> {
+        let db = self.database();
+
+        let read_txn = db.begin_read()?;
+
+        let table = read_txn.open_table(SEARCH_PAYMENTS_TABLE)?;
+
+        let mut payments = Vec::new();
+
+        for entry in table.iter()? {
+            let (id, payment) = entry?;
+            let payment: SearchPayment = serde_json::from_str(payment.value())?;
+
+            if payment.refund.is_none() {
+                payments.push((id.value().to_string(), payment));
+            }
+        }
+
+        Ok(payments)
+    }
+
+    fn get_last_pay_index(&self) -> Result<Option<u64>> {
+        let db = self.database();
+
+        let read_txn = db.begin_read()?;
+
+        let table = read_txn.open_table(CLN_STATE_TABLE)?;
+
+        let last_pay_index = table.get(LAST_PAY_INDEX_KEY)?.map(|v| v.value());
+
+        Ok(last_pay_index)
+    }
+
+    fn set_last_pay_index(&self, pay_index: u64) -> Result<()> {
+        let db = self.database();
+
+        let write_txn = db.begin_write()?;
+
+        {
+            let mut table = write_txn.open_table(CLN_STATE_TABLE)?;
+            table.insert(LAST_PAY_INDEX_KEY, pay_index)?;
+        }
+
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    fn add_revenue(&self, payment_hash: &str, msats: u64) -> Result<bool> {
+        let db = self.database();
+
+        let now = unix_time();
+        let today_key = day_key(now);
+        let this_month_key = month_key(now);
+
+        let write_txn = db.begin_write()?;
+
+        let added = {
+            let mut payments = write_txn.open_table(RECEIVED_PAYMENTS_TABLE)?;
+
+            if payments.get(payment_hash)?.is_some() {
+                false
+            } else {
+                let payment = ReceivedPayment {
+                    msats,
+                    received_at: now,
+                };
+                payments.insert(payment_hash, serde_json::to_string(&payment)?.as_str())?;
+
+                let mut table = write_txn.open_table(REVENUE_TABLE)?;
+
+                for key in [
+                    ALL_TIME_REVENUE_KEY,
+                    today_key.as_str(),
+                    this_month_key.as_str(),
+                ] {
+                    let current = table.get(key)?.map(|v| v.value()).unwrap_or(0);
+                    table.insert(key, current + msats)?;
+                }
+
+                true
+            }
+        };
+
+        write_txn.commit()?;
+
+        Ok(added)
+    }
+
+    fn get_revenue_summary(&self) -> Result<RevenueSummary> {
+        let db = self.database();
+
+        let read_txn = db.begin_read()?;
+
+        let table = read_txn.open_table(REVENUE_TABLE)?;
+
+        let now = unix_time();
+
+        let all_time = table
+            .get(ALL_TIME_REVENUE_KEY)?
+            .map(|v| v.value())
+            .unwrap_or(0);
+
+        let today = table
+            .get(day_key(now).as_str())?
+            .map(|v| v.value())
+            .unwrap_or(0);
+
+        let month = table
+            .get(month_key(now).as_str())?
+            .map(|v| v.value())
+            .unwrap_or(0);
+
+        Ok(RevenueSummary {
+            all_time_msats: all_time,
+            today_msats: today,
+            month_msats: month,
+        })
+    }
+
+    fn any_used_ys(&self, ys: &[PublicKey]) -> Result<bool> {
+        let db = self.database();
+
+        let read_txn = db.begin_read()?;
+
+        let table = read_txn.open_table(USED_YS_TABLE)?;
+
+        for y in ys {
+            if table.get(y.to_hex().as_str())?.is_some() {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn add_used_ys(&self, ys: &[PublicKey]) -> Result<()> {
+        let db = self.database();
+
+        let now = unix_time();
+
+        let write_txn = db.begin_write()?;
+
+        {
+            let mut table = write_txn.open_table(USED_YS_TABLE)?;
+
+            for y in ys {
+                table.insert(y.to_hex().as_str(), now)?;
+            }
+        }
+
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    fn prune_used_ys(&self, before: u64) -> Result<u64> {
+        let db = self.database();
+
+        let write_txn = db.begin_write()?;
+
+        let pruned = {
+            let mut table = write_txn.open_table(USED_YS_TABLE)?;
+
+            let expired: Vec<String> = table
+                .iter()?
+                .filter_map(|entry| match entry {
+                    Ok((y, used_at)) if used_at.value() < before => Some(Ok(y.value().to_string())),
+                    Ok(_) => None,
+                    Err(err) => Some(Err(err)),
+                })
+                .collect::<Result<_, _>>()?;
+
+            for y in expired.iter() {
+                table.remove(y.as_str())?;
+            }
+
+            expired.len() as u64
+        };
+
+        write_txn.commit()?;
+
+        Ok(pruned)
+    }
+
+    fn set_kagi_balance(&self, balance_usd: f64) -> Result<Option<KagiBalance>> {
+        let db = self.database();
+
+        let write_txn = db.begin_write()?;
+
+        let previous = {
+            let mut table = write_txn.open_table(META_TABLE)?;
+
+            let previous = table
+                .get(KAGI_BALANCE_KEY)?
+                .map(|v| serde_json::from_str(v.value()))
+                .transpose()?;
+
+            let balance = KagiBalance {
+                balance_usd,
+                updated_at: unix_time(),
+            };
+            table.insert(KAGI_BALANCE_KEY, serde_json::to_string(&balance)?.as_str())?;
+
+            previous
+        };
+
+        write_txn.commit()?;
+
+        Ok(previous)
+    }
+
+    fn get_kagi_balance(&self) -> Result<Option<KagiBalance>> {
+        let db = self.database();
+
+        let read_txn = db.begin_read()?;
+
+        let table = read_txn.open_table(META_TABLE)?;
+
+        let balance = table
+            .get(KAGI_BALANCE_KEY)?
+            .map(|v| serde_json::from_str(v.value()))
+            .transpose()?;
+
+        Ok(balance)
+    }
+
+    fn record_search_analytics(
+        &self,
+        query_hash: &str,
+        length_bucket: &str,
+        results_bucket: &str,
+    ) -> Result<()> {
+        let db = self.database();
+
+        let write_txn = db.begin_write()?;
+
+        {
+            let mut table = write_txn.open_table(ANALYTICS_TABLE)?;
+
+            for key in [
+                format!("{}{}", QUERY_KEY_PREFIX, query_hash),
+                format!("{}{}", LENGTH_KEY_PREFIX, length_bucket),
+                format!("{}{}", RESULTS_KEY_PREFIX, results_bucket),
+            ] {
+                let current = table.get(key.as_str())?.map(|v| v.value()).unwrap_or(0);
+                table.insert(key.as_str(), current + 1)?;
+            }
+        }
+
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    fn get_search_analytics(&self, top: usize) -> Result<SearchAnalytics> {
+        let db = self.database();
+
+        let read_txn = db.begin_read()?;
+
+        let table = read_txn.open_table(ANALYTICS_TABLE)?;
+
+        let rows = table
+            .iter()?
+            .map(|entry| entry.map(|(key, count)| (key.value().to_string(), count.value())))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(SearchAnalytics::from_rows(rows.into_iter(), top))
+    }
+
+    fn add_keyset_amounts(&self, flow: KeysetFlow, amounts: &[(Id, u64)]) -> Result<()> {
+        let db = self.database();
+
+        let write_txn = db.begin_write()?;
+
+        {
+            let mut table = write_txn.open_table(KEYSET_AMOUNTS_TABLE)?;
+
+            for (keyset_id, amount) in amounts {
+                let key = format!("{}{}", flow.key_prefix(), keyset_id);
+
+                let current = table.get(key.as_str())?.map(|v| v.value()).unwrap_or(0);
+                table.insert(key.as_str(), current + amount)?;
+            }
+        }
+
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    fn get_issuance_summary(&self) -> Result<Vec<KeysetIssuance>> {
+        let db = self.database();
+
+        let read_txn = db.begin_read()?;
+
+        let table = read_txn.open_table(KEYSET_AMOUNTS_TABLE)?;
+
+        let mut amounts = Vec::new();
+
+        for entry in table.iter()? {
+            let (key, amount) = entry?;
+
+            let flow = KeysetFlow::ALL.into_iter().find_map(|flow| {
+                key.value()
+                    .strip_prefix(flow.key_prefix())
+                    .map(|keyset_id| (flow, keyset_id.to_string()))
+            });
+
+            if let Some((flow, keyset_id)) = flow {
+                amounts.push((flow, keyset_id, amount.value()));
+            }
+        }
+
+        Ok(KeysetIssuance::from_amounts(amounts.into_iter()))
+    }
+
+    fn add_pending_melt(&self, quote_id: &str, melt: &PendingMelt) -> Result<()> {
+        let db = self.database();
+
+        let write_txn = db.begin_write()?;
+
+        {
+            let mut table = write_txn.open_table(PENDING_MELTS_TABLE)?;
+            table.insert(quote_id, serde_json::to_string(melt)?.as_str())?;
+        }
+
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    fn take_pending_melt(&self, quote_id: &str) -> Result<Option<PendingMelt>> {
+        let db = self.database();
+
+        let write_txn = db.begin_write()?;
+
+        let melt = {
+            let mut table = write_txn.open_table(PENDING_MELTS_TABLE)?;
+
+            let melt = table
+                .remove(quote_id)?
+                .map(|v| serde_json::from_str(v.value()))
+                .transpose()?;
+            melt
+        };
+
+        write_txn.commit()?;
+
+        Ok(melt)
+    }
+
+    fn add_session(&self, id: &str, session: &Session) -> Result<()> {
+        let db = self.database();
+
+        let write_txn = db.begin_write()?;
+
+        {
+            let mut table = write_txn.open_table(SESSIONS_TABLE)?;
+            table.insert(id, serde_json::to_string(session)?.as_str())?;
+        }
+
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    fn get_session(&self, id: &str) -> Result<Option<Session>> {
+        let db = self.database();
+
+        let read_txn = db.begin_read()?;
+
+        let table = read_txn.open_table(SESSIONS_TABLE)?;
+
+        let session = table
+            .get(id)?
+            .map(|v| serde_json::from_str(v.value()))
+            .transpose()?;
+
+        Ok(session)
+    }
+
+    fn debit_session(
+        &self,
+        id: &str,
+        amount: u64,
+        now: u64,
+        idle_secs: u64,
+    ) -> Result<SessionDebit> {
+        let db = self.database();
+
+        let write_txn = db.begin_write()?;
+
+        let debit = {
+            let mut table = write_txn.open_table(SESSIONS_TABLE)?;
+
+            let session = table
+                .get(id)?
+                .map(|v| serde_json::from_str::<Session>(v.value()))
+                .transpose()?;
+
+            match session {
+                Some(session) if session.closed || session.is_expired(now, idle_secs) => {
+                    SessionDebit::NotFound
+                }
+                Some(session) if session.balance < amount => {
+                    SessionDebit::Insufficient(session.balance)
+                }
+                Some(mut session) => {
+                    session.balance -= amount;
+                    session.last_used_at = now;
+                    table.insert(id, serde_json::to_string(&session)?.as_str())?;
+
+                    SessionDebit::Debited(session.balance)
+                }
+                None => SessionDebit::NotFound,
+            }
+        };
+
+        write_txn.commit()?;
+
+        Ok(debit)
+    }
+
+    fn credit_session(&self, id: &str, amount: u64) -> Result<()> {
+        let db = self.database();
+
+        let write_txn = db.begin_write()?;
+
+        {
+            let mut table = write_txn.open_table(SESSIONS_TABLE)?;
+
+            let session = table
+                .get(id)?
+                .map(|v| serde_json::from_str::<Session>(v.value()))
+                .transpose()?;
+
+            if let Some(mut session) = session {
+                session.balance += amount;
+                table.insert(id, serde_json::to_string(&session)?.as_str())?;
+            }
+        }
+
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    fn close_session(&self, id: &str) -> Result<SessionClose> {
+        let db = self.database();
+
+        let write_txn = db.begin_write()?;
+
+        let close = {
+            let mut table = write_txn.open_table(SESSIONS_TABLE)?;
+
+            let session = table
+                .get(id)?
+                .map(|v| serde_json::from_str::<Session>(v.value()))
+                .transpose()?;
+
+            match session {
+                Some(Session {
+                    refund: Some(refund),
+                    ..
+                }) => SessionClose::Refunded(refund),
+                Some(session) if session.closed => SessionClose::Closing,
+                Some(session) if session.balance == 0 => SessionClose::Empty,
+                Some(mut session) => {
+                    let balance = session.balance;
+
+                    session.balance = 0;
+                    session.closed = true;
+                    table.insert(id, serde_json::to_string(&session)?.as_str())?;
+
+                    SessionClose::Closed(balance)
+                }
+                None => SessionClose::NotFound,
+            }
+        };
+
+        write_txn.commit()?;
+
+        Ok(close)
+    }
+
+    fn set_session_refund(&self, id: &str, refund: &str) -> Result<()> {
+        let db = self.database();
+
+        let write_txn = db.begin_write()?;
+
+        {
+            let mut table = write_txn.open_table(SESSIONS_TABLE)?;
+
+            let session = table
+                .get(id)?
+                .map(|v| serde_json::from_str::<Session>(v.value()))
+                .transpose()?;
+
+            if let Some(mut session) = session {
+                session.refund = Some(refund.to_string());
+                table.insert(id, serde_json::to_string(&session)?.as_str())?;
+            }
+        }
+
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    fn reopen_session(&self, id: &str, balance: u64) -> Result<()> {
+        let db = self.database();
+
+        let write_txn = db.begin_write()?;
+
+        {
+            let mut table = write_txn.open_table(SESSIONS_TABLE)?;
+
+            let session = table
+                .get(id)?
+                .map(|v| serde_json::from_str::<Session>(v.value()))
+                .transpose()?;
+
+            if let Some(mut session) = session {
+                session.balance = balance;
+                session.closed = false;
+                table.insert(id, serde_json::to_string(&session)?.as_str())?;
+            }
+        }
+
+        write_txn.commit()?;
+
+        Ok(())
+    }
+}
+
+/// [`SearchStore`] kept in memory, for tests
+#[derive(Clone, Default)]
+pub struct MemoryDb {
+    inner: Arc<Mutex<MemoryTables>>,
+    /// Days daily search counts are kept for
+    count_retention_days: u64,
+}
+
+#[derive(Default)]
+struct MemoryTables {
+    /// Keyed like the redb search counts so ranges sort the same
+    search_counts: BTreeMap<String, u64>,
+    search_payments: HashMap<String, SearchPayment>,
+    last_pay_index: Option<u64>,
+    received_payments: HashMap<String, ReceivedPayment>,
+    revenue: HashMap<String, u64>,
+    used_ys: HashMap<PublicKey, u64>,
+    /// Keyed like the redb analytics
+    analytics: HashMap<String, u64>,
+    keyset_amounts: HashMap<(KeysetFlow, Id), u64>,
+    kagi_balance: Option<KagiBalance>,
+    sessions: HashMap<String, Session>,
+    pending_melts: HashMap<String, PendingMelt>,
+}
+
+impl MemoryDb {
+    pub fn new(count_retention_days: u64) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(MemoryTables::default())),
+            count_retention_days,
+        }
+    }
+
+    fn tables(&self) -> MutexGuard<'_, MemoryTables> {
+        self.inner.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl SearchStore for MemoryDb {
+    fn stats(&self) -> Result<DbStats> {
+        let tables = self.tables();
+
+        let rows = [
+            (SEARCH_COUNTS_TABLE.name(), tables.search_counts.len()),
+            (SEARCH_PAYMENTS_TABLE.name(), tables.search_payments.len()),
+            (
+                CLN_STATE_TABLE.name(),
+                usize::from(tables.last_pay_index.is_some()),
+            ),
+            (SESSIONS_TABLE.name(), tables.sessions.len()),
+            (
+                RECEIVED_PAYMENTS_TABLE.name(),
+                tables.received_payments.len(),
+            ),
+            (REVENUE_TABLE.name(), tables.revenue.len()),
+            (USED_YS_TABLE.name(), tables.used_ys.len()),
+            (ANALYTICS_TABLE.name(), tables.analytics.len()),
+            (KEYSET_AMOUNTS_TABLE.name(), tables.keyset_amounts.len()),
+            (
+                META_TABLE.name(),
+                usize::from(tables.kagi_balance.is_some()),
+            ),
+            (PENDING_MELTS_TABLE.name(), tables.pending_melts.len()),
+        ];
+
+        Ok(DbStats {
+            file_size_bytes: None,
+            tables: rows
+                .into_iter()
+                .map(|(name, rows)| (name.to_string(), rows as u64))
+                .collect(),
+        })
+    }
+
+    fn increment_search_count(&self) -> Result<()> {
+        let now = unix_time();
+        let today_key = day_key(now);
+
+        let mut tables = self.tables();
+        let counts = &mut tables.search_counts;
+
+        let first_today = !counts.contains_key(&today_key);
+
+        for key in [ALL_TIME_KEY.to_string(), today_key, month_key(now)] {
+            *counts.entry(key).or_insert(0) += 1;
+        }
+
+        if first_today {
+            let cutoff =
+                day_key(now.saturating_sub(self.count_retention_days.saturating_mul(SECS_PER_DAY)));
+
+            counts.retain(|key, _| !key.starts_with(DAY_KEY_PREFIX) || key >= &cutoff);
+        }
+
+        Ok(())
+    }
+
+    fn check(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn get_search_count(&self) -> Result<SearchCount> {
+        let now = unix_time();
+
+        let tables = self.tables();
+        let count = |key: &str| tables.search_counts.get(key).copied().unwrap_or(0);
+
+        Ok(SearchCount {
+            all_time_search_count: count(ALL_TIME_KEY),
+            today_search_count: count(&day_key(now)),
+            month_search_count: count(&month_key(now)),
+        })
+    }
+
+    fn get_search_counts_range(&self, from: &str, to: &str) -> Result<Vec<(String, u64)>> {
+        let from = format!("{}{}", DAY_KEY_PREFIX, from);
+        let to = format!("{}{}", DAY_KEY_PREFIX, to);
+
+        if from > to {
+            return Ok(Vec::new());
+        }
+
+        let counts = self
+            .tables()
+            .search_counts
+            .range(from..=to)
+            .filter_map(|(key, count)| {
+                key.strip_prefix(DAY_KEY_PREFIX)
+                    .map(|day| (day.to_string(), *count))
+            })
+            .collect();
+
+        Ok(counts)
+    }
+
+    fn add_search_payment(&self, id: &str, payment: &SearchPayment) -> Result<()> {
+        self.tables()
+            .search_payments
+            .insert(id.to_string(), payment.clone());
+
+        Ok(())
+    }
+
+    fn remove_search_payment(&self, id: &str) -> Result<()> {
+        self.tables().search_payments.remove(id);
+
+        Ok(())
+    }
+
+    fn set_search_payment_refund(&self, id: &str, refund: &str) -> Result<()> {
+        if let Some(payment) = self.tables().search_payments.get_mut(id) {
+            payment.refund = Some(refund.to_string());
+        }
+
+        Ok(())
+    }
+
+    fn get_unrefunded_search_payments(&self) -> Result<Vec<(String, SearchPayment)>> {
+        let payments = self
+            .tables()
+            .search_payments
+            .iter()
+            .filter(|(_, payment)| payment.refund.is_none())
+            .map(|(id, payment)| (id.clone(), payment.clone()))
+            .collect();
+
+        Ok(payments)
+    }
+
+    fn get_last_pay_index(&self) -> Result<Option<u64>> {
+        Ok(self.tables().last_pay_index)
+    }
+
+    fn set_last_pay_index(&self, pay_index: u64) -> Result<()> {
+        self.tables().last_pay_index = Some(pay_index);
+
+        Ok(())
+    }
+
+    fn add_revenue(&self, payment_hash: &str, msats: u64) -> Result<bool> {
+        let now = unix_time();
+
+        let mut tables = self.tables();
+
+        if tables.received_payments.contains_key(payment_hash) {
+            return Ok(false);
+        }
+
+        tables.received_payments.insert(
+            payment_hash.to_string(),
+            ReceivedPayment {
+                msats,
+                received_at: now,
+            },
+        );
+
+        for key in [
+            ALL_TIME_REVENUE_KEY.to_string(),
+            day_key(now),
+            month_key(now),
+        ] {
+            *tables.revenue.entry(key).or_insert(0) += msats;
+        }
+
+        Ok(true)
+    }
+
+    fn get_revenue_summary(&self) -> Result<RevenueSummary> {
+        let now = unix_time();
+
+        let tables = self.tables();
+        let revenue = |key: &str| tables.revenue.get(key).copied().unwrap_or(0);
+
+        Ok(RevenueSummary {
+            all_time_msats: revenue(ALL_TIME_REVENUE_KEY),
+            today_msats: revenue(&day_key(now)),
+            month_msats: revenue(&month_key(now)),
+        })
+    }
+
+    fn any_used_ys(&self, ys: &[PublicKey]) -> Result<bool> {
+        let tables = self.tables();
+
+        Ok(ys.iter().any(|y| tables.used_ys.contains_key(y)))
+    }
+
+    fn add_used_ys(&self, ys: &[PublicKey]) -> Result<()> {
+        let now = unix_time();
+
+        let mut tables = self.tables();
+
+        for y in ys {
+            tables.used_ys.insert(*y, now);
+        }
+
+        Ok(())
+    }
+
+    fn prune_used_ys(&self, before: u64) -> Result<u64> {
+        let mut tables = self.tables();
+
+        let before_len = tables.used_ys.len();
+        tables.used_ys.retain(|_, used_at| *used_at >= before);
+
+        Ok((before_len - tables.used_ys.len()) as u64)
+    }
+
+    fn set_kagi_balance(&self, balance_usd: f64) -> Result<Option<KagiBalance>> {
+        let balance = KagiBalance {
+            balance_usd,
+            updated_at: unix_time(),
+        };
+
+        Ok(self.tables().kagi_balance.replace(balance))
+    }
+
+    fn get_kagi_balance(&self) -> Result<Option<KagiBalance>> {
+        Ok(self.tables().kagi_balance)
+    }
+
+    fn record_search_analytics(
+        &self,
+        query_hash: &str,
+        length_bucket: &str,
+        results_bucket: &str,
+    ) -> Result<()> {
+        let mut tables = self.tables();
+
+        for key in [
+            format!("{}{}", QUERY_KEY_PREFIX, query_hash),
+            format!("{}{}", LENGTH_KEY_PREFIX, length_bucket),
+            format!("{}{}", RESULTS_KEY_PREFIX, results_bucket),
+        ] {
+            *tables.analytics.entry(key).or_insert(0) += 1;
+        }
+
+        Ok(())
+    }
+
+    fn get_search_analytics(&self, top: usize) -> Result<SearchAnalytics> {
+        let rows = self.tables().analytics.clone();
+
+        Ok(SearchAnalytics::from_rows(rows.into_iter(), top))
+    }
+
+    fn add_keyset_amounts(&self, flow: KeysetFlow, amounts: &[(Id, u64)]) -> Result<()> {
+        let mut tables = self.tables();
+
+        for (keyset_id, amount) in amounts {
+            *tables.keyset_amounts.entry((flow, *keyset_id)).or_insert(0) += amount;
+        }
+
+        Ok(())
+    }
+
+    fn get_issuance_summary(&self) -> Result<Vec<KeysetIssuance>> {
+        let amounts: Vec<_> = self
+            .tables()
+            .keyset_amounts
+            .iter()
+            .map(|((flow, keyset_id), amount)| (*flow, keyset_id.to_string(), *amount))
+            .collect();
+
+        Ok(KeysetIssuance::from_amounts(amounts.into_iter()))
+    }
+
+    fn add_pending_melt(&self, quote_id: &str, melt: &PendingMelt) -> Result<()> {
+        self.tables()
+            .pending_melts
+            .insert(quote_id.to_string(), melt.clone());
+
+        Ok(())
+    }
+
+    fn take_pending_melt(&self, quote_id: &str) -> Result<Option<PendingMelt>> {
+        Ok(self.tables().pending_melts.remove(quote_id))
+    }
+
+    fn add_session(&self, id: &str, session: &Session) -> Result<()> {
+        self.tables()
+            .sessions
+            .insert(id.to_string(), session.clone());
+
+        Ok(())
+    }
+
+    fn get_session(&self, id: &str) -> Result<Option<Session>> {
+        Ok(self.tables().sessions.get(id).cloned())
+    }
+
+    fn debit_session(
+        &self,
+        id: &str,
+        amount: u64,
+        now: u64,
+        idle_secs: u64,
+    ) -> Result<SessionDebit> {
+        let debit = match self.tables().sessions.get_mut(id) {
+            Some(session) if session.closed || session.is_expired(now, idle_secs) => {
+                SessionDebit::NotFound
+            }
+            Some(session) if session.balance < amount => {
+                SessionDebit::Insufficient(session.balance)
+            }
+            Some(session) => {
+                session.balance -= amount;
+                session.last_used_at = now;
+
+                SessionDebit::Debited(session.balance)
+            }
+            None => SessionDebit::NotFound,
+        };
+
+        Ok(debit)
+    }
+
+    fn credit_session(&self, id: &str, amount: u64) -> Result<()> {
+        if let Some(session) = self.tables().sessions.get_mut(id) {
+            session.balance += amount;
+        }
+
+        Ok(())
+    }
+
+    fn close_session(&self, id: &str) -> Result<SessionClose> {
+        let close = match self.tables().sessions.get_mut(id) {
+            Some(Session {
+                refund: Some(refund),
+                ..
+            }) => SessionClose::Refunded(refund.clone()),
+            Some(session) if session.closed => SessionClose::Closing,
+            Some(session) if session.balance == 0 => SessionClose::Empty,
+            Some(session) => {
+                let balance = session.balance;
+
+                session.balance = 0;
+                session.closed = true;
+
+                SessionClose::Closed(balance)
+            }
+            None => SessionClose::NotFound,
+        };
+
+        Ok(close)
+    }
+
+    fn set_session_refund(&self, id: &str, refund: &str) -> Result<()> {
+        if let Some(session) = self.tables().sessions.get_mut(id) {
+            session.refund = Some(refund.to_string());
+        }
+
+        Ok(())
+    }
+
+    fn reopen_session(&self, id: &str, balance: u64) -> Result<()> {
+        if let Some(session) = self.tables().sessions.get_mut(id) {
+            session.balance = balance;
+            session.closed = false;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Hash, Serialize, Deserialize, ToSchema)]
+pub struct SearchCount {
+    pub all_time_search_count: u64,
+    /// Searches since midnight UTC
+    #[serde(default)]
+    pub today_search_count: u64,
+    /// Searches since the start of the month UTC
+    #[serde(default)]
+    pub month_search_count: u64,
+}
+
+/// Msats received for mint quotes
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema)]
+pub struct RevenueSummary {
+    pub all_time_msats: u64,
+    /// Received since midnight UTC
+    pub today_msats: u64,
+    /// Received since the start of the month UTC
+    pub month_msats: u64,
+}
+
+/// Way an amount of ecash entered or left circulation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeysetFlow {
+    /// Signed through the mint, which records the signatures too
+    Issued,
+    /// Signed directly for a refund, the mint keeps no record of these
+    IssuedDirect,
+    /// Signed for the price of a search paid with change, never handed out
+    Burned,
+    /// Spent
+    Redeemed,
+}
+
+impl KeysetFlow {
+    const ALL: [KeysetFlow; 4] = [
+        KeysetFlow::Issued,
+        KeysetFlow::IssuedDirect,
+        KeysetFlow::Burned,
+        KeysetFlow::Redeemed,
+    ];
+
+    fn key_prefix(&self) -> &'static str {
+        match self {
+            KeysetFlow::Issued => "issued:",
+            KeysetFlow::IssuedDirect => "issued_direct:",
+            KeysetFlow::Burned => "burned:",
+            KeysetFlow::Redeemed => "redeemed:",
+        }
+    }
+}
+
+/// Totals of one keyset since accounting started
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct KeysetIssuance {
+    pub keyset_id: String,
+    /// Signed through the mint and handed out
+    pub issued: u64,
+    /// Signed directly for refunds
+    pub issued_direct: u64,
+    /// Signed through the mint for search prices and never handed out
+    pub burned: u64,
+    pub redeemed: u64,
+    /// Handed out and not yet redeemed, negative when proofs issued before
+    /// accounting started are redeemed
+    pub outstanding: i64,
+}
+
+impl KeysetIssuance {
+    /// Summaries from the amount of each flow and keyset
+    fn from_amounts(amounts: impl Iterator<Item = (KeysetFlow, String, u64)>) -> Vec<Self> {
+        let mut summaries: BTreeMap<String, KeysetIssuance> = BTreeMap::new();
+
+        for (flow, keyset_id, amount) in amounts {
+            let summary = summaries
+                .entry(keyset_id.clone())
+                .or_insert_with(|| KeysetIssuance {
+                    keyset_id,
+                    ..Default::default()
+                });
+
+            match flow {
+                KeysetFlow::Issued => summary.issued += amount,
+                KeysetFlow::IssuedDirect => summary.issued_direct += amount,
+                KeysetFlow::Burned => summary.burned += amount,
+                KeysetFlow::Redeemed => summary.redeemed += amount,
+            }
+        }
+
+        summaries
+            .into_values()
+            .map(|mut summary| {
+                summary.outstanding =
+                    (summary.issued + summary.issued_direct) as i64 - summary.redeemed as i64;
+                summary
+            })
+            .collect()
+    }
+}
+
+/// Searches counted by hashed query and by coarse buckets
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct SearchAnalytics {
+    /// Most searched query hashes with their counts, most searched first
+    pub top_queries: Vec<(String, u64)>,
+    /// Searches by normalized query length in characters
+    pub query_lengths: BTreeMap<String, u64>,
+    /// Searches by number of results returned
+    pub result_counts: BTreeMap<String, u64>,
+}
+
+impl SearchAnalytics {
+    /// Sort the counts read from `rows`, keyed like the analytics table
+    fn from_rows(rows: impl Iterator<Item = (String, u64)>, top: usize) -> Self {
+        let mut analytics = SearchAnalytics {
+            top_queries: Vec::new(),
+            query_lengths: BTreeMap::new(),
+            result_counts: BTreeMap::new(),
+        };
+
+        for (key, count) in rows {
+            if let Some(hash) = key.strip_prefix(QUERY_KEY_PREFIX) {
+                analytics.top_queries.push((hash.to_string(), count));
+            } else if let Some(bucket) = key.strip_prefix(LENGTH_KEY_PREFIX) {
+                analytics.query_lengths.insert(bucket.to_string(), count);
+            } else if let Some(bucket) = key.strip_prefix(RESULTS_KEY_PREFIX) {
+                analytics.result_counts.insert(bucket.to_string(), count);
+            }
+        }
+
+        analytics
+            .top_queries
+            .sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        analytics.top_queries.truncate(top);
+
+        analytics
+    }
+}
+
+/// Size of the search api database
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct DbStats {
+    /// `None` for a store kept in memory
+    pub file_size_bytes: Option<u64>,
+    /// Rows in each table, by table name
+    pub tables: BTreeMap<String, u64>,
+}
+
+/// Outcome of [`Db::compact_if_fragmented`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compaction {
+    /// Compacted, carries the file size before and after
+    Compacted { before_bytes: u64, after_bytes: u64 },
+    /// Too little of the file is fragmented to be worth it
+    NotNeeded { fragmented_percent: u64 },
+    /// A transaction was open, try again later
+    Busy,
+}
+
+/// Every table of the search api database, keyed by table name
+///
+/// Numeric tables hold json numbers, the others the json the rows are
+/// stored as.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DbDump {
+    pub version: u32,
+    pub tables: BTreeMap<String, BTreeMap<String, serde_json::Value>>,
+}
+
+impl DbDump {
+    /// Name and number of rows of each table
+    pub fn row_counts(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.tables
+            .iter()
+            .map(|(name, rows)| (name.as_str(), rows.len()))
+    }
+}
+
+/// Kagi api balance as last reported by kagi
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema)]
+pub struct KagiBalance {
+    pub balance_usd: f64,
+    /// Unix time of the response that reported it
+    pub updated_at: u64,
+}
+
+const SECS_PER_DAY: u64 = 86_400;
+
+/// Key of the daily count for the UTC day of `unix_time`
+fn day_key(unix_time: u64) -> String {
+    let (year, month, day) = civil_date(unix_time);
+
+    format!("{}{:04}-{:02}-{:02}", DAY_KEY_PREFIX, year, month, day)
+}
+
+/// Key of the monthly count for the UTC month of `unix_time`
+fn month_key(unix_time: u64) -> String {
+    let (year, month, _) = civil_date(unix_time);
+
+    format!("{}{:04}-{:02}", MONTH_KEY_PREFIX, year, month)
+}
+
+/// UTC year, month and day of `unix_time`
+fn civil_date(unix_time: u64) -> (u64, u64, u64) {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = unix_time / SECS_PER_DAY + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    (year, month, day)
+}
+
+/// Proofs spent for a search that has not been served yet
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchPayment {
+    pub ys: Vec<PublicKey>,
+    pub created_at: u64,
+    /// Token issued if the upstream request failed
+    pub refund: Option<String>,
+}
+
+/// Incoming payment counted towards revenue
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReceivedPayment {
+    pub msats: u64,
+    pub received_at: u64,
+}
+
+/// Inputs of a melt the mint answered while its payment was still pending
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingMelt {
+    /// Input amounts per keyset, recorded as redeemed once the melt is paid
+    pub inputs: Vec<(Id, u64)>,
+    pub created_at: u64,
+}
+
+/// Prepaid balance requests can be paid from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub balance: u64,
+    pub created_at: u64,
+    pub last_used_at: u64,
+    /// Set once the session has been closed for a refund
+    #[serde(default)]
+    pub closed: bool,
+    /// Token the remaining balance was refunded with
+    #[serde(default)]
+    pub refund: Option<String>,
+}
+
+impl Session {
+    /// Unix time the session expires at if it is not used again
+    pub fn expires_at(&self, idle_secs: u64) -> u64 {
+        self.last_used_at.saturating_add(idle_secs)
+    }
+
+    pub fn is_expired(&self, now: u64, idle_secs: u64) -> bool {
+        now >= self.expires_at(idle_secs)
+    }
+}
+
+/// Outcome of debiting a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionDebit {
+    /// Debited, carries the remaining balance
+    Debited(u64),
+    /// Balance is lower than the amount, carries the balance
+    Insufficient(u64),
+    /// Session does not exist or has expired
+    NotFound,
+}
+
+/// Outcome of closing a session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionClose {
+    /// Closed, carries the balance to refund
+    Closed(u64),
+    /// Already refunded, carries the refund token
+    Refunded(String),
+    /// Closed by another request that has not stored its refund yet
+    Closing,
+    /// Session has no balance to refund
+    Empty,
+    /// Session does not exist
+    NotFound,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use cdk::nuts::SecretKey;
+
+    use super::*;
+    use crate::test_utils::temp_dir;
+
+    /// Tests every [`SearchStore`] has to pass, run against the store built
+    /// by `$store`
+    macro_rules! search_store_tests {
+        ($name:ident, $store:expr) => {
+            mod $name {
+                use super::*;
+
+                fn store() -> impl SearchStore {
+                    $store
+                }
+
+                #[test]
+                fn increment_counts_today_and_this_month() {
+                    let store = store();
+
+                    store.increment_search_count().unwrap();
+                    store.increment_search_count().unwrap();
+
+                    let count = store.get_search_count().unwrap();
+                    assert_eq!(count.all_time_search_count, 2);
+                    assert_eq!(count.today_search_count, 2);
+                    assert_eq!(count.month_search_count, 2);
+
+                    let today = day_key(unix_time());
+                    let today = today.strip_prefix(DAY_KEY_PREFIX).unwrap();
+                    assert_eq!(
+                        store.get_search_counts_range(today, today).unwrap(),
+                        vec![(today.to_string(), 2)]
+                    );
+                    assert!(store
+                        .get_search_counts_range("2024-02-01", "2024-01-01")
+                        .unwrap()
+                        .is_empty());
+                }
+
+                #[test]
+                fn unrefunded_payments_are_kept_until_served() {
+                    let store = store();
+                    let payment = SearchPayment {
+                        ys: vec![SecretKey::generate().public_key()],
+                        created_at: 1,
+                        refund: None,
+                    };
+
+                    store.add_search_payment("served", &payment).unwrap();
+                    store.add_search_payment("refunded", &payment).unwrap();
+                    store.add_search_payment("lost", &payment).unwrap();
+
+                    store.remove_search_payment("served").unwrap();
+                    store
+                        .set_search_payment_refund("refunded", "cashuB")
+                        .unwrap();
+
+                    let unrefunded = store.get_unrefunded_search_payments().unwrap();
+                    assert_eq!(unrefunded.len(), 1);
+                    assert_eq!(unrefunded[0].0, "lost");
+                    assert_eq!(unrefunded[0].1.ys, payment.ys);
+                }
+
+                #[test]
+                fn last_pay_index_is_kept() {
+                    let store = store();
+
+                    assert_eq!(store.get_last_pay_index().unwrap(), None);
+                    store.set_last_pay_index(5).unwrap();
+                    store.set_last_pay_index(6).unwrap();
+                    assert_eq!(store.get_last_pay_index().unwrap(), Some(6));
+                }
+
+                #[test]
+                fn revenue_is_counted_once_per_payment() {
+                    let store = store();
+
+                    assert!(store.add_revenue("hash", 1_000).unwrap());
+                    assert!(!store.add_revenue("hash", 1_000).unwrap());
+                    assert!(store.add_revenue("other", 500).unwrap());
+
+                    let revenue = store.get_revenue_summary().unwrap();
+                    assert_eq!(revenue.all_time_msats, 1_500);
+                    assert_eq!(revenue.today_msats, 1_500);
+                    assert_eq!(revenue.month_msats, 1_500);
+                }
+
+                #[test]
+                fn used_ys_are_found_until_pruned() {
+                    let store = store();
+                    let used = SecretKey::generate().public_key();
+                    let unused = SecretKey::generate().public_key();
+
+                    store.add_used_ys(&[used]).unwrap();
+
+                    assert!(store.any_used_ys(&[unused, used]).unwrap());
+                    assert!(!store.any_used_ys(&[unused]).unwrap());
+
+                    assert_eq!(store.prune_used_ys(0).unwrap(), 0);
+                    assert_eq!(store.prune_used_ys(unix_time() + 1).unwrap(), 1);
+                    assert!(!store.any_used_ys(&[used]).unwrap());
+                }
+
+                #[test]
+                fn kagi_balance_returns_the_previous_one() {
+                    let store = store();
+
+                    assert!(store.get_kagi_balance().unwrap().is_none());
+                    assert!(store.set_kagi_balance(10.5).unwrap().is_none());
+
+                    let previous = store.set_kagi_balance(9.25).unwrap().unwrap();
+                    assert_eq!(previous.balance_usd, 10.5);
+                    assert_eq!(store.get_kagi_balance().unwrap().unwrap().balance_usd, 9.25);
+                }
+
+                #[test]
+                fn analytics_are_counted_by_hash_and_bucket() {
+                    let store = store();
+
+                    store.record_search_analytics("aa", "1-10", "10").unwrap();
+                    store.record_search_analytics("aa", "1-10", "0").unwrap();
+                    store.record_search_analytics("bb", "11-20", "10").unwrap();
+
+                    let analytics = store.get_search_analytics(1).unwrap();
+                    assert_eq!(analytics.top_queries, vec![("aa".to_string(), 2)]);
+                    assert_eq!(analytics.query_lengths["1-10"], 2);
+                    assert_eq!(analytics.query_lengths["11-20"], 1);
+                    assert_eq!(analytics.result_counts["10"], 2);
+                    assert_eq!(analytics.result_counts["0"], 1);
+                }
+
+                #[test]
+                fn keyset_amounts_add_up_per_flow() {
+                    let store = store();
+                    let keyset_id = Id::from_str("009a1f293253e41e").unwrap();
+
+                    store
+                        .add_keyset_amounts(KeysetFlow::Issued, &[(keyset_id, 10)])
+                        .unwrap();
+                    store
+                        .add_keyset_amounts(KeysetFlow::IssuedDirect, &[(keyset_id, 2)])
+                        .unwrap();
+                    store
+                        .add_keyset_amounts(KeysetFlow::Burned, &[(keyset_id, 1)])
+                        .unwrap();
+                    store
+                        .add_keyset_amounts(KeysetFlow::Redeemed, &[(keyset_id, 4)])
+                        .unwrap();
+                    store
+                        .add_keyset_amounts(KeysetFlow::Redeemed, &[(keyset_id, 3)])
+                        .unwrap();
+
+                    let summary = store.get_issuance_summary().unwrap();
+                    assert_eq!(summary.len(), 1);
+                    assert_eq!(summary[0].keyset_id, keyset_id.to_string());
+                    assert_eq!(summary[0].issued, 10);
+                    assert_eq!(summary[0].issued_direct, 2);
+                    assert_eq!(summary[0].burned, 1);
+                    assert_eq!(summary[0].redeemed, 7);
+                    assert_eq!(summary[0].outstanding, 5);
+                }
+
+                #[test]
+                fn session_balance_is_debited_and_credited() {
+                    let store = store();
+                    let session = Session {
+                        balance: 10,
+                        created_at: 100,
+                        last_used_at: 100,
+                        closed: false,
+                        refund: None,
+                    };
+                    store.add_session("session", &session).unwrap();
+
+                    assert_eq!(
+                        store.debit_session("session", 3, 110, 60).unwrap(),
+                        SessionDebit::Debited(7)
+                    );
+                    assert_eq!(
+                        store.debit_session("session", 8, 120, 60).unwrap(),
+                        SessionDebit::Insufficient(7)
+                    );
+                    // Idle since the debit at 110
+                    assert_eq!(
+                        store.debit_session("session", 1, 170, 60).unwrap(),
+                        SessionDebit::NotFound
+                    );
+                    assert_eq!(
+                        store.debit_session("missing", 1, 110, 60).unwrap(),
+                        SessionDebit::NotFound
+                    );
+
+                    store.credit_session("session", 3).unwrap();
+
+                    let session = store.get_session("session").unwrap().unwrap();
+                    assert_eq!(session.balance, 10);
+                    assert_eq!(session.last_used_at, 110);
+                }
+
+                #[test]
+                fn session_is_refunded_once() {
+                    let store = store();
+                    let session = Session {
+                        balance: 10,
+                        created_at: 100,
+                        last_used_at: 100,
+                        closed: false,
+                        refund: None,
+                    };
+                    store.add_session("session", &session).unwrap();
+
+                    assert_eq!(
+                        store.close_session("session").unwrap(),
+                        SessionClose::Closed(10)
+                    );
+                    assert_eq!(
+                        store.close_session("session").unwrap(),
+                        SessionClose::Closing
+                    );
+                    assert_eq!(
+                        store.debit_session("session", 1, 110, 60).unwrap(),
+                        SessionDebit::NotFound
+                    );
+
+                    // Refund failed, the balance is handed back
+                    store.reopen_session("session", 10).unwrap();
+                    assert_eq!(
+                        store.debit_session("session", 1, 110, 60).unwrap(),
+                        SessionDebit::Debited(9)
+                    );
+
+                    assert_eq!(
+                        store.close_session("session").unwrap(),
+                        SessionClose::Closed(9)
+                    );
+                    store.set_session_refund("session", "cashuB").unwrap();
+                    assert_eq!(
+                        store.close_session("session").unwrap(),
+                        SessionClose::Refunded("cashuB".to_string())
+                    );
+                    assert_eq!(
+                        store.close_session("missing").unwrap(),
+                        SessionClose::NotFound
+                    );
+                }
+
+                #[test]
+                fn empty_session_is_not_closed() {
+                    let store = store();
+                    let session = Session {
+                        balance: 0,
+                        created_at: 100,
+                        last_used_at: 100,
+                        closed: false,
+                        refund: None,
+                    };
+                    store.add_session("session", &session).unwrap();
+
+                    assert_eq!(store.close_session("session").unwrap(), SessionClose::Empty);
+                }
+
+                #[test]
+                fn pending_melt_is_taken_once() {
+                    let store = store();
+                    let melt = PendingMelt {
+                        inputs: vec![(Id::from_str("009a1f293253e41e").unwrap(), 64)],
+                        created_at: 100,
+                    };
+                    store.add_pending_melt("quote", &melt).unwrap();
+
+                    assert_eq!(store.take_pending_melt("quote").unwrap(), Some(melt));
+                    assert_eq!(store.take_pending_melt("quote").unwrap(), None);
+                    assert_eq!(store.take_pending_melt("missing").unwrap(), None);
+                }
+
+                #[test]
+                fn stats_count_rows() {
+                    let store = store();
+
+                    store.increment_search_count().unwrap();
+                    store.set_last_pay_index(1).unwrap();
+
+                    let stats = store.stats().unwrap();
+                    // All time, today and this month
+                    assert_eq!(stats.tables[SEARCH_COUNTS_TABLE.name()], 3);
+                    assert_eq!(stats.tables[SESSIONS_TABLE.name()], 0);
+                }
+            }
+        };
+    }
+
+    search_store_tests!(redb, test_db(400));
+    search_store_tests!(memory, MemoryDb::new(400));
+
+    fn test_db(count_retention_days: u64) -> Db {
+        Db::new(&temp_dir().join("search.redb"), count_retention_days).unwrap()
+    }
+
+    fn set_count(db: &Db, key: &str, count: u64) {
+        let database = db.database();
+        let write_txn = database.begin_write().unwrap();
+        write_txn
+            .open_table(SEARCH_COUNTS_TABLE)
+            .unwrap()
+            .insert(key, count)
+            .unwrap();
+        write_txn.commit().unwrap();
+    }
+
+    fn get_count(db: &Db, key: &str) -> Option<u64> {
+        let database = db.database();
+        let read_txn = database.begin_read().unwrap();
+        let table = read_txn.open_table(SEARCH_COUNTS_TABLE).unwrap();
+        let count = table.get(key).unwrap().map(|v| v.value());
+        count
+    }
+
+    #[test]
+    fn day_and_month_keys_follow_utc_calendar() {
+        // 2024-01-31 23:59:59
+        assert_eq!(day_key(1_706_745_599), "day:2024-01-31");
+        assert_eq!(month_key(1_706_745_599), "month:2024-01");
+        // 2024-02-29, a leap day
+        assert_eq!(day_key(1_709_164_800), "day:2024-02-29");
+        assert_eq!(month_key(1_709_164_800), "month:2024-02");
+        // 2024-03-01 00:00:00
+        assert_eq!(day_key(1_709_251_200), "day:2024-03-01");
+        assert_eq!(month_key(1_709_251_200), "month:2024-03");
+    }
+
+    #[test]
+    fn range_is_inclusive_and_only_has_days() {
+        let db = test_db(400);
+
+        set_count(&db, "day:2024-01-31", 1);
+        set_count(&db, "day:2024-02-01", 2);
+        set_count(&db, "day:2024-02-29", 3);
+        set_count(&db, "day:2024-03-01", 4);
+        set_count(&db, "month:2024-02", 5);
+
+        assert_eq!(
+            db.get_search_counts_range("2024-02-01", "2024-02-29")
+                .unwrap(),
+            vec![("2024-02-01".to_string(), 2), ("2024-02-29".to_string(), 3)]
+        );
+    }
+
+    #[test]
+    fn first_search_of_a_day_prunes_expired_days() {
+        let db = test_db(30);
+
+        let now = unix_time();
+        let recent = day_key(now - 10 * SECS_PER_DAY);
+        let expired = day_key(now - 31 * SECS_PER_DAY);
+
+        set_count(&db, &recent, 1);
+        set_count(&db, &expired, 1);
+        set_count(&db, "month:2000-01", 1);
+
+        db.increment_search_count().unwrap();
+
+        assert_eq!(get_count(&db, &recent), Some(1));
+        assert_eq!(get_count(&db, &expired), None);
+        // Monthly counts are kept for good
+        assert_eq!(get_count(&db, "month:2000-01"), Some(1));
+    }
+
+    /// Put a row in every table of `db`
+    fn populate(db: &Db) {
+        db.increment_search_count().unwrap();
+        db.add_search_payment(
+            "payment",
+            &SearchPayment {
+                ys: vec![SecretKey::generate().public_key()],
+                created_at: 1,
+                refund: Some("cashuB".to_string()),
+            },
+        )
+        .unwrap();
+        db.set_last_pay_index(7).unwrap();
+        db.add_revenue("hash", 1_000).unwrap();
+        db.add_used_ys(&[SecretKey::generate().public_key()])
+            .unwrap();
+        db.set_kagi_balance(10.5).unwrap();
+        db.record_search_analytics("aa", "1-10", "10").unwrap();
+        db.add_keyset_amounts(
+            KeysetFlow::Issued,
+            &[(Id::from_str("009a1f293253e41e").unwrap(), 10)],
+        )
+        .unwrap();
+        db.add_session(
+            "session",
+            &Session {
+                balance: 10,
+                created_at: 100,
+                last_used_at: 100,
+                closed: false,
+                refund: None,
+            },
+        )
+        .unwrap();
+        db.add_pending_melt(
+            "quote",
+            &PendingMelt {
+                inputs: vec![(Id::from_str("009a1f293253e41e").unwrap(), 64)],
+                created_at: 100,
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn export_import_round_trip_keeps_every_table() {
+        let db = test_db(400);
+        populate(&db);
+
+        let dump = db.export().unwrap();
+        for (table, rows) in dump.row_counts() {
+            assert!(rows > 0, "{} was not populated", table);
+        }
+
+        // As written to and read from the file by `db export` and `db import`
+        let dump: DbDump = serde_json::from_str(&serde_json::to_string(&dump).unwrap()).unwrap();
+
+        let imported = test_db(400);
+        imported.import(&dump, false).unwrap();
+
+        assert_eq!(imported.export().unwrap().tables, dump.tables);
+        assert_eq!(
+            imported.get_search_count().unwrap().all_time_search_count,
+            1
+        );
+        assert_eq!(imported.get_last_pay_index().unwrap(), Some(7));
+        assert_eq!(
+            imported.get_session("session").unwrap().unwrap().balance,
+            10
+        );
+        assert_eq!(
+            imported.get_kagi_balance().unwrap().unwrap().balance_usd,
+            10.5
+        );
+    }
+
+    #[test]
+    fn import_into_a_used_db_needs_merge() {
+        let db = test_db(400);
+        populate(&db);
+        let dump = db.export().unwrap();
+
+        assert!(db.import(&dump, false).is_err());
+
+        db.import(&dump, true).unwrap();
+
+        // Counters add up, everything else keeps the existing row
+        assert_eq!(db.get_search_count().unwrap().all_time_search_count, 2);
+        assert_eq!(db.get_revenue_summary().unwrap().all_time_msats, 2_000);
+        assert_eq!(db.get_last_pay_index().unwrap(), Some(7));
+        assert_eq!(db.get_issuance_summary().unwrap()[0].issued, 20);
+    }
+
+    #[test]
+    fn import_rejects_other_versions_and_unknown_tables() {
+        let db = test_db(400);
+
+        let mut dump = test_db(400).export().unwrap();
+        dump.version = DUMP_VERSION + 1;
+        assert!(db.import(&dump, false).is_err());
+
+        dump.version = DUMP_VERSION;
+        dump.tables.insert("unknown".to_string(), BTreeMap::new());
+        assert!(db.import(&dump, false).is_err());
+        assert!(db.is_empty().unwrap());
+    }
+
+    #[test]
+    fn compaction_shrinks_the_file_and_preserves_contents() {
+        let db = test_db(400);
+        populate(&db);
+
+        // Free pages left behind by rows written and pruned again
+        for _ in 0..50 {
+            let ys: Vec<PublicKey> = (0..200)
+                .map(|_| SecretKey::generate().public_key())
+                .collect();
+            db.add_used_ys(&ys).unwrap();
+        }
+        db.prune_used_ys(unix_time() + 1).unwrap();
+        db.add_used_ys(&[SecretKey::generate().public_key()])
+            .unwrap();
+
+        let before = db.export().unwrap();
+        let size_before = std::fs::metadata(&db.path).unwrap().len();
+
+        let Compaction::Compacted {
+            before_bytes,
+            after_bytes,
+        } = db.compact_if_fragmented(0).unwrap()
+        else {
+            panic!("Fragmented db was not compacted");
+        };
+
+        let size_after = std::fs::metadata(&db.path).unwrap().len();
+        assert!(
+            size_after < size_before,
+            "{} bytes before, {} after",
+            size_before,
+            size_after
+        );
+        assert_eq!((before_bytes, after_bytes), (size_before, size_after));
+
+        assert_eq!(db.export().unwrap().tables, before.tables);
+
+        // Still writable after compacting
+        db.increment_search_count().unwrap();
+        assert_eq!(db.get_search_count().unwrap().all_time_search_count, 2);
+    }
+
+    #[test]
+    fn compaction_is_skipped_below_the_threshold_or_while_busy() {
+        let db = test_db(400);
+        populate(&db);
+
+        assert!(matches!(
+            db.compact_if_fragmented(101).unwrap(),
+            Compaction::NotNeeded { .. }
+        ));
+
+        let _reading = db.database();
+        assert_eq!(db.compact_if_fragmented(0).unwrap(), Compaction::Busy);
+    }
 }
@@ -0,0 +1,118 @@
+//! Removal of duplicate search results
+
+use std::collections::HashSet;
+
+use reqwest::Url;
+
+use crate::search_provider::SearchResult;
+
+/// Normalize `url` so different spellings of the same page compare equal
+///
+/// The scheme, default ports, trailing slashes, fragments and `utm_*` tracking
+/// params are dropped and the host is lowercased. Urls that cannot be parsed
+/// are only trimmed.
+pub fn normalize_url(url: &str) -> String {
+    let Ok(parsed) = Url::parse(url.trim()) else {
+        return url.trim().to_string();
+    };
+
+    let Some(host) = parsed.host_str() else {
+        return url.trim().to_string();
+    };
+
+    let mut normalized = host.trim_end_matches('.').to_lowercase();
+
+    // `port` is `None` when it is the default for the scheme
+    if let Some(port) = parsed.port() {
+        normalized.push_str(&format!(":{}", port));
+    }
+
+    normalized.push_str(parsed.path().trim_end_matches('/'));
+
+    let query: Vec<String> = parsed
+        .query_pairs()
+        .filter(|(key, _)| !key.starts_with("utm_"))
+        .map(|(key, value)| match value.is_empty() {
+            true => key.to_string(),
+            false => format!("{}={}", key, value),
+        })
+        .collect();
+
+    if !query.is_empty() {
+        normalized.push('?');
+        normalized.push_str(&query.join("&"));
+    }
+
+    normalized
+}
+
+/// Drop results whose normalized url was already seen, keeping the first
+pub fn dedup_results(results: &mut Vec<SearchResult>) {
+    let mut seen = HashSet::new();
+
+    results.retain(|result| seen.insert(normalize_url(&result.url)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_url_table() {
+        let cases = [
+            // Scheme
+            ("https://example.com/page", "example.com/page"),
+            ("http://example.com/page", "example.com/page"),
+            // Trailing slash
+            ("https://example.com/page/", "example.com/page"),
+            ("https://example.com/", "example.com"),
+            ("https://example.com", "example.com"),
+            // Default ports
+            ("https://example.com:443/a", "example.com/a"),
+            ("http://example.com:80/a", "example.com/a"),
+            ("https://example.com:8443/a", "example.com:8443/a"),
+            ("http://example.com:443/a", "example.com:443/a"),
+            // Tracking params
+            (
+                "https://example.com/a?utm_source=x&id=1&utm_medium=y",
+                "example.com/a?id=1",
+            ),
+            ("https://example.com/a?utm_campaign=spring", "example.com/a"),
+            ("https://example.com/a?flag", "example.com/a?flag"),
+            // Host case, the path is case sensitive
+            ("https://WWW.Example.COM/Path", "www.example.com/Path"),
+            ("https://example.com./a", "example.com/a"),
+            // Fragment
+            ("https://example.com/a#top", "example.com/a"),
+            // Unparseable or hostless urls are only trimmed
+            ("  not a url  ", "not a url"),
+            ("mailto:me@example.com", "mailto:me@example.com"),
+            ("", ""),
+        ];
+
+        for (url, expected) in cases {
+            assert_eq!(normalize_url(url), expected, "{:?}", url);
+        }
+    }
+
+    #[test]
+    fn first_of_each_duplicate_is_kept() {
+        let result = |url: &str, title: &str| SearchResult {
+            url: url.to_string(),
+            title: title.to_string(),
+            description: None,
+            age: None,
+            image: None,
+        };
+
+        let mut results = vec![
+            result("https://example.com/a", "first"),
+            result("http://EXAMPLE.com/a/?utm_source=feed", "duplicate"),
+            result("https://example.com/b", "other"),
+        ];
+        dedup_results(&mut results);
+
+        let titles: Vec<&str> = results.iter().map(|r| r.title.as_str()).collect();
+        assert_eq!(titles, ["first", "other"]);
+    }
+}
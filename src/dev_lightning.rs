@@ -0,0 +1,271 @@
+//! CDK lightning backend for development without a lightning node
+//!
+//! Invoices are never paid by anyone, every mint quote is reported paid
+//! shortly after it is created. Melting is not supported.
+
+#![warn(missing_docs)]
+
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::secp256k1::rand::{thread_rng, Rng};
+use bitcoin::secp256k1::{Secp256k1, SecretKey};
+use cdk::amount::{to_unit, Amount};
+use cdk::cdk_lightning::{
+    self, CreateInvoiceResponse, MintLightning, PayInvoiceResponse, PaymentQuoteResponse, Settings,
+};
+use cdk::lightning_invoice::{Currency, InvoiceBuilder, PaymentSecret};
+use cdk::mint;
+use cdk::nuts::{
+    CurrencyUnit, MeltMethodSettings, MeltQuoteBolt11Request, MeltQuoteState, MintMethodSettings,
+    MintQuoteState,
+};
+use cdk::util::unix_time;
+use futures::{Stream, StreamExt};
+use reqwest::Url;
+use thiserror::Error;
+use tokio::sync::{mpsc, Mutex};
+use tokio_util::sync::CancellationToken;
+
+/// Time between creating an invoice and reporting it paid, so the mint has
+/// stored the quote before it hears about the payment
+const PAYMENT_DELAY: Duration = Duration::from_secs(1);
+
+/// Nominal msats an XSR invoice is made out for
+const MSATS_PER_XSR: u64 = 1_000;
+
+/// Dev Lightning Error
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Melting needs a real lightning node
+    #[error("Melting is not supported without lightning")]
+    MeltUnsupported,
+    /// Invoice could not be built
+    #[error("Could not create invoice: {0}")]
+    Invoice(String),
+    /// Amount Error
+    #[error(transparent)]
+    Amount(#[from] cdk::amount::Error),
+}
+
+impl From<Error> for cdk::cdk_lightning::Error {
+    fn from(e: Error) -> Self {
+        Self::Lightning(Box::new(e))
+    }
+}
+
+/// Lightning backend that marks every mint quote paid without a payment
+#[derive(Clone)]
+pub struct DevLightning {
+    mint_settings: MintMethodSettings,
+    melt_settings: MeltMethodSettings,
+    paid_sender: mpsc::UnboundedSender<String>,
+    paid_receiver: Arc<Mutex<mpsc::UnboundedReceiver<String>>>,
+    wait_invoice_cancel_token: CancellationToken,
+    wait_invoice_is_active: Arc<AtomicBool>,
+}
+
+impl DevLightning {
+    /// Create new [`DevLightning`]
+    pub fn new(mint_settings: MintMethodSettings, melt_settings: MeltMethodSettings) -> Self {
+        let (paid_sender, paid_receiver) = mpsc::unbounded_channel();
+
+        Self {
+            mint_settings,
+            melt_settings,
+            paid_sender,
+            paid_receiver: Arc::new(Mutex::new(paid_receiver)),
+            wait_invoice_cancel_token: CancellationToken::new(),
+            wait_invoice_is_active: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+#[async_trait]
+impl MintLightning for DevLightning {
+    type Err = cdk_lightning::Error;
+
+    fn get_settings(&self) -> Settings {
+        Settings {
+            mpp: false,
+            unit: CurrencyUnit::Msat,
+            mint_settings: self.mint_settings,
+            melt_settings: self.melt_settings,
+            invoice_description: true,
+        }
+    }
+
+    /// Is wait invoice active
+    fn is_wait_invoice_active(&self) -> bool {
+        self.wait_invoice_is_active.load(Ordering::SeqCst)
+    }
+
+    /// Cancel wait invoice
+    fn cancel_wait_invoice(&self) {
+        self.wait_invoice_cancel_token.cancel()
+    }
+
+    async fn wait_any_invoice(
+        &self,
+    ) -> Result<Pin<Box<dyn Stream<Item = String> + Send>>, Self::Err> {
+        let stream = futures::stream::unfold(
+            (
+                Arc::clone(&self.paid_receiver),
+                self.wait_invoice_cancel_token.clone(),
+                Arc::clone(&self.wait_invoice_is_active),
+            ),
+            |(paid_receiver, cancel_token, is_active)| async move {
+                is_active.store(true, Ordering::SeqCst);
+
+                let paid = {
+                    let mut receiver = paid_receiver.lock().await;
+
+                    tokio::select! {
+                        _ = cancel_token.cancelled() => None,
+                        paid = receiver.recv() => paid,
+                    }
+                };
+
+                match paid {
+                    Some(request_lookup_id) => {
+                        Some((request_lookup_id, (paid_receiver, cancel_token, is_active)))
+                    }
+                    None => {
+                        is_active.store(false, Ordering::SeqCst);
+                        None
+                    }
+                }
+            },
+        )
+        .boxed();
+
+        Ok(stream)
+    }
+
+    async fn get_payment_quote(
+        &self,
+        _melt_quote_request: &MeltQuoteBolt11Request,
+    ) -> Result<PaymentQuoteResponse, Self::Err> {
+        Err(Error::MeltUnsupported.into())
+    }
+
+    async fn pay_invoice(
+        &self,
+        _melt_quote: mint::MeltQuote,
+        _partial_amount: Option<Amount>,
+        _max_fee: Option<Amount>,
+    ) -> Result<PayInvoiceResponse, Self::Err> {
+        Err(Error::MeltUnsupported.into())
+    }
+
+    async fn create_invoice(
+        &self,
+        amount: Amount,
+        unit: &CurrencyUnit,
+        description: String,
+        unix_expiry: u64,
+    ) -> Result<CreateInvoiceResponse, Self::Err> {
+        let amount_msat: u64 = if unit
+            == &CurrencyUnit::from_str("XSR").map_err(|err| Error::Invoice(err.to_string()))?
+        {
+            u64::from(amount) * MSATS_PER_XSR
+        } else {
+            to_unit(amount, unit, &CurrencyUnit::Msat)
+                .map_err(Error::from)?
+                .into()
+        };
+
+        let mut rng = thread_rng();
+        let private_key = SecretKey::new(&mut rng);
+        let preimage: [u8; 32] = rng.gen();
+        let payment_hash = sha256::Hash::hash(&preimage);
+
+        let secp = Secp256k1::new();
+
+        let request = InvoiceBuilder::new(Currency::Regtest)
+            .description(description)
+            .payment_hash(payment_hash)
+            .payment_secret(PaymentSecret(rng.gen()))
+            .amount_milli_satoshis(amount_msat)
+            .current_timestamp()
+            .expiry_time(Duration::from_secs(unix_expiry.saturating_sub(unix_time())))
+            .min_final_cltv_expiry_delta(144)
+            .build_signed(|hash| secp.sign_ecdsa_recoverable(hash, &private_key))
+            .map_err(|err| Error::Invoice(err.to_string()))?;
+
+        let request_lookup_id = payment_hash.to_string();
+
+        tracing::warn!(
+            "DEV MODE: invoice {} will be reported paid without payment",
+            request_lookup_id
+        );
+
+        tokio::spawn({
+            let paid_sender = self.paid_sender.clone();
+            let request_lookup_id = request_lookup_id.clone();
+
+            async move {
+                tokio::time::sleep(PAYMENT_DELAY).await;
+                let _ = paid_sender.send(request_lookup_id);
+            }
+        });
+
+        Ok(CreateInvoiceResponse {
+            request_lookup_id,
+            request,
+            expiry: Some(unix_expiry),
+        })
+    }
+
+    async fn check_incoming_invoice_status(
+        &self,
+        _request_lookup_id: &str,
+    ) -> Result<MintQuoteState, Self::Err> {
+        Ok(MintQuoteState::Paid)
+    }
+
+    async fn check_outgoing_payment(
+        &self,
+        request_lookup_id: &str,
+    ) -> Result<PayInvoiceResponse, Self::Err> {
+        Ok(PayInvoiceResponse {
+            payment_lookup_id: request_lookup_id.to_string(),
+            payment_preimage: None,
+            status: MeltQuoteState::Unknown,
+            total_spent: Amount::ZERO,
+            unit: CurrencyUnit::Msat,
+        })
+    }
+}
+
+/// Whether `url` points somewhere only reachable in development, a loopback
+/// or private address or a reserved development domain
+pub fn is_dev_url(url: &str) -> bool {
+    let Some(host) = Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(|host| host.to_lowercase()))
+    else {
+        return false;
+    };
+
+    if let Ok(ip) = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<std::net::IpAddr>()
+    {
+        return match ip {
+            std::net::IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+            std::net::IpAddr::V6(ip) => ip.is_loopback(),
+        };
+    }
+
+    host == "localhost"
+        || [".localhost", ".local", ".test", ".internal"]
+            .iter()
+            .any(|suffix| host.ends_with(suffix))
+}
@@ -0,0 +1,143 @@
+//! Operator configured blocking and boosting of result domains
+
+use reqwest::Url;
+
+use crate::search_provider::SearchResult;
+
+/// Drops results from blocked domains and moves boosted domains to the front
+///
+/// A domain matches its own host and every subdomain of it, so `example.com`
+/// matches `www.example.com` but not `notexample.com`.
+#[derive(Debug, Clone, Default)]
+pub struct DomainFilter {
+    blocked: Vec<String>,
+    boosted: Vec<String>,
+}
+
+impl DomainFilter {
+    /// Create new [`DomainFilter`]
+    pub fn new(blocked: Vec<String>, boosted: Vec<String>) -> Self {
+        Self {
+            blocked: blocked.iter().map(|d| normalize(d)).collect(),
+            boosted: boosted.iter().map(|d| normalize(d)).collect(),
+        }
+    }
+
+    /// Filter and reorder `results`, the order within boosted and other
+    /// results is kept
+    pub fn apply(&self, results: &mut Vec<SearchResult>) {
+        if self.blocked.is_empty() && self.boosted.is_empty() {
+            return;
+        }
+
+        results.retain(|result| !matches_any(&result.url, &self.blocked));
+
+        results.sort_by_key(|result| !matches_any(&result.url, &self.boosted));
+    }
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim().trim_matches('.').to_lowercase()
+}
+
+/// Whether the host of `url` is or is a subdomain of any of `domains`
+fn matches_any(url: &str, domains: &[String]) -> bool {
+    let Some(host) = Url::parse(url).ok().and_then(|url| {
+        url.host_str()
+            .map(|host| host.trim_end_matches('.').to_lowercase())
+    }) else {
+        return false;
+    };
+
+    domains.iter().any(|domain| {
+        host == *domain
+            || host
+                .strip_suffix(domain.as_str())
+                .is_some_and(|prefix| prefix.ends_with('.'))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(url: &str) -> SearchResult {
+        SearchResult {
+            url: url.to_string(),
+            title: url.to_string(),
+            description: None,
+            age: None,
+            image: None,
+        }
+    }
+
+    fn urls(results: &[SearchResult]) -> Vec<&str> {
+        results.iter().map(|result| result.url.as_str()).collect()
+    }
+
+    #[test]
+    fn blocked_domain_matches_itself_and_its_subdomains() {
+        let blocked = vec![normalize(" Example.com. ")];
+
+        for (url, matches) in [
+            ("https://example.com/", true),
+            ("https://www.example.com/page", true),
+            ("https://a.b.EXAMPLE.com./", true),
+            ("https://notexample.com/", false),
+            ("https://example.com.evil.test/", false),
+            ("https://example.org/", false),
+            ("not a url", false),
+        ] {
+            assert_eq!(matches_any(url, &blocked), matches, "{}", url);
+        }
+    }
+
+    #[test]
+    fn blocked_results_are_dropped() {
+        let filter = DomainFilter::new(vec!["example.com".to_string()], Vec::new());
+
+        let mut results = vec![
+            result("https://www.example.com/"),
+            result("https://notexample.com/"),
+            result("https://example.com/"),
+            result("https://rust-lang.org/"),
+        ];
+        filter.apply(&mut results);
+
+        assert_eq!(
+            urls(&results),
+            ["https://notexample.com/", "https://rust-lang.org/"]
+        );
+    }
+
+    #[test]
+    fn boosted_results_move_to_the_front_in_a_stable_order() {
+        let filter = DomainFilter::new(
+            vec!["blocked.test".to_string()],
+            vec!["docs.rs".to_string(), "rust-lang.org".to_string()],
+        );
+
+        let mut results = vec![
+            result("https://a.test/"),
+            result("https://doc.rust-lang.org/std"),
+            result("https://blocked.test/"),
+            result("https://b.test/"),
+            result("https://docs.rs/tokio"),
+            result("https://c.test/"),
+            result("https://rust-lang.org/"),
+        ];
+        filter.apply(&mut results);
+
+        assert_eq!(
+            urls(&results),
+            [
+                "https://doc.rust-lang.org/std",
+                "https://docs.rs/tokio",
+                "https://rust-lang.org/",
+                "https://a.test/",
+                "https://b.test/",
+                "https://c.test/",
+            ]
+        );
+    }
+}
@@ -0,0 +1,428 @@
+//! Amounts issued and redeemed per keyset, kept alongside the mint db so the
+//! two can be cross checked with `athenut-mint audit`
+//!
+//! Nothing here ever fails or alters a successful request, amounts that could
+//! not be recorded are only logged and show up as a discrepancy in the audit.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use axum::body::{boxed, Body, BoxBody, Bytes, StreamBody};
+use axum::extract::State;
+use axum::http::{Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use cdk::nuts::{
+    BlindSignature, Id, MeltBolt11Request, MeltQuoteBolt11Response, MeltQuoteState,
+    MintBolt11Response, Proof, SwapRequest, SwapResponse,
+};
+use cdk::util::unix_time;
+use cdk::Amount;
+use futures::stream::{self, Stream};
+use http_body::{LengthLimitError, Limited};
+use hyper::body::HttpBody;
+use serde::de::DeserializeOwned;
+
+use crate::db::{KeysetFlow, KeysetIssuance, PendingMelt, SearchStore};
+
+/// Sum of `amounts` per keyset
+pub fn keyset_amounts(amounts: impl IntoIterator<Item = (Id, Amount)>) -> Vec<(Id, u64)> {
+    let mut totals: HashMap<Id, u64> = HashMap::new();
+
+    for (keyset_id, amount) in amounts {
+        *totals.entry(keyset_id).or_insert(0) += u64::from(amount);
+    }
+
+    totals.into_iter().collect()
+}
+
+/// Sum of `proofs` per keyset
+pub fn proof_amounts<'a>(proofs: impl IntoIterator<Item = &'a Proof>) -> Vec<(Id, u64)> {
+    keyset_amounts(proofs.into_iter().map(|p| (p.keyset_id, p.amount)))
+}
+
+/// Sum of `signatures` per keyset
+pub fn signature_amounts<'a>(
+    signatures: impl IntoIterator<Item = &'a BlindSignature>,
+) -> Vec<(Id, u64)> {
+    keyset_amounts(signatures.into_iter().map(|s| (s.keyset_id, s.amount)))
+}
+
+/// Add `amounts` to the totals of `flow`, logging on failure
+pub fn record(db: &dyn SearchStore, flow: KeysetFlow, amounts: &[(Id, u64)]) {
+    if amounts.is_empty() {
+        return;
+    }
+
+    if let Err(err) = db.add_keyset_amounts(flow, amounts) {
+        tracing::error!(
+            "Could not record {:?} keyset amounts {:?}: {}",
+            flow,
+            amounts,
+            err
+        );
+    }
+}
+
+/// Largest mint, swap or melt request body buffered to be read, the limit
+/// the cashu api puts on json bodies
+pub const MAX_MINT_REQUEST_BODY_BYTES: usize = 2 * 1024 * 1024;
+
+/// Path a melt quote is checked under, followed by the quote id
+const MELT_QUOTE_PATH: &str = "/v1/melt/quote/bolt11/";
+
+/// Record what successful mint, swap and melt requests to the cashu api
+/// issued and redeemed
+///
+/// Bodies of those requests are buffered to be read, their responses are
+/// streamed through as they are and only read once fully sent. Inputs of a
+/// melt still pending when answered are kept until a check of its quote
+/// reports it paid. Every other request is passed through untouched.
+pub async fn account_mint_api(
+    State(db): State<Arc<dyn SearchStore>>,
+    mut request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let path = request.uri().path().to_string();
+
+    let accounted = match *request.method() {
+        Method::POST => matches!(
+            path.as_str(),
+            "/v1/mint/bolt11" | "/v1/swap" | "/v1/melt/bolt11"
+        ),
+        Method::GET => path.starts_with(MELT_QUOTE_PATH),
+        _ => false,
+    };
+
+    if !accounted {
+        return next.run(request).await;
+    }
+
+    let request_body = if request.method() == Method::POST {
+        let (parts, body) = request.into_parts();
+
+        let bytes =
+            match hyper::body::to_bytes(Limited::new(body, MAX_MINT_REQUEST_BODY_BYTES)).await {
+                Ok(bytes) => bytes,
+                Err(err) if err.downcast_ref::<LengthLimitError>().is_some() => {
+                    return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+                }
+                Err(err) => {
+                    tracing::warn!("Could not read {} request body: {}", path, err);
+                    return StatusCode::BAD_REQUEST.into_response();
+                }
+            };
+
+        request = Request::from_parts(parts, Body::from(bytes.clone()));
+
+        bytes
+    } else {
+        Bytes::new()
+    };
+
+    let response = next.run(request).await;
+
+    if !response.status().is_success() {
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+
+    let body = tee_body(body, path.clone(), move |response_body| {
+        record_exchange(db.as_ref(), &path, &request_body, response_body)
+    });
+
+    Response::from_parts(parts, boxed(StreamBody::new(body)))
+}
+
+/// Forward the chunks of `body` as they come, handing the whole body to
+/// `on_complete` once the last one has been sent
+///
+/// A body that fails part way is forwarded with its error and never handed
+/// over, so nothing is recorded for it.
+fn tee_body(
+    body: BoxBody,
+    path: String,
+    on_complete: impl FnOnce(&[u8]) + Send + 'static,
+) -> impl Stream<Item = Result<Bytes, axum::Error>> + Send + 'static {
+    let on_complete: Box<dyn FnOnce(&[u8]) + Send> = Box::new(on_complete);
+
+    stream::unfold(
+        (body, Vec::new(), Some(on_complete)),
+        move |(mut body, mut buffered, on_complete)| {
+            let path = path.clone();
+
+            async move {
+                match body.data().await {
+                    Some(Ok(chunk)) => {
+                        buffered.extend_from_slice(&chunk);
+                        Some((Ok(chunk), (body, buffered, on_complete)))
+                    }
+                    Some(Err(err)) => {
+                        tracing::warn!(
+                            "Could not read {} response body for keyset accounting: {}",
+                            path,
+                            err
+                        );
+                        Some((Err(err), (body, buffered, None)))
+                    }
+                    None => {
+                        if let Some(on_complete) = on_complete {
+                            on_complete(&buffered);
+                        }
+                        None
+                    }
+                }
+            }
+        },
+    )
+}
+
+/// Record what a successful request to `path` issued and redeemed
+fn record_exchange(db: &dyn SearchStore, path: &str, request_body: &[u8], response_body: &[u8]) {
+    match path {
+        "/v1/mint/bolt11" => {
+            if let Some(response) = parse::<MintBolt11Response>(path, response_body) {
+                record(
+                    db,
+                    KeysetFlow::Issued,
+                    &signature_amounts(&response.signatures),
+                );
+            }
+        }
+        "/v1/swap" => {
+            if let (Some(request), Some(response)) = (
+                parse::<SwapRequest>(path, request_body),
+                parse::<SwapResponse>(path, response_body),
+            ) {
+                record(db, KeysetFlow::Redeemed, &proof_amounts(&request.inputs));
+                record(
+                    db,
+                    KeysetFlow::Issued,
+                    &signature_amounts(&response.signatures),
+                );
+            }
+        }
+        "/v1/melt/bolt11" => {
+            if let (Some(request), Some(response)) = (
+                parse::<MeltBolt11Request>(path, request_body),
+                parse::<MeltQuoteBolt11Response>(path, response_body),
+            ) {
+                let inputs = proof_amounts(&request.inputs);
+
+                match response.state {
+                    MeltQuoteState::Paid => {
+                        record(db, KeysetFlow::Redeemed, &inputs);
+                        record(
+                            db,
+                            KeysetFlow::Issued,
+                            &signature_amounts(response.change.iter().flatten()),
+                        );
+                    }
+                    // Recorded once a check of the quote reports it paid
+                    MeltQuoteState::Pending | MeltQuoteState::Unknown => {
+                        let melt = PendingMelt {
+                            inputs,
+                            created_at: unix_time(),
+                        };
+
+                        if let Err(err) = db.add_pending_melt(&response.quote, &melt) {
+                            tracing::error!(
+                                "Could not keep pending melt {} inputs {:?}: {}",
+                                response.quote,
+                                melt.inputs,
+                                err
+                            );
+                        }
+                    }
+                    // The inputs were handed back unspent
+                    MeltQuoteState::Unpaid | MeltQuoteState::Failed => (),
+                }
+            }
+        }
+        _ => {
+            if let Some(response) = parse::<MeltQuoteBolt11Response>(path, response_body) {
+                match response.state {
+                    MeltQuoteState::Paid => {
+                        if let Some(melt) = take_pending_melt(db, &response.quote) {
+                            record(db, KeysetFlow::Redeemed, &melt.inputs);
+                            record(
+                                db,
+                                KeysetFlow::Issued,
+                                &signature_amounts(response.change.iter().flatten()),
+                            );
+                        }
+                    }
+                    MeltQuoteState::Unpaid | MeltQuoteState::Failed => {
+                        take_pending_melt(db, &response.quote);
+                    }
+                    MeltQuoteState::Pending | MeltQuoteState::Unknown => (),
+                }
+            }
+        }
+    }
+}
+
+/// Remove the pending melt of `quote_id`, logging on failure
+fn take_pending_melt(db: &dyn SearchStore, quote_id: &str) -> Option<PendingMelt> {
+    match db.take_pending_melt(quote_id) {
+        Ok(melt) => melt,
+        Err(err) => {
+            tracing::error!("Could not take pending melt {}: {}", quote_id, err);
+            None
+        }
+    }
+}
+
+fn parse<T: DeserializeOwned>(path: &str, body: &[u8]) -> Option<T> {
+    match serde_json::from_slice(body) {
+        Ok(value) => Some(value),
+        Err(err) => {
+            tracing::warn!(
+                "Could not parse {} body for keyset accounting: {}",
+                path,
+                err
+            );
+            None
+        }
+    }
+}
+
+/// Recorded totals of a keyset next to what the mint db holds for it
+#[derive(Debug, Clone)]
+pub struct KeysetAudit {
+    pub keyset_id: Id,
+    pub recorded: KeysetIssuance,
+    /// Sum of the blind signatures the mint stored
+    pub mint_issued: u64,
+    /// Sum of the proofs the mint marked spent
+    pub mint_redeemed: u64,
+}
+
+impl KeysetAudit {
+    /// Whether the mint signed what was recorded as issued and burned
+    ///
+    /// Direct issues are left out, the mint does not store those signatures.
+    pub fn issued_matches(&self) -> bool {
+        self.mint_issued == self.recorded.issued + self.recorded.burned
+    }
+
+    /// Whether the mint spent what was recorded as redeemed
+    pub fn redeemed_matches(&self) -> bool {
+        self.mint_redeemed == self.recorded.redeemed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing::{get, post};
+    use axum::{middleware, Router};
+    use cdk::nuts::SecretKey;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::db::MemoryDb;
+
+    const KEYSET_ID: &str = "009a1f293253e41e";
+
+    fn melt_response(state: &str) -> String {
+        format!(
+            r#"{{"quote":"quote","amount":60,"fee_reserve":4,"paid":{},"state":"{}","expiry":0}}"#,
+            state == "PAID",
+            state
+        )
+    }
+
+    /// Cashu api answering melts as pending and quote checks with `checked`
+    fn mint_api(db: Arc<dyn SearchStore>, checked: &'static str) -> Router {
+        Router::new()
+            .route(
+                "/v1/melt/bolt11",
+                post(|| async { melt_response("PENDING") }),
+            )
+            .route(
+                "/v1/melt/quote/bolt11/:quote_id",
+                get(move || async move { melt_response(checked) }),
+            )
+            .layer(middleware::from_fn_with_state(db, account_mint_api))
+    }
+
+    fn melt_request() -> Request<Body> {
+        let body = serde_json::json!({
+            "quote": "quote",
+            "inputs": [{
+                "amount": 64,
+                "id": KEYSET_ID,
+                "secret": "secret",
+                "C": SecretKey::generate().public_key().to_hex(),
+            }],
+        });
+
+        Request::post("/v1/melt/bolt11")
+            .header("Content-Type", "application/json")
+            .body(Body::from(body.to_string()))
+            .expect("valid request")
+    }
+
+    fn check_request() -> Request<Body> {
+        Request::get("/v1/melt/quote/bolt11/quote")
+            .body(Body::empty())
+            .expect("valid request")
+    }
+
+    fn redeemed(db: &dyn SearchStore) -> u64 {
+        db.get_issuance_summary()
+            .unwrap()
+            .iter()
+            .map(|summary| summary.redeemed)
+            .sum()
+    }
+
+    #[tokio::test]
+    async fn pending_melt_is_redeemed_once_checked_paid() {
+        let db: Arc<dyn SearchStore> = Arc::new(MemoryDb::new(400));
+        let router = mint_api(Arc::clone(&db), "PAID");
+
+        let response = router.clone().oneshot(melt_request()).await.unwrap();
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(redeemed(db.as_ref()), 0);
+
+        // Passed through as the mint answered it
+        let response = router.clone().oneshot(check_request()).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, melt_response("PAID").as_bytes());
+        assert_eq!(redeemed(db.as_ref()), 64);
+
+        // Later checks find nothing left to record
+        let response = router.oneshot(check_request()).await.unwrap();
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(redeemed(db.as_ref()), 64);
+    }
+
+    #[tokio::test]
+    async fn failed_melt_is_never_redeemed() {
+        let db: Arc<dyn SearchStore> = Arc::new(MemoryDb::new(400));
+        let router = mint_api(Arc::clone(&db), "UNPAID");
+
+        for request in [melt_request(), check_request()] {
+            let response = router.clone().oneshot(request).await.unwrap();
+            hyper::body::to_bytes(response.into_body()).await.unwrap();
+        }
+
+        assert_eq!(redeemed(db.as_ref()), 0);
+        assert_eq!(db.take_pending_melt("quote").unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn oversized_request_body_is_rejected() {
+        let db: Arc<dyn SearchStore> = Arc::new(MemoryDb::new(400));
+        let router = mint_api(db, "PAID");
+
+        let request = Request::post("/v1/melt/bolt11")
+            .body(Body::from(vec![b' '; MAX_MINT_REQUEST_BODY_BYTES + 1]))
+            .expect("valid request");
+
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -0,0 +1,57 @@
+//! Page served at `/` for people opening the mint url in a browser
+//!
+//! Everything the page needs is compiled in, so it loads over Tor without
+//! reaching any other host.
+
+use serde::{Deserialize, Serialize};
+
+/// Html of the page with `{{...}}` placeholders
+const TEMPLATE: &str = include_str!("landing_page.html");
+
+/// Favicon served when no `icon_url` is configured
+pub const DEFAULT_FAVICON: &[u8] = include_bytes!("favicon.ico");
+
+/// What the landing page shows about the mint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LandingPage {
+    pub name: String,
+    pub description: String,
+    /// Served at `/favicon.ico` by redirect, the embedded icon when `None`
+    pub icon_url: Option<String>,
+    /// Link to the Swagger UI at `/docs`
+    pub link_docs: bool,
+}
+
+impl LandingPage {
+    /// Html of the page with `search_count` searches served so far
+    pub fn render(&self, search_count: u64) -> String {
+        let mut links = vec![r#"<li><a href="/info">Search api info</a></li>"#];
+
+        if self.link_docs {
+            links.push(r#"<li><a href="/docs">Api documentation</a></li>"#);
+        }
+
+        TEMPLATE
+            .replace("{{name}}", &escape_html(&self.name))
+            .replace("{{description}}", &escape_html(&self.description))
+            .replace("{{search_count}}", &search_count.to_string())
+            .replace("{{links}}", &links.join("\n"))
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
@@ -1,30 +1,181 @@
-use std::path::PathBuf;
+use std::fs::DirBuilder;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
+use bip39::Mnemonic;
+use bitcoin::bip32::{ChildNumber, DerivationPath, Fingerprint, Xpriv, Xpub};
+use bitcoin::secp256k1::rand::{thread_rng, Rng};
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::Network;
 
+pub mod alerts;
+pub mod analytics;
 pub mod cli;
 pub mod cln;
 pub mod config;
 pub mod db;
+pub mod dedup;
+pub mod dev_lightning;
+pub mod domain_filter;
+pub mod keyset_accounting;
+pub mod landing_page;
+pub mod nostr;
+pub mod phoenixd;
+pub mod price;
+pub mod rate_limit;
+pub mod runtime_settings;
+pub mod sanitize;
+pub mod search_cache;
+pub mod search_provider;
 pub mod search_route_handlers;
+pub mod server;
+#[cfg(test)]
+mod test_utils;
+pub mod tls;
 
+/// Environment variable that sets the work dir
+pub const WORK_DIR_ENV_VAR: &str = "ATHENUT_WORK_DIR";
+
+/// Work dir from `ATHENUT_WORK_DIR`, else `$XDG_DATA_HOME/athenut-mint`, else
+/// `~/.athenut-mint`, created with 0700 permissions if missing
+///
+/// An existing `~/.athenut-mint` is kept over `XDG_DATA_HOME` so setting it
+/// does not move a running mint off its databases.
 pub fn work_dir() -> Result<PathBuf> {
-    let home_dir = home::home_dir().ok_or(anyhow!("Unknown home dir"))?;
+    let home_work_dir = home::home_dir().map(|home_dir| home_dir.join(".athenut-mint"));
+
+    let work_dir = match (
+        std::env::var_os(WORK_DIR_ENV_VAR).filter(|dir| !dir.is_empty()),
+        std::env::var_os("XDG_DATA_HOME").filter(|dir| !dir.is_empty()),
+        home_work_dir,
+    ) {
+        (Some(work_dir), _, _) => PathBuf::from(work_dir),
+        (None, _, Some(home_work_dir)) if home_work_dir.is_dir() => home_work_dir,
+        (None, Some(data_home), _) => PathBuf::from(data_home).join("athenut-mint"),
+        (None, None, Some(home_work_dir)) => home_work_dir,
+        (None, None, None) => bail!(
+            "Unknown home dir, set {} or pass --work-dir",
+            WORK_DIR_ENV_VAR
+        ),
+    };
+
+    create_private_dir(&work_dir)?;
+
+    Ok(work_dir)
+}
+
+/// Create `dir` and its parents, readable only by the owner
+pub fn create_private_dir(dir: &Path) -> Result<()> {
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .map_err(|err| anyhow!("Could not create {}: {}", dir.display(), err))
+}
+
+/// Generate a new BIP-39 mnemonic of `word_count` words, 12 to 24 in steps of 3
+pub fn generate_mnemonic(word_count: usize) -> Result<Mnemonic> {
+    if !(12..=24).contains(&word_count) || word_count % 3 != 0 {
+        bail!("A mnemonic has 12, 15, 18, 21 or 24 words");
+    }
+
+    let mut entropy = [0u8; 32];
+    thread_rng().fill(&mut entropy);
+
+    // Every 3 words encode 4 bytes of entropy
+    Ok(Mnemonic::from_entropy(&entropy[..word_count / 3 * 4])?)
+}
+
+/// Derivation path of the first XSR keyset
+pub fn search_derivation_path() -> DerivationPath {
+    search_keyset_derivation_path(0).expect("0 is a valid index")
+}
 
-    Ok(home_dir.join(".athenut-mint"))
+/// Derivation path of the XSR keyset at `index`, keysets rotated to get the
+/// next index
+pub fn search_keyset_derivation_path(index: u32) -> Result<DerivationPath> {
+    Ok(DerivationPath::from(vec![
+        ChildNumber::from_hardened_idx(0)?,
+        ChildNumber::from_hardened_idx(4)?,
+        ChildNumber::from_hardened_idx(index)?,
+    ]))
 }
 
+/// Fingerprint of the XSR key derived from `mnemonic`
+///
+/// Identifies which mnemonic a mint runs on without revealing any key.
+pub fn search_key_fingerprint(mnemonic: &Mnemonic) -> Result<Fingerprint> {
+    let secp = Secp256k1::new();
+
+    let master = Xpriv::new_master(Network::Bitcoin, &mnemonic.to_seed_normalized(""))?;
+    let search_key = master.derive_priv(&secp, &search_derivation_path())?;
+
+    Ok(Xpub::from_priv(&secp, &search_key).fingerprint())
+}
+
+/// Expand a leading `~` to the home dir, other paths are returned as they are
+///
+/// `~user` paths are not supported and give `None`, as does `~` when the home
+/// dir is unknown.
 pub fn expand_path(path: &str) -> Option<PathBuf> {
-    if path.starts_with('~') {
-        if let Some(home_dir) = home::home_dir().as_mut() {
-            let remainder = &path[2..];
-            home_dir.push(remainder);
-            let expanded_path = home_dir;
-            Some(expanded_path.clone())
-        } else {
-            None
+    let Some(rest) = path.strip_prefix('~') else {
+        return Some(PathBuf::from(path));
+    };
+
+    if !rest.is_empty() && !rest.starts_with('/') {
+        tracing::warn!("Cannot expand {}, only ~ and ~/ are supported", path);
+        return None;
+    }
+
+    let Some(home_dir) = home::home_dir() else {
+        tracing::warn!("Cannot expand {}, unknown home dir", path);
+        return None;
+    };
+
+    // Joining an absolute remainder would replace the home dir
+    match rest.trim_start_matches('/') {
+        "" => Some(home_dir),
+        rest => Some(home_dir.join(rest)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_path_table() {
+        let home = home::home_dir().expect("tests run with a home dir");
+
+        let cases = [
+            ("~", Some(home.clone())),
+            ("~/", Some(home.clone())),
+            ("~/x", Some(home.join("x"))),
+            ("~//x", Some(home.join("x"))),
+            ("~/.lightning/rpc", Some(home.join(".lightning/rpc"))),
+            ("~user/x", None),
+            ("~foo", None),
+            (
+                "/var/run/lightning/rpc",
+                Some(PathBuf::from("/var/run/lightning/rpc")),
+            ),
+            ("relative/rpc", Some(PathBuf::from("relative/rpc"))),
+            ("", Some(PathBuf::from(""))),
+            (
+                r"C:\Users\me\lightning\rpc",
+                Some(PathBuf::from(r"C:\Users\me\lightning\rpc")),
+            ),
+            (
+                r"\\?\pipe\lightning",
+                Some(PathBuf::from(r"\\?\pipe\lightning")),
+            ),
+            // A backslash after `~` is not a separator here
+            (r"~\x", None),
+        ];
+
+        for (path, expected) in cases {
+            assert_eq!(expand_path(path), expected, "{:?}", path);
         }
-    } else {
-        Some(PathBuf::from(path))
     }
 }
@@ -1,295 +1,658 @@
-use std::collections::HashMap;
-use std::str::FromStr;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{anyhow, bail};
-use athenut_mint::cli::CLIArgs;
-use athenut_mint::cln::Cln;
-use athenut_mint::db::Db;
-use athenut_mint::search_route_handlers::{search_router, ApiState};
-use athenut_mint::{config, expand_path, work_dir};
-use axum::Router;
-use bip39::Mnemonic;
-use bitcoin::bip32::{ChildNumber, DerivationPath};
-use cdk::cdk_lightning::{self, MintLightning};
-use cdk::mint::{FeeReserve, Mint};
-use cdk::mint_url::MintUrl;
-use cdk::nuts::{
-    nut04, nut05, ContactInfo, CurrencyUnit, MeltMethodSettings, MintInfo, MintMethodSettings,
-    MintVersion, Nuts, PaymentMethod,
+use athenut_mint::cli::{CLIArgs, Command, DbCommand, MnemonicCommand};
+use athenut_mint::config::{LnBackend, LogFormat};
+use athenut_mint::db::DbDump;
+use athenut_mint::price::{FiatCurrency, PriceSource};
+use athenut_mint::runtime_settings::SharedRuntimeSettings;
+use athenut_mint::search_provider::KagiProvider;
+use athenut_mint::server::{
+    self, probe_cln, runtime_settings_from, DEFAULT_ANSWER_PRICE, DEFAULT_CACHE_TTI_SECS,
+    DEFAULT_CACHE_TTL_SECS, DEFAULT_CLN_PAY_TIMEOUT_SECS, DEFAULT_CLN_RPC_TIMEOUT_SECS,
+    DEFAULT_COST_PER_SEARCH_CENTS, DEFAULT_DB_COMPACTION_FRAGMENTED_PERCENT,
+    DEFAULT_DB_COMPACTION_INTERVAL_SECS, DEFAULT_KAGI_BASE_URL, DEFAULT_KAGI_MAX_RETRIES,
+    DEFAULT_KAGI_TIMEOUT_SECS, DEFAULT_KEYSET_MAX_ORDER, DEFAULT_MAX_BTC_PRICE,
+    DEFAULT_MAX_RESULTS, DEFAULT_MIN_BTC_PRICE, DEFAULT_PRICE_CACHE_TTL_SECS,
+    DEFAULT_PRICE_MAX_STALE_SECS, DEFAULT_SEARCH_CACHE_SIZE, DEFAULT_SEARCH_CACHE_TTL_SECS,
+    DEFAULT_SEARCH_COUNT_RETENTION_DAYS, DEFAULT_SESSION_IDLE_SECS, DEFAULT_SUMMARIZE_PRICE,
+};
+use athenut_mint::{
+    config, expand_path, generate_mnemonic, search_derivation_path, search_key_fingerprint,
+    work_dir,
 };
-use cdk::types::{LnKey, QuoteTTL};
-use cdk_redb::MintRedbDatabase;
+use bip39::Mnemonic;
+use cdk::Amount;
 use clap::Parser;
-use reqwest::Client;
-use tokio::sync::Notify;
-use tower_http::cors::CorsLayer;
-use tracing_subscriber::EnvFilter;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter};
 
-const CARGO_PKG_VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
-const DEFAULT_QUOTE_TTL_SECS: u64 = 1800;
-const DEFAULT_CACHE_TTL_SECS: u64 = 1800;
-const DEFAULT_CACHE_TTI_SECS: u64 = 1800;
+const DEFAULT_LOG_LEVEL: &str = "info";
+/// Dependencies that are too chatty below warn
+const QUIET_LOG_TARGETS: &str = "sqlx=warn,hyper=warn";
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let default_filter = "debug";
-
-    let sqlx_filter = "sqlx=warn";
-    let hyper_filter = "hyper=warn";
-
-    let env_filter = EnvFilter::new(format!(
-        "{},{},{}",
-        default_filter, sqlx_filter, hyper_filter
-    ));
-
-    tracing_subscriber::fmt().with_env_filter(env_filter).init();
-
     let args = CLIArgs::parse();
 
+    if let Some(Command::Mnemonic(command)) = args.command {
+        return mnemonic_command(command);
+    }
+
     let work_dir = match args.work_dir {
         Some(w) => w,
         None => work_dir()?,
     };
 
-    let redb_path = work_dir.join("cdk-mintd.redb");
-    let localstore = Arc::new(MintRedbDatabase::new(&redb_path)?);
+    if let Some(Command::GenerateConfig { stdout, force }) = args.command {
+        let config_file = args.config.unwrap_or_else(|| work_dir.join("config.toml"));
 
-    let mint_version = MintVersion::new(
-        "cdk-athenut-mint".to_string(),
-        CARGO_PKG_VERSION.unwrap_or("Unknown").to_string(),
-    );
+        return generate_config(&config_file, stdout, force);
+    }
 
-    // get config file name from args
-    let config_file_arg = match args.config {
-        Some(c) => c,
-        None => work_dir.join("config.toml"),
-    };
+    let mut settings =
+        config::Settings::new(args.config.as_deref(), &work_dir.join("config.toml"))?;
+
+    if args.dev {
+        settings.ln.enable_ln = Some(false);
+    }
+
+    // Held until main returns so buffered lines reach the log file
+    let _log_guard = init_logging(&settings.logging)?;
+
+    if let Some(Command::Check { probe }) = args.command {
+        return check_config(&settings, probe).await;
+    }
+
+    // Nothing is opened or probed with a config the mint cannot start with
+    settings.validate()?;
+
+    if args.announce_now {
+        if settings.nostr.relays.is_empty() {
+            bail!("Set nostr.relays to announce to");
+        }
+
+        server::announce_now(&settings, &work_dir).await?;
+        println!("Published the mint announcement and status note");
+
+        return Ok(());
+    }
+
+    if let Some(Command::RotateKeyset { unit }) = args.command {
+        if !unit.eq_ignore_ascii_case("xsr") {
+            bail!("Only the xsr keyset can be rotated");
+        }
+
+        let (old_ids, new_id) = server::rotate_search_keyset(&settings, &work_dir).await?;
+
+        for old_id in old_ids {
+            println!(
+                "Deactivated keyset {}, its tokens can still be spent",
+                old_id
+            );
+        }
+        println!("New active keyset {}, restart the mint to use it", new_id);
 
-    let settings = config::Settings::new(&Some(config_file_arg));
+        return Ok(());
+    }
+
+    if let Some(Command::Db(command)) = args.command {
+        return db_command(&settings, &work_dir, command);
+    }
+
+    if let Some(Command::Audit) = args.command {
+        return audit(&settings, &work_dir).await;
+    }
+
+    // Kept to tell which settings a reload changed that need a restart
+    let running_settings = settings.clone();
+
+    if !args.skip_checks {
+        server::self_check(&settings, &work_dir).await?;
+    }
 
-    let mut contact_info: Option<Vec<ContactInfo>> = None;
+    let shutdown = async {
+        if let Err(err) = shutdown_signal().await {
+            tracing::error!("Could not listen for shutdown signals: {}", err);
+            std::future::pending::<()>().await;
+        }
+    };
 
-    if let Some(nostr_contact) = &settings.mint_info.contact_nostr_public_key {
-        let nostr_contact = ContactInfo::new("nostr".to_string(), nostr_contact.to_string());
+    let server = server::run(settings, &work_dir, shutdown).await?;
 
-        contact_info = match contact_info {
-            Some(mut vec) => {
-                vec.push(nostr_contact);
-                Some(vec)
+    tokio::spawn({
+        let runtime_settings = server.runtime_settings.clone();
+        let kagi = Arc::clone(&server.kagi);
+        let default_config_file = work_dir.join("config.toml");
+
+        async move {
+            if let Err(err) = reload_on_sighup(
+                args.config,
+                default_config_file,
+                running_settings,
+                runtime_settings,
+                kagi,
+            )
+            .await
+            {
+                tracing::error!("Config reload on SIGHUP is unavailable: {}", err);
             }
-            None => Some(vec![nostr_contact]),
-        };
+        }
+    });
+
+    server.wait().await
+}
+
+/// Wait for Ctrl-C or SIGTERM, which systemd and docker send on stop
+async fn shutdown_signal() -> anyhow::Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            result?;
+            tracing::info!("Received Ctrl-C, shutting down");
+        }
+        _ = sigterm.recv() => {
+            tracing::info!("Received SIGTERM, shutting down");
+        }
     }
 
-    if let Some(email_contact) = &settings.mint_info.contact_email {
-        let email_contact = ContactInfo::new("email".to_string(), email_contact.to_string());
+    Ok(())
+}
 
-        contact_info = match contact_info {
-            Some(mut vec) => {
-                vec.push(email_contact);
-                Some(vec)
+/// Re-read the config on every SIGHUP and apply the settings that can change
+/// while running
+///
+/// A config that fails to load or validate is logged and the current
+/// settings kept. Changes to settings that need a restart are only warned
+/// about.
+async fn reload_on_sighup(
+    config_file: Option<PathBuf>,
+    default_config_file: PathBuf,
+    running: config::Settings,
+    runtime_settings: SharedRuntimeSettings,
+    kagi: Arc<KagiProvider>,
+) -> anyhow::Result<()> {
+    let mut sighup = signal(SignalKind::hangup())?;
+
+    while sighup.recv().await.is_some() {
+        tracing::info!("Received SIGHUP, reloading config");
+
+        let settings = match config::Settings::new(config_file.as_deref(), &default_config_file) {
+            Ok(settings) => settings,
+            Err(err) => {
+                tracing::error!("Could not reload config, keeping current settings: {}", err);
+                continue;
             }
-            None => Some(vec![email_contact]),
         };
+
+        if let Err(err) = settings.validate() {
+            tracing::error!("Could not reload config, keeping current settings: {}", err);
+            continue;
+        }
+
+        for field in restart_required_changes(&running, &settings) {
+            tracing::warn!("{} changed, it only takes effect after a restart", field);
+        }
+
+        kagi.set_auth_token(settings.search_settings.kagi_auth_token.clone());
+        runtime_settings.store(runtime_settings_from(&settings));
+
+        tracing::info!("Config reloaded");
     }
 
-    let relative_ln_fee = settings.ln.fee_percent;
+    Ok(())
+}
 
-    let absolute_ln_fee_reserve = settings.ln.reserve_fee_min;
+/// Settings that differ between `running` and `reloaded` but are only read
+/// at startup
+fn restart_required_changes(
+    running: &config::Settings,
+    reloaded: &config::Settings,
+) -> Vec<&'static str> {
+    let changes = [
+        ("info.url", running.info.url != reloaded.info.url),
+        (
+            "info.listen_host",
+            running.info.listen_host != reloaded.info.listen_host,
+        ),
+        (
+            "info.listen_port",
+            running.info.listen_port != reloaded.info.listen_port,
+        ),
+        (
+            "info.search_listen_host",
+            running.info.search_listen_host != reloaded.info.search_listen_host,
+        ),
+        (
+            "info.search_listen_port",
+            running.info.search_listen_port != reloaded.info.search_listen_port,
+        ),
+        (
+            "info.mint_db_path",
+            running.info.mint_db_path != reloaded.info.mint_db_path,
+        ),
+        (
+            "info.search_db_path",
+            running.info.search_db_path != reloaded.info.search_db_path,
+        ),
+        (
+            "info.mnemonic",
+            running.info.mnemonic != reloaded.info.mnemonic,
+        ),
+        (
+            "ln.ln_backend",
+            running.ln.ln_backend != reloaded.ln.ln_backend,
+        ),
+        (
+            "search_settings.kagi_base_url",
+            running.search_settings.kagi_base_url != reloaded.search_settings.kagi_base_url,
+        ),
+        (
+            "cln.rpc_path",
+            running.cln.rpc_path != reloaded.cln.rpc_path,
+        ),
+        (
+            "phoenixd.url",
+            running.phoenixd.url != reloaded.phoenixd.url,
+        ),
+        (
+            "search_settings.provider",
+            running.search_settings.provider != reloaded.search_settings.provider,
+        ),
+        (
+            "search_settings.trusted_proxies",
+            running.search_settings.trusted_proxies != reloaded.search_settings.trusted_proxies,
+        ),
+    ];
+
+    changes
+        .into_iter()
+        .filter_map(|(field, changed)| changed.then_some(field))
+        .collect()
+}
 
-    let fee_reserve = FeeReserve {
-        min_fee_reserve: absolute_ln_fee_reserve,
-        percent_fee_reserve: relative_ln_fee,
+/// Log to stdout, and to a daily rotated file when one is configured
+///
+/// `RUST_LOG` overrides the configured level. The returned guard flushes the
+/// file writer when dropped.
+fn init_logging(logging: &config::Logging) -> anyhow::Result<Option<WorkerGuard>> {
+    let env_filter = match EnvFilter::try_from_default_env() {
+        Ok(env_filter) => env_filter,
+        Err(_) => EnvFilter::try_new(format!(
+            "{},{}",
+            logging.level.as_deref().unwrap_or(DEFAULT_LOG_LEVEL),
+            QUIET_LOG_TARGETS
+        ))?,
     };
 
-    let mut ln_backends: HashMap<
-        LnKey,
-        Arc<dyn MintLightning<Err = cdk_lightning::Error> + Send + Sync>,
-    > = HashMap::new();
+    let (file_writer, guard, file_path) = match &logging.file_path {
+        Some(file_path) => {
+            let file_path = file_path
+                .to_str()
+                .and_then(expand_path)
+                .ok_or(anyhow!("Invalid log file path {}", file_path.display()))?;
 
-    let mut supported_units = HashMap::new();
+            let (Some(dir), Some(file_name)) = (file_path.parent(), file_path.file_name()) else {
+                bail!("Invalid log file path {}", file_path.display());
+            };
 
-    let cln_socket = expand_path(
-        settings
-            .cln
-            .rpc_path
-            .to_str()
-            .ok_or(anyhow!("cln socket not defined"))?,
-    )
-    .ok_or(anyhow!("cln socket not defined"))?;
-
-    let cln = Arc::new(
-        Cln::new(
-            cln_socket,
-            fee_reserve,
-            MintMethodSettings::default(),
-            MeltMethodSettings::default(),
-        )
-        .await?,
-    );
+            std::fs::create_dir_all(dir)?;
 
-    let search_unit = CurrencyUnit::from_str("XSR")?;
-    ln_backends.insert(LnKey::new(search_unit, PaymentMethod::Bolt11), cln);
-    supported_units.insert(search_unit, (0, 1));
-
-    let nut04_settings = nut04::Settings::new(
-        vec![MintMethodSettings {
-            method: PaymentMethod::Bolt11,
-            unit: search_unit,
-            min_amount: Some(1.into()),
-            max_amount: Some(100.into()),
-            description: true,
-        }],
-        false,
-    );
+            let (writer, guard) =
+                tracing_appender::non_blocking(tracing_appender::rolling::daily(dir, file_name));
 
-    let nut05_settings = nut05::Settings::new(
-        vec![MeltMethodSettings {
-            method: PaymentMethod::Bolt11,
-            unit: search_unit,
-            min_amount: None,
-            max_amount: None,
-        }],
-        true,
-    );
+            (Some(writer), Some(guard), Some(file_path))
+        }
+        None => (None, None, None),
+    };
 
-    let nuts = Nuts::new()
-        .nut04(nut04_settings)
-        .nut05(nut05_settings)
-        .nut07(true)
-        .nut08(true)
-        .nut09(true)
-        .nut10(true)
-        .nut11(true)
-        .nut12(true)
-        .nut14(true);
-
-    let mut mint_info = MintInfo::new()
-        .name(settings.mint_info.name)
-        .version(mint_version)
-        .description(settings.mint_info.description)
-        .nuts(nuts);
-
-    if let Some(long_description) = &settings.mint_info.description_long {
-        mint_info = mint_info.long_description(long_description);
+    let registry = tracing_subscriber::registry().with(env_filter);
+
+    // Json lines carry the fields of the current span, the request id of a
+    // request included
+    match logging.format {
+        LogFormat::Text => registry
+            .with(fmt::layer())
+            .with(file_writer.map(|writer| fmt::layer().with_ansi(false).with_writer(writer)))
+            .try_init()?,
+        LogFormat::Json => registry
+            .with(fmt::layer().json().with_current_span(true))
+            .with(file_writer.map(|writer| {
+                fmt::layer()
+                    .json()
+                    .with_current_span(true)
+                    .with_writer(writer)
+            }))
+            .try_init()?,
     }
 
-    if let Some(contact_info) = contact_info {
-        mint_info = mint_info.contact_info(contact_info);
+    match file_path {
+        Some(file_path) => tracing::info!(
+            "Logging to stdout and {}, rotated daily",
+            file_path.display()
+        ),
+        None => tracing::info!("Logging to stdout"),
     }
 
-    if let Some(pubkey) = settings.mint_info.pubkey {
-        mint_info = mint_info.pubkey(pubkey);
-    }
+    Ok(guard)
+}
 
-    if let Some(icon_url) = &settings.mint_info.icon_url {
-        mint_info = mint_info.icon_url(icon_url);
-    }
+/// Validate `settings` and print them with secrets masked, without opening
+/// the databases or binding a listener
+///
+/// With `probe` the lightning node and kagi token are checked live too.
+async fn check_config(settings: &config::Settings, probe: bool) -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string_pretty(&settings.redacted())?);
 
-    if let Some(motd) = settings.mint_info.motd {
-        mint_info = mint_info.motd(motd);
+    if let Err(err) = settings.validate() {
+        eprintln!("{}", err);
+        bail!("Config is invalid");
     }
 
-    let quote_ttl = QuoteTTL::new(DEFAULT_QUOTE_TTL_SECS, DEFAULT_QUOTE_TTL_SECS);
+    if !probe {
+        println!("Config is valid");
+        return Ok(());
+    }
 
-    let search_der_path = DerivationPath::from(vec![
-        ChildNumber::from_hardened_idx(0).expect("0 is a valid index"),
-        ChildNumber::from_hardened_idx(4).expect("0 is a valid index"),
-        ChildNumber::from_hardened_idx(0).expect("0 is a valid index"),
-    ]);
+    let mut probe_failed = false;
+
+    match (
+        settings.ln.enable_ln.unwrap_or(true),
+        settings.ln.ln_backend,
+    ) {
+        (false, _) => println!("Lightning is disabled, not probed"),
+        (true, LnBackend::Cln) => match probe_cln(settings).await {
+            Ok(()) => println!("CLN: ok"),
+            Err(err) => {
+                eprintln!("CLN: {}", err);
+                probe_failed = true;
+            }
+        },
+        (true, LnBackend::Phoenixd) => println!("Phoenixd is not probed"),
+    }
 
-    let mut custom_ders = HashMap::new();
+    let kagi = KagiProvider::new(
+        settings
+            .search_settings
+            .kagi_base_url
+            .as_deref()
+            .unwrap_or(DEFAULT_KAGI_BASE_URL),
+        settings.search_settings.kagi_auth_token.clone(),
+        Duration::from_secs(DEFAULT_KAGI_TIMEOUT_SECS),
+        0,
+    )?;
+
+    match kagi.check().await {
+        Ok(()) => println!("Kagi: ok"),
+        Err(err) => {
+            eprintln!("Kagi: {}", err);
+            probe_failed = true;
+        }
+    }
 
-    custom_ders.insert(search_unit, search_der_path);
+    if probe_failed {
+        bail!("Config is valid but a probe failed");
+    }
 
-    let mnemonic = Mnemonic::from_str(&settings.info.mnemonic)?;
+    println!("Config is valid");
 
-    let mint = Mint::new(
-        &settings.info.url,
-        &mnemonic.to_seed_normalized(""),
-        mint_info,
-        quote_ttl,
-        localstore,
-        ln_backends.clone(),
-        supported_units,
-        custom_ders,
-    )
-    .await?;
+    Ok(())
+}
 
-    let mint = Arc::new(mint);
+/// Write a commented example config with a fresh mnemonic to `config_file`,
+/// or print it with `stdout`
+fn generate_config(config_file: &Path, stdout: bool, force: bool) -> anyhow::Result<()> {
+    let config = example_settings(generate_mnemonic(24)?.to_string()).to_commented_toml()?;
 
-    let listen_addr = settings.info.listen_host;
-    let listen_port = settings.info.listen_port;
+    if stdout {
+        print!("{}", config);
+        return Ok(());
+    }
 
-    let cache_ttl = settings
-        .info
-        .seconds_to_cache_requests_for
-        .unwrap_or(DEFAULT_CACHE_TTL_SECS);
-    let cache_tti = settings
-        .info
-        .seconds_to_extend_cache_by
-        .unwrap_or(DEFAULT_CACHE_TTI_SECS);
+    if config_file.exists() && !force {
+        bail!(
+            "{} already exists, pass --force to overwrite it",
+            config_file.display()
+        );
+    }
 
-    let v1_service = cdk_axum::create_mint_router(Arc::clone(&mint), cache_ttl, cache_tti).await?;
+    if let Some(dir) = config_file.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
 
-    // Database for athenmint
-    let athenmint_db = work_dir.join("athenmint_search_api.redb");
-    let db = Db::new(&athenmint_db)?;
+    std::fs::write(config_file, config)?;
 
-    let mint_url = MintUrl::from_str(&settings.info.url)?;
-    let info = athenut_mint::search_route_handlers::Info {
-        mint: mint_url.clone(),
-    };
+    println!(
+        "Wrote {}, set info.url and search_settings.kagi_auth_token before starting the mint",
+        config_file.display()
+    );
 
-    let search_settings = athenut_mint::search_route_handlers::Settings {
-        kagi_auth_token: settings.search_settings.kagi_auth_token,
-        mint_url,
-    };
+    Ok(())
+}
 
-    let api_state = ApiState {
-        info,
-        mint: Arc::clone(&mint),
-        settings: search_settings,
-        reqwest_client: Client::new(),
-        db,
-    };
+/// Settings written by `generate-config`, with the default filled in for
+/// every setting that has one
+fn example_settings(mnemonic: String) -> config::Settings {
+    let mut settings = config::Settings::default();
+
+    settings.info.url = "http://127.0.0.1:8085".to_string();
+    settings.info.listen_host = "127.0.0.1".to_string();
+    settings.info.listen_port = 8085;
+    settings.info.mnemonic = mnemonic;
+    settings.info.seconds_to_cache_requests_for = Some(DEFAULT_CACHE_TTL_SECS);
+    settings.info.seconds_to_extend_cache_by = Some(DEFAULT_CACHE_TTI_SECS);
+    settings.info.input_fee_ppk = Some(0);
+    settings.info.keyset_max_order = Some(DEFAULT_KEYSET_MAX_ORDER);
+
+    settings.mint_info.name = "Athenut Mint".to_string();
+    settings.mint_info.description = "Ecash for private searches".to_string();
+
+    settings.ln.enable_ln = Some(true);
+    settings.ln.fee_percent = 0.04;
+    settings.ln.reserve_fee_min = Amount::from(4);
+
+    settings.cln.rpc_path = PathBuf::from("/var/lib/clightning/bitcoin/lightning-rpc");
+    settings.cln.pay_timeout_secs = Some(DEFAULT_CLN_PAY_TIMEOUT_SECS);
+    settings.cln.rpc_timeout_secs = Some(DEFAULT_CLN_RPC_TIMEOUT_SECS);
+
+    settings.price.cache_ttl_secs = Some(DEFAULT_PRICE_CACHE_TTL_SECS);
+    settings.price.max_stale_secs = Some(DEFAULT_PRICE_MAX_STALE_SECS);
+    settings.price.sources = Some(PriceSource::DEFAULT.to_vec());
+    settings.price.min_price = Some(DEFAULT_MIN_BTC_PRICE);
+    settings.price.max_price = Some(DEFAULT_MAX_BTC_PRICE);
+
+    let search_settings = &mut settings.search_settings;
+    search_settings.kagi_auth_token = "replace-with-your-kagi-token".to_string();
+    search_settings.kagi_base_url = Some(DEFAULT_KAGI_BASE_URL.to_string());
+    search_settings.kagi_timeout_secs = Some(DEFAULT_KAGI_TIMEOUT_SECS);
+    search_settings.kagi_max_retries = Some(DEFAULT_KAGI_MAX_RETRIES);
+    search_settings.cost_per_search_cents = Some(DEFAULT_COST_PER_SEARCH_CENTS);
+    search_settings.price_currency = Some(FiatCurrency::default());
+    search_settings.answer_price = Some(DEFAULT_ANSWER_PRICE);
+    search_settings.summarize_price = Some(DEFAULT_SUMMARIZE_PRICE);
+    search_settings.max_results = Some(DEFAULT_MAX_RESULTS);
+    search_settings.search_cache_ttl_secs = Some(DEFAULT_SEARCH_CACHE_TTL_SECS);
+    search_settings.search_cache_size = Some(DEFAULT_SEARCH_CACHE_SIZE);
+    search_settings.session_idle_secs = Some(DEFAULT_SESSION_IDLE_SECS);
+    search_settings.search_count_retention_days = Some(DEFAULT_SEARCH_COUNT_RETENTION_DAYS);
+    search_settings.db_compaction_interval_secs = Some(DEFAULT_DB_COMPACTION_INTERVAL_SECS);
+    search_settings.db_compaction_fragmented_percent =
+        Some(DEFAULT_DB_COMPACTION_FRAGMENTED_PERCENT);
+
+    settings.logging.level = Some(DEFAULT_LOG_LEVEL.to_string());
+
+    settings
+}
 
-    let search_router = search_router(api_state);
+/// Run a `mnemonic` subcommand, offline so seeds can be handled on an
+/// air-gapped machine
+fn mnemonic_command(command: MnemonicCommand) -> anyhow::Result<()> {
+    match command {
+        MnemonicCommand::Generate { words } => {
+            println!("{}", generate_mnemonic(words)?);
+        }
+        MnemonicCommand::Verify { phrase } => {
+            let mnemonic = Mnemonic::parse_normalized(&phrase.join(" "))
+                .map_err(|err| anyhow!("Invalid mnemonic: {}", err))?;
+
+            println!("Valid {} word mnemonic", mnemonic.word_count());
+            println!(
+                "XSR key fingerprint ({}): {}",
+                search_derivation_path(),
+                search_key_fingerprint(&mnemonic)?
+            );
+        }
+    }
 
-    let mint_service = Router::new()
-        .merge(v1_service)
-        .merge(search_router)
-        .layer(CorsLayer::permissive());
+    Ok(())
+}
 
-    let shutdown = Arc::new(Notify::new());
+/// Export or import the search api database
+fn db_command(
+    settings: &config::Settings,
+    work_dir: &Path,
+    command: DbCommand,
+) -> anyhow::Result<()> {
+    match command {
+        DbCommand::Export { out } => {
+            let dump = server::export_search_db(settings, work_dir)?;
+
+            let file = File::create(&out)
+                .map_err(|err| anyhow!("Could not create {}: {}", out.display(), err))?;
+            serde_json::to_writer_pretty(file, &dump)?;
+
+            for (table, rows) in dump.row_counts() {
+                println!("{}: {} rows", table, rows);
+            }
+            println!("Wrote {}", out.display());
+        }
+        DbCommand::Import { input, merge } => {
+            let file = File::open(&input)
+                .map_err(|err| anyhow!("Could not open {}: {}", input.display(), err))?;
+            let dump: DbDump = serde_json::from_reader(BufReader::new(file))?;
 
-    tokio::spawn({
-        let shutdown = Arc::clone(&shutdown);
-        async move { mint.wait_for_paid_invoices(shutdown).await }
-    });
+            server::import_search_db(settings, work_dir, &dump, merge)?;
 
-    let axum_result = axum::Server::bind(
-        &format!("{}:{}", listen_addr, listen_port)
-            .as_str()
-            .parse()?,
-    )
-    .serve(mint_service.into_make_service())
-    .await;
+            for (table, rows) in dump.row_counts() {
+                println!("{}: {} rows", table, rows);
+            }
+            println!("Imported {}", input.display());
+        }
+    }
 
-    shutdown.notify_waiters();
+    Ok(())
+}
 
-    match axum_result {
-        Ok(_) => {
-            tracing::info!("Axum server stopped with okay status");
+/// Print the per keyset totals against the mint db, failing on any mismatch
+async fn audit(settings: &config::Settings, work_dir: &Path) -> anyhow::Result<()> {
+    let audits = server::audit(settings, work_dir).await?;
+
+    let mut mismatched = 0;
+
+    for audit in audits {
+        let recorded = &audit.recorded;
+
+        println!(
+            "{}: issued {} (direct {}, burned {}), redeemed {}, outstanding {}",
+            audit.keyset_id,
+            recorded.issued,
+            recorded.issued_direct,
+            recorded.burned,
+            recorded.redeemed,
+            recorded.outstanding
+        );
+
+        if !audit.issued_matches() {
+            mismatched += 1;
+            println!(
+                "  MISMATCH mint signed {}, recorded issued and burned {}",
+                audit.mint_issued,
+                recorded.issued + recorded.burned
+            );
         }
-        Err(err) => {
-            tracing::warn!("Axum server stopped with error");
-            tracing::error!("{}", err);
 
-            bail!("Axum exited with error")
+        if !audit.redeemed_matches() {
+            mismatched += 1;
+            println!(
+                "  MISMATCH mint spent {}, recorded redeemed {}",
+                audit.mint_redeemed, recorded.redeemed
+            );
         }
     }
 
+    if mismatched > 0 {
+        bail!(
+            "{} mismatches, activity from before accounting started is not recorded",
+            mismatched
+        );
+    }
+
+    println!("All keysets match the mint db");
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("athenut-mint-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn generated_config_loads_and_validates_once_filled_in() {
+        let dir = temp_dir();
+        let config_file = dir.join("config.toml");
+
+        generate_config(&config_file, false, false).unwrap();
+        assert!(generate_config(&config_file, false, false).is_err());
+
+        let generated = std::fs::read_to_string(&config_file).unwrap();
+
+        // As written only the lightning socket, which is machine specific, is missing
+        let settings = config::Settings::new(Some(&config_file), &config_file).unwrap();
+        assert_eq!(
+            settings.search_settings.kagi_base_url.as_deref(),
+            Some(DEFAULT_KAGI_BASE_URL)
+        );
+        assert_eq!(
+            settings.info.keyset_max_order,
+            Some(DEFAULT_KEYSET_MAX_ORDER)
+        );
+        let problems = settings.validate().unwrap_err().0;
+        assert!(
+            problems
+                .iter()
+                .all(|problem| problem.starts_with("cln.rpc_path")),
+            "{:?}",
+            problems
+        );
+
+        let rpc_path = dir.join("lightning-rpc");
+        std::fs::write(&rpc_path, "").unwrap();
+        let filled = generated
+            .replace(
+                "/var/lib/clightning/bitcoin/lightning-rpc",
+                rpc_path.to_str().unwrap(),
+            )
+            .replace("replace-with-your-kagi-token", "kagi-token");
+        std::fs::write(&config_file, filled).unwrap();
+
+        let settings = config::Settings::new(Some(&config_file), &config_file).unwrap();
+        settings.validate().unwrap();
+
+        assert_eq!(settings.search_settings.kagi_auth_token, "kagi-token");
+        assert_eq!(settings.info.mnemonic.split_whitespace().count(), 24);
+    }
+}
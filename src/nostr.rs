@@ -0,0 +1,187 @@
+//! Announcing the mint and its status on nostr
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use bip39::Mnemonic;
+use bitcoin::bip32::{DerivationPath, Xpriv};
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::Network;
+use nostr_sdk::{Client, EventBuilder, Keys, Kind, SecretKey, Tag};
+use thiserror::Error;
+
+use crate::config;
+use crate::db::{Db, SearchStore};
+
+/// NIP-87 cashu mint announcement
+const MINT_ANNOUNCEMENT_KIND: u16 = 38172;
+
+/// NIP-06 path the signing key is derived at when none is configured
+const NOSTR_DERIVATION_PATH: &str = "m/44'/1237'/0'/0/0";
+
+/// NUTs the mint supports, kept in step with the mint info built by the server
+const SUPPORTED_NUTS: &str = "1,2,3,4,5,6,7,8,9,10,11,12,14";
+
+/// Nostr Error
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Configured secret key could not be parsed
+    #[error("Invalid nostr secret key: {0}")]
+    SecretKey(String),
+    /// Event could not be built
+    #[error("Could not build event: {0}")]
+    Event(String),
+    /// Nostr Client Error
+    #[error(transparent)]
+    Client(#[from] nostr_sdk::client::Error),
+    /// Key Derivation Error
+    #[error(transparent)]
+    Bip32(#[from] bitcoin::bip32::Error),
+}
+
+/// What the announcement says about the mint
+#[derive(Debug, Clone)]
+pub struct MintAnnouncement {
+    pub url: String,
+    pub name: String,
+    pub description: String,
+}
+
+/// Client publishing the mint announcement and status notes
+pub struct Announcer {
+    client: Client,
+    mint: MintAnnouncement,
+}
+
+impl Announcer {
+    /// Create new [`Announcer`] connected to the configured relays, signing
+    /// with the configured key or one derived from `mnemonic`
+    pub async fn new(
+        settings: &config::Nostr,
+        mnemonic: &Mnemonic,
+        mint: MintAnnouncement,
+    ) -> Result<Self, Error> {
+        let client = Client::new(signing_keys(settings, mnemonic)?);
+
+        for relay in &settings.relays {
+            client.add_relay(relay.as_str()).await?;
+        }
+
+        client.connect().await;
+
+        Ok(Self { client, mint })
+    }
+
+    /// Publish the NIP-87 announcement of the mint
+    pub async fn announce(&self) -> Result<(), Error> {
+        let content = serde_json::json!({
+            "name": self.mint.name,
+            "about": self.mint.description,
+        })
+        .to_string();
+
+        let tags = [
+            ["d", self.mint.url.as_str()],
+            ["u", self.mint.url.as_str()],
+            ["nuts", SUPPORTED_NUTS],
+            ["n", "mainnet"],
+        ]
+        .iter()
+        .map(|tag| Tag::parse(tag).map_err(|err| Error::Event(err.to_string())))
+        .collect::<Result<Vec<Tag>, Error>>()?;
+
+        let event = EventBuilder::new(Kind::Custom(MINT_ANNOUNCEMENT_KIND), content, tags);
+
+        self.client.send_event_builder(event).await?;
+
+        Ok(())
+    }
+
+    /// Publish a note with the number of searches served so far
+    pub async fn publish_status(&self, search_count: u64) -> Result<(), Error> {
+        let content = format!(
+            "{} has served {} searches paid for with ecash. {}",
+            self.mint.name, search_count, self.mint.url
+        );
+
+        self.client
+            .send_event_builder(EventBuilder::text_note(content, []))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Close the relay connections
+    pub async fn disconnect(&self) {
+        if let Err(err) = self.client.disconnect().await {
+            tracing::warn!("Could not disconnect from nostr relays: {}", err);
+        }
+    }
+}
+
+/// Announce the mint, then publish a status note every `interval`
+///
+/// Failures are logged and never stop the mint, a failed announcement is
+/// not retried until the next start.
+pub async fn announce_periodically(
+    settings: config::Nostr,
+    mnemonic: Mnemonic,
+    mint: MintAnnouncement,
+    db: Db,
+    interval: Duration,
+) {
+    let announcer = match Announcer::new(&settings, &mnemonic, mint).await {
+        Ok(announcer) => announcer,
+        Err(err) => {
+            tracing::warn!("Nostr announcements are unavailable: {}", err);
+            return;
+        }
+    };
+
+    match announcer.announce().await {
+        Ok(()) => tracing::info!("Announced mint on nostr"),
+        Err(err) => tracing::warn!("Could not announce mint on nostr: {}", err),
+    }
+
+    let mut status_interval = tokio::time::interval(interval);
+
+    loop {
+        status_interval.tick().await;
+
+        let search_count = match db.get_search_count() {
+            Ok(search_count) => search_count.all_time_search_count,
+            Err(err) => {
+                tracing::warn!("Could not read search count for nostr status: {}", err);
+                continue;
+            }
+        };
+
+        if let Err(err) = announcer.publish_status(search_count).await {
+            tracing::warn!("Could not publish nostr status: {}", err);
+        }
+    }
+}
+
+/// Keys events are signed with, the configured secret key or one derived
+/// from `mnemonic`
+pub fn signing_keys(settings: &config::Nostr, mnemonic: &Mnemonic) -> Result<Keys, Error> {
+    match &settings.secret_key {
+        Some(secret_key) => {
+            Keys::parse(secret_key).map_err(|err| Error::SecretKey(err.to_string()))
+        }
+        None => derive_keys(mnemonic),
+    }
+}
+
+fn derive_keys(mnemonic: &Mnemonic) -> Result<Keys, Error> {
+    let secp = Secp256k1::new();
+
+    let path = DerivationPath::from_str(NOSTR_DERIVATION_PATH)?;
+    let xpriv = Xpriv::new_master(Network::Bitcoin, &mnemonic.to_seed_normalized(""))?
+        .derive_priv(&secp, &path)?;
+
+    let secret_key = SecretKey::from_slice(&xpriv.private_key.secret_bytes())
+        .map_err(|err| Error::SecretKey(err.to_string()))?;
+
+    Ok(Keys::new(secret_key))
+}
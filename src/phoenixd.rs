@@ -0,0 +1,600 @@
+//! CDK lightning backend for phoenixd
+
+#![warn(missing_docs)]
+#![warn(rustdoc::bare_urls)]
+
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bitcoin::base64::engine::general_purpose::STANDARD as BASE64;
+use bitcoin::base64::Engine;
+use cdk::amount::{to_unit, Amount};
+use cdk::cdk_lightning::{
+    self, CreateInvoiceResponse, MintLightning, PayInvoiceResponse, PaymentQuoteResponse, Settings,
+};
+use cdk::mint::FeeReserve;
+use cdk::nuts::{
+    CurrencyUnit, MeltMethodSettings, MeltQuoteBolt11Request, MeltQuoteState, MintMethodSettings,
+    MintQuoteState,
+};
+use cdk::util::unix_time;
+use cdk::{mint, Bolt11Invoice};
+use futures::{SinkExt, Stream, StreamExt};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::header::AUTHORIZATION;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::db::{Db, SearchStore};
+use crate::price::{self, cents_to_msats, PriceCache};
+use crate::runtime_settings::SharedRuntimeSettings;
+
+/// Flat part of the fee phoenixd charges on outgoing payments, in msats
+const PHOENIXD_FEE_BASE_MSAT: u64 = 4_000;
+/// Proportional part of the fee phoenixd charges on outgoing payments, in ppm
+const PHOENIXD_FEE_PPM: u64 = 4_000;
+
+/// Phoenixd Error
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Invoice amount not defined
+    #[error("Unknown invoice amount")]
+    UnknownInvoiceAmount,
+    /// Phoenixd does not pay part of an invoice
+    #[error("Partial payments are not supported")]
+    PartialPaymentUnsupported,
+    /// Api password can not be sent in a header
+    #[error("Invalid api password")]
+    InvalidApiPassword,
+    /// Phoenixd returned an unexpected status
+    #[error("Phoenixd returned status {0}")]
+    Status(u16),
+    /// BTC price could not be fetched to price the invoice
+    #[error("Price unavailable")]
+    PriceUnavailable,
+    /// Reqwest Error
+    #[error(transparent)]
+    Reqwest(#[from] reqwest::Error),
+    /// Websocket Error
+    #[error(transparent)]
+    Websocket(#[from] tokio_tungstenite::tungstenite::Error),
+    /// Price Error
+    #[error(transparent)]
+    Price(#[from] price::Error),
+    /// Amount Error
+    #[error(transparent)]
+    Amount(#[from] cdk::amount::Error),
+}
+
+impl From<Error> for cdk::cdk_lightning::Error {
+    fn from(e: Error) -> Self {
+        Self::Lightning(Box::new(e))
+    }
+}
+
+/// Operator settings for the [`Phoenixd`] backend
+#[derive(Clone)]
+pub struct PhoenixdSettings {
+    /// Base url of the phoenixd http api
+    pub url: String,
+    /// Password for the phoenixd http api
+    pub api_password: String,
+    pub fee_reserve: FeeReserve,
+    /// Holds the price of one XSR, reloadable without a restart
+    pub runtime_settings: SharedRuntimeSettings,
+    /// Invoice description used when the quote does not give one
+    pub mint_name: String,
+}
+
+/// Phoenixd mint backend
+#[derive(Clone)]
+pub struct Phoenixd {
+    settings: PhoenixdSettings,
+    client: reqwest::Client,
+    mint_settings: MintMethodSettings,
+    melt_settings: MeltMethodSettings,
+    wait_invoice_cancel_token: CancellationToken,
+    wait_invoice_is_active: Arc<AtomicBool>,
+    price_cache: PriceCache,
+    db: Db,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateInvoiceRequest {
+    description: String,
+    amount_sat: u64,
+    expiry_seconds: u64,
+    external_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateInvoiceResponseBody {
+    serialized: String,
+}
+
+#[derive(Debug, Serialize)]
+struct PayInvoiceRequest {
+    invoice: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PayInvoiceResponseBody {
+    recipient_amount_sat: u64,
+    routing_fee_sat: u64,
+    payment_hash: String,
+    payment_preimage: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IncomingPayment {
+    is_paid: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OutgoingPayment {
+    payment_hash: String,
+    preimage: Option<String>,
+    is_paid: bool,
+    /// Sats sent to the recipient
+    sent: u64,
+    /// Fees paid in msats
+    fees: u64,
+    completed_at: Option<u64>,
+}
+
+/// Message pushed over the phoenixd websocket
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WebsocketEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    payment_hash: Option<String>,
+    amount_sat: Option<u64>,
+}
+
+impl Phoenixd {
+    /// Create new [`Phoenixd`]
+    ///
+    /// `price_cache` prices invoices for the XSR unit, `db` records the sats
+    /// received.
+    pub fn new(
+        settings: PhoenixdSettings,
+        mint_settings: MintMethodSettings,
+        melt_settings: MeltMethodSettings,
+        price_cache: PriceCache,
+        db: Db,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            settings,
+            client: reqwest::Client::builder().build()?,
+            mint_settings,
+            melt_settings,
+            wait_invoice_cancel_token: CancellationToken::new(),
+            wait_invoice_is_active: Arc::new(AtomicBool::new(false)),
+            price_cache,
+            db,
+        })
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.settings.url.trim_end_matches('/'), path)
+    }
+
+    /// Check that phoenixd answers an authenticated request
+    pub async fn check_connection(&self) -> Result<(), Error> {
+        let response = self
+            .client
+            .get(self.url("getinfo"))
+            .basic_auth("", Some(&self.settings.api_password))
+            .send()
+            .await?;
+
+        match response.status().is_success() {
+            true => Ok(()),
+            false => Err(Error::Status(response.status().as_u16())),
+        }
+    }
+
+    /// Open the phoenixd websocket that pushes received payments
+    async fn connect_websocket(
+        url: &str,
+        api_password: &str,
+    ) -> Result<
+        tokio_tungstenite::WebSocketStream<
+            tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+        >,
+        Error,
+    > {
+        let url = format!("{}/websocket", url.trim_end_matches('/')).replacen("http", "ws", 1);
+
+        let mut request = url.into_client_request()?;
+        let auth = format!("Basic {}", BASE64.encode(format!(":{}", api_password)));
+        request.headers_mut().insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&auth).map_err(|_| Error::InvalidApiPassword)?,
+        );
+
+        let (websocket, _) = tokio_tungstenite::connect_async(request).await?;
+
+        Ok(websocket)
+    }
+}
+
+#[async_trait]
+impl MintLightning for Phoenixd {
+    type Err = cdk_lightning::Error;
+
+    fn get_settings(&self) -> Settings {
+        Settings {
+            mpp: false,
+            unit: CurrencyUnit::Sat,
+            mint_settings: self.mint_settings,
+            melt_settings: self.melt_settings,
+            invoice_description: true,
+        }
+    }
+
+    /// Is wait invoice active
+    fn is_wait_invoice_active(&self) -> bool {
+        self.wait_invoice_is_active.load(Ordering::SeqCst)
+    }
+
+    /// Cancel wait invoice
+    fn cancel_wait_invoice(&self) {
+        self.wait_invoice_cancel_token.cancel()
+    }
+
+    async fn wait_any_invoice(
+        &self,
+    ) -> Result<Pin<Box<dyn Stream<Item = String> + Send>>, Self::Err> {
+        let websocket =
+            Self::connect_websocket(&self.settings.url, &self.settings.api_password).await?;
+
+        let stream = futures::stream::unfold(
+            (
+                Some(websocket),
+                self.settings.clone(),
+                self.wait_invoice_cancel_token.clone(),
+                Arc::clone(&self.wait_invoice_is_active),
+                self.db.clone(),
+            ),
+            |(mut websocket, settings, cancel_token, is_active, db)| async move {
+                // Set the stream as active
+                is_active.store(true, Ordering::SeqCst);
+
+                loop {
+                    let Some(ws) = websocket.as_mut() else {
+                        tokio::select! {
+                            _ = cancel_token.cancelled() => {
+                                is_active.store(false, Ordering::SeqCst);
+                                return None;
+                            }
+                            _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+                        }
+
+                        match Self::connect_websocket(&settings.url, &settings.api_password).await
+                        {
+                            Ok(ws) => websocket = Some(ws),
+                            Err(err) => {
+                                tracing::warn!("Could not reconnect to phoenixd websocket: {}", err)
+                            }
+                        }
+                        continue;
+                    };
+
+                    tokio::select! {
+                        _ = cancel_token.cancelled() => {
+                            // Set the stream as inactive
+                            is_active.store(false, Ordering::SeqCst);
+                            // End the stream
+                            return None;
+                        }
+                        message = ws.next() => {
+                            let text = match message {
+                                Some(Ok(Message::Text(text))) => text,
+                                Some(Ok(Message::Ping(payload))) => {
+                                    if let Err(err) = ws.send(Message::Pong(payload)).await {
+                                        tracing::warn!("Could not answer phoenixd ping: {}", err);
+                                    }
+                                    continue;
+                                }
+                                Some(Ok(_)) => continue,
+                                Some(Err(err)) => {
+                                    tracing::warn!("Phoenixd websocket error: {}", err);
+                                    websocket = None;
+                                    continue;
+                                }
+                                None => {
+                                    tracing::warn!("Phoenixd websocket closed");
+                                    websocket = None;
+                                    continue;
+                                }
+                            };
+
+                            let event: WebsocketEvent = match serde_json::from_str(&text) {
+                                Ok(event) => event,
+                                Err(err) => {
+                                    tracing::warn!("Failed to parse phoenixd event: {}", err);
+                                    continue;
+                                }
+                            };
+
+                            if event.kind != "payment_received" {
+                                continue;
+                            }
+
+                            if let Some(payment_hash) = event.payment_hash {
+                                if let Some(amount_sat) = event.amount_sat {
+                                    if let Err(err) = db.add_revenue(&payment_hash, amount_sat * 1000) {
+                                        tracing::error!("Could not record revenue for {}: {}", payment_hash, err);
+                                    }
+                                }
+
+                                return Some((payment_hash, (websocket, settings, cancel_token, is_active, db)));
+                            }
+                        }
+                    }
+                }
+            },
+        )
+        .boxed();
+
+        Ok(stream)
+    }
+
+    async fn get_payment_quote(
+        &self,
+        melt_quote_request: &MeltQuoteBolt11Request,
+    ) -> Result<PaymentQuoteResponse, Self::Err> {
+        let invoice_amount_msat = melt_quote_request
+            .request
+            .amount_milli_satoshis()
+            .ok_or(Error::UnknownInvoiceAmount)?;
+
+        let amount = to_unit(
+            invoice_amount_msat,
+            &CurrencyUnit::Msat,
+            &melt_quote_request.unit,
+        )?;
+
+        let relative_fee_reserve =
+            (self.settings.fee_reserve.percent_fee_reserve * u64::from(amount) as f32) as u64;
+
+        let absolute_fee_reserve: u64 = self.settings.fee_reserve.min_fee_reserve.into();
+
+        // Phoenixd charges its own service fee on top of routing, the reserve
+        // has to cover it whatever the operator configured
+        let phoenixd_fee_msat =
+            PHOENIXD_FEE_BASE_MSAT + invoice_amount_msat * PHOENIXD_FEE_PPM / 1_000_000;
+        let phoenixd_fee: u64 = to_unit(
+            phoenixd_fee_msat,
+            &CurrencyUnit::Msat,
+            &melt_quote_request.unit,
+        )?
+        .into();
+
+        let fee = relative_fee_reserve
+            .max(absolute_fee_reserve)
+            .max(phoenixd_fee);
+
+        Ok(PaymentQuoteResponse {
+            request_lookup_id: melt_quote_request.request.payment_hash().to_string(),
+            amount,
+            fee: fee.into(),
+            state: MeltQuoteState::Unpaid,
+        })
+    }
+
+    async fn pay_invoice(
+        &self,
+        melt_quote: mint::MeltQuote,
+        partial_amount: Option<Amount>,
+        _max_fee: Option<Amount>,
+    ) -> Result<PayInvoiceResponse, Self::Err> {
+        if partial_amount.is_some() {
+            return Err(Error::PartialPaymentUnsupported.into());
+        }
+
+        let bolt11 = Bolt11Invoice::from_str(&melt_quote.request)?;
+        let pay_state = self
+            .check_outgoing_payment(&bolt11.payment_hash().to_string())
+            .await?;
+
+        match pay_state.status {
+            MeltQuoteState::Unpaid | MeltQuoteState::Unknown | MeltQuoteState::Failed => (),
+            MeltQuoteState::Paid => {
+                tracing::debug!("Melt attempted on invoice already paid");
+                return Err(Self::Err::InvoiceAlreadyPaid);
+            }
+            MeltQuoteState::Pending => {
+                tracing::debug!("Melt attempted on invoice already pending");
+                return Err(Self::Err::InvoicePaymentPending);
+            }
+        }
+
+        let response = self
+            .client
+            .post(self.url("payinvoice"))
+            .basic_auth("", Some(&self.settings.api_password))
+            .form(&PayInvoiceRequest {
+                invoice: melt_quote.request.to_string(),
+            })
+            .send()
+            .await
+            .map_err(Error::from)?;
+
+        if !response.status().is_success() {
+            tracing::error!(
+                "Error attempting to pay invoice: {}",
+                bolt11.payment_hash().to_string()
+            );
+            return Err(Error::Status(response.status().as_u16()).into());
+        }
+
+        let pay_response: PayInvoiceResponseBody = response.json().await.map_err(Error::from)?;
+
+        Ok(PayInvoiceResponse {
+            payment_preimage: Some(pay_response.payment_preimage),
+            payment_lookup_id: pay_response.payment_hash,
+            status: MeltQuoteState::Paid,
+            total_spent: to_unit(
+                pay_response.recipient_amount_sat + pay_response.routing_fee_sat,
+                &CurrencyUnit::Sat,
+                &melt_quote.unit,
+            )?,
+            unit: melt_quote.unit,
+        })
+    }
+
+    async fn create_invoice(
+        &self,
+        amount: Amount,
+        unit: &CurrencyUnit,
+        description: String,
+        unix_expiry: u64,
+    ) -> Result<CreateInvoiceResponse, Self::Err> {
+        let time_now = unix_time();
+
+        let amount_sat: u64 =
+            if unit == &CurrencyUnit::from_str("XSR").map_err(|_| Error::UnknownInvoiceAmount)? {
+                let btc_price = self.price_cache.get_price().await.map_err(|err| {
+                    tracing::error!("Could not price invoice: {}", err);
+                    Error::PriceUnavailable
+                })?;
+                cents_to_msats(
+                    self.settings.runtime_settings.load().cost_per_search_cents * u64::from(amount),
+                    btc_price,
+                )? / 1000
+            } else {
+                to_unit(amount, unit, &CurrencyUnit::Sat)?.into()
+            };
+
+        let description = match description.is_empty() {
+            true => self.settings.mint_name.clone(),
+            false => description,
+        };
+
+        let external_id = format!(
+            "athenut:{}:{}:{}",
+            unit.to_string().to_lowercase(),
+            time_now,
+            &Uuid::new_v4().simple().to_string()[..8]
+        );
+
+        let response = self
+            .client
+            .post(self.url("createinvoice"))
+            .basic_auth("", Some(&self.settings.api_password))
+            .form(&CreateInvoiceRequest {
+                description,
+                amount_sat,
+                expiry_seconds: unix_expiry.saturating_sub(time_now),
+                external_id,
+            })
+            .send()
+            .await
+            .map_err(Error::from)?;
+
+        if !response.status().is_success() {
+            tracing::warn!("Phoenixd could not create invoice: {}", response.status());
+            return Err(Error::Status(response.status().as_u16()).into());
+        }
+
+        let invoice: CreateInvoiceResponseBody = response.json().await.map_err(Error::from)?;
+
+        let request = Bolt11Invoice::from_str(&invoice.serialized)?;
+        let expiry = request.expires_at().map(|t| t.as_secs());
+        let payment_hash = request.payment_hash();
+
+        Ok(CreateInvoiceResponse {
+            request_lookup_id: payment_hash.to_string(),
+            request,
+            expiry,
+        })
+    }
+
+    async fn check_incoming_invoice_status(
+        &self,
+        payment_hash: &str,
+    ) -> Result<MintQuoteState, Self::Err> {
+        let response = self
+            .client
+            .get(self.url(&format!("payments/incoming/{}", payment_hash)))
+            .basic_auth("", Some(&self.settings.api_password))
+            .send()
+            .await
+            .map_err(Error::from)?;
+
+        if !response.status().is_success() {
+            tracing::info!(
+                "Check invoice called on unknown look up id: {}",
+                payment_hash
+            );
+            return Err(Error::Status(response.status().as_u16()).into());
+        }
+
+        let payment: IncomingPayment = response.json().await.map_err(Error::from)?;
+
+        Ok(match payment.is_paid {
+            true => MintQuoteState::Paid,
+            false => MintQuoteState::Unpaid,
+        })
+    }
+
+    async fn check_outgoing_payment(
+        &self,
+        payment_hash: &str,
+    ) -> Result<PayInvoiceResponse, Self::Err> {
+        let response = self
+            .client
+            .get(self.url(&format!("payments/outgoingbyhash/{}", payment_hash)))
+            .basic_auth("", Some(&self.settings.api_password))
+            .send()
+            .await
+            .map_err(Error::from)?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(PayInvoiceResponse {
+                payment_lookup_id: payment_hash.to_string(),
+                payment_preimage: None,
+                status: MeltQuoteState::Unknown,
+                total_spent: Amount::ZERO,
+                unit: CurrencyUnit::Msat,
+            });
+        }
+
+        if !response.status().is_success() {
+            return Err(Error::Status(response.status().as_u16()).into());
+        }
+
+        let payment: OutgoingPayment = response.json().await.map_err(Error::from)?;
+
+        let status = match (payment.is_paid, payment.completed_at) {
+            (true, _) => MeltQuoteState::Paid,
+            (false, Some(_)) => MeltQuoteState::Failed,
+            (false, None) => MeltQuoteState::Pending,
+        };
+
+        Ok(PayInvoiceResponse {
+            payment_lookup_id: payment.payment_hash,
+            payment_preimage: payment.preimage,
+            status,
+            total_spent: (payment.sent * 1000 + payment.fees).into(),
+            unit: CurrencyUnit::Msat,
+        })
+    }
+}
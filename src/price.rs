@@ -0,0 +1,752 @@
+//! BTC price used to quote searches priced in fiat
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::{Mutex, RwLock};
+use tokio::time::Instant;
+
+/// Price Error
+#[derive(Debug, Error)]
+pub enum Error {
+    /// None of the price sources returned a price
+    #[error("Price unavailable")]
+    PriceUnavailable,
+    /// Price source response could not be parsed
+    #[error("Invalid price source response")]
+    InvalidResponse,
+    /// Price source answered with an error status
+    #[error("Price source returned status {0}")]
+    Status(u16),
+    /// Price is outside the configured sane band
+    #[error("Price {0} is out of range")]
+    OutOfRange(u64),
+    /// Converted amount does not fit in msats
+    #[error("Amount overflow")]
+    AmountOverflow,
+    /// Reqwest Error
+    #[error(transparent)]
+    Reqwest(#[from] reqwest::Error),
+}
+
+/// Fiat currency searches are priced in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum FiatCurrency {
+    #[default]
+    Usd,
+    Eur,
+    Gbp,
+    Cad,
+    Chf,
+    Aud,
+}
+
+impl fmt::Display for FiatCurrency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FiatCurrency::Usd => write!(f, "USD"),
+            FiatCurrency::Eur => write!(f, "EUR"),
+            FiatCurrency::Gbp => write!(f, "GBP"),
+            FiatCurrency::Cad => write!(f, "CAD"),
+            FiatCurrency::Chf => write!(f, "CHF"),
+            FiatCurrency::Aud => write!(f, "AUD"),
+        }
+    }
+}
+
+/// Source of the BTC price
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PriceSource {
+    Mempool,
+    Coinbase,
+    Kraken,
+}
+
+impl PriceSource {
+    /// Sources tried, in order, when none are configured
+    pub const DEFAULT: [PriceSource; 3] = [
+        PriceSource::Mempool,
+        PriceSource::Coinbase,
+        PriceSource::Kraken,
+    ];
+
+    fn url(&self, currency: FiatCurrency) -> String {
+        match self {
+            PriceSource::Mempool => "https://mempool.space/api/v1/prices".to_string(),
+            PriceSource::Coinbase => {
+                format!("https://api.coinbase.com/v2/prices/BTC-{}/spot", currency)
+            }
+            PriceSource::Kraken => format!(
+                "https://api.kraken.com/0/public/Ticker?pair=XBT{}",
+                currency
+            ),
+        }
+    }
+
+    /// Parse the BTC price in whole units of `currency` out of a response
+    /// from this source
+    fn parse(&self, body: &str, currency: FiatCurrency) -> Result<u64, Error> {
+        let price = match self {
+            PriceSource::Mempool => {
+                // Prices in every currency mempool.space supports keyed by code
+                let response: HashMap<String, serde_json::Value> =
+                    serde_json::from_str(body).map_err(|_| Error::InvalidResponse)?;
+                response
+                    .get(&currency.to_string())
+                    .and_then(|price| price.as_f64())
+                    .ok_or(Error::InvalidResponse)?
+            }
+            PriceSource::Coinbase => {
+                let response: CoinbaseResponse =
+                    serde_json::from_str(body).map_err(|_| Error::InvalidResponse)?;
+                response
+                    .data
+                    .amount
+                    .parse()
+                    .map_err(|_| Error::InvalidResponse)?
+            }
+            PriceSource::Kraken => {
+                let response: KrakenResponse =
+                    serde_json::from_str(body).map_err(|_| Error::InvalidResponse)?;
+                // The pair is keyed by kraken's own name for it, "XXBTZUSD"
+                // for USD
+                let ticker = response
+                    .result
+                    .values()
+                    .next()
+                    .ok_or(Error::InvalidResponse)?;
+                ticker
+                    .c
+                    .first()
+                    .ok_or(Error::InvalidResponse)?
+                    .parse()
+                    .map_err(|_| Error::InvalidResponse)?
+            }
+        };
+
+        if !price.is_finite() || price < 1.0 {
+            return Err(Error::InvalidResponse);
+        }
+
+        Ok(price.round() as u64)
+    }
+}
+
+impl fmt::Display for PriceSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PriceSource::Mempool => write!(f, "mempool"),
+            PriceSource::Coinbase => write!(f, "coinbase"),
+            PriceSource::Kraken => write!(f, "kraken"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CoinbaseResponse {
+    data: CoinbasePrice,
+}
+
+#[derive(Debug, Deserialize)]
+struct CoinbasePrice {
+    amount: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct KrakenResponse {
+    result: HashMap<String, KrakenTicker>,
+}
+
+#[derive(Debug, Deserialize)]
+struct KrakenTicker {
+    /// Last trade closed, price and lot volume
+    c: Vec<String>,
+}
+
+/// Http client price sources are fetched with
+///
+/// Implemented by [`HttpFetcher`] and by in memory mocks so prices can be
+/// tested without the network.
+#[async_trait]
+pub trait PriceFetcher: fmt::Debug + Send + Sync {
+    /// Status and body of a GET of `url`
+    async fn get(&self, url: &str) -> Result<(u16, String), Error>;
+}
+
+/// [`PriceFetcher`] over reqwest
+#[derive(Debug, Clone, Default)]
+pub struct HttpFetcher {
+    client: reqwest::Client,
+}
+
+#[async_trait]
+impl PriceFetcher for HttpFetcher {
+    async fn get(&self, url: &str) -> Result<(u16, String), Error> {
+        let response = self.client.get(url).send().await?;
+        let status = response.status().as_u16();
+
+        Ok((status, response.text().await?))
+    }
+}
+
+/// BTC price in the configured fiat currency cached for `ttl`
+///
+/// Once the cached price expires it is refreshed in the background while the
+/// stale price keeps being served, so a failed refresh does not fail a quote.
+/// A price stale for longer than `max_stale` past the ttl is no longer served,
+/// lookups then wait on the price sources as the first lookup does.
+#[derive(Debug, Clone)]
+pub struct PriceCache {
+    ttl: Duration,
+    max_stale: Duration,
+    currency: FiatCurrency,
+    /// Tried in order until one returns a price
+    sources: Vec<PriceSource>,
+    /// Prices outside this band in whole units are treated as a failed
+    /// source rather than used to price invoices
+    bounds: (u64, u64),
+    fetcher: Arc<dyn PriceFetcher>,
+    cached: Arc<RwLock<Option<(u64, Instant)>>>,
+    /// Held while fetching so concurrent lookups share a single request
+    fetch_lock: Arc<Mutex<()>>,
+    refreshing: Arc<AtomicBool>,
+}
+
+impl PriceCache {
+    /// Create new [`PriceCache`] for the BTC price in `currency`, accepting
+    /// prices between `min_price` and `max_price` whole units
+    pub fn new(
+        ttl: Duration,
+        max_stale: Duration,
+        currency: FiatCurrency,
+        sources: Vec<PriceSource>,
+        min_price: u64,
+        max_price: u64,
+    ) -> Self {
+        Self::with_fetcher(
+            ttl,
+            max_stale,
+            currency,
+            sources,
+            min_price,
+            max_price,
+            Arc::new(HttpFetcher::default()),
+        )
+    }
+
+    /// Create new [`PriceCache`] fetching the price sources with `fetcher`
+    pub fn with_fetcher(
+        ttl: Duration,
+        max_stale: Duration,
+        currency: FiatCurrency,
+        sources: Vec<PriceSource>,
+        min_price: u64,
+        max_price: u64,
+        fetcher: Arc<dyn PriceFetcher>,
+    ) -> Self {
+        Self {
+            ttl,
+            max_stale,
+            currency,
+            sources,
+            bounds: (min_price, max_price),
+            fetcher,
+            cached: Arc::new(RwLock::new(None)),
+            fetch_lock: Arc::new(Mutex::new(())),
+            refreshing: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Currency prices are in
+    pub fn currency(&self) -> FiatCurrency {
+        self.currency
+    }
+
+    /// Whether the cached price was fetched within the cache ttl plus `grace`
+    pub async fn is_fresh(&self, grace: Duration) -> bool {
+        matches!(
+            *self.cached.read().await,
+            Some((_, fetched_at)) if fetched_at.elapsed() <= self.ttl + grace
+        )
+    }
+
+    /// BTC price in whole units of the configured currency
+    ///
+    /// Fails with [`Error::PriceUnavailable`] when nothing usable is cached
+    /// and no source returns a price.
+    pub async fn get_price(&self) -> Result<u64, Error> {
+        let cached = *self.cached.read().await;
+
+        match cached {
+            Some((price, fetched_at)) if fetched_at.elapsed() < self.ttl => Ok(price),
+            Some((price, fetched_at)) if fetched_at.elapsed() < self.ttl + self.max_stale => {
+                self.refresh_in_background();
+                Ok(price)
+            }
+            _ => {
+                let _fetch = self.fetch_lock.lock().await;
+
+                // Another lookup may have fetched the price while we waited
+                if let Some((price, fetched_at)) = *self.cached.read().await {
+                    if fetched_at.elapsed() < self.ttl + self.max_stale {
+                        return Ok(price);
+                    }
+                }
+
+                self.refresh().await
+            }
+        }
+    }
+
+    fn refresh_in_background(&self) {
+        if self.refreshing.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let cache = self.clone();
+
+        tokio::spawn(async move {
+            let _fetch = cache.fetch_lock.lock().await;
+
+            if let Err(err) = cache.refresh().await {
+                tracing::warn!("Could not refresh BTC price, serving stale price: {}", err);
+            }
+
+            cache.refreshing.store(false, Ordering::SeqCst);
+        });
+    }
+
+    async fn refresh(&self) -> Result<u64, Error> {
+        let price = self.fetch_price().await?;
+
+        *self.cached.write().await = Some((price, Instant::now()));
+
+        Ok(price)
+    }
+
+    async fn fetch_price(&self) -> Result<u64, Error> {
+        for source in &self.sources {
+            let price = fetch_from_source(self.fetcher.as_ref(), *source, self.currency)
+                .await
+                .and_then(
+                    |price| match (self.bounds.0..=self.bounds.1).contains(&price) {
+                        true => Ok(price),
+                        false => Err(Error::OutOfRange(price)),
+                    },
+                );
+
+            match price {
+                Ok(price) => return Ok(price),
+                Err(err) => tracing::warn!("Could not get BTC price from {}: {}", source, err),
+            }
+        }
+
+        Err(Error::PriceUnavailable)
+    }
+}
+
+async fn fetch_from_source(
+    fetcher: &dyn PriceFetcher,
+    source: PriceSource,
+    currency: FiatCurrency,
+) -> Result<u64, Error> {
+    let (status, body) = fetcher.get(&source.url(currency)).await?;
+
+    if !(200..300).contains(&status) {
+        return Err(Error::Status(status));
+    }
+
+    source.parse(&body, currency)
+}
+
+/// Convert `cents`, or the minor unit of any currency with 100 of them to the
+/// whole unit, to msats at a BTC price of `btc_price` whole units, rounded up
+/// to a whole sat
+pub fn cents_to_msats(cents: u64, btc_price: u64) -> Result<u64, Error> {
+    if btc_price == 0 {
+        return Err(Error::OutOfRange(btc_price));
+    }
+
+    // 1 BTC = 100_000_000 sats = btc_price * 100 cents, rounded up in one
+    // division so a fraction of a msat is not lost before rounding
+    let sats = (cents as u128 * 1_000_000).div_ceil(btc_price as u128);
+
+    u64::try_from(sats * 1000).map_err(|_| Error::AmountOverflow)
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::test_utils::{mempool_prices, FakePriceFetcher};
+
+    const TTL: Duration = Duration::from_secs(60);
+    const MAX_STALE: Duration = Duration::from_secs(600);
+
+    fn test_cache(fetcher: &Arc<FakePriceFetcher>) -> PriceCache {
+        PriceCache::with_fetcher(
+            TTL,
+            MAX_STALE,
+            FiatCurrency::Usd,
+            vec![PriceSource::Mempool],
+            1_000,
+            10_000_000,
+            fetcher.clone(),
+        )
+    }
+
+    /// Let a background refresh started by a lookup finish
+    async fn settle() {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_price_is_served_while_the_refresh_fails() {
+        let fetcher = Arc::new(FakePriceFetcher::new(200, &mempool_prices(60_000, 55_000)));
+        let cache = test_cache(&fetcher);
+
+        assert_eq!(cache.get_price().await.unwrap(), 60_000);
+
+        fetcher.respond(500, "");
+        tokio::time::advance(TTL).await;
+
+        assert_eq!(cache.get_price().await.unwrap(), 60_000);
+        settle().await;
+
+        assert_eq!(fetcher.calls(), 2);
+        assert_eq!(cache.get_price().await.unwrap(), 60_000);
+        assert!(!cache.is_fresh(Duration::ZERO).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_price_is_replaced_once_refreshed() {
+        let fetcher = Arc::new(FakePriceFetcher::new(200, &mempool_prices(60_000, 55_000)));
+        let cache = test_cache(&fetcher);
+
+        assert_eq!(cache.get_price().await.unwrap(), 60_000);
+
+        fetcher.respond(200, &mempool_prices(70_000, 64_000));
+        tokio::time::advance(TTL).await;
+
+        // Served stale while the refresh runs
+        assert_eq!(cache.get_price().await.unwrap(), 60_000);
+        settle().await;
+
+        assert_eq!(cache.get_price().await.unwrap(), 70_000);
+        assert!(cache.is_fresh(Duration::ZERO).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn price_stale_past_max_stale_is_unavailable() {
+        let fetcher = Arc::new(FakePriceFetcher::new(200, &mempool_prices(60_000, 55_000)));
+        let cache = test_cache(&fetcher);
+
+        assert_eq!(cache.get_price().await.unwrap(), 60_000);
+
+        fetcher.respond(500, "");
+        tokio::time::advance(TTL + MAX_STALE).await;
+
+        assert!(matches!(
+            cache.get_price().await,
+            Err(Error::PriceUnavailable)
+        ));
+
+        // Served again as soon as a source is back
+        fetcher.respond(200, &mempool_prices(61_000, 56_000));
+        assert_eq!(cache.get_price().await.unwrap(), 61_000);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_first_lookups_share_one_fetch() {
+        let fetcher = Arc::new(
+            FakePriceFetcher::new(200, &mempool_prices(60_000, 55_000))
+                .with_delay(Duration::from_millis(500)),
+        );
+        let cache = test_cache(&fetcher);
+
+        let lookups: Vec<_> = (0..10)
+            .map(|_| {
+                let cache = cache.clone();
+                tokio::spawn(async move { cache.get_price().await })
+            })
+            .collect();
+
+        for lookup in lookups {
+            assert_eq!(lookup.await.unwrap().unwrap(), 60_000);
+        }
+
+        assert_eq!(fetcher.calls(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_stale_lookups_start_one_refresh() {
+        let fetcher = Arc::new(
+            FakePriceFetcher::new(200, &mempool_prices(60_000, 55_000))
+                .with_delay(Duration::from_millis(500)),
+        );
+        let cache = test_cache(&fetcher);
+
+        assert_eq!(cache.get_price().await.unwrap(), 60_000);
+
+        fetcher.respond(200, &mempool_prices(70_000, 64_000));
+        tokio::time::advance(TTL).await;
+
+        let lookups: Vec<_> = (0..10)
+            .map(|_| {
+                let cache = cache.clone();
+                tokio::spawn(async move { cache.get_price().await })
+            })
+            .collect();
+
+        for lookup in lookups {
+            assert_eq!(lookup.await.unwrap().unwrap(), 60_000);
+        }
+
+        settle().await;
+
+        assert_eq!(fetcher.calls(), 2);
+        assert_eq!(cache.get_price().await.unwrap(), 70_000);
+    }
+
+    const COINBASE_USD: &str = r#"{"data":{"amount":"62345.67","base":"BTC","currency":"USD"}}"#;
+
+    const KRAKEN_USD: &str = r#"{"error":[],"result":{"XXBTZUSD":{"a":["62350.10000","1","1.000"],"b":["62350.00000","2","2.000"],"c":["62345.60000","0.00100000"],"v":["1200.1","2400.2"],"p":["62100.1","62000.2"],"t":[10000,20000],"l":["61000.0","60500.0"],"h":["63000.0","63500.0"],"o":"62000.00000"}}}"#;
+
+    #[test]
+    fn mempool_price_is_parsed() {
+        assert_eq!(
+            PriceSource::Mempool
+                .parse(&mempool_prices(60_000, 55_000), FiatCurrency::Usd)
+                .unwrap(),
+            60_000
+        );
+    }
+
+    #[test]
+    fn coinbase_price_is_parsed_and_rounded() {
+        assert_eq!(
+            PriceSource::Coinbase
+                .parse(COINBASE_USD, FiatCurrency::Usd)
+                .unwrap(),
+            62_346
+        );
+    }
+
+    #[test]
+    fn kraken_price_is_the_last_trade() {
+        assert_eq!(
+            PriceSource::Kraken
+                .parse(KRAKEN_USD, FiatCurrency::Usd)
+                .unwrap(),
+            62_346
+        );
+    }
+
+    #[test]
+    fn mempool_price_is_read_for_the_configured_currency() {
+        let body = mempool_prices(60_000, 55_000);
+
+        assert_eq!(
+            PriceSource::Mempool
+                .parse(&body, FiatCurrency::Usd)
+                .unwrap(),
+            60_000
+        );
+        assert_eq!(
+            PriceSource::Mempool
+                .parse(&body, FiatCurrency::Eur)
+                .unwrap(),
+            55_000
+        );
+    }
+
+    #[test]
+    fn coinbase_and_kraken_are_asked_for_the_configured_currency() {
+        assert_eq!(
+            PriceSource::Coinbase.url(FiatCurrency::Eur),
+            "https://api.coinbase.com/v2/prices/BTC-EUR/spot"
+        );
+        assert_eq!(
+            PriceSource::Kraken.url(FiatCurrency::Eur),
+            "https://api.kraken.com/0/public/Ticker?pair=XBTEUR"
+        );
+        assert_eq!(
+            PriceSource::Kraken
+                .parse(
+                    r#"{"error":[],"result":{"XXBTZEUR":{"c":["55321.40000","0.01"]}}}"#,
+                    FiatCurrency::Eur
+                )
+                .unwrap(),
+            55_321
+        );
+    }
+
+    #[test]
+    fn currency_is_read_from_config_in_upper_case() {
+        assert_eq!(
+            serde_json::from_str::<FiatCurrency>(r#""EUR""#).unwrap(),
+            FiatCurrency::Eur
+        );
+        assert_eq!(
+            serde_json::from_str::<FiatCurrency>(r#""USD""#).unwrap(),
+            FiatCurrency::Usd
+        );
+        assert_eq!(FiatCurrency::default(), FiatCurrency::Usd);
+    }
+
+    #[test]
+    fn euro_cents_convert_at_the_eur_price() {
+        // 3 euro cents at 55,000 EUR a BTC is 54.5 sats
+        assert_eq!(cents_to_msats(3, 55_000).unwrap(), 55_000);
+        // and 50 sats at 60,000 USD
+        assert_eq!(cents_to_msats(3, 60_000).unwrap(), 50_000);
+    }
+
+    #[tokio::test]
+    async fn eur_cache_prices_in_eur() {
+        let fetcher = Arc::new(FakePriceFetcher::new(200, &mempool_prices(60_000, 55_000)));
+        let cache = PriceCache::with_fetcher(
+            TTL,
+            MAX_STALE,
+            FiatCurrency::Eur,
+            vec![PriceSource::Mempool],
+            1_000,
+            10_000_000,
+            fetcher,
+        );
+
+        assert_eq!(cache.currency(), FiatCurrency::Eur);
+        assert_eq!(cache.get_price().await.unwrap(), 55_000);
+    }
+
+    #[test]
+    fn missing_currency_is_invalid() {
+        assert!(matches!(
+            PriceSource::Mempool.parse(r#"{"time":1728000000,"EUR":55000}"#, FiatCurrency::Usd),
+            Err(Error::InvalidResponse)
+        ));
+    }
+
+    #[test]
+    fn kraken_error_response_is_invalid() {
+        assert!(matches!(
+            PriceSource::Kraken.parse(
+                r#"{"error":["EQuery:Unknown asset pair"],"result":{}}"#,
+                FiatCurrency::Usd
+            ),
+            Err(Error::InvalidResponse)
+        ));
+    }
+
+    #[test]
+    fn zero_and_garbage_prices_are_invalid() {
+        for (source, body) in [
+            (PriceSource::Mempool, r#"{"USD":0}"#),
+            (PriceSource::Mempool, r#"{"USD":"60000"}"#),
+            (PriceSource::Coinbase, r#"{"data":{"amount":"0.00"}}"#),
+            (PriceSource::Coinbase, r#"{"data":{"amount":"NaN"}}"#),
+            (PriceSource::Kraken, r#"{"result":{"XXBTZUSD":{"c":[]}}}"#),
+            (PriceSource::Coinbase, "<html>Too Many Requests</html>"),
+        ] {
+            assert!(
+                matches!(
+                    source.parse(body, FiatCurrency::Usd),
+                    Err(Error::InvalidResponse)
+                ),
+                "{} accepted {}",
+                source,
+                body
+            );
+        }
+    }
+
+    #[test]
+    fn zero_cents_is_zero_msats() {
+        assert_eq!(cents_to_msats(0, 60_000).unwrap(), 0);
+    }
+
+    #[test]
+    fn one_cent_at_a_high_price_rounds_up_to_a_sat() {
+        // 1 cent at 10,000,000 a BTC is 100 msats
+        assert_eq!(cents_to_msats(1, 10_000_000).unwrap(), 1_000);
+    }
+
+    #[test]
+    fn three_cents_at_60k_rounds_up_to_a_whole_sat() {
+        // 50 sats exactly
+        assert_eq!(cents_to_msats(3, 60_000).unwrap(), 50_000);
+        // 49.18 sats
+        assert_eq!(cents_to_msats(3, 61_000).unwrap(), 50_000);
+    }
+
+    #[test]
+    fn zero_price_is_rejected() {
+        assert!(matches!(cents_to_msats(3, 0), Err(Error::OutOfRange(0))));
+    }
+
+    #[test]
+    fn huge_amounts_overflow_instead_of_wrapping() {
+        assert!(matches!(
+            cents_to_msats(u64::MAX, 1),
+            Err(Error::AmountOverflow)
+        ));
+        assert!(cents_to_msats(u64::MAX, u64::MAX).is_ok());
+        // Just under u64::MAX msats at 1 a BTC
+        assert!(cents_to_msats(u64::MAX / 1_000_000_000, 1).is_ok());
+    }
+
+    #[test]
+    fn fraction_of_a_msat_still_rounds_up() {
+        // 1000.5 msats, flooring to msats first would give exactly 1 sat
+        assert_eq!(cents_to_msats(2001, 2_000_000_000).unwrap(), 2_000);
+    }
+
+    proptest! {
+        #[test]
+        fn msats_cover_the_price_to_the_next_whole_sat(cents: u64, btc_price in 1u64..) {
+            // Exact amount is cents * 1_000_000 / btc_price sats
+            let exact = cents as u128 * 1_000_000;
+
+            match cents_to_msats(cents, btc_price) {
+                Ok(msats) => {
+                    prop_assert_eq!(msats % 1000, 0);
+
+                    let sats = msats as u128 / 1000;
+                    prop_assert!(sats * btc_price as u128 >= exact);
+                    prop_assert!(sats == 0 || (sats - 1) * (btc_price as u128) < exact);
+                }
+                Err(Error::AmountOverflow) => {
+                    prop_assert!(exact.div_ceil(btc_price as u128) * 1000 > u64::MAX as u128);
+                }
+                Err(err) => prop_assert!(false, "unexpected error {}", err),
+            }
+        }
+
+        #[test]
+        fn msats_never_fall_as_cents_rise(a: u64, b: u64, btc_price in 1u64..) {
+            let (low, high) = (a.min(b), a.max(b));
+
+            if let Ok(high_msats) = cents_to_msats(high, btc_price) {
+                prop_assert!(cents_to_msats(low, btc_price).unwrap() <= high_msats);
+            }
+        }
+
+        #[test]
+        fn msats_never_rise_as_the_price_rises(cents: u64, a in 1u64.., b in 1u64..) {
+            let (low, high) = (a.min(b), a.max(b));
+
+            if let Ok(low_price_msats) = cents_to_msats(cents, low) {
+                prop_assert!(cents_to_msats(cents, high).unwrap() <= low_price_msats);
+            }
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! In memory per client rate limiting
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use axum::http::HeaderMap;
+use cdk::util::unix_time;
+
+/// Length of a rate limit window
+const WINDOW_SECS: u64 = 60;
+
+/// Kind of request counted against a limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RequestKind {
+    /// Request that was paid for with a valid token
+    Paid,
+    /// Request rejected because of a missing or invalid token
+    Invalid,
+}
+
+/// Fixed window rate limiter keyed on client ip
+///
+/// Paid and invalid requests are counted separately so heavy paying users are
+/// not throttled by the invalid request limit.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    paid_per_minute: Option<u32>,
+    invalid_per_minute: Option<u32>,
+    /// Peers whose `X-Forwarded-For` header is believed
+    trusted_proxies: Vec<IpAddr>,
+    windows: Arc<Mutex<HashMap<(IpAddr, RequestKind), Window>>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    started_at: u64,
+    count: u32,
+}
+
+impl RateLimiter {
+    /// Create new [`RateLimiter`], a `None` limit is not enforced
+    pub fn new(
+        paid_per_minute: Option<u32>,
+        invalid_per_minute: Option<u32>,
+        trusted_proxies: Vec<IpAddr>,
+    ) -> Self {
+        Self {
+            paid_per_minute,
+            invalid_per_minute,
+            trusted_proxies,
+            windows: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn limit(&self, kind: RequestKind) -> Option<u32> {
+        match kind {
+            RequestKind::Paid => self.paid_per_minute,
+            RequestKind::Invalid => self.invalid_per_minute,
+        }
+    }
+
+    /// Seconds until `ip` may make another request, `None` if it is not limited
+    pub fn retry_after(&self, ip: IpAddr) -> Option<u64> {
+        let now = unix_time();
+        let windows = self.windows.lock().ok()?;
+
+        [RequestKind::Paid, RequestKind::Invalid]
+            .into_iter()
+            .filter_map(|kind| {
+                let limit = self.limit(kind)?;
+                let window = windows.get(&(ip, kind))?;
+                let elapsed = now.saturating_sub(window.started_at);
+
+                match elapsed < WINDOW_SECS && window.count >= limit {
+                    true => Some(WINDOW_SECS - elapsed),
+                    false => None,
+                }
+            })
+            .max()
+    }
+
+    /// Count a request made by `ip`
+    pub fn record(&self, ip: IpAddr, kind: RequestKind) {
+        if self.limit(kind).is_none() {
+            return;
+        }
+
+        let now = unix_time();
+
+        let Ok(mut windows) = self.windows.lock() else {
+            return;
+        };
+
+        // Drop expired windows so the map doesn't grow unbounded
+        windows.retain(|_, window| now.saturating_sub(window.started_at) < WINDOW_SECS);
+
+        let window = windows.entry((ip, kind)).or_insert(Window {
+            started_at: now,
+            count: 0,
+        });
+
+        window.count += 1;
+    }
+
+    /// Ip of the client behind `peer`
+    ///
+    /// `X-Forwarded-For` is only read when `peer` is a trusted proxy, the
+    /// client is then the last entry not added by a trusted proxy. Anyone
+    /// else could put any address in the header.
+    pub fn client_ip(&self, headers: &HeaderMap, peer: SocketAddr) -> IpAddr {
+        let peer_ip = peer.ip();
+
+        if !self.trusted_proxies.contains(&peer_ip) {
+            return peer_ip;
+        }
+
+        headers
+            .get_all("X-Forwarded-For")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .find(|ip| !self.trusted_proxies.contains(ip))
+            .unwrap_or(peer_ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forwarded_for(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Forwarded-For", value.parse().unwrap());
+        headers
+    }
+
+    fn proxy() -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], 40000))
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_from_untrusted_peers() {
+        let limiter = RateLimiter::new(None, None, Vec::new());
+
+        assert_eq!(
+            limiter.client_ip(&forwarded_for("203.0.113.7"), proxy()),
+            proxy().ip()
+        );
+    }
+
+    #[test]
+    fn forwarded_for_is_read_from_trusted_proxies() {
+        let limiter = RateLimiter::new(None, None, vec![proxy().ip()]);
+
+        assert_eq!(
+            limiter.client_ip(&forwarded_for("203.0.113.7"), proxy()),
+            IpAddr::from([203, 0, 113, 7])
+        );
+    }
+
+    #[test]
+    fn spoofed_entries_before_the_proxy_are_skipped() {
+        let limiter = RateLimiter::new(None, None, vec![proxy().ip()]);
+
+        // The client sent "1.2.3.4" itself, the proxy appended the real address
+        assert_eq!(
+            limiter.client_ip(&forwarded_for("1.2.3.4, 203.0.113.7"), proxy()),
+            IpAddr::from([203, 0, 113, 7])
+        );
+    }
+
+    #[test]
+    fn chained_trusted_proxies_are_skipped() {
+        let limiter = RateLimiter::new(None, None, vec![proxy().ip(), IpAddr::from([10, 0, 0, 2])]);
+
+        assert_eq!(
+            limiter.client_ip(&forwarded_for("203.0.113.7, 10.0.0.2"), proxy()),
+            IpAddr::from([203, 0, 113, 7])
+        );
+    }
+
+    #[test]
+    fn trusted_proxy_without_header_is_the_client() {
+        let limiter = RateLimiter::new(None, None, vec![proxy().ip()]);
+
+        assert_eq!(limiter.client_ip(&HeaderMap::new(), proxy()), proxy().ip());
+    }
+
+    #[test]
+    fn paid_limit_is_enforced() {
+        let limiter = RateLimiter::new(Some(2), None, Vec::new());
+        let ip = proxy().ip();
+
+        limiter.record(ip, RequestKind::Paid);
+        assert!(limiter.retry_after(ip).is_none());
+
+        limiter.record(ip, RequestKind::Paid);
+        assert!(limiter.retry_after(ip).is_some());
+    }
+}
@@ -0,0 +1,49 @@
+//! Settings that are reloaded from the config without restarting the mint
+
+use std::sync::{Arc, RwLock};
+
+use crate::domain_filter::DomainFilter;
+
+/// Settings consulted on every request, replaced as a whole on reload
+#[derive(Debug, Clone, Default)]
+pub struct RuntimeSettings {
+    /// Price of one XSR in the minor unit of the price currency
+    pub cost_per_search_cents: u64,
+    /// Origins allowed to call the search api, any origin when `None`
+    pub cors_allowed_origins: Option<Vec<String>>,
+    pub domain_filter: DomainFilter,
+}
+
+/// Shared handle to the current [`RuntimeSettings`]
+///
+/// Readers get a snapshot that stays consistent for as long as they hold it,
+/// a reload swaps in new settings without waiting on them.
+#[derive(Debug, Clone, Default)]
+pub struct SharedRuntimeSettings {
+    inner: Arc<RwLock<Arc<RuntimeSettings>>>,
+}
+
+impl SharedRuntimeSettings {
+    /// Create new [`SharedRuntimeSettings`]
+    pub fn new(settings: RuntimeSettings) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(Arc::new(settings))),
+        }
+    }
+
+    /// Current settings
+    pub fn load(&self) -> Arc<RuntimeSettings> {
+        // The lock only guards swapping an Arc, a panicked writer can not
+        // leave it half updated
+        let settings = self.inner.read().unwrap_or_else(|err| err.into_inner());
+
+        Arc::clone(&settings)
+    }
+
+    /// Replace the settings for every later [`SharedRuntimeSettings::load`]
+    pub fn store(&self, settings: RuntimeSettings) {
+        let mut current = self.inner.write().unwrap_or_else(|err| err.into_inner());
+
+        *current = Arc::new(settings);
+    }
+}
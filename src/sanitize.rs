@@ -0,0 +1,244 @@
+//! Sanitizing of html in upstream result text
+
+/// Tags kept when formatting is allowed
+const ALLOWED_TAGS: [&str; 2] = ["b", "i"];
+
+/// Tags whose content is dropped along with the tag
+const DROPPED_CONTENT_TAGS: [&str; 2] = ["script", "style"];
+
+/// Sanitize html in `input`
+///
+/// With `strip_html` every tag is removed and entities are decoded, leaving
+/// plain text. Otherwise bare `<b>` and `<i>` tags are kept, balanced, and all
+/// other text is escaped so the output is safe to insert as html.
+pub fn sanitize(input: &str, strip_html: bool) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut open_tags: Vec<&str> = Vec::new();
+    let mut rest = input;
+
+    while let Some(start) = rest.find('<') {
+        push_text(&mut output, &rest[..start], strip_html);
+
+        let Some(end) = rest[start..].find('>') else {
+            // Unclosed tag, treat the rest as text
+            push_text(&mut output, &rest[start..], strip_html);
+            rest = "";
+            break;
+        };
+
+        let tag = &rest[start + 1..start + end];
+        rest = &rest[start + end + 1..];
+
+        let (closing, name) = match tag.strip_prefix('/') {
+            Some(name) => (true, name.trim()),
+            None => (false, tag.trim()),
+        };
+
+        let name = name.to_ascii_lowercase();
+
+        if !closing {
+            let tag_name = name.split(|c: char| c.is_whitespace() || c == '/').next();
+
+            if let Some(dropped) = DROPPED_CONTENT_TAGS
+                .iter()
+                .find(|dropped| Some(**dropped) == tag_name)
+            {
+                rest = skip_past_closing_tag(rest, dropped);
+                continue;
+            }
+        }
+
+        if strip_html {
+            continue;
+        }
+
+        let Some(allowed) = ALLOWED_TAGS.iter().find(|allowed| **allowed == name) else {
+            continue;
+        };
+
+        match closing {
+            false => {
+                open_tags.push(*allowed);
+                output.push_str(&format!("<{}>", allowed));
+            }
+            true => {
+                // Close any tags opened inside this one so nesting stays valid
+                if let Some(position) = open_tags.iter().rposition(|open| open == allowed) {
+                    for open in open_tags.drain(position..).rev() {
+                        output.push_str(&format!("</{}>", open));
+                    }
+                }
+            }
+        }
+    }
+
+    push_text(&mut output, rest, strip_html);
+
+    for open in open_tags.into_iter().rev() {
+        output.push_str(&format!("</{}>", open));
+    }
+
+    output
+}
+
+/// Rest of `input` after the closing tag of `name`, empty if there is none
+fn skip_past_closing_tag<'a>(input: &'a str, name: &str) -> &'a str {
+    let closing = format!("</{}", name);
+
+    let Some(start) = input.to_ascii_lowercase().find(&closing) else {
+        return "";
+    };
+
+    match input[start..].find('>') {
+        Some(end) => &input[start + end + 1..],
+        None => "",
+    }
+}
+
+/// Decode entities in `text` and push it, escaped unless output is plain text
+fn push_text(output: &mut String, text: &str, strip_html: bool) {
+    let decoded = decode_entities(text);
+
+    match strip_html {
+        true => output.push_str(&decoded),
+        false => {
+            for c in decoded.chars() {
+                match c {
+                    '&' => output.push_str("&amp;"),
+                    '<' => output.push_str("&lt;"),
+                    '>' => output.push_str("&gt;"),
+                    '"' => output.push_str("&quot;"),
+                    '\'' => output.push_str("&#39;"),
+                    c => output.push(c),
+                }
+            }
+        }
+    }
+}
+
+/// Decode named and numeric html entities, unknown entities are left as is
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let entity = rest[1..]
+            .find(';')
+            .filter(|end| *end <= 10)
+            .and_then(|end| decode_entity(&rest[1..end + 1]).map(|c| (c, end + 2)));
+
+        match entity {
+            Some((c, len)) => {
+                decoded.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    decoded.push_str(rest);
+
+    decoded
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    match entity {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some(' '),
+        _ => {
+            let code = match entity.strip_prefix("#x").or(entity.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => entity.strip_prefix('#')?.parse().ok()?,
+            };
+
+            char::from_u32(code)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entities_are_decoded_and_escaped_again() {
+        let cases = [
+            ("Tom &amp; Jerry", "Tom &amp; Jerry", "Tom & Jerry"),
+            ("a & b", "a &amp; b", "a & b"),
+            ("&amp;lt;b&amp;gt;", "&amp;lt;b&amp;gt;", "&lt;b&gt;"),
+            ("&#x41;&#66;&quot;", "AB&quot;", "AB\""),
+            ("&bogus; &", "&amp;bogus; &amp;", "&bogus; &"),
+        ];
+
+        for (input, formatted, plain) in cases {
+            assert_eq!(sanitize(input, false), formatted, "{}", input);
+            assert_eq!(sanitize(input, true), plain, "{}", input);
+        }
+    }
+
+    #[test]
+    fn nested_tags_stay_balanced() {
+        let cases = [
+            ("<b><i>both</i></b>", "<b><i>both</i></b>"),
+            // Closing the outer tag closes the inner one, the stray close is dropped
+            ("<b><i>x</b>y</i>", "<b><i>x</i></b>y"),
+            ("</b>stray", "stray"),
+            ("<B>loud</B>", "<b>loud</b>"),
+            ("<b><i>never closed", "<b><i>never closed</i></b>"),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(sanitize(input, false), expected, "{}", input);
+        }
+
+        assert_eq!(sanitize("<b><i>x</b>y</i>", true), "xy");
+    }
+
+    #[test]
+    fn unclosed_tag_at_the_end_is_text() {
+        assert_eq!(sanitize("Rust <b", false), "Rust &lt;b");
+        assert_eq!(sanitize("Rust <b", true), "Rust <b");
+    }
+
+    #[test]
+    fn script_and_style_content_is_dropped() {
+        let cases = [
+            ("a<script>alert(1)</script>b", "ab"),
+            (
+                "a<SCRIPT type=\"text/javascript\">alert(1)</Script >b",
+                "ab",
+            ),
+            ("a<style>b { color: red }</style>b", "ab"),
+            ("a<script>never closed <b>bold</b>", "a"),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(sanitize(input, false), expected, "{}", input);
+            assert_eq!(sanitize(input, true), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn tags_with_attributes_are_stripped() {
+        let cases = [
+            ("<b class=x>bold</b>", "bold"),
+            ("<a href=\"https://evil.example\">link</a>", "link"),
+            ("<img src=x onerror=alert(1)>", ""),
+            ("<i style=\"color:red\">x</i> <b>y</b>", "x <b>y</b>"),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(sanitize(input, false), expected, "{}", input);
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! In memory cache of recent search results
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use cdk::util::unix_time;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::search_provider::{SearchQuery, SearchResults};
+
+/// Bounded least recently used cache of search results
+///
+/// Entries expire `ttl_secs` after they were inserted, when the cache is full
+/// the least recently used entry is evicted.
+#[derive(Debug, Clone, Default)]
+pub struct SearchCache {
+    ttl_secs: u64,
+    max_entries: usize,
+    entries: Arc<Mutex<HashMap<SearchQuery, Entry>>>,
+    /// Incremented on every access to order entries by recency
+    clock: Arc<AtomicU64>,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    inserted_at: u64,
+    last_used: u64,
+    results: SearchResults,
+}
+
+/// Cache hit and miss counts since startup
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, ToSchema)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl SearchCache {
+    /// Create new [`SearchCache`], caching is disabled when `max_entries` is 0
+    pub fn new(ttl_secs: u64, max_entries: usize) -> Self {
+        Self {
+            ttl_secs,
+            max_entries,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            clock: Arc::new(AtomicU64::new(0)),
+            hits: Arc::new(AtomicU64::new(0)),
+            misses: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Cached results for `query` if they have not expired
+    pub fn get(&self, query: &SearchQuery) -> Option<SearchResults> {
+        if self.max_entries == 0 {
+            return None;
+        }
+
+        let now = unix_time();
+        let tick = self.clock.fetch_add(1, Ordering::Relaxed);
+
+        let results = self.entries.lock().ok().and_then(|mut entries| {
+            let entry = entries.get_mut(query)?;
+
+            if now.saturating_sub(entry.inserted_at) >= self.ttl_secs {
+                entries.remove(query);
+                return None;
+            }
+
+            entry.last_used = tick;
+            Some(entry.results.clone())
+        });
+
+        match results.is_some() {
+            true => self.hits.fetch_add(1, Ordering::Relaxed),
+            false => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+
+        results
+    }
+
+    /// Cache `results` for `query`
+    pub fn insert(&self, query: SearchQuery, results: SearchResults) {
+        if self.max_entries == 0 {
+            return;
+        }
+
+        let now = unix_time();
+        let tick = self.clock.fetch_add(1, Ordering::Relaxed);
+
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+
+        entries.retain(|_, entry| now.saturating_sub(entry.inserted_at) < self.ttl_secs);
+
+        if entries.len() >= self.max_entries && !entries.contains_key(&query) {
+            let least_recent = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(query, _)| query.clone());
+
+            if let Some(least_recent) = least_recent {
+                entries.remove(&least_recent);
+            }
+        }
+
+        entries.insert(
+            query,
+            Entry {
+                inserted_at: now,
+                last_used: tick,
+                results,
+            },
+        );
+    }
+
+    /// Hit and miss counts since startup
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! Brave search provider
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use cdk::util::unix_time;
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use super::{Error, SearchProvider, SearchQuery, SearchResult, SearchResults};
+
+const BRAVE_SEARCH_URL: &str = "https://api.search.brave.com/res/v1/web/search";
+
+const SUBSCRIPTION_TOKEN_HEADER: &str = "X-Subscription-Token";
+
+/// Brave search provider
+#[derive(Debug, Clone)]
+pub struct BraveProvider {
+    client: Client,
+    auth_token: String,
+}
+
+impl BraveProvider {
+    /// Create new [`BraveProvider`]
+    pub fn new(auth_token: String, timeout: Duration) -> Result<Self, Error> {
+        let client = Client::builder().timeout(timeout).build()?;
+
+        Ok(Self { client, auth_token })
+    }
+}
+
+#[async_trait]
+impl SearchProvider for BraveProvider {
+    async fn search(&self, query: &SearchQuery) -> Result<SearchResults, Error> {
+        let time = unix_time();
+
+        let mut request = self
+            .client
+            .get(BRAVE_SEARCH_URL)
+            .header(SUBSCRIPTION_TOKEN_HEADER, &self.auth_token)
+            .header(reqwest::header::ACCEPT, "application/json")
+            .query(&[("q", &query.q)]);
+
+        if let Some(limit) = query.limit {
+            request = request.query(&[("count", limit)]);
+        }
+
+        if let Some(region) = &query.region {
+            request = request.query(&[("country", region)]);
+        }
+
+        if let Some(safesearch) = query.safesearch {
+            request = request.query(&[("safesearch", safesearch.to_string())]);
+        }
+
+        let response = request.send().await.map_err(|err| {
+            tracing::error!("Failed to make brave request: {}", err);
+            Error::from_request(err)
+        })?;
+
+        tracing::info!("Brave time: {}", unix_time() - time);
+
+        check_status(response.status())?;
+
+        let response: BraveSearchResponse = response.json().await.map_err(|err| {
+            tracing::error!("Invalid response from brave: {}", err);
+            match err.is_timeout() {
+                true => Error::from_request(err),
+                false => Error::InvalidResponse,
+            }
+        })?;
+
+        Ok(response.into())
+    }
+
+    async fn check(&self) -> Result<(), Error> {
+        let response = self
+            .client
+            .head(BRAVE_SEARCH_URL)
+            .header(SUBSCRIPTION_TOKEN_HEADER, &self.auth_token)
+            .send()
+            .await
+            .map_err(Error::from_request)?;
+
+        match response.status() {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(Error::Unauthorized),
+            status if status.is_server_error() => Err(Error::Status(status.as_u16())),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Map a non success Brave status to an [`Error`], the same way as Kagi
+fn check_status(status: StatusCode) -> Result<(), Error> {
+    match status {
+        status if status.is_success() => Ok(()),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            tracing::error!("Brave rejected auth token");
+            Err(Error::Unauthorized)
+        }
+        status => {
+            tracing::error!("Brave returned status {}", status);
+            Err(Error::Status(status.as_u16()))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BraveSearchResponse {
+    web: Option<BraveWebResults>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BraveWebResults {
+    results: Vec<BraveSearchResult>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BraveSearchResult {
+    url: String,
+    title: String,
+    description: Option<String>,
+    age: Option<String>,
+}
+
+impl From<BraveSearchResult> for SearchResult {
+    fn from(brave: BraveSearchResult) -> SearchResult {
+        SearchResult {
+            url: brave.url,
+            title: brave.title,
+            description: brave.description,
+            age: brave.age,
+            image: None,
+        }
+    }
+}
+
+impl From<BraveSearchResponse> for SearchResults {
+    fn from(response: BraveSearchResponse) -> SearchResults {
+        SearchResults {
+            results: response
+                .web
+                .map(|web| web.results.into_iter().map(|r| r.into()).collect())
+                .unwrap_or_default(),
+            related: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search_provider::kagi;
+
+    const SEARCH_FIXTURE: &str = include_str!("../../fixtures/brave_search.json");
+
+    #[test]
+    fn recorded_search_maps_into_results() {
+        let response: BraveSearchResponse = serde_json::from_str(SEARCH_FIXTURE).unwrap();
+
+        let results = SearchResults::from(response);
+
+        assert_eq!(results.results.len(), 2);
+        assert!(results.related.is_empty());
+
+        let first = &results.results[0];
+        assert_eq!(first.url, "https://cashu.space/");
+        assert_eq!(first.title, "Cashu - Open-source Ecash");
+        assert_eq!(
+            first.description.as_deref(),
+            Some(
+                "Cashu is a free and open-source <strong>Chaumian ecash</strong> protocol built for Bitcoin."
+            )
+        );
+        assert_eq!(first.age.as_deref(), Some("March 12, 2024"));
+        assert_eq!(first.image, None);
+
+        let second = &results.results[1];
+        assert_eq!(second.url, "https://github.com/cashubtc/nuts");
+        assert_eq!(second.description, None);
+        assert_eq!(second.age, None);
+    }
+
+    #[test]
+    fn response_without_web_results_is_empty() {
+        let response: BraveSearchResponse =
+            serde_json::from_str(r#"{"type":"search","query":{"original":"cashu"}}"#).unwrap();
+
+        assert!(SearchResults::from(response).results.is_empty());
+    }
+
+    #[test]
+    fn statuses_map_to_the_same_errors_as_kagi() {
+        for status in [
+            StatusCode::OK,
+            StatusCode::UNAUTHORIZED,
+            StatusCode::FORBIDDEN,
+            StatusCode::TOO_MANY_REQUESTS,
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ] {
+            assert_eq!(
+                format!("{:?}", check_status(status)),
+                format!("{:?}", kagi::check_status(status)),
+                "{}",
+                status
+            );
+        }
+
+        assert!(matches!(
+            check_status(StatusCode::UNAUTHORIZED),
+            Err(Error::Unauthorized)
+        ));
+        assert!(matches!(
+            check_status(StatusCode::TOO_MANY_REQUESTS),
+            Err(Error::Status(429))
+        ));
+    }
+}
@@ -0,0 +1,512 @@
+//! Kagi search provider
+
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use bitcoin::secp256k1::rand::{thread_rng, Rng};
+use cdk::util::unix_time;
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::ToSchema;
+
+use super::{Error, Image, SearchProvider, SearchQuery, SearchResult, SearchResults};
+
+/// Summarization engine used when none is requested
+pub const DEFAULT_SUMMARY_ENGINE: &str = "cecil";
+
+/// Backoff before the first retry, doubled on each further retry
+const RETRY_BASE_MS: u64 = 200;
+
+/// Kagi search provider
+#[derive(Debug, Clone)]
+pub struct KagiProvider {
+    client: Client,
+    /// Api url the endpoint paths are appended to
+    base_url: String,
+    /// Swapped on config reload, shared with every clone
+    auth_token: Arc<RwLock<String>>,
+    timeout: Duration,
+    max_retries: u32,
+    /// Api balance in USD reported by the last search
+    api_balance: Arc<RwLock<Option<f64>>>,
+}
+
+impl KagiProvider {
+    /// Create new [`KagiProvider`]
+    ///
+    /// `timeout` is the total time allowed for a search including retries.
+    pub fn new(
+        base_url: &str,
+        auth_token: String,
+        timeout: Duration,
+        max_retries: u32,
+    ) -> Result<Self, Error> {
+        let client = Client::builder().timeout(timeout).build()?;
+
+        Ok(Self {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            auth_token: Arc::new(RwLock::new(auth_token)),
+            timeout,
+            max_retries,
+            api_balance: Arc::new(RwLock::new(None)),
+        })
+    }
+
+    /// Api balance in USD reported by the last search, `None` before the
+    /// first search
+    pub fn api_balance(&self) -> Option<f64> {
+        *self
+            .api_balance
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Use `auth_token` for every later request
+    pub fn set_auth_token(&self, auth_token: String) {
+        *self
+            .auth_token
+            .write()
+            .unwrap_or_else(|err| err.into_inner()) = auth_token;
+    }
+
+    fn url(&self, endpoint: &str) -> String {
+        format!("{}/{}", self.base_url, endpoint)
+    }
+
+    fn auth_header(&self) -> String {
+        let auth_token = self
+            .auth_token
+            .read()
+            .unwrap_or_else(|err| err.into_inner());
+
+        format!("Bot {}", auth_token)
+    }
+
+    /// Answer a question with FastGPT
+    pub async fn answer(&self, query: &str) -> Result<Answer, Error> {
+        let time = unix_time();
+
+        let response = self
+            .client
+            .post(self.url("fastgpt"))
+            .header(reqwest::header::AUTHORIZATION, self.auth_header())
+            .json(&FastGptRequest { query })
+            .send()
+            .await
+            .map_err(|err| {
+                tracing::error!("Failed to make kagi fastgpt request: {}", err);
+                Error::from_request(err)
+            })?;
+
+        tracing::info!("Kagi fastgpt time: {}", unix_time() - time);
+
+        check_status(response.status())?;
+
+        let response: FastGptResponse = response.json().await.map_err(|err| {
+            tracing::error!("Invalid fastgpt response from kagi: {}", err);
+            match err.is_timeout() {
+                true => Error::from_request(err),
+                false => Error::InvalidResponse,
+            }
+        })?;
+
+        Ok(Answer {
+            answer: response.data.output,
+            references: response.data.references,
+        })
+    }
+
+    /// Summarize a url or a block of text
+    pub async fn summarize(
+        &self,
+        source: &SummarizeSource,
+        engine: Option<&str>,
+    ) -> Result<Summary, Error> {
+        let time = unix_time();
+
+        let engine = engine.unwrap_or(DEFAULT_SUMMARY_ENGINE);
+
+        let (url, text) = match source {
+            SummarizeSource::Url(url) => (Some(url.as_str()), None),
+            SummarizeSource::Text(text) => (None, Some(text.as_str())),
+        };
+
+        let response = self
+            .client
+            .post(self.url("summarize"))
+            .header(reqwest::header::AUTHORIZATION, self.auth_header())
+            .json(&SummarizeRequest { url, text, engine })
+            .send()
+            .await
+            .map_err(|err| {
+                tracing::error!("Failed to make kagi summarize request: {}", err);
+                Error::from_request(err)
+            })?;
+
+        tracing::info!("Kagi summarize time: {}", unix_time() - time);
+
+        check_status(response.status())?;
+
+        let response: SummarizeResponse = response.json().await.map_err(|err| {
+            tracing::error!("Invalid summarize response from kagi: {}", err);
+            match err.is_timeout() {
+                true => Error::from_request(err),
+                false => Error::InvalidResponse,
+            }
+        })?;
+
+        Ok(Summary {
+            summary: response.data.output,
+            engine: engine.to_string(),
+            tokens: response.data.tokens,
+        })
+    }
+}
+
+/// Map a non success Kagi status to an [`Error`]
+pub(super) fn check_status(status: StatusCode) -> Result<(), Error> {
+    match status {
+        status if status.is_success() => Ok(()),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            tracing::error!("Kagi rejected auth token");
+            Err(Error::Unauthorized)
+        }
+        status => {
+            tracing::error!("Kagi returned status {}", status);
+            Err(Error::Status(status.as_u16()))
+        }
+    }
+}
+
+#[async_trait]
+impl SearchProvider for KagiProvider {
+    /// 5xx responses and connect errors are retried with jittered exponential
+    /// backoff, as long as the retry stays within the timeout.
+    async fn search(&self, query: &SearchQuery) -> Result<SearchResults, Error> {
+        let deadline = Instant::now() + self.timeout;
+        let mut attempt = 0;
+
+        let response = loop {
+            let time = unix_time();
+
+            let mut request = self
+                .client
+                .get(self.url("search"))
+                .header(reqwest::header::AUTHORIZATION, self.auth_header())
+                .query(&[("q", &query.q)])
+                .timeout(deadline.saturating_duration_since(Instant::now()));
+
+            if let Some(limit) = query.limit {
+                request = request.query(&[("limit", limit)]);
+            }
+
+            if let Some(region) = &query.region {
+                request = request.query(&[("region", region)]);
+            }
+
+            if let Some(safesearch) = query.safesearch {
+                request = request.query(&[("safesearch", safesearch.to_string())]);
+            }
+
+            let result = request.send().await;
+
+            tracing::info!("Kagi time: {}", unix_time() - time);
+
+            let retry_reason = match &result {
+                Ok(response) if response.status().is_server_error() => {
+                    Some(format!("status {}", response.status()))
+                }
+                Err(err) if err.is_connect() => Some(err.to_string()),
+                _ => None,
+            };
+
+            let Some(retry_reason) = retry_reason else {
+                break result;
+            };
+
+            let backoff = retry_backoff(attempt);
+
+            if attempt >= self.max_retries || Instant::now() + backoff >= deadline {
+                break result;
+            }
+
+            attempt += 1;
+
+            tracing::warn!(
+                "Kagi request failed ({}), retry attempt {} of {}",
+                retry_reason,
+                attempt,
+                self.max_retries
+            );
+
+            tokio::time::sleep(backoff).await;
+        };
+
+        let response = response.map_err(|err| {
+            tracing::error!("Failed to make kagi request: {}", err);
+            Error::from_request(err)
+        })?;
+
+        check_status(response.status())?;
+
+        let json_response = response.json::<Value>().await.map_err(|err| {
+            tracing::error!("Failed to read kagi response: {}", err);
+            Error::from_request(err)
+        })?;
+
+        let response: KagiSearchResponse = serde_json::from_value(json_response).map_err(|_| {
+            tracing::error!("Invalid response from kagi");
+            Error::InvalidResponse
+        })?;
+
+        tracing::info!(
+            "fetched response: {} from {}",
+            response.meta.ms,
+            response.meta.node
+        );
+
+        if let Some(api_balance) = response.meta.api_balance {
+            *self
+                .api_balance
+                .write()
+                .unwrap_or_else(|err| err.into_inner()) = Some(api_balance);
+        }
+
+        Ok(response.into())
+    }
+
+    async fn check(&self) -> Result<(), Error> {
+        let response = self
+            .client
+            .head(self.url("search"))
+            .header(reqwest::header::AUTHORIZATION, self.auth_header())
+            .send()
+            .await
+            .map_err(Error::from_request)?;
+
+        match response.status() {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(Error::Unauthorized),
+            status if status.is_server_error() => Err(Error::Status(status.as_u16())),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Exponential backoff with up to 100ms of jitter
+fn retry_backoff(attempt: u32) -> Duration {
+    let base = RETRY_BASE_MS.saturating_mul(2u64.saturating_pow(attempt));
+    let jitter = thread_rng().gen_range(0..100);
+
+    Duration::from_millis(base + jitter)
+}
+
+/// FastGPT answer
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Answer {
+    /// Answer text
+    pub answer: String,
+    /// Pages the answer was based on
+    pub references: Vec<Reference>,
+}
+
+/// Page an answer was based on
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Reference {
+    /// Title of the page
+    pub title: String,
+    /// Url of the page
+    pub url: String,
+    /// Snippet of the page
+    pub snippet: String,
+}
+
+/// What to summarize
+#[derive(Debug, Clone)]
+pub enum SummarizeSource {
+    /// Page at a url
+    Url(String),
+    /// Block of text
+    Text(String),
+}
+
+/// Summary of a url or text
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Summary {
+    /// Summary text
+    pub summary: String,
+    /// Engine used to summarize
+    pub engine: String,
+    /// Tokens processed by Kagi
+    pub tokens: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct SummarizeRequest<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<&'a str>,
+    engine: &'a str,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct SummarizeResponse {
+    data: SummarizeData,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct SummarizeData {
+    output: String,
+    #[serde(default)]
+    tokens: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct FastGptRequest<'a> {
+    query: &'a str,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct FastGptResponse {
+    data: FastGptData,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct FastGptData {
+    output: String,
+    #[serde(default)]
+    references: Vec<Reference>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct KagiSearchResponse {
+    meta: Meta,
+    data: Vec<KagiSearchObject>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Meta {
+    id: String,
+    node: String,
+    ms: u64,
+    api_balance: Option<f64>,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum KagiSearchObject {
+    SearchResult(KagiSearchResult),
+    RelatedSearches(KagiRelatedSearches),
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
+struct KagiSearchResult {
+    t: u8,
+    rank: Option<u64>,
+    url: String,
+    title: String,
+    snippet: Option<String>,
+    published: Option<String>,
+    image: Option<Image>,
+    list: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
+struct KagiRelatedSearches {
+    t: u8,
+    list: Vec<String>,
+}
+
+impl From<KagiSearchResult> for SearchResult {
+    fn from(kagi: KagiSearchResult) -> SearchResult {
+        SearchResult {
+            url: kagi.url,
+            title: kagi.title,
+            description: kagi.snippet,
+            age: kagi.published,
+            image: kagi.image,
+        }
+    }
+}
+
+impl From<KagiSearchResponse> for SearchResults {
+    fn from(response: KagiSearchResponse) -> SearchResults {
+        let mut results = SearchResults::default();
+
+        for object in response.data {
+            match object {
+                KagiSearchObject::SearchResult(sr) => results.results.push(sr.into()),
+                KagiSearchObject::RelatedSearches(rs) => results.related.extend(rs.list),
+            }
+        }
+
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEARCH_FIXTURE: &str = include_str!("../../fixtures/kagi_search.json");
+
+    #[test]
+    fn recorded_search_keeps_thumbnails() {
+        let response: KagiSearchResponse = serde_json::from_str(SEARCH_FIXTURE).unwrap();
+        assert_eq!(response.meta.api_balance, Some(9.975));
+
+        let results = SearchResults::from(response);
+
+        let images: Vec<Option<Image>> = results
+            .results
+            .iter()
+            .map(|result| result.image.clone())
+            .collect();
+        assert_eq!(
+            images,
+            [
+                Some(Image {
+                    url: "https://kagi.com/proxy/cashu.png?c=abc123".to_string(),
+                    height: 630,
+                    width: 1200,
+                }),
+                None,
+                Some(Image {
+                    url: "https://kagi.com/proxy/ecash.jpg?c=def456".to_string(),
+                    height: 360,
+                    width: 640,
+                }),
+            ]
+        );
+
+        let first = &results.results[0];
+        assert_eq!(first.url, "https://cashu.space/");
+        assert_eq!(first.title, "Cashu - Open-source Ecash");
+        assert_eq!(first.age.as_deref(), Some("2024-03-12T00:00:00Z"));
+        assert_eq!(results.results[2].description, None);
+
+        assert_eq!(
+            results.related,
+            ["cashu wallet", "cashu mint", "chaumian ecash"]
+        );
+    }
+
+    #[test]
+    fn image_is_only_serialized_when_present() {
+        let results = SearchResults::from(
+            serde_json::from_str::<KagiSearchResponse>(SEARCH_FIXTURE).unwrap(),
+        );
+
+        let with_image = serde_json::to_value(&results.results[0]).unwrap();
+        assert_eq!(with_image["image"]["width"], 1200);
+
+        let without_image = serde_json::to_value(&results.results[1]).unwrap();
+        assert!(without_image.get("image").is_none());
+        // Other absent fields are still serialized as null
+        assert!(without_image["age"].is_null());
+        assert!(without_image.get("age").is_some());
+    }
+}
@@ -0,0 +1,166 @@
+//! Upstream search providers
+
+use std::fmt;
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use utoipa::ToSchema;
+
+pub mod brave;
+pub mod kagi;
+
+pub use brave::BraveProvider;
+pub use kagi::KagiProvider;
+
+/// Search provider Error
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Provider did not respond in time
+    #[error("Search provider timed out: {0}")]
+    Timeout(String),
+    /// Provider rejected the auth token
+    #[error("Search provider rejected auth token")]
+    Unauthorized,
+    /// Provider returned an unexpected status
+    #[error("Search provider returned status {0}")]
+    Status(u16),
+    /// Provider response could not be parsed
+    #[error("Invalid search provider response")]
+    InvalidResponse,
+    /// Reqwest Error
+    #[error(transparent)]
+    Reqwest(#[from] reqwest::Error),
+}
+
+impl Error {
+    /// Map a failed request to [`Error::Timeout`] if it timed out
+    pub(crate) fn from_request(err: reqwest::Error) -> Self {
+        match err.is_timeout() {
+            true => Self::Timeout(err.to_string()),
+            false => Self::Reqwest(err),
+        }
+    }
+}
+
+/// Search query sent to a provider
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SearchQuery {
+    /// Search terms
+    pub q: String,
+    /// Max number of results
+    pub limit: Option<u64>,
+    /// Lowercase ISO 3166-1 alpha-2 country code to localize results to
+    pub region: Option<String>,
+    /// Filtering of adult content
+    pub safesearch: Option<SafeSearch>,
+}
+
+/// Filtering of adult content, ordered from least to most strict
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SafeSearch {
+    Off,
+    Moderate,
+    Strict,
+}
+
+impl fmt::Display for SafeSearch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SafeSearch::Off => write!(f, "off"),
+            SafeSearch::Moderate => write!(f, "moderate"),
+            SafeSearch::Strict => write!(f, "strict"),
+        }
+    }
+}
+
+impl FromStr for SafeSearch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(SafeSearch::Off),
+            "moderate" => Ok(SafeSearch::Moderate),
+            "strict" => Ok(SafeSearch::Strict),
+            _ => Err(format!(
+                "Invalid safesearch {}, expected off, moderate or strict",
+                s
+            )),
+        }
+    }
+}
+
+/// ISO 3166-1 alpha-2 country codes accepted as a search region
+const REGIONS: &[&str] = &[
+    "ad", "ae", "af", "ag", "ai", "al", "am", "ao", "aq", "ar", "as", "at", "au", "aw", "ax", "az",
+    "ba", "bb", "bd", "be", "bf", "bg", "bh", "bi", "bj", "bl", "bm", "bn", "bo", "bq", "br", "bs",
+    "bt", "bv", "bw", "by", "bz", "ca", "cc", "cd", "cf", "cg", "ch", "ci", "ck", "cl", "cm", "cn",
+    "co", "cr", "cu", "cv", "cw", "cx", "cy", "cz", "de", "dj", "dk", "dm", "do", "dz", "ec", "ee",
+    "eg", "eh", "er", "es", "et", "fi", "fj", "fk", "fm", "fo", "fr", "ga", "gb", "gd", "ge", "gf",
+    "gg", "gh", "gi", "gl", "gm", "gn", "gp", "gq", "gr", "gs", "gt", "gu", "gw", "gy", "hk", "hm",
+    "hn", "hr", "ht", "hu", "id", "ie", "il", "im", "in", "io", "iq", "ir", "is", "it", "je", "jm",
+    "jo", "jp", "ke", "kg", "kh", "ki", "km", "kn", "kp", "kr", "kw", "ky", "kz", "la", "lb", "lc",
+    "li", "lk", "lr", "ls", "lt", "lu", "lv", "ly", "ma", "mc", "md", "me", "mf", "mg", "mh", "mk",
+    "ml", "mm", "mn", "mo", "mp", "mq", "mr", "ms", "mt", "mu", "mv", "mw", "mx", "my", "mz", "na",
+    "nc", "ne", "nf", "ng", "ni", "nl", "no", "np", "nr", "nu", "nz", "om", "pa", "pe", "pf", "pg",
+    "ph", "pk", "pl", "pm", "pn", "pr", "ps", "pt", "pw", "py", "qa", "re", "ro", "rs", "ru", "rw",
+    "sa", "sb", "sc", "sd", "se", "sg", "sh", "si", "sj", "sk", "sl", "sm", "sn", "so", "sr", "ss",
+    "st", "sv", "sx", "sy", "sz", "tc", "td", "tf", "tg", "th", "tj", "tk", "tl", "tm", "tn", "to",
+    "tr", "tt", "tv", "tw", "tz", "ua", "ug", "um", "us", "uy", "uz", "va", "vc", "ve", "vg", "vi",
+    "vn", "vu", "wf", "ws", "ye", "yt", "za", "zm", "zw",
+];
+
+/// Normalize a region to a lowercase country code, `None` if it is not one
+pub fn parse_region(region: &str) -> Option<String> {
+    let region = region.to_lowercase();
+
+    REGIONS.contains(&region.as_str()).then_some(region)
+}
+
+/// Results returned by a provider
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SearchResults {
+    /// Search results
+    pub results: Vec<SearchResult>,
+    /// Related search terms
+    pub related: Vec<String>,
+}
+
+/// Search result
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct SearchResult {
+    /// Url of the result
+    pub url: String,
+    /// Title of the result
+    pub title: String,
+    /// Snippet of the result
+    pub description: Option<String>,
+    /// Publish date of the result
+    pub age: Option<String>,
+    /// Thumbnail of the result
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<Image>,
+}
+
+/// Result thumbnail
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Image {
+    /// Url of the image
+    pub url: String,
+    /// Height in px
+    pub height: u64,
+    /// Width in px
+    pub width: u64,
+}
+
+/// Upstream search provider
+#[async_trait]
+pub trait SearchProvider {
+    /// Run a search
+    async fn search(&self, query: &SearchQuery) -> Result<SearchResults, Error>;
+
+    /// Check the provider is reachable and accepts our credentials
+    async fn check(&self) -> Result<(), Error>;
+}
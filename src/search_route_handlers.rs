@@ -1,236 +1,3516 @@
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
 
-use axum::extract::{Query, State};
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{ConnectInfo, DefaultBodyLimit, Path, Query, State};
 use axum::http::header::{
-    ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_ORIGIN, AUTHORIZATION, CONTENT_TYPE,
+    ACCEPT, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_ORIGIN, AUTHORIZATION,
+    CONTENT_TYPE, RETRY_AFTER,
 };
-use axum::http::{HeaderMap, HeaderName, StatusCode};
-use axum::routing::get;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use axum::routing::{get, post};
 use axum::{Json, Router};
+use cdk::amount::SplitTarget;
+use cdk::dhke::construct_proofs;
 use cdk::mint::Mint;
 use cdk::mint_url::MintUrl;
-use cdk::nuts::TokenV4;
+use cdk::nuts::{
+    CurrencyUnit, Id, Keys, MintQuoteBolt11Request, PreMintSecrets, Proofs, PublicKey,
+    State as ProofState, SwapRequest, Token, TokenV3, TokenV4,
+};
 use cdk::util::unix_time;
-use reqwest::Client as ReqwestClient;
+use cdk::Amount;
+use futures::stream::{self, StreamExt};
+use reqwest::Url;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use tower_http::cors::CorsLayer;
+use tower_http::compression::predicate::{NotForContentType, Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::Instrument;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, SecurityScheme};
+use utoipa::{IntoParams, Modify, OpenApi, ToSchema};
+use utoipa_swagger_ui::SwaggerUi;
+use uuid::Uuid;
+
+use crate::analytics::{self, QueryAnalytics};
+use crate::cln::Cln;
+use crate::db::{
+    DbStats, KagiBalance, KeysetFlow, RevenueSummary, SearchAnalytics, SearchCount, SearchPayment,
+    SearchStore, Session, SessionClose, SessionDebit,
+};
+use crate::dedup::dedup_results;
+use crate::dev_lightning::DevLightning;
+use crate::keyset_accounting;
+use crate::landing_page::{LandingPage, DEFAULT_FAVICON};
+use crate::phoenixd::Phoenixd;
+use crate::rate_limit::{RateLimiter, RequestKind};
+use crate::runtime_settings::SharedRuntimeSettings;
+use crate::sanitize::sanitize;
+use crate::search_cache::{CacheStats, SearchCache};
+use crate::search_provider::kagi::{Answer, Reference, SummarizeSource, Summary};
+use crate::search_provider::{
+    self, parse_region, Image, KagiProvider, SafeSearch, SearchProvider, SearchQuery, SearchResult,
+    SearchResults,
+};
+
+/// Maximum accepted size of a `POST /search` body, larger bodies get a 413
+pub const MAX_SEARCH_BODY_BYTES: usize = 16 * 1024;
+
+/// Maximum accepted size of a `POST /summarize` body, larger bodies get a 413
+pub const MAX_SUMMARIZE_BODY_BYTES: usize = 256 * 1024;
+
+/// Most queries accepted in one `POST /search/batch`
+pub const MAX_BATCH_SIZE: usize = 10;
+
+/// Searches a websocket client may send per minute
+const WS_MESSAGES_PER_MINUTE: u32 = 30;
+
+/// Length of the websocket rate limit window
+const WS_WINDOW_SECS: u64 = 60;
+
+/// Invalid tokens a websocket client may send before it is disconnected
+const MAX_WS_INVALID_TOKENS: u32 = 3;
+
+/// Most upstream searches run at once for a batch
+const MAX_BATCH_CONCURRENCY: usize = 4;
+
+/// Responses smaller than this are not worth compressing
+const MIN_COMPRESS_BYTES: u16 = 256;
+
+/// Header carrying the id of a request
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Header carrying the id of a prepaid session to pay from
+pub const SESSION_HEADER: &str = "x-session";
+
+/// Header carrying the balance left in a session after a request
+pub const SESSION_BALANCE_HEADER: &str = "x-session-balance";
+
+/// Longest incoming request id that is honored
+const MAX_REQUEST_ID_LEN: usize = 128;
 
-use crate::db::{Db, SearchCount};
+/// Seconds a `/stats` response is served from cache
+const STATS_CACHE_SECS: u64 = 5;
 
-async fn get_search_count(State(state): State<ApiState>) -> Result<Json<SearchCount>, StatusCode> {
+/// Seconds a search provider health check result is reused for
+const SEARCH_PROVIDER_HEALTH_CACHE_SECS: u64 = 60;
+
+#[utoipa::path(
+    get,
+    path = "/search_count",
+    responses((status = 200, body = SearchCount), (status = 500, body = ErrorResponse))
+)]
+async fn get_search_count(State(state): State<ApiState>) -> Result<Json<SearchCount>, ApiError> {
     let db = state.db;
 
-    let search_count = db
-        .get_search_count()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let search_count = db.get_search_count().map_err(|_| ApiError::Internal)?;
 
     Ok(Json(search_count))
 }
 
-async fn get_info(State(state): State<ApiState>) -> Result<Json<Info>, StatusCode> {
-    Ok(Json(state.info))
+async fn get_landing_page(State(state): State<ApiState>) -> Result<Html<String>, ApiError> {
+    let landing_page = state.settings.landing_page.ok_or(ApiError::NotFound)?;
+
+    let search_count = state
+        .db
+        .get_search_count()
+        .map_err(|_| ApiError::Internal)?;
+
+    Ok(Html(
+        landing_page.render(search_count.all_time_search_count),
+    ))
+}
+
+/// The configured mint icon by redirect, or the embedded default
+async fn get_favicon(State(state): State<ApiState>) -> Response {
+    match state
+        .settings
+        .landing_page
+        .and_then(|landing_page| landing_page.icon_url)
+    {
+        Some(icon_url) => Redirect::temporary(&icon_url).into_response(),
+        None => ([(CONTENT_TYPE, "image/x-icon")], DEFAULT_FAVICON).into_response(),
+    }
+}
+
+async fn get_operator_revenue(
+    State(state): State<ApiState>,
+) -> Result<Json<RevenueSummary>, ApiError> {
+    let revenue = state
+        .db
+        .get_revenue_summary()
+        .map_err(|_| ApiError::Internal)?;
+
+    Ok(Json(revenue))
+}
+
+/// Query hashes returned by `/operator/analytics` when `top` is not given
+const DEFAULT_ANALYTICS_TOP: usize = 20;
+
+#[derive(Debug, Deserialize)]
+struct AnalyticsParams {
+    /// Most searched query hashes to return
+    top: Option<usize>,
+}
+
+async fn get_operator_analytics(
+    params: Query<AnalyticsParams>,
+    State(state): State<ApiState>,
+) -> Result<Json<SearchAnalytics>, ApiError> {
+    let analytics = state
+        .db
+        .get_search_analytics(params.top.unwrap_or(DEFAULT_ANALYTICS_TOP))
+        .map_err(|err| {
+            tracing::error!("Could not read search analytics: {}", err);
+            ApiError::Internal
+        })?;
+
+    Ok(Json(analytics))
+}
+
+async fn get_operator_db(State(state): State<ApiState>) -> Result<Json<DbStats>, ApiError> {
+    let stats = state.db.stats().map_err(|err| {
+        tracing::error!("Could not read db stats: {}", err);
+        ApiError::Internal
+    })?;
+
+    Ok(Json(stats))
+}
+
+async fn get_operator_kagi_balance(
+    State(state): State<ApiState>,
+) -> Result<Json<OperatorKagiBalance>, ApiError> {
+    let balance = state
+        .db
+        .get_kagi_balance()
+        .map_err(|_| ApiError::Internal)?
+        .ok_or(ApiError::NotFound)?;
+
+    let cost_per_search_cents = state.runtime_settings.load().cost_per_search_cents;
+
+    Ok(Json(OperatorKagiBalance {
+        searches_remaining: searches_remaining(balance.balance_usd, cost_per_search_cents),
+        balance,
+    }))
+}
+
+/// Searches `balance_usd` pays for at `cost_per_search_cents` each
+fn searches_remaining(balance_usd: f64, cost_per_search_cents: u64) -> Option<u64> {
+    (cost_per_search_cents > 0)
+        .then(|| (balance_usd.max(0.0) * 100.0 / cost_per_search_cents as f64).floor() as u64)
+}
+
+#[utoipa::path(
+    get,
+    path = "/stats",
+    responses((status = 200, body = Stats), (status = 500, body = ErrorResponse))
+)]
+async fn get_stats(State(state): State<ApiState>) -> Result<Json<Stats>, ApiError> {
+    let now = unix_time();
+
+    if let Some((cached_at, stats)) = state
+        .stats_cache
+        .read()
+        .map_err(|_| ApiError::Internal)?
+        .as_ref()
+    {
+        if now.saturating_sub(*cached_at) < STATS_CACHE_SECS {
+            return Ok(Json(stats.clone()));
+        }
+    }
+
+    let search_count = state
+        .db
+        .get_search_count()
+        .map_err(|_| ApiError::Internal)?;
+
+    let stats = Stats {
+        search_count,
+        uptime_seconds: now.saturating_sub(state.started_at),
+        mint_name: state.info.name.clone(),
+        cache: state.search_cache.stats(),
+    };
+
+    *state.stats_cache.write().map_err(|_| ApiError::Internal)? = Some((now, stats.clone()));
+
+    Ok(Json(stats))
+}
+
+#[utoipa::path(
+    get,
+    path = "/health",
+    responses(
+        (status = 200, description = "Every component is reachable", body = Health),
+        (status = 503, description = "A component is unreachable", body = Health)
+    )
+)]
+async fn get_health(State(state): State<ApiState>) -> (StatusCode, Json<Health>) {
+    let lightning = state.lightning.check_connection().await;
+
+    let db = match state.db.check() {
+        Ok(()) => ComponentStatus::Ok,
+        Err(err) => {
+            tracing::warn!("Db health check failed: {}", err);
+            ComponentStatus::Error
+        }
+    };
+
+    let search_provider = check_search_provider(&state).await;
+
+    let health = Health {
+        lightning,
+        search_provider,
+        db,
+        listeners: state.settings.listeners.clone(),
+    };
+
+    let status = match health.is_ok() {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    (status, Json(health))
+}
+
+/// Check the search provider is reachable, the result is cached for
+/// [`SEARCH_PROVIDER_HEALTH_CACHE_SECS`] so health checks don't burn api quota
+async fn check_search_provider(state: &ApiState) -> ComponentStatus {
+    let now = unix_time();
+
+    if let Ok(cache) = state.search_provider_health_cache.read() {
+        if let Some((checked_at, status)) = *cache {
+            if now.saturating_sub(checked_at) < SEARCH_PROVIDER_HEALTH_CACHE_SECS {
+                return status;
+            }
+        }
+    }
+
+    let status = match state.search_provider.check().await {
+        Ok(()) => ComponentStatus::Ok,
+        Err(err) => {
+            tracing::warn!("Search provider health check failed: {}", err);
+            ComponentStatus::Error
+        }
+    };
+
+    if let Ok(mut cache) = state.search_provider_health_cache.write() {
+        *cache = Some((now, status));
+    }
+
+    status
+}
+
+#[utoipa::path(get, path = "/info", responses((status = 200, body = Info)))]
+async fn get_info(State(state): State<ApiState>) -> Result<Json<Info>, ApiError> {
+    let mut info = state.info;
+    info.cost_per_search_cents = state.runtime_settings.load().cost_per_search_cents;
+
+    Ok(Json(info))
 }
 
+#[utoipa::path(
+    get,
+    path = "/search",
+    params(Params),
+    security(("cashu" = []), ("cashu_authorization" = [])),
+    responses(
+        (status = 200, body = SearchResponse),
+        (status = 400, body = ErrorResponse),
+        (status = 402, description = "Payment required, the body carries the challenge", body = ErrorResponse),
+        (status = 429, body = ErrorResponse),
+        (status = 502, description = "Upstream failed, the body carries a refund", body = ErrorResponse),
+        (status = 504, description = "Upstream timed out, the body carries a refund", body = ErrorResponse)
+    )
+)]
 async fn get_search(
     headers: HeaderMap,
     q: Query<Params>,
     State(state): State<ApiState>,
-) -> Result<Json<Vec<SearchResult>>, StatusCode> {
-    let x_cashu = headers
-        .get("X-Cashu")
-        .ok_or(StatusCode::PAYMENT_REQUIRED)?
-        .to_str()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+) -> Result<(HeaderMap, Json<SearchResponse>), ApiError> {
+    let request = SearchRequest {
+        q: q.0.q,
+        quote: q.0.quote,
+        limit: q.0.limit,
+        related: q.0.related,
+        region: q.0.region,
+        safesearch: q.0.safesearch,
+    };
 
-    let token: TokenV4 = TokenV4::from_str(x_cashu).unwrap();
+    search(&state, &headers, &request).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/search",
+    request_body = SearchRequest,
+    security(("cashu" = []), ("cashu_authorization" = [])),
+    responses(
+        (status = 200, body = SearchResponse),
+        (status = 400, body = ErrorResponse),
+        (status = 402, description = "Payment required, the body carries the challenge", body = ErrorResponse),
+        (status = 429, body = ErrorResponse),
+        (status = 502, description = "Upstream failed, the body carries a refund", body = ErrorResponse),
+        (status = 504, description = "Upstream timed out, the body carries a refund", body = ErrorResponse)
+    )
+)]
+async fn post_search(
+    headers: HeaderMap,
+    State(state): State<ApiState>,
+    Json(request): Json<SearchRequest>,
+) -> Result<(HeaderMap, Json<SearchResponse>), ApiError> {
+    search(&state, &headers, &request).await
+}
+
+/// Verify the payment in the request headers and run the search upstream
+async fn search(
+    state: &ApiState,
+    headers: &HeaderMap,
+    search_request: &SearchRequest,
+) -> Result<(HeaderMap, Json<SearchResponse>), ApiError> {
+    let query = search_query(state, search_request)?;
+
+    let payment = match take_payment(state, headers, state.settings.search_price).await {
+        Ok(payment) => payment,
+        Err(ApiError::PaymentRequired(mut challenge))
+            if challenge.reason == PaymentRequiredReason::MissingToken
+                && search_request.quote == Some(true)
+                && accepts_json(headers) =>
+        {
+            challenge.quote = mint_quote(state, challenge.amount).await;
+            return Err(ApiError::PaymentRequired(challenge));
+        }
+        Err(err) => return Err(err),
+    };
 
-    let token_amount = token.value().unwrap();
+    let upstream_start = Instant::now();
 
-    if token_amount != 1.into() {
-        return Err(StatusCode::PAYMENT_REQUIRED);
+    let results = fetch_results(state, &query).await;
+
+    record_ms("upstream_ms", upstream_start);
+
+    let results = match results {
+        Ok(results) => results,
+        Err(err) => return Err(refund_payment(state, payment, err.into()).await),
+    };
+
+    finish_payment(state, &payment);
+
+    if let Err(err) = state.db.increment_search_count() {
+        tracing::error!("Could not update search counter: {}", err);
     }
 
-    let proofs = token.proofs();
-    let proof = proofs.first().ok_or(StatusCode::PAYMENT_REQUIRED)?;
+    record_analytics(state, &query, &results);
 
-    let time = unix_time();
+    let serialize_start = Instant::now();
 
-    let mint = state.mint;
+    let response = search_response(results, &query, search_request.related);
 
-    mint.verify_proof(proof).await.map_err(|_| {
-        tracing::warn!("P2PK verification failed");
-        StatusCode::PAYMENT_REQUIRED
-    })?;
+    record_ms("serialize_ms", serialize_start);
 
-    let y = proof.y().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    tracing::info!("Search served");
 
-    mint.check_ys_spendable(&[y], cdk::nuts::State::Spent)
-        .await
-        .map_err(|_| StatusCode::PAYMENT_REQUIRED)?;
+    Ok((payment.headers, Json(response)))
+}
 
-    tracing::info!("Time to verify: {}", unix_time() - time);
+async fn get_ws(ws: WebSocketUpgrade, State(state): State<ApiState>) -> Response {
+    ws.on_upgrade(move |socket| search_session(socket, state))
+}
 
-    let time = unix_time();
+/// Serve paid searches over `socket`, one search per client message
+///
+/// The socket is closed once the client has sent [`MAX_WS_INVALID_TOKENS`]
+/// invalid tokens.
+async fn search_session(mut socket: WebSocket, state: ApiState) {
+    let mut window_started_at = unix_time();
+    let mut window_count = 0;
+    let mut invalid_tokens = 0;
 
-    tracing::info!("Send: {}", unix_time() - time);
+    while let Some(Ok(message)) = socket.recv().await {
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
 
-    let time = unix_time();
+        let now = unix_time();
+        let elapsed = now.saturating_sub(window_started_at);
 
-    let response = state
-        .reqwest_client
-        .get("https://kagi.com/api/v0/search")
-        .header(
-            reqwest::header::AUTHORIZATION,
-            format!("Bot {}", state.settings.kagi_auth_token),
-        )
-        .query(&[("q", q.q.clone())])
-        .send()
-        .await
-        .map_err(|err| {
-            tracing::error!("Failed to make kagi request: {}", err);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+        if elapsed >= WS_WINDOW_SECS {
+            window_started_at = now;
+            window_count = 0;
+        }
 
-    tracing::info!("Kagi time: {}", unix_time() - time);
-    let time = unix_time();
-    let json_response = response
-        .json::<Value>()
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        window_count += 1;
 
-    let results: KagiSearchResponse = serde_json::from_value(json_response).map_err(|_| {
-        tracing::error!("Invalid response from kagi");
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+        let reply = match window_count > WS_MESSAGES_PER_MINUTE {
+            true => Err(ApiError::RateLimited(
+                WS_WINDOW_SECS.saturating_sub(elapsed),
+            )),
+            false => ws_search(&state, &text).await,
+        };
+
+        let reply = match reply {
+            Ok(reply) => serde_json::to_string(&reply),
+            Err(err) => {
+                if matches!(
+                    err,
+                    ApiError::InvalidToken(_) | ApiError::PaymentRequired(_)
+                ) {
+                    invalid_tokens += 1;
+                }
+
+                serde_json::to_string(&err.into_body())
+            }
+        };
+
+        let reply = match reply {
+            Ok(reply) => reply,
+            Err(err) => {
+                tracing::error!("Could not serialize websocket reply: {}", err);
+                break;
+            }
+        };
+
+        if socket.send(Message::Text(reply)).await.is_err() {
+            break;
+        }
+
+        if invalid_tokens >= MAX_WS_INVALID_TOKENS {
+            tracing::debug!("Closing websocket after {} invalid tokens", invalid_tokens);
+
+            let close = CloseFrame {
+                code: close_code::POLICY,
+                reason: "Too many invalid tokens".into(),
+            };
 
-    tracing::info!(
-        "fetched response: {} from {}",
-        results.meta.ms,
-        results.meta.node
+            let _ = socket.send(Message::Close(Some(close))).await;
+            break;
+        }
+    }
+}
+
+/// Run the search in a websocket message through the same pipeline as `/search`
+async fn ws_search(state: &ApiState, text: &str) -> Result<WsSearchResponse, ApiError> {
+    let request: WsSearchRequest =
+        serde_json::from_str(text).map_err(|err| ApiError::InvalidRequest(err.to_string()))?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "X-Cashu",
+        HeaderValue::from_str(&request.token)
+            .map_err(|err| ApiError::InvalidToken(err.to_string()))?,
     );
 
-    if let Err(err) = state.db.increment_search_count() {
-        tracing::error!("Could not update search counter: {}", err);
+    let (headers, Json(results)) = search(state, &headers, &request.search).await?;
+
+    let change = headers
+        .get("X-Cashu-Change")
+        .and_then(|change| change.to_str().ok())
+        .map(|change| change.to_string());
+
+    Ok(WsSearchResponse { results, change })
+}
+
+/// Verify the payment covers every query and run them upstream concurrently
+///
+/// Queries that fail upstream get an error object in place of their results
+/// and are refunded together in one token.
+#[utoipa::path(
+    post,
+    path = "/search/batch",
+    request_body = BatchSearchRequest,
+    security(("cashu" = []), ("cashu_authorization" = [])),
+    responses(
+        (status = 200, description = "Results or an error for each query, failed queries are refunded in X-Cashu-Refund", body = [BatchSearchResult]),
+        (status = 400, body = ErrorResponse),
+        (status = 402, description = "Payment required, the body carries the challenge", body = ErrorResponse),
+        (status = 429, body = ErrorResponse)
+    )
+)]
+async fn post_search_batch(
+    headers: HeaderMap,
+    State(state): State<ApiState>,
+    Json(batch): Json<BatchSearchRequest>,
+) -> Result<(HeaderMap, Json<Vec<BatchSearchResult>>), ApiError> {
+    if batch.queries.is_empty() {
+        return Err(ApiError::InvalidRequest("No queries".to_string()));
+    }
+
+    if batch.queries.len() > MAX_BATCH_SIZE {
+        return Err(ApiError::InvalidRequest(format!(
+            "Batch of {} queries is over the max of {}",
+            batch.queries.len(),
+            MAX_BATCH_SIZE
+        )));
     }
 
-    let search_results: Vec<KagiSearchResult> = results
-        .data
+    let queries = batch
+        .queries
+        .iter()
+        .map(|q| {
+            search_query(
+                &state,
+                &SearchRequest {
+                    q: q.clone(),
+                    quote: None,
+                    limit: batch.limit,
+                    related: batch.related,
+                    region: batch.region.clone(),
+                    safesearch: batch.safesearch.clone(),
+                },
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let price = batch_price(state.settings.search_price, queries.len());
+
+    let mut payment = take_payment(&state, &headers, price).await?;
+
+    let results: Vec<_> = stream::iter(queries.iter())
+        .map(|query| fetch_results(&state, query))
+        .buffered(MAX_BATCH_CONCURRENCY)
+        .collect()
+        .await;
+
+    let mut failed = 0;
+
+    let results: Vec<BatchSearchResult> = results
         .into_iter()
-        .flat_map(|s| match s {
-            KagiSearchObject::SearchResult(sr) => Some(sr),
-            KagiSearchObject::RelatedSearches(_) => None,
+        .zip(queries.iter())
+        .map(|(result, query)| match result {
+            Ok(results) => {
+                if let Err(err) = state.db.increment_search_count() {
+                    tracing::error!("Could not update search counter: {}", err);
+                }
+
+                record_analytics(&state, query, &results);
+
+                BatchSearchResult::Results(search_response(results, query, batch.related))
+            }
+            Err(err) => {
+                failed += 1;
+                BatchSearchResult::Error {
+                    error: ApiError::from(err).into_body(),
+                }
+            }
         })
         .collect();
 
-    let results: Vec<SearchResult> = search_results.into_iter().map(|r| r.into()).collect();
+    match failed {
+        0 => finish_payment(&state, &payment),
+        failed => {
+            let amount = batch_price(state.settings.search_price, failed);
+
+            let refunded = match payment.session.clone() {
+                Some(session_id) => credit_session(&state, &session_id, amount),
+                None => refund(&state, &mut payment, amount).await.is_some(),
+            };
 
-    tracing::info!("Json time: {}", unix_time() - time);
-    Ok(Json(results))
+            if !refunded {
+                tracing::error!(
+                    "Could not refund {} failed searches of payment {}",
+                    failed,
+                    payment.id
+                );
+            }
+        }
+    }
+
+    Ok((payment.headers, Json(results)))
 }
 
-pub fn search_router(state: ApiState) -> Router {
-    Router::new()
-        .route("/info", get(get_info))
-        .route("/search", get(get_search))
-        .route("/search_count", get(get_search_count))
-        .layer(CorsLayer::very_permissive().allow_headers([
-            AUTHORIZATION,
-            CONTENT_TYPE,
-            ACCESS_CONTROL_ALLOW_CREDENTIALS,
-            ACCESS_CONTROL_ALLOW_ORIGIN,
-            HeaderName::from_str("X-Cashu").unwrap(),
-        ]))
-        .with_state(state)
+/// Price of `count` queries
+fn batch_price(search_price: Amount, count: usize) -> Amount {
+    Amount::from(u64::from(search_price) * count as u64)
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Params {
-    q: String,
+/// Open a prepaid session funded by the full value of the token sent
+#[utoipa::path(
+    post,
+    path = "/session",
+    security(("cashu" = []), ("cashu_authorization" = [])),
+    responses(
+        (status = 200, body = SessionResponse),
+        (status = 400, body = ErrorResponse),
+        (status = 402, description = "Payment required, the body carries the challenge", body = ErrorResponse),
+        (status = 429, body = ErrorResponse)
+    )
+)]
+async fn post_session(
+    headers: HeaderMap,
+    State(state): State<ApiState>,
+) -> Result<(HeaderMap, Json<SessionResponse>), ApiError> {
+    if headers.contains_key(SESSION_HEADER) {
+        return Err(ApiError::InvalidRequest(
+            "A session can not be funded from a session".to_string(),
+        ));
+    }
+
+    let token = match payment_token(&headers)? {
+        Some(token) => parse_token(token)?,
+        None => {
+            return Err(state.settings.payment_required(
+                state.settings.search_price,
+                PaymentRequiredReason::MissingToken,
+            ))
+        }
+    };
+
+    let token_amount = token
+        .value()
+        .map_err(|err| ApiError::InvalidToken(err.to_string()))?;
+
+    let proofs = token_proofs(&token, state.settings.search_price, &state.settings)?;
+
+    // The session is funded with what is left of the token after its input fee
+    let input_fee = state
+        .mint
+        .get_proofs_fee(&proofs)
+        .await
+        .map_err(|err| ApiError::InvalidToken(err.to_string()))?;
+
+    let amount = token_amount.checked_sub(input_fee).unwrap_or(Amount::ZERO);
+
+    if amount == Amount::ZERO {
+        return Err(ApiError::InvalidToken(
+            "Token has no value after input fees".to_string(),
+        ));
+    }
+
+    let payment = take_payment(&state, &headers, amount).await?;
+
+    let now = unix_time();
+
+    let session = Session {
+        balance: amount.into(),
+        created_at: now,
+        last_used_at: now,
+        closed: false,
+        refund: None,
+    };
+
+    let session_id = Uuid::new_v4().to_string();
+
+    if let Err(err) = state.db.add_session(&session_id, &session) {
+        tracing::error!("Could not store session: {}", err);
+        return Err(refund_payment(&state, payment, ApiError::Internal).await);
+    }
+
+    finish_payment(&state, &payment);
+
+    let response = SessionResponse::new(session_id, &session, state.settings.session_idle_secs);
+
+    Ok((payment.headers, Json(response)))
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Info {
-    pub mint: MintUrl,
+#[utoipa::path(
+    get,
+    path = "/session/{id}",
+    params(("id" = String, Path, description = "Session id")),
+    responses((status = 200, body = SessionResponse), (status = 404, body = ErrorResponse))
+)]
+async fn get_session(
+    Path(session_id): Path<String>,
+    State(state): State<ApiState>,
+) -> Result<Json<SessionResponse>, ApiError> {
+    let idle_secs = state.settings.session_idle_secs;
+
+    let session = state
+        .db
+        .get_session(&session_id)
+        .map_err(|err| {
+            tracing::error!("Could not get session: {}", err);
+            ApiError::Internal
+        })?
+        .filter(|session| !session.is_expired(unix_time(), idle_secs))
+        .ok_or(ApiError::NotFound)?;
+
+    Ok(Json(SessionResponse::new(session_id, &session, idle_secs)))
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Settings {
-    pub kagi_auth_token: String,
-    pub mint_url: MintUrl,
+/// Close a session and refund its remaining balance as a token
+///
+/// Repeated calls return the token issued by the first one.
+#[utoipa::path(
+    post,
+    path = "/session/{id}/refund",
+    params(("id" = String, Path, description = "Session id")),
+    responses(
+        (status = 200, body = SessionRefundResponse),
+        (status = 400, body = ErrorResponse),
+        (status = 404, body = ErrorResponse)
+    )
+)]
+async fn post_session_refund(
+    Path(session_id): Path<String>,
+    State(state): State<ApiState>,
+) -> Result<Json<SessionRefundResponse>, ApiError> {
+    let close = state.db.close_session(&session_id).map_err(|err| {
+        tracing::error!("Could not close session: {}", err);
+        ApiError::Internal
+    })?;
+
+    let balance = match close {
+        SessionClose::Closed(balance) => balance,
+        SessionClose::Refunded(token) => {
+            return Ok(Json(SessionRefundResponse { token }));
+        }
+        SessionClose::Closing => {
+            return Err(ApiError::InvalidRequest(
+                "Session refund is already in progress".to_string(),
+            ))
+        }
+        SessionClose::Empty => {
+            return Err(ApiError::InvalidRequest(
+                "Session has no balance to refund".to_string(),
+            ))
+        }
+        SessionClose::NotFound => return Err(ApiError::NotFound),
+    };
+
+    let token = match issue_token(&state, balance.into()).await {
+        Ok(token) => token.to_string(),
+        Err(err) => {
+            if let Err(err) = state.db.reopen_session(&session_id, balance) {
+                tracing::error!(
+                    "Could not reopen session {} with balance {}: {}",
+                    session_id,
+                    balance,
+                    err
+                );
+            }
+
+            return Err(err);
+        }
+    };
+
+    if let Err(err) = state.db.set_session_refund(&session_id, &token) {
+        tracing::error!("Could not store refund of session {}: {}", session_id, err);
+    }
+
+    Ok(Json(SessionRefundResponse { token }))
 }
 
-#[derive(Clone)]
-pub struct ApiState {
-    pub info: Info,
-    pub mint: Arc<Mint>,
-    pub settings: Settings,
-    pub reqwest_client: ReqwestClient,
-    pub db: Db,
+/// Whether the client asked for a json response
+fn accepts_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"))
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct KagiSearchResponse {
-    meta: Meta,
-    data: Vec<KagiSearchObject>,
+/// Create a mint quote for `amount` to attach to a payment challenge
+///
+/// The quote expires after the mint's quote ttl like any other mint quote.
+async fn mint_quote(state: &ApiState, amount: Amount) -> Option<PaymentQuote> {
+    let request = MintQuoteBolt11Request {
+        amount,
+        unit: state.settings.unit,
+    };
+
+    let quote = state
+        .mint
+        .get_mint_bolt11_quote(request)
+        .await
+        .map_err(|err| tracing::error!("Could not create mint quote for challenge: {}", err))
+        .ok()?;
+
+    Some(PaymentQuote {
+        mint_url: state.settings.mint_url.clone(),
+        quote_id: quote.quote,
+        request: quote.request,
+        amount,
+        unit: state.settings.unit,
+        expiry: quote.expiry,
+    })
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Meta {
-    id: String,
-    node: String,
-    ms: u64,
-    api_balance: Option<f64>,
+/// Validate a search request and build the upstream query for it
+fn search_query(state: &ApiState, search_request: &SearchRequest) -> Result<SearchQuery, ApiError> {
+    let region = match &search_request.region {
+        Some(region) => Some(
+            parse_region(region)
+                .ok_or_else(|| ApiError::InvalidRequest(format!("Invalid region {}", region)))?,
+        ),
+        None => state.settings.default_region.clone(),
+    };
+
+    let safesearch = search_request
+        .safesearch
+        .as_deref()
+        .map(SafeSearch::from_str)
+        .transpose()
+        .map_err(ApiError::InvalidRequest)?;
+
+    // The operator setting is a floor clients can only make stricter
+    let safesearch = safesearch.max(state.settings.safesearch);
+
+    let limit = search_request
+        .limit
+        .unwrap_or(state.settings.max_results)
+        .min(state.settings.max_results);
+
+    Ok(SearchQuery {
+        q: search_request.q.clone(),
+        limit: Some(limit),
+        region,
+        safesearch,
+    })
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct SearchResult {
-    url: String,
-    title: String,
-    description: Option<String>,
-    age: Option<String>,
+/// Results for `query` from the cache, or from the search provider on a miss
+///
+/// Titles and descriptions from the provider are sanitized, duplicates
+/// removed and the operator domain filter applied before caching.
+async fn fetch_results(
+    state: &ApiState,
+    query: &SearchQuery,
+) -> Result<SearchResults, search_provider::Error> {
+    if let Some(results) = state.search_cache.get(query) {
+        return Ok(results);
+    }
+
+    let mut results = state.search_provider.search(query).await?;
+
+    record_kagi_balance(state);
+
+    for result in results.results.iter_mut() {
+        result.title = sanitize(&result.title, state.settings.strip_html);
+        result.description = result
+            .description
+            .as_deref()
+            .map(|description| sanitize(description, state.settings.strip_html));
+    }
+
+    if state.settings.dedup_results {
+        dedup_results(&mut results.results);
+    }
+
+    state
+        .runtime_settings
+        .load()
+        .domain_filter
+        .apply(&mut results.results);
+
+    state.search_cache.insert(query.clone(), results.clone());
+
+    Ok(results)
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
-#[serde(untagged)]
-enum KagiSearchObject {
-    SearchResult(KagiSearchResult),
-    RelatedSearches(KagiRelatedSearches),
+/// Count a served search when hashed analytics are enabled
+///
+/// Only the keyed hash of the query and coarse buckets are stored.
+fn record_analytics(state: &ApiState, query: &SearchQuery, results: &SearchResults) {
+    let Some(query_analytics) = &state.analytics else {
+        return;
+    };
+
+    if let Err(err) = state.db.record_search_analytics(
+        &query_analytics.query_hash(&query.q),
+        &analytics::length_bucket(&query.q),
+        &analytics::result_bucket(results.results.len()),
+    ) {
+        tracing::warn!("Could not record search analytics: {}", err);
+    }
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
-struct KagiSearchResult {
-    t: u8,
-    rank: Option<u64>,
-    url: String,
-    title: String,
-    snippet: Option<String>,
-    published: Option<String>,
-    image: Option<Image>,
-    list: Option<Vec<String>>,
+/// Store the balance reported by the last kagi response, warning when it
+/// drops below the configured low balance
+///
+/// Failures are only logged, the search is served regardless.
+fn record_kagi_balance(state: &ApiState) {
+    let Some(balance_usd) = state.kagi.api_balance() else {
+        return;
+    };
+
+    let previous = match state.db.set_kagi_balance(balance_usd) {
+        Ok(previous) => previous,
+        Err(err) => {
+            tracing::warn!("Could not record kagi balance: {}", err);
+            return;
+        }
+    };
+
+    if let Some(low_balance) = state.settings.kagi_low_balance {
+        let was_above = previous.map_or(true, |previous| previous.balance_usd >= low_balance);
+
+        if balance_usd < low_balance && was_above {
+            tracing::warn!(
+                "Kagi api balance is ${:.2}, below ${:.2}",
+                balance_usd,
+                low_balance
+            );
+        }
+    }
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
-struct Image {
-    url: String,
-    height: u64,
-    width: u64,
+/// Response for `results`, truncated to the query limit
+fn search_response(
+    results: SearchResults,
+    query: &SearchQuery,
+    related: Option<bool>,
+) -> SearchResponse {
+    let SearchResults {
+        mut results,
+        related: related_searches,
+    } = results;
+
+    if let Some(limit) = query.limit {
+        results.truncate(limit as usize);
+    }
+
+    match related {
+        Some(true) => SearchResponse::WithRelated {
+            results,
+            related: related_searches,
+        },
+        _ => SearchResponse::Results(results),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/answer",
+    params(AnswerParams),
+    security(("cashu" = []), ("cashu_authorization" = [])),
+    responses(
+        (status = 200, body = Answer),
+        (status = 400, body = ErrorResponse),
+        (status = 402, description = "Payment required, the body carries the challenge", body = ErrorResponse),
+        (status = 429, body = ErrorResponse),
+        (status = 502, description = "Upstream failed, the body carries a refund", body = ErrorResponse),
+        (status = 504, description = "Upstream timed out, the body carries a refund", body = ErrorResponse)
+    )
+)]
+async fn get_answer(
+    headers: HeaderMap,
+    q: Query<AnswerParams>,
+    State(state): State<ApiState>,
+) -> Result<(HeaderMap, Json<Answer>), ApiError> {
+    let payment = take_payment(&state, &headers, state.settings.answer_price).await?;
+
+    let answer = match state.kagi.answer(&q.q).await {
+        Ok(answer) => answer,
+        Err(err) => return Err(refund_payment(&state, payment, err.into()).await),
+    };
+
+    finish_payment(&state, &payment);
+
+    Ok((payment.headers, Json(answer)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/summarize",
+    params(SummarizeParams),
+    security(("cashu" = []), ("cashu_authorization" = [])),
+    responses(
+        (status = 200, body = Summary),
+        (status = 400, body = ErrorResponse),
+        (status = 402, description = "Payment required, the body carries the challenge", body = ErrorResponse),
+        (status = 429, body = ErrorResponse),
+        (status = 502, description = "Upstream failed, the body carries a refund", body = ErrorResponse),
+        (status = 504, description = "Upstream timed out, the body carries a refund", body = ErrorResponse)
+    )
+)]
+async fn get_summarize(
+    headers: HeaderMap,
+    q: Query<SummarizeParams>,
+    State(state): State<ApiState>,
+) -> Result<(HeaderMap, Json<Summary>), ApiError> {
+    validate_summarize_url(&q.url)?;
+
+    let request = SummarizeRequest {
+        url: Some(q.0.url),
+        text: None,
+        engine: q.0.engine,
+    };
+
+    summarize(&state, &headers, request).await
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
-struct KagiRelatedSearches {
-    t: u8,
-    list: Vec<String>,
+#[utoipa::path(
+    post,
+    path = "/summarize",
+    request_body = SummarizeRequest,
+    security(("cashu" = []), ("cashu_authorization" = [])),
+    responses(
+        (status = 200, body = Summary),
+        (status = 400, body = ErrorResponse),
+        (status = 402, description = "Payment required, the body carries the challenge", body = ErrorResponse),
+        (status = 429, body = ErrorResponse),
+        (status = 502, description = "Upstream failed, the body carries a refund", body = ErrorResponse),
+        (status = 504, description = "Upstream timed out, the body carries a refund", body = ErrorResponse)
+    )
+)]
+async fn post_summarize(
+    headers: HeaderMap,
+    State(state): State<ApiState>,
+    Json(request): Json<SummarizeRequest>,
+) -> Result<(HeaderMap, Json<Summary>), ApiError> {
+    if let Some(url) = &request.url {
+        validate_summarize_url(url)?;
+    }
+
+    summarize(&state, &headers, request).await
 }
 
-impl From<KagiSearchResult> for SearchResult {
-    fn from(kagi: KagiSearchResult) -> SearchResult {
-        SearchResult {
-            url: kagi.url,
-            title: kagi.title,
-            description: kagi.snippet,
-            age: kagi.published,
+/// Verify the payment in the request headers and summarize upstream
+async fn summarize(
+    state: &ApiState,
+    headers: &HeaderMap,
+    request: SummarizeRequest,
+) -> Result<(HeaderMap, Json<Summary>), ApiError> {
+    let source = match (request.url, request.text) {
+        (Some(url), None) => SummarizeSource::Url(url),
+        (None, Some(text)) => SummarizeSource::Text(text),
+        _ => {
+            return Err(ApiError::InvalidRequest(
+                "Exactly one of url or text is required".to_string(),
+            ))
+        }
+    };
+
+    let payment = take_payment(state, headers, state.settings.summarize_price).await?;
+
+    let summary = match state
+        .kagi
+        .summarize(&source, request.engine.as_deref())
+        .await
+    {
+        Ok(summary) => summary,
+        Err(err) => return Err(refund_payment(state, payment, err.into()).await),
+    };
+
+    finish_payment(state, &payment);
+
+    Ok((payment.headers, Json(summary)))
+}
+
+/// Only allow summarizing public http(s) urls so the mint can't be used as a
+/// proxy into private networks
+fn validate_summarize_url(url: &str) -> Result<(), ApiError> {
+    let invalid = |detail: &str| ApiError::InvalidRequest(detail.to_string());
+
+    let url = Url::parse(url).map_err(|_| invalid("Invalid url"))?;
+
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(invalid("Only http and https urls can be summarized"));
+    }
+
+    let host = url
+        .host_str()
+        .ok_or_else(|| invalid("Url must have a host"))?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .trim_end_matches('.')
+        .to_lowercase();
+
+    let is_private = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+        }
+        Ok(IpAddr::V6(ip)) => ip.is_loopback() || ip.is_unspecified(),
+        Err(_) => host == "localhost" || host.ends_with(".localhost"),
+    };
+
+    if is_private {
+        return Err(invalid("Url must point to a public host"));
+    }
+
+    Ok(())
+}
+
+/// Payment taken for a request that has not been served yet
+struct Payment {
+    id: String,
+    price: Amount,
+    /// Headers to return with the response, carries any change token
+    headers: HeaderMap,
+    /// Session the payment was debited from
+    session: Option<String>,
+}
+
+/// Encoded token sent with the request
+///
+/// `Authorization: Cashu <token>` is preferred, falling back to `X-Cashu`.
+fn payment_token(headers: &HeaderMap) -> Result<Option<&str>, ApiError> {
+    if let Some(authorization) = headers.get(AUTHORIZATION) {
+        let authorization = authorization
+            .to_str()
+            .map_err(|err| ApiError::InvalidToken(err.to_string()))?;
+
+        if let Some((scheme, token)) = authorization.split_once(' ') {
+            if scheme.eq_ignore_ascii_case("Cashu") {
+                let token = token.trim();
+
+                if token.is_empty() {
+                    return Err(ApiError::InvalidToken(
+                        "Empty Cashu authorization".to_string(),
+                    ));
+                }
+
+                return Ok(Some(token));
+            }
+        }
+    }
+
+    let Some(x_cashu) = headers.get("X-Cashu") else {
+        return Ok(None);
+    };
+
+    let x_cashu = x_cashu
+        .to_str()
+        .map_err(|err| ApiError::InvalidToken(err.to_string()))?;
+
+    if x_cashu.is_empty() {
+        return Err(ApiError::InvalidToken("Empty X-Cashu header".to_string()));
+    }
+
+    Ok(Some(x_cashu))
+}
+
+/// Verify the token in the request headers is worth at least `price` and spend it
+///
+/// The payment is recorded in the db until the request is either served with
+/// [`finish_payment`] or refunded with [`refund_payment`].
+async fn take_payment(
+    state: &ApiState,
+    headers: &HeaderMap,
+    price: Amount,
+) -> Result<Payment, ApiError> {
+    let settings = &state.settings;
+
+    if let Some(session_id) = headers.get(SESSION_HEADER) {
+        let session_id = session_id
+            .to_str()
+            .map_err(|err| ApiError::InvalidRequest(err.to_string()))?;
+
+        return debit_session(state, session_id, price);
+    }
+
+    let encoded_token = payment_token(headers)?
+        .ok_or_else(|| settings.payment_required(price, PaymentRequiredReason::MissingToken))?;
+
+    let token = parse_token(encoded_token)?;
+
+    let token_amount = token
+        .value()
+        .map_err(|err| ApiError::InvalidToken(err.to_string()))?;
+
+    if token_amount < price {
+        return Err(settings.payment_required(price, PaymentRequiredReason::WrongAmount));
+    }
+
+    let proofs = token_proofs(&token, price, settings)?;
+
+    if proofs.is_empty() || proofs.iter().any(|p| p.amount == Amount::ZERO) {
+        return Err(settings.payment_required(price, PaymentRequiredReason::WrongAmount));
+    }
+
+    let ys = proofs
+        .iter()
+        .map(|p| p.y())
+        .collect::<Result<Vec<PublicKey>, _>>()
+        .map_err(|_| ApiError::Internal)?;
+
+    // Cheap check before the mint verifies anything, the mint db stays the
+    // authority on whether the proofs are spent
+    if state.db.any_used_ys(&ys).map_err(|err| {
+        tracing::error!("Could not check used ys: {}", err);
+        ApiError::Internal
+    })? {
+        tracing::warn!("Token has already been used");
+        return Err(settings.payment_required(price, PaymentRequiredReason::TokenSpent));
+    }
+
+    let verify_start = Instant::now();
+
+    let mint = &state.mint;
+
+    check_unit(mint, &proofs, price, settings).await?;
+
+    // The keyset input fee is paid on top of the price, as it would be in a
+    // swap at the mint
+    let input_fee = mint.get_proofs_fee(&proofs).await.map_err(|err| {
+        tracing::error!("Could not get input fee: {}", err);
+        ApiError::Internal
+    })?;
+
+    let price_with_fee = price.checked_add(input_fee).ok_or(ApiError::Internal)?;
+
+    if token_amount < price_with_fee {
+        return Err(settings.payment_required(price_with_fee, PaymentRequiredReason::WrongAmount));
+    }
+
+    for proof in proofs.iter() {
+        mint.verify_proof(proof).await.map_err(|_| {
+            tracing::warn!("P2PK verification failed");
+            settings.payment_required(price, PaymentRequiredReason::InvalidProof)
+        })?;
+    }
+
+    let mut response_headers = HeaderMap::new();
+
+    let redeemed = keyset_accounting::proof_amounts(&proofs);
+
+    if token_amount == price_with_fee {
+        spend_proofs(mint, &proofs, &ys, price, settings).await?;
+    } else {
+        let change = swap_for_change(state, proofs, token_amount, price).await?;
+
+        let change = HeaderValue::from_str(&change.to_string()).map_err(|_| ApiError::Internal)?;
+        response_headers.insert("X-Cashu-Change", change);
+    }
+
+    if let Err(err) = state.db.add_used_ys(&ys) {
+        tracing::error!("Could not record used ys: {}", err);
+    }
+
+    keyset_accounting::record(state.db.as_ref(), KeysetFlow::Redeemed, &redeemed);
+
+    record_ms("verify_ms", verify_start);
+
+    let payment_id = Uuid::new_v4().to_string();
+
+    if let Err(err) = state.db.add_search_payment(
+        &payment_id,
+        &SearchPayment {
+            ys,
+            created_at: unix_time(),
+            refund: None,
+        },
+    ) {
+        tracing::error!("Could not record search payment: {}", err);
+    }
+
+    Ok(Payment {
+        id: payment_id,
+        price,
+        headers: response_headers,
+        session: None,
+    })
+}
+
+/// Pay `price` from the balance of a session
+///
+/// The remaining balance is returned in the `X-Session-Balance` header.
+fn debit_session(state: &ApiState, session_id: &str, price: Amount) -> Result<Payment, ApiError> {
+    let settings = &state.settings;
+
+    let debit = state
+        .db
+        .debit_session(
+            session_id,
+            price.into(),
+            unix_time(),
+            settings.session_idle_secs,
+        )
+        .map_err(|err| {
+            tracing::error!("Could not debit session: {}", err);
+            ApiError::Internal
+        })?;
+
+    match debit {
+        SessionDebit::Debited(balance) => {
+            let mut headers = HeaderMap::new();
+            headers.insert(SESSION_BALANCE_HEADER, HeaderValue::from(balance));
+
+            Ok(Payment {
+                id: session_id.to_string(),
+                price,
+                headers,
+                session: Some(session_id.to_string()),
+            })
+        }
+        SessionDebit::Insufficient(balance) => {
+            let mut challenge = settings.challenge(price, PaymentRequiredReason::SessionExhausted);
+            challenge.balance = Some(balance.into());
+
+            Err(ApiError::PaymentRequired(challenge))
+        }
+        SessionDebit::NotFound => {
+            Err(settings.payment_required(price, PaymentRequiredReason::SessionNotFound))
+        }
+    }
+}
+
+/// Credit `amount` back to the session of a payment that was not served
+fn credit_session(state: &ApiState, session_id: &str, amount: Amount) -> bool {
+    match state.db.credit_session(session_id, amount.into()) {
+        Ok(()) => true,
+        Err(err) => {
+            tracing::error!("Could not credit session {}: {}", session_id, err);
+            false
+        }
+    }
+}
+
+/// Mark a payment as served
+fn finish_payment(state: &ApiState, payment: &Payment) {
+    if payment.session.is_some() {
+        return;
+    }
+
+    if let Err(err) = state.db.remove_search_payment(&payment.id) {
+        tracing::error!("Could not remove search payment: {}", err);
+    }
+}
+
+/// Record the proofs and transition all of their Ys to spent
+///
+/// This happens before the upstream request is made so the same token cannot
+/// be replayed. If any Y was already claimed by another request, the Ys this
+/// call transitioned are reset so none of the proofs are spent.
+async fn spend_proofs(
+    mint: &Mint,
+    proofs: &Proofs,
+    ys: &[PublicKey],
+    price: Amount,
+    settings: &Settings,
+) -> Result<(), ApiError> {
+    if ys.iter().collect::<HashSet<_>>().len() != ys.len() {
+        tracing::debug!("Token contains duplicate proofs");
+        return Err(settings.payment_required(price, PaymentRequiredReason::InvalidProof));
+    }
+
+    mint.localstore
+        .add_proofs(proofs.clone())
+        .await
+        .map_err(|err| {
+            tracing::error!("Could not add proofs to mint db: {}", err);
+            ApiError::Internal
+        })?;
+
+    let previous_states = mint
+        .localstore
+        .update_proofs_states(ys, ProofState::Spent)
+        .await
+        .map_err(|err| {
+            tracing::error!("Could not update proof states: {}", err);
+            ApiError::Internal
+        })?;
+
+    let is_claimed =
+        |state: &Option<ProofState>| matches!(state, Some(ProofState::Spent | ProofState::Pending));
+
+    if previous_states.iter().any(is_claimed) {
+        let unclaimed: Vec<PublicKey> = ys
+            .iter()
+            .zip(previous_states.iter())
+            .filter(|(_, state)| !is_claimed(state))
+            .map(|(y, _)| *y)
+            .collect();
+
+        if !unclaimed.is_empty() {
+            if let Err(err) = mint
+                .localstore
+                .update_proofs_states(&unclaimed, ProofState::Unspent)
+                .await
+            {
+                tracing::error!("Could not reset proof states: {}", err);
+            }
+        }
+
+        tracing::warn!("Token has already been spent");
+        return Err(settings.payment_required(price, PaymentRequiredReason::TokenSpent));
+    }
+
+    Ok(())
+}
+
+/// Swap an overpaying token through the mint, returning a token for the change
+///
+/// The outputs for the search price are signed and discarded, only the
+/// change is unblinded and handed back to the client.
+async fn swap_for_change(
+    state: &ApiState,
+    proofs: Proofs,
+    token_amount: Amount,
+    price: Amount,
+) -> Result<Token, ApiError> {
+    let mint = &state.mint;
+    let settings = &state.settings;
+
+    let internal_error = |err: cdk::Error| {
+        tracing::error!("Could not create change: {}", err);
+        ApiError::Internal
+    };
+
+    let input_fee = mint.get_proofs_fee(&proofs).await.map_err(internal_error)?;
+
+    let change_amount = token_amount
+        .checked_sub(price)
+        .and_then(|a| a.checked_sub(input_fee))
+        .ok_or_else(|| settings.payment_required(price, PaymentRequiredReason::WrongAmount))?;
+
+    let (keyset_id, keys) = active_keys(mint, settings).await?;
+
+    let split_target = SplitTarget::default();
+    let price_outputs = PreMintSecrets::random(keyset_id, price, &split_target)
+        .map_err(|err| internal_error(err.into()))?;
+    let change_outputs = PreMintSecrets::random(keyset_id, change_amount, &split_target)
+        .map_err(|err| internal_error(err.into()))?;
+
+    let mut blinded_messages = change_outputs.blinded_messages();
+    blinded_messages.extend(price_outputs.blinded_messages());
+
+    let swap_response = mint
+        .process_swap_request(SwapRequest::new(proofs, blinded_messages))
+        .await
+        .map_err(|err| match err {
+            cdk::Error::TokenAlreadySpent | cdk::Error::TokenPending => {
+                tracing::warn!("Token has already been spent");
+                settings.payment_required(price, PaymentRequiredReason::TokenSpent)
+            }
+            err => internal_error(err),
+        })?;
+
+    let mut change_signatures = swap_response.signatures;
+    let price_signatures = change_signatures.split_off(change_outputs.len());
+
+    keyset_accounting::record(
+        state.db.as_ref(),
+        KeysetFlow::Issued,
+        &keyset_accounting::signature_amounts(&change_signatures),
+    );
+    keyset_accounting::record(
+        state.db.as_ref(),
+        KeysetFlow::Burned,
+        &keyset_accounting::signature_amounts(&price_signatures),
+    );
+
+    let change_proofs = construct_proofs(
+        change_signatures,
+        change_outputs.rs(),
+        change_outputs.secrets(),
+        &keys,
+    )
+    .map_err(|err| internal_error(err.into()))?;
+
+    Ok(Token::new(
+        settings.mint_url.clone(),
+        change_proofs,
+        None,
+        Some(settings.unit),
+    ))
+}
+
+/// Issue a new token worth `amount` signed by the active keyset
+async fn issue_token(state: &ApiState, amount: Amount) -> Result<Token, ApiError> {
+    let mint = &state.mint;
+    let settings = &state.settings;
+
+    let internal_error = |err: cdk::Error| {
+        tracing::error!("Could not issue token: {}", err);
+        ApiError::Internal
+    };
+
+    let (keyset_id, keys) = active_keys(mint, settings).await?;
+
+    let outputs = PreMintSecrets::random(keyset_id, amount, &SplitTarget::default())
+        .map_err(|err| internal_error(err.into()))?;
+
+    let mut signatures = Vec::with_capacity(outputs.len());
+
+    for blinded_message in outputs.blinded_messages() {
+        signatures.push(
+            mint.blind_sign(&blinded_message)
+                .await
+                .map_err(internal_error)?,
+        );
+    }
+
+    keyset_accounting::record(
+        state.db.as_ref(),
+        KeysetFlow::IssuedDirect,
+        &keyset_accounting::signature_amounts(&signatures),
+    );
+
+    let proofs = construct_proofs(signatures, outputs.rs(), outputs.secrets(), &keys)
+        .map_err(|err| internal_error(err.into()))?;
+
+    Ok(Token::new(
+        settings.mint_url.clone(),
+        proofs,
+        None,
+        Some(settings.unit),
+    ))
+}
+
+/// Id and keys of the active keyset for the search unit
+async fn active_keys(mint: &Mint, settings: &Settings) -> Result<(Id, Keys), ApiError> {
+    let keyset_id = mint
+        .localstore
+        .get_active_keyset_id(&settings.unit)
+        .await
+        .map_err(|err| {
+            tracing::error!("Could not get active keyset: {}", err);
+            ApiError::Internal
+        })?
+        .ok_or(ApiError::Internal)?;
+
+    let keys = mint
+        .keyset_pubkeys(&keyset_id)
+        .await
+        .map_err(|err| {
+            tracing::error!("Could not get keyset keys: {}", err);
+            ApiError::Internal
+        })?
+        .keysets
+        .pop()
+        .ok_or(ApiError::Internal)?
+        .keys;
+
+    Ok((keyset_id, keys))
+}
+
+/// Check every proof is from a keyset of the unit requests are priced in
+async fn check_unit(
+    mint: &Mint,
+    proofs: &Proofs,
+    price: Amount,
+    settings: &Settings,
+) -> Result<(), ApiError> {
+    let keyset_ids: HashSet<Id> = proofs.iter().map(|p| p.keyset_id).collect();
+
+    for keyset_id in keyset_ids {
+        let keyset_info = mint
+            .localstore
+            .get_keyset_info(&keyset_id)
+            .await
+            .map_err(|err| {
+                tracing::error!("Could not get keyset info: {}", err);
+                ApiError::Internal
+            })?;
+
+        match keyset_info {
+            Some(keyset_info) if keyset_info.unit == settings.unit => (),
+            Some(keyset_info) => {
+                tracing::debug!(
+                    "Token keyset {} is for unit {}, expected {}",
+                    keyset_id,
+                    keyset_info.unit,
+                    settings.unit
+                );
+                return Err(settings.payment_required(price, PaymentRequiredReason::WrongUnit));
+            }
+            None => {
+                tracing::debug!("Token keyset {} is unknown", keyset_id);
+                return Err(settings.payment_required(price, PaymentRequiredReason::InvalidProof));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Issue a refund for a request that was paid for but failed upstream
+///
+/// The refund is stored against the payment so a failure to hand it back can
+/// be reconciled. If no refund can be issued the original error is returned
+/// and the payment is left unrefunded in the db.
+async fn refund_payment(state: &ApiState, mut payment: Payment, err: ApiError) -> ApiError {
+    let price = payment.price;
+
+    if let Some(session_id) = &payment.session {
+        credit_session(state, session_id, price);
+        return err;
+    }
+
+    let Some(refund) = refund(state, &mut payment, price).await else {
+        return err;
+    };
+
+    ApiError::Refunded {
+        error: Box::new(err),
+        refund,
+        headers: payment.headers,
+    }
+}
+
+/// Issue a token worth `amount` back to the payer of `payment`
+///
+/// The token is recorded against the payment and added to its response
+/// headers as `X-Cashu-Refund`.
+async fn refund(state: &ApiState, payment: &mut Payment, amount: Amount) -> Option<String> {
+    let refund = issue_token(state, amount).await.ok()?.to_string();
+
+    if let Err(err) = state.db.set_search_payment_refund(&payment.id, &refund) {
+        tracing::error!("Could not store search refund: {}", err);
+    }
+
+    if let Ok(value) = HeaderValue::from_str(&refund) {
+        payment.headers.insert("X-Cashu-Refund", value);
+    }
+
+    Some(refund)
+}
+
+/// Parse a V4 token falling back to V3 for older wallets
+fn parse_token(x_cashu: &str) -> Result<Token, ApiError> {
+    match TokenV4::from_str(x_cashu) {
+        Ok(token) => Ok(Token::TokenV4(token)),
+        Err(_) => TokenV3::from_str(x_cashu)
+            .map(Token::TokenV3)
+            .map_err(|err| ApiError::InvalidToken(err.to_string())),
+    }
+}
+
+/// Proofs of a token, only if every proof is from this mint
+fn token_proofs(token: &Token, price: Amount, settings: &Settings) -> Result<Proofs, ApiError> {
+    let mut proofs = token.proofs();
+
+    if proofs.len() != 1 {
+        tracing::debug!("Token contains proofs from {} mints", proofs.len());
+        return Err(settings.payment_required(price, PaymentRequiredReason::WrongMint));
+    }
+
+    proofs.remove(&settings.mint_url).ok_or_else(|| {
+        tracing::debug!("Token is not from this mint");
+        settings.payment_required(price, PaymentRequiredReason::WrongMint)
+    })
+}
+
+/// OpenAPI spec of the search api
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        get_search,
+        post_search,
+        post_search_batch,
+        get_answer,
+        get_summarize,
+        post_summarize,
+        post_session,
+        get_session,
+        post_session_refund,
+        get_info,
+        get_search_count,
+        get_stats,
+        get_health
+    ),
+    components(schemas(
+        SearchRequest,
+        BatchSearchRequest,
+        SummarizeRequest,
+        SearchResponse,
+        BatchSearchResult,
+        SearchResult,
+        Image,
+        Answer,
+        Reference,
+        Summary,
+        ErrorResponse,
+        PaymentChallenge,
+        PaymentQuote,
+        PaymentRequiredReason,
+        SessionResponse,
+        SessionRefundResponse,
+        Info,
+        SearchCount,
+        Stats,
+        CacheStats,
+        Health,
+        Listeners,
+        ComponentStatus
+    )),
+    modifiers(&CashuSecurity)
+)]
+pub struct ApiDoc;
+
+/// Adds the ecash payment headers as security schemes
+struct CashuSecurity;
+
+impl Modify for CashuSecurity {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+
+        components.add_security_scheme(
+            "cashu",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                "X-Cashu",
+                "Cashu token worth the price of the request",
+            ))),
+        );
+        components.add_security_scheme(
+            "cashu_authorization",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                "Authorization",
+                "`Cashu <token>` worth the price of the request",
+            ))),
+        );
+    }
+}
+
+pub fn search_router(state: ApiState) -> Router {
+    let paid_routes = Router::new()
+        .route(
+            "/search",
+            get(get_search)
+                .post(post_search)
+                .layer(DefaultBodyLimit::max(MAX_SEARCH_BODY_BYTES)),
+        )
+        .route(
+            "/search/batch",
+            post(post_search_batch).layer(DefaultBodyLimit::max(MAX_SEARCH_BODY_BYTES)),
+        )
+        .route("/ws", get(get_ws))
+        .route("/session", post(post_session))
+        .route("/session/:id", get(get_session))
+        .route("/session/:id/refund", post(post_session_refund))
+        .route("/answer", get(get_answer))
+        .route(
+            "/summarize",
+            get(get_summarize)
+                .post(post_summarize)
+                .layer(DefaultBodyLimit::max(MAX_SUMMARIZE_BODY_BYTES)),
+        )
+        .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit));
+
+    let mut router = Router::new().merge(paid_routes);
+
+    if state.settings.operator_token.is_some() {
+        let operator_routes = Router::new()
+            .route("/operator/revenue", get(get_operator_revenue))
+            .route("/operator/kagi_balance", get(get_operator_kagi_balance))
+            .route("/operator/db", get(get_operator_db))
+            .route("/operator/analytics", get(get_operator_analytics))
+            .route_layer(middleware::from_fn_with_state(state.clone(), operator_auth));
+
+        router = router.merge(operator_routes);
+    }
+
+    if state.settings.landing_page.is_some() {
+        router = router
+            .route("/", get(get_landing_page))
+            .route("/favicon.ico", get(get_favicon));
+    }
+
+    if state.settings.openapi {
+        router = router.merge(SwaggerUi::new("/docs").url("/openapi.json", ApiDoc::openapi()));
+    }
+
+    router
+        .route("/info", get(get_info))
+        .route("/search_count", get(get_search_count))
+        .route("/stats", get(get_stats))
+        .route("/health", get(get_health))
+        .layer(middleware::from_fn(request_id))
+        // Every response is covered, including 402 challenges and error bodies
+        .layer(
+            CompressionLayer::new().compress_when(
+                SizeAbove::new(MIN_COMPRESS_BYTES)
+                    .and(NotForContentType::GRPC)
+                    .and(NotForContentType::IMAGES),
+            ),
+        )
+        .layer(cors_layer(state.runtime_settings.clone()))
+        .with_state(state)
+}
+
+/// Reject requests to operator endpoints without the operator bearer token
+async fn operator_auth<B>(
+    State(state): State<ApiState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|auth| auth.to_str().ok())
+        .and_then(|auth| auth.strip_prefix("Bearer "));
+
+    let authorized = match (token, &state.settings.operator_token) {
+        (Some(token), Some(expected)) => constant_time_eq(token.as_bytes(), expected.as_bytes()),
+        _ => false,
+    };
+
+    if !authorized {
+        return ApiError::Unauthorized.into_response();
+    }
+
+    next.run(request).await
+}
+
+/// Compare without returning early so the time taken does not leak how much
+/// of a token matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Reject clients over their rate limit with a 429, and count the request
+/// against the paid or invalid limit depending on the response
+///
+/// Only successful responses count as paid. Rejected client requests count as
+/// invalid, server errors and 429s are not counted at all.
+async fn rate_limit<B>(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let ip = state.rate_limiter.client_ip(request.headers(), peer);
+
+    if let Some(retry_after) = state.rate_limiter.retry_after(ip) {
+        tracing::debug!("Rate limited request from {}", ip);
+        return ApiError::RateLimited(retry_after).into_response();
+    }
+
+    let response = next.run(request).await;
+
+    let status = response.status();
+
+    if status.is_success() {
+        state.rate_limiter.record(ip, RequestKind::Paid);
+    } else if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS {
+        state.rate_limiter.record(ip, RequestKind::Invalid);
+    }
+
+    response
+}
+
+/// Cors layer checking origins against the allowed origins current at the
+/// time of each request, so a reload applies without rebuilding the router
+fn cors_layer(runtime_settings: SharedRuntimeSettings) -> CorsLayer {
+    let allow_origin =
+        AllowOrigin::predicate(move |origin: &HeaderValue, _| {
+            match &runtime_settings.load().cors_allowed_origins {
+                Some(origins) => origins
+                    .iter()
+                    .any(|allowed| allowed.as_bytes() == origin.as_bytes()),
+                None => true,
+            }
+        });
+
+    CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::GET, Method::POST])
+        .allow_headers([
+            AUTHORIZATION,
+            CONTENT_TYPE,
+            ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            ACCESS_CONTROL_ALLOW_ORIGIN,
+            HeaderName::from_str("X-Cashu").unwrap(),
+            HeaderName::from_static(REQUEST_ID_HEADER),
+            HeaderName::from_static(SESSION_HEADER),
+        ])
+        .expose_headers([
+            HeaderName::from_static(SESSION_BALANCE_HEADER),
+            HeaderName::from_static(REQUEST_ID_HEADER),
+            HeaderName::from_static("x-cashu-change"),
+            HeaderName::from_static("x-cashu-refund"),
+        ])
+}
+
+/// Wrap the request in a span carrying its request id, and echo the id back
+///
+/// The id from an incoming `X-Request-Id` header is used if it is sane,
+/// otherwise a new one is generated.
+async fn request_id<B>(request: Request<B>, next: Next<B>) -> Response {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .map(|id| id.to_string())
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    let span = tracing::info_span!(
+        "request",
+        request_id = %request_id,
+        method = %request.method(),
+        path = %request.uri().path(),
+        verify_ms = tracing::field::Empty,
+        upstream_ms = tracing::field::Empty,
+        serialize_ms = tracing::field::Empty,
+    );
+
+    let mut response = next.run(request).instrument(span).await;
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+
+    response
+}
+
+/// Record the milliseconds since `start` as `field` on the current span
+fn record_ms(field: &str, start: Instant) {
+    tracing::Span::current().record(field, start.elapsed().as_millis() as u64);
+}
+
+/// Error returned by the search api
+#[derive(Debug)]
+pub enum ApiError {
+    /// Payment token could not be parsed
+    InvalidToken(String),
+    /// Request parameters are not valid
+    InvalidRequest(String),
+    /// Payment is missing or not acceptable
+    PaymentRequired(PaymentChallenge),
+    /// Client is over its rate limit, carries the seconds until it may retry
+    RateLimited(u64),
+    /// Upstream provider did not respond in time
+    UpstreamTimeout(String),
+    /// Upstream provider request failed
+    Upstream(String),
+    /// Operator token is missing or wrong
+    Unauthorized,
+    /// Requested resource does not exist
+    NotFound,
+    /// Internal error, details are only logged
+    Internal,
+    /// Upstream request failed after payment and a refund token was issued
+    Refunded {
+        error: Box<ApiError>,
+        refund: String,
+        headers: HeaderMap,
+    },
+}
+
+impl ApiError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidToken(_) | ApiError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::PaymentRequired(_) => StatusCode::PAYMENT_REQUIRED,
+            ApiError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::UpstreamTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            ApiError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::NotFound => StatusCode::NOT_FOUND,
+            ApiError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Refunded { error, .. } => error.status(),
+        }
+    }
+
+    /// Machine readable error code
+    fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidToken(_) => "invalid_token",
+            ApiError::InvalidRequest(_) => "invalid_request",
+            ApiError::PaymentRequired(challenge) => match challenge.reason {
+                PaymentRequiredReason::MissingToken => "payment_required",
+                PaymentRequiredReason::WrongMint => "wrong_mint",
+                PaymentRequiredReason::WrongAmount => "insufficient_amount",
+                PaymentRequiredReason::WrongUnit => "wrong_unit",
+                PaymentRequiredReason::InvalidProof => "invalid_token",
+                PaymentRequiredReason::TokenSpent => "token_spent",
+                PaymentRequiredReason::SessionNotFound => "session_not_found",
+                PaymentRequiredReason::SessionExhausted => "session_exhausted",
+            },
+            ApiError::RateLimited(_) => "rate_limited",
+            ApiError::UpstreamTimeout(_) => "upstream_timeout",
+            ApiError::Upstream(_) => "upstream_error",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::NotFound => "not_found",
+            ApiError::Internal => "internal_error",
+            ApiError::Refunded { error, .. } => error.code(),
+        }
+    }
+
+    fn message(&self) -> &'static str {
+        match self {
+            ApiError::InvalidToken(_) => "Payment token could not be parsed",
+            ApiError::InvalidRequest(_) => "Invalid request",
+            ApiError::PaymentRequired(challenge) => match challenge.reason {
+                PaymentRequiredReason::MissingToken => "A payment token is required",
+                PaymentRequiredReason::WrongMint => "Token is not from this mint",
+                PaymentRequiredReason::WrongAmount => "Token amount is not enough",
+                PaymentRequiredReason::WrongUnit => {
+                    "Token is not in the unit requests are priced in"
+                }
+                PaymentRequiredReason::InvalidProof => "Token proofs are not valid",
+                PaymentRequiredReason::TokenSpent => "Token has already been spent",
+                PaymentRequiredReason::SessionNotFound => "Session does not exist or has expired",
+                PaymentRequiredReason::SessionExhausted => "Session balance is too low",
+            },
+            ApiError::RateLimited(_) => "Too many requests",
+            ApiError::UpstreamTimeout(_) => "Upstream provider timed out",
+            ApiError::Upstream(_) => "Upstream provider request failed",
+            ApiError::Unauthorized => "Operator token is missing or wrong",
+            ApiError::NotFound => "Not found",
+            ApiError::Internal => "Internal error",
+            ApiError::Refunded { error, .. } => error.message(),
+        }
+    }
+
+    fn into_body(self) -> ErrorResponse {
+        let code = self.code().to_string();
+        let message = self.message().to_string();
+
+        let (detail, challenge, refund) = match self {
+            ApiError::InvalidToken(detail)
+            | ApiError::InvalidRequest(detail)
+            | ApiError::UpstreamTimeout(detail)
+            | ApiError::Upstream(detail) => (Some(detail), None, None),
+            ApiError::PaymentRequired(challenge) => (None, Some(challenge), None),
+            ApiError::RateLimited(retry_after) => (
+                Some(format!("Retry after {retry_after} seconds")),
+                None,
+                None,
+            ),
+            ApiError::Unauthorized | ApiError::NotFound | ApiError::Internal => (None, None, None),
+            ApiError::Refunded { error, refund, .. } => {
+                let body = error.into_body();
+                (body.detail, body.challenge, Some(refund))
+            }
+        };
+
+        ErrorResponse {
+            code,
+            message,
+            detail,
+            refund,
+            challenge,
+        }
+    }
+}
+
+impl From<search_provider::Error> for ApiError {
+    fn from(err: search_provider::Error) -> Self {
+        match err {
+            search_provider::Error::Timeout(detail) => Self::UpstreamTimeout(detail),
+            err => Self::Upstream(err.to_string()),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(mut self) -> Response {
+        let status = self.status();
+
+        let mut headers = match &mut self {
+            ApiError::Refunded { headers, .. } => std::mem::take(headers),
+            _ => HeaderMap::new(),
+        };
+
+        if let ApiError::RateLimited(retry_after) = &self {
+            if let Ok(value) = HeaderValue::from_str(&retry_after.to_string()) {
+                headers.insert(RETRY_AFTER, value);
+            }
+        }
+
+        (status, headers, Json(self.into_body())).into_response()
+    }
+}
+
+/// Why a payment was not accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentRequiredReason {
+    /// No token was sent in the Authorization or X-Cashu header
+    MissingToken,
+    /// Token is not from this mint
+    WrongMint,
+    /// Token value is less than the price plus the keyset input fee,
+    /// overpayment is accepted and the excess returned as change
+    WrongAmount,
+    /// Token is not denominated in the unit requests are priced in
+    WrongUnit,
+    /// Proof failed verification
+    InvalidProof,
+    /// Proof has already been spent
+    TokenSpent,
+    /// Session does not exist or has expired
+    SessionNotFound,
+    /// Session balance is lower than the price
+    SessionExhausted,
+}
+
+/// Body of a 402 response describing the token the client must send
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct PaymentChallenge {
+    pub reason: PaymentRequiredReason,
+    #[schema(value_type = String)]
+    pub mint: MintUrl,
+    #[schema(value_type = u64)]
+    pub amount: Amount,
+    #[schema(value_type = String)]
+    pub unit: CurrencyUnit,
+    /// Mint quote for the price, only when asked for with `quote=true`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quote: Option<PaymentQuote>,
+    /// Remaining balance of an exhausted session
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<u64>)]
+    pub balance: Option<Amount>,
+}
+
+/// Mint quote attached to a payment challenge
+///
+/// Paying `request` does not pay for the request itself, the client still
+/// has to mint the token against `quote_id` once the invoice is paid and then
+/// retry with it. The quote only saves the round trip of creating it.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct PaymentQuote {
+    #[schema(value_type = String)]
+    pub mint_url: MintUrl,
+    pub quote_id: String,
+    /// Bolt11 invoice to pay
+    pub request: String,
+    #[schema(value_type = u64)]
+    pub amount: Amount,
+    #[schema(value_type = String)]
+    pub unit: CurrencyUnit,
+    /// Unix time the quote expires at
+    pub expiry: Option<u64>,
+}
+
+/// Body of every error response
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ErrorResponse {
+    pub code: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// Token refunding the payment for a failed request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refund: Option<String>,
+    /// Token the client must send for a payment error
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub challenge: Option<PaymentChallenge>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct Params {
+    q: String,
+    /// Attach a mint quote to the 402 response when no token is sent
+    quote: Option<bool>,
+    limit: Option<u64>,
+    related: Option<bool>,
+    region: Option<String>,
+    safesearch: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AnswerParams {
+    q: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SummarizeParams {
+    url: String,
+    engine: Option<String>,
+}
+
+/// Body of a `POST /summarize` request, exactly one of `url` or `text` is required
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+struct SummarizeRequest {
+    url: Option<String>,
+    text: Option<String>,
+    engine: Option<String>,
+}
+
+/// Body of a `POST /search` request
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+struct SearchRequest {
+    q: String,
+    /// Attach a mint quote to the 402 response when no token is sent
+    quote: Option<bool>,
+    limit: Option<u64>,
+    related: Option<bool>,
+    region: Option<String>,
+    safesearch: Option<String>,
+}
+
+/// Body of a `POST /search/batch` request
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+struct BatchSearchRequest {
+    queries: Vec<String>,
+    limit: Option<u64>,
+    related: Option<bool>,
+    region: Option<String>,
+    safesearch: Option<String>,
+}
+
+/// Balance of a prepaid session
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct SessionResponse {
+    /// Bearer id to send in the `X-Session` header
+    pub id: String,
+    #[schema(value_type = u64)]
+    pub balance: Amount,
+    /// Unix time the session expires at if it is not used again
+    pub expires_at: u64,
+}
+
+impl SessionResponse {
+    fn new(id: String, session: &Session, idle_secs: u64) -> Self {
+        Self {
+            id,
+            balance: session.balance.into(),
+            expires_at: session.expires_at(idle_secs),
+        }
+    }
+}
+
+/// Token refunding the balance of a closed session
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct SessionRefundResponse {
+    pub token: String,
+}
+
+/// Search sent as a websocket message
+#[derive(Debug, Clone, Deserialize)]
+struct WsSearchRequest {
+    token: String,
+    #[serde(flatten)]
+    search: SearchRequest,
+}
+
+/// Reply to a websocket search
+#[derive(Debug, Clone, Serialize)]
+struct WsSearchResponse {
+    results: SearchResponse,
+    /// Change for a token that overpaid
+    #[serde(skip_serializing_if = "Option::is_none")]
+    change: Option<String>,
+}
+
+/// Outcome of one query in a batch
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(untagged)]
+enum BatchSearchResult {
+    Results(SearchResponse),
+    Error { error: ErrorResponse },
+}
+
+/// Search response, a bare list of results unless related searches were asked for
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(untagged)]
+enum SearchResponse {
+    Results(Vec<SearchResult>),
+    WithRelated {
+        results: Vec<SearchResult>,
+        related: Vec<String>,
+    },
+}
+
+/// Details wallets need to pay for requests
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Info {
+    #[schema(value_type = String)]
+    pub mint: MintUrl,
+    pub name: String,
+    /// Url of the operator's terms of service
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tos_url: Option<String>,
+    /// Url of the operator's privacy policy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub privacy_policy_url: Option<String>,
+    /// Unit requests are priced in
+    pub unit: String,
+    /// Price of a `/search` request
+    #[schema(value_type = u64)]
+    pub search_price: Amount,
+    /// Price of an `/answer` request
+    #[schema(value_type = u64)]
+    pub answer_price: Amount,
+    /// Price of a `/summarize` request
+    #[schema(value_type = u64)]
+    pub summarize_price: Amount,
+    /// Price of one unit in the minor unit of `price_currency` when minted
+    /// over lightning
+    pub cost_per_search_cents: u64,
+    /// Fiat currency units are priced in
+    pub price_currency: String,
+    /// Fee per thousand proofs paid on top of the price of a request
+    pub input_fee_ppk: u64,
+    /// Most results returned for a search
+    pub max_results: u64,
+    /// Most queries accepted in a batch search
+    pub max_batch_size: usize,
+    /// Token versions accepted in payments
+    pub token_versions: Vec<String>,
+    /// Paid endpoints served
+    pub endpoints: Vec<String>,
+    /// Version of the server
+    pub version: String,
+}
+
+/// Token versions accepted by [`parse_token`]
+pub const TOKEN_VERSIONS: [&str; 2] = ["v4", "v3"];
+
+/// Paid endpoints served by [`search_router`]
+pub const PAID_ENDPOINTS: [&str; 6] = [
+    "search",
+    "search/batch",
+    "ws",
+    "session",
+    "answer",
+    "summarize",
+];
+
+/// Lightning backend the mint is paid through
+#[derive(Clone)]
+pub enum LightningBackend {
+    Cln(Arc<Cln>),
+    Phoenixd(Arc<Phoenixd>),
+    /// No lightning node, for development
+    Dev(Arc<DevLightning>),
+}
+
+impl LightningBackend {
+    /// Check the backend is reachable
+    async fn check_connection(&self) -> ComponentStatus {
+        let result = match self {
+            LightningBackend::Cln(cln) => cln.check_connection().await.map_err(|e| e.to_string()),
+            LightningBackend::Phoenixd(phoenixd) => {
+                phoenixd.check_connection().await.map_err(|e| e.to_string())
+            }
+            LightningBackend::Dev(_) => Ok(()),
+        };
+
+        match result {
+            Ok(()) => ComponentStatus::Ok,
+            Err(err) => {
+                tracing::warn!("Lightning backend health check failed: {}", err);
+                ComponentStatus::Error
+            }
+        }
+    }
+}
+
+/// Status of a component the search api depends on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ComponentStatus {
+    Ok,
+    Error,
+}
+
+/// Health of the search api dependencies
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Health {
+    /// Lightning backend the mint is paid through
+    pub lightning: ComponentStatus,
+    pub search_provider: ComponentStatus,
+    pub db: ComponentStatus,
+    pub listeners: Listeners,
+}
+
+/// Addresses the apis are served on
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Listeners {
+    /// Address the cashu mint api is served on
+    pub mint: String,
+    /// Address the search api is served on, the same as `mint` unless it has
+    /// a listener of its own
+    pub search: String,
+}
+
+impl Health {
+    fn is_ok(&self) -> bool {
+        [self.lightning, self.search_provider, self.db]
+            .iter()
+            .all(|s| s == &ComponentStatus::Ok)
+    }
+}
+
+/// Kagi api balance with the searches it still pays for
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct OperatorKagiBalance {
+    #[serde(flatten)]
+    pub balance: KagiBalance,
+    /// Searches left at the configured cost per search, `None` when
+    /// searches are free
+    pub searches_remaining: Option<u64>,
+}
+
+/// Public usage stats of the search api
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Stats {
+    #[serde(flatten)]
+    pub search_count: SearchCount,
+    pub uptime_seconds: u64,
+    pub mint_name: String,
+    /// Search result cache hits and misses
+    pub cache: CacheStats,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Settings {
+    pub mint_url: MintUrl,
+    pub unit: CurrencyUnit,
+    pub search_price: Amount,
+    pub answer_price: Amount,
+    pub summarize_price: Amount,
+    /// Most results returned for a search
+    pub max_results: u64,
+    /// Region used for searches that do not ask for one
+    pub default_region: Option<String>,
+    /// Minimum safe search level
+    pub safesearch: Option<SafeSearch>,
+    /// Serve the OpenAPI spec at `/openapi.json` and Swagger UI at `/docs`
+    pub openapi: bool,
+    /// Return result titles and descriptions as plain text instead of html
+    /// limited to `<b>` and `<i>`
+    pub strip_html: bool,
+    /// Drop results that point at the same page as an earlier result
+    pub dedup_results: bool,
+    /// Seconds a session may go unused before it expires
+    pub session_idle_secs: u64,
+    /// Bearer token for the `/operator` endpoints, they are not served when `None`
+    pub operator_token: Option<String>,
+    /// Kagi api balance in USD below which a warning is logged
+    pub kagi_low_balance: Option<f64>,
+    /// Page served at `/`, not served when `None`
+    pub landing_page: Option<LandingPage>,
+    /// Addresses the apis are served on, reported by `/health`
+    pub listeners: Listeners,
+}
+
+impl Settings {
+    fn challenge(&self, price: Amount, reason: PaymentRequiredReason) -> PaymentChallenge {
+        PaymentChallenge {
+            reason,
+            mint: self.mint_url.clone(),
+            amount: price,
+            unit: self.unit,
+            quote: None,
+            balance: None,
+        }
+    }
+
+    fn payment_required(&self, price: Amount, reason: PaymentRequiredReason) -> ApiError {
+        ApiError::PaymentRequired(self.challenge(price, reason))
+    }
+}
+
+/// State shared by the search api handlers
+///
+/// Payments are verified and signed by `mint` directly, the search api holds
+/// no keys of its own.
+#[derive(Clone)]
+pub struct ApiState {
+    pub info: Info,
+    pub mint: Arc<Mint>,
+    pub lightning: LightningBackend,
+    pub settings: Settings,
+    pub search_provider: Arc<dyn SearchProvider + Send + Sync>,
+    /// Kagi client used for endpoints beyond search
+    pub kagi: Arc<KagiProvider>,
+    pub rate_limiter: RateLimiter,
+    pub search_cache: SearchCache,
+    /// Settings that are reloaded without a restart
+    pub runtime_settings: SharedRuntimeSettings,
+    pub db: Arc<dyn SearchStore>,
+    /// Hashes queries for the analytics counters, `None` when analytics are off
+    pub analytics: Option<QueryAnalytics>,
+    /// Unix time the api was started at
+    pub started_at: u64,
+    /// Last computed stats and the unix time they were computed at
+    pub stats_cache: Arc<RwLock<Option<(u64, Stats)>>>,
+    /// Last search provider health check result and the unix time it was made at
+    pub search_provider_health_cache: Arc<RwLock<Option<(u64, ComponentStatus)>>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::runtime_settings::RuntimeSettings;
+    use crate::test_utils::{
+        mint_proofs, mint_token, payment_headers, peer, search_unit, temp_dir, test_state,
+        test_state_in, MINT_URL,
+    };
+
+    /// Provider that is always down
+    struct FailingProvider;
+
+    #[async_trait]
+    impl SearchProvider for FailingProvider {
+        async fn search(
+            &self,
+            _query: &SearchQuery,
+        ) -> Result<SearchResults, search_provider::Error> {
+            Err(search_provider::Error::Status(503))
+        }
+
+        async fn check(&self) -> Result<(), search_provider::Error> {
+            Err(search_provider::Error::Status(503))
+        }
+    }
+
+    fn search_request(token: &str) -> Request<Body> {
+        Request::builder()
+            .uri("/search?q=cashu")
+            .header("X-Cashu", token)
+            .extension(ConnectInfo(peer()))
+            .body(Body::empty())
+            .expect("valid request")
+    }
+
+    /// Status and json body of `response`
+    async fn json_body(response: Response) -> (StatusCode, serde_json::Value) {
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_searches_with_one_token_spend_it_once() {
+        let state = test_state(0).await;
+        let token = mint_token(&state.mint, state.settings.unit, 1).await;
+
+        let router = search_router(state);
+
+        let first = tokio::spawn(router.clone().oneshot(search_request(&token)));
+        let second = tokio::spawn(router.clone().oneshot(search_request(&token)));
+
+        let mut statuses = vec![
+            first.await.unwrap().unwrap().status(),
+            second.await.unwrap().unwrap().status(),
+        ];
+        statuses.sort();
+
+        assert_eq!(statuses, [StatusCode::OK, StatusCode::PAYMENT_REQUIRED]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_searches_from_a_session_with_one_credit_debit_it_once() {
+        let state = test_state(0).await;
+        let now = unix_time();
+        state
+            .db
+            .add_session(
+                "one-credit",
+                &Session {
+                    balance: 1,
+                    created_at: now,
+                    last_used_at: now,
+                    closed: false,
+                    refund: None,
+                },
+            )
+            .unwrap();
+
+        let router = search_router(state.clone());
+        let session_request = || {
+            Request::builder()
+                .uri("/search?q=cashu")
+                .header(SESSION_HEADER, "one-credit")
+                .extension(ConnectInfo(peer()))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let first = tokio::spawn(router.clone().oneshot(session_request()));
+        let second = tokio::spawn(router.clone().oneshot(session_request()));
+
+        let mut statuses = vec![
+            first.await.unwrap().unwrap().status(),
+            second.await.unwrap().unwrap().status(),
+        ];
+        statuses.sort();
+
+        assert_eq!(statuses, [StatusCode::OK, StatusCode::PAYMENT_REQUIRED]);
+
+        let session = state.db.get_session("one-credit").unwrap().unwrap();
+        assert_eq!(session.balance, 0);
+    }
+
+    #[tokio::test]
+    async fn only_successful_searches_count_against_the_paid_limit() {
+        let mut state = test_state(0).await;
+        state.rate_limiter = RateLimiter::new(Some(1), None, Vec::new());
+
+        let failing = search_router(ApiState {
+            search_provider: Arc::new(FailingProvider),
+            ..state.clone()
+        });
+        let router = search_router(state.clone());
+
+        let token = mint_token(&state.mint, state.settings.unit, 1).await;
+        let response = failing.oneshot(search_request(&token)).await.unwrap();
+        assert!(response.status().is_server_error());
+
+        let response = router
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/search?q=cashu")
+                    .extension(ConnectInfo(peer()))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+
+        let token = mint_token(&state.mint, state.settings.unit, 1).await;
+        let response = router
+            .clone()
+            .oneshot(search_request(&token))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let token = mint_token(&state.mint, state.settings.unit, 1).await;
+        let response = router.oneshot(search_request(&token)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn token_from_the_sat_keyset_is_rejected() {
+        let state = test_state(0).await;
+        let token = mint_token(&state.mint, CurrencyUnit::Sat, 1).await;
+
+        let result = take_payment(&state, &payment_headers(&token), Amount::from(1)).await;
+
+        match result {
+            Err(ApiError::PaymentRequired(challenge)) => {
+                assert_eq!(challenge.reason, PaymentRequiredReason::WrongUnit)
+            }
+            Err(err) => panic!("Expected a payment challenge, got {:?}", err),
+            Ok(_) => panic!("Sat token paid for a search"),
+        }
+    }
+
+    #[tokio::test]
+    async fn underpaid_fee_is_challenged_for_price_with_fee() {
+        // One XSR of fee per proof
+        let state = test_state(1000).await;
+        let router = search_router(state.clone());
+
+        // Covers the price of 1 but not the fee on its proof
+        let token = mint_token(&state.mint, state.settings.unit, 1).await;
+        let response = router
+            .clone()
+            .oneshot(search_request(&token))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let challenge = serde_json::from_slice::<ErrorResponse>(&body)
+            .unwrap()
+            .challenge
+            .unwrap();
+        assert_eq!(challenge.reason, PaymentRequiredReason::WrongAmount);
+        assert_eq!(challenge.amount, Amount::from(2));
+
+        // Paying the advertised amount is accepted, as a single proof of 2
+        let token = mint_token(&state.mint, state.settings.unit, 2).await;
+        let response = router.oneshot(search_request(&token)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("X-Cashu-Change").is_none());
+    }
+
+    async fn advertised_cost(router: &Router) -> u64 {
+        let response = router
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/info")
+                    .extension(ConnectInfo(peer()))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        serde_json::from_slice::<Info>(&body)
+            .unwrap()
+            .cost_per_search_cents
+    }
+
+    #[tokio::test]
+    async fn reloaded_price_is_served_by_the_running_router() {
+        let state = test_state(0).await;
+        let runtime_settings = state.runtime_settings.clone();
+        let router = search_router(state);
+
+        assert_eq!(advertised_cost(&router).await, 3);
+
+        runtime_settings.store(RuntimeSettings {
+            cost_per_search_cents: 7,
+            ..Default::default()
+        });
+
+        assert_eq!(advertised_cost(&router).await, 7);
+    }
+
+    #[tokio::test]
+    async fn search_results_are_gzipped_when_asked_for() {
+        use std::io::Read;
+
+        let state = test_state(0).await;
+        let router = search_router(state.clone());
+        // The fake provider echoes the query as the title, long enough to be compressed
+        let uri = format!("/search?q={}", "cashu+".repeat(100));
+
+        let mut bodies = Vec::new();
+        for gzip in [false, true] {
+            let token = mint_token(&state.mint, state.settings.unit, 1).await;
+            let mut request = Request::builder()
+                .uri(&uri)
+                .header("X-Cashu", &token)
+                .extension(ConnectInfo(peer()));
+            if gzip {
+                request = request.header("Accept-Encoding", "gzip");
+            }
+
+            let response = router
+                .clone()
+                .oneshot(request.body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers().get("Content-Encoding"),
+                gzip.then(|| HeaderValue::from_static("gzip")).as_ref()
+            );
+
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body = if gzip {
+                let mut decoded = Vec::new();
+                flate2::read::GzDecoder::new(&body[..])
+                    .read_to_end(&mut decoded)
+                    .unwrap();
+                decoded
+            } else {
+                body.to_vec()
+            };
+            bodies.push(body);
+        }
+
+        assert_eq!(bodies[0], bodies[1]);
+    }
+
+    #[tokio::test]
+    async fn only_allowed_origins_get_cors_headers() {
+        let state = test_state(0).await;
+        state.runtime_settings.store(RuntimeSettings {
+            cors_allowed_origins: Some(vec!["https://allowed.example".to_string()]),
+            ..Default::default()
+        });
+        let router = search_router(state);
+
+        for (origin, allowed) in [
+            ("https://allowed.example", true),
+            ("https://evil.example", false),
+        ] {
+            let response = router
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri("/info")
+                        .header("Origin", origin)
+                        .extension(ConnectInfo(peer()))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN),
+                allowed.then(|| HeaderValue::from_static(origin)).as_ref(),
+                "{}",
+                origin
+            );
+        }
+    }
+
+    /// Whether any file in `dir` contains `needle`
+    fn written_to_disk(dir: &std::path::Path, needle: &[u8]) -> bool {
+        std::fs::read_dir(dir).unwrap().any(|entry| {
+            let contents = std::fs::read(entry.unwrap().path()).unwrap();
+
+            contents
+                .windows(needle.len())
+                .any(|window| window.eq_ignore_ascii_case(needle))
+        })
+    }
+
+    #[tokio::test]
+    async fn raw_queries_never_reach_disk() {
+        for hashed in [false, true] {
+            let dir = temp_dir();
+
+            let mut state = test_state_in(&dir, 0).await;
+            if hashed {
+                state.analytics = Some(QueryAnalytics::load_or_create(&dir).unwrap());
+            }
+            let db = state.db.clone();
+            let router = search_router(state.clone());
+
+            let token = mint_token(&state.mint, state.settings.unit, 1).await;
+            let response = router
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri("/search?q=Zebra+Quagga+Okapi")
+                        .header("X-Cashu", &token)
+                        .extension(ConnectInfo(peer()))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let token = mint_token(&state.mint, state.settings.unit, 1).await;
+            let response = router
+                .oneshot(
+                    Request::builder()
+                        .method(Method::POST)
+                        .uri("/search")
+                        .header("X-Cashu", &token)
+                        .header(CONTENT_TYPE, "application/json")
+                        .extension(ConnectInfo(peer()))
+                        .body(Body::from(r#"{"q":"zebra quagga okapi"}"#))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let analytics = db.get_search_analytics(10).unwrap();
+            match hashed {
+                // Both spellings count as the same query
+                true => assert_eq!(analytics.top_queries.len(), 1),
+                false => assert!(analytics.top_queries.is_empty()),
+            }
+            assert_eq!(db.get_search_count().unwrap().all_time_search_count, 2);
+
+            drop(state);
+            drop(db);
+
+            for word in ["zebra", "quagga", "okapi"] {
+                assert!(
+                    !written_to_disk(&dir, word.as_bytes()),
+                    "{} written to disk with hashed analytics {}",
+                    word,
+                    hashed
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn unparsable_tokens_get_a_400_with_an_error_body() {
+        let router = search_router(test_state(0).await);
+
+        for token in ["cashuB!!not-base64!!", "cashuA", "garbage"] {
+            let (status, body) =
+                json_body(router.clone().oneshot(search_request(token)).await.unwrap()).await;
+
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", token);
+            assert_eq!(body["code"], "invalid_token");
+            assert_eq!(body["message"], "Payment token could not be parsed");
+            assert!(body["detail"].is_string(), "{}", token);
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_token_header_gets_a_400_with_an_error_body() {
+        let router = search_router(test_state(0).await);
+
+        let (status, body) = json_body(router.oneshot(search_request("")).await.unwrap()).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "code": "invalid_token",
+                "message": "Payment token could not be parsed",
+                "detail": "Empty X-Cashu header",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn token_for_another_mint_is_challenged_and_not_spent() {
+        let state = test_state(0).await;
+        let router = search_router(state.clone());
+
+        let proofs = mint_proofs(&state.mint, state.settings.unit, 1).await;
+        let token = Token::new(
+            MintUrl::from_str("https://other-mint.example.com").unwrap(),
+            proofs.clone(),
+            None,
+            Some(state.settings.unit),
+        );
+
+        let (status, body) = json_body(
+            router
+                .clone()
+                .oneshot(search_request(&token.to_string()))
+                .await
+                .unwrap(),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::PAYMENT_REQUIRED);
+        assert_eq!(body["code"], "wrong_mint");
+        assert_eq!(body["message"], "Token is not from this mint");
+        assert_eq!(body["reason"], "wrong_mint");
+        assert_eq!(body["amount"], 1);
+        assert_eq!(
+            body["mint"],
+            serde_json::to_value(&state.settings.mint_url).unwrap()
+        );
+
+        // The same proofs are still good for this mint
+        let token = Token::new(
+            MintUrl::from_str(MINT_URL).unwrap(),
+            proofs,
+            None,
+            Some(state.settings.unit),
+        );
+        let response = router
+            .oneshot(search_request(&token.to_string()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn v3_token_is_accepted() {
+        let state = test_state(0).await;
+        let router = search_router(state.clone());
+
+        let token = TokenV3::new(
+            MintUrl::from_str(MINT_URL).unwrap(),
+            mint_proofs(&state.mint, state.settings.unit, 1).await,
+            None,
+            Some(state.settings.unit),
+        )
+        .unwrap()
+        .to_string();
+        assert!(token.starts_with("cashuA"));
+
+        let parsed = parse_token(&token).unwrap();
+        assert!(matches!(parsed, Token::TokenV3(_)));
+        assert_eq!(parsed.value().unwrap(), Amount::from(1));
+
+        let response = router
+            .clone()
+            .oneshot(search_request(&token))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Spent like a V4 token would be
+        let (status, body) = json_body(router.oneshot(search_request(&token)).await.unwrap()).await;
+        assert_eq!(status, StatusCode::PAYMENT_REQUIRED);
+        assert_eq!(body["code"], "token_spent");
+    }
+
+    fn token_of(proofs: Proofs) -> String {
+        Token::new(
+            MintUrl::from_str(MINT_URL).unwrap(),
+            proofs,
+            None,
+            Some(search_unit()),
+        )
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn token_replayed_after_a_restart_is_rejected_by_used_ys() {
+        let dir = temp_dir();
+
+        let state = test_state_in(&dir, 0).await;
+        let proofs = mint_proofs(&state.mint, search_unit(), 1).await;
+        let ys: Vec<PublicKey> = proofs.iter().map(|proof| proof.y().unwrap()).collect();
+        let token = token_of(proofs);
+
+        let router = search_router(state.clone());
+        let response = router.oneshot(search_request(&token)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        drop(state);
+
+        // Restarted on the same databases
+        let state = test_state_in(&dir, 0).await;
+        assert!(state.db.any_used_ys(&ys).unwrap());
+
+        let response = search_router(state.clone())
+            .oneshot(search_request(&token))
+            .await
+            .unwrap();
+        let (status, body) = json_body(response).await;
+        assert_eq!(status, StatusCode::PAYMENT_REQUIRED);
+        assert_eq!(body["reason"], "token_spent");
+
+        // A mint that has never seen the token still rejects it, only the
+        // used ys in the search db can
+        let unaware_mint = test_state(0).await.mint;
+        let response = search_router(ApiState {
+            mint: unaware_mint,
+            ..state
+        })
+        .oneshot(search_request(&token))
+        .await
+        .unwrap();
+        let (status, body) = json_body(response).await;
+        assert_eq!(status, StatusCode::PAYMENT_REQUIRED);
+        assert_eq!(body["reason"], "token_spent");
+    }
+
+    #[tokio::test]
+    async fn two_proofs_overpaying_get_change() {
+        let state = test_state(0).await;
+        let router = search_router(state.clone());
+
+        let mut proofs = mint_proofs(&state.mint, state.settings.unit, 1).await;
+        proofs.extend(mint_proofs(&state.mint, state.settings.unit, 1).await);
+        assert_eq!(proofs.len(), 2);
+
+        let response = router
+            .clone()
+            .oneshot(search_request(&token_of(proofs)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let change = response
+            .headers()
+            .get("X-Cashu-Change")
+            .expect("overpayment returns change")
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            parse_token(&change).unwrap().value().unwrap(),
+            Amount::from(1)
+        );
+
+        // The change is a valid token of this mint
+        let response = router.oneshot(search_request(&change)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn split_with_a_zero_proof_is_rejected_and_spends_nothing() {
+        let state = test_state(0).await;
+        let router = search_router(state.clone());
+
+        let proofs = mint_proofs(&state.mint, state.settings.unit, 1).await;
+        let mut zero = proofs[0].clone();
+        zero.amount = Amount::ZERO;
+
+        let mut split = proofs.clone();
+        split.push(zero);
+
+        let (status, body) = json_body(
+            router
+                .clone()
+                .oneshot(search_request(&token_of(split)))
+                .await
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::PAYMENT_REQUIRED);
+        assert_eq!(body["code"], "insufficient_amount");
+
+        let response = router
+            .oneshot(search_request(&token_of(proofs)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    fn paid_search_request(headers: &[(&str, &str)]) -> Request<Body> {
+        let mut request = Request::builder()
+            .uri("/search?q=cashu")
+            .extension(ConnectInfo(peer()));
+
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+
+        request.body(Body::empty()).expect("valid request")
+    }
+
+    #[tokio::test]
+    async fn token_is_accepted_in_either_header() {
+        let state = test_state(0).await;
+        let router = search_router(state.clone());
+
+        let token = mint_token(&state.mint, state.settings.unit, 1).await;
+        let response = router
+            .clone()
+            .oneshot(paid_search_request(&[("X-Cashu", &token)]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        for scheme in ["Cashu", "cashu"] {
+            let token = mint_token(&state.mint, state.settings.unit, 1).await;
+            let authorization = format!("{} {}", scheme, token);
+            let response = router
+                .clone()
+                .oneshot(paid_search_request(&[("Authorization", &authorization)]))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{}", scheme);
+        }
+    }
+
+    #[tokio::test]
+    async fn authorization_wins_over_x_cashu() {
+        let state = test_state(0).await;
+        let router = search_router(state.clone());
+
+        let token = mint_token(&state.mint, state.settings.unit, 1).await;
+        let authorization = format!("Cashu {}", token);
+        let response = router
+            .clone()
+            .oneshot(paid_search_request(&[
+                ("Authorization", &authorization),
+                ("X-Cashu", "garbage"),
+            ]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let token = mint_token(&state.mint, state.settings.unit, 1).await;
+        let (status, body) = json_body(
+            router
+                .clone()
+                .oneshot(paid_search_request(&[
+                    ("Authorization", "Cashu garbage"),
+                    ("X-Cashu", &token),
+                ]))
+                .await
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "invalid_token");
+
+        // Other schemes are left to the X-Cashu header
+        let response = router
+            .oneshot(paid_search_request(&[
+                ("Authorization", "Bearer abc"),
+                ("X-Cashu", &token),
+            ]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    /// Check `refund` is a token of this mint worth exactly 1 XSR
+    async fn assert_refund_of_one_xsr(state: &ApiState, refund: &str) {
+        let token = parse_token(refund).unwrap();
+        assert_eq!(token.value().unwrap(), Amount::from(1));
+
+        let proofs = token_proofs(&token, Amount::from(1), &state.settings).unwrap();
+        check_unit(&state.mint, &proofs, Amount::from(1), &state.settings)
+            .await
+            .unwrap();
+        for proof in proofs.iter() {
+            state.mint.verify_proof(proof).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_upstream_search_is_refunded() {
+        let state = test_state(0).await;
+        let failing = search_router(ApiState {
+            search_provider: Arc::new(FailingProvider),
+            ..state.clone()
+        });
+
+        let token = mint_token(&state.mint, state.settings.unit, 1).await;
+        let response = failing.oneshot(search_request(&token)).await.unwrap();
+
+        let header = response
+            .headers()
+            .get("X-Cashu-Refund")
+            .expect("refund header")
+            .to_str()
+            .unwrap()
+            .to_string();
+        let (status, body) = json_body(response).await;
+
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(body["code"], "upstream_error");
+        assert_eq!(body["refund"], header.as_str());
+
+        assert_refund_of_one_xsr(&state, &header).await;
+
+        // The refund pays for the retry
+        let response = search_router(state)
+            .oneshot(search_request(&header))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    /// Url of a search api that answers after `delay`
+    fn slow_upstream(delay: std::time::Duration) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let upstream = Router::new().route(
+            "/search",
+            get(move || async move {
+                tokio::time::sleep(delay).await;
+                Json(serde_json::json!({ "data": [] }))
+            }),
+        );
+
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(upstream.into_make_service()),
+        );
+
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn upstream_timeout_is_a_504_with_a_refund() {
+        let state = test_state(0).await;
+        let kagi = KagiProvider::new(
+            &slow_upstream(std::time::Duration::from_secs(5)),
+            "token".to_string(),
+            std::time::Duration::from_millis(200),
+            0,
+        )
+        .unwrap();
+        let router = search_router(ApiState {
+            search_provider: Arc::new(kagi),
+            ..state.clone()
+        });
+
+        let token = mint_token(&state.mint, state.settings.unit, 1).await;
+        let started = Instant::now();
+        let (status, body) = json_body(router.oneshot(search_request(&token)).await.unwrap()).await;
+
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(body["code"], "upstream_timeout");
+        assert_eq!(body["message"], "Upstream provider timed out");
+
+        assert_refund_of_one_xsr(&state, body["refund"].as_str().expect("refund token")).await;
+    }
+
+    #[tokio::test]
+    async fn error_bodies_keep_their_shape() {
+        use serde_json::json;
+
+        let settings = test_state(0).await.settings;
+        let mint = serde_json::to_value(&settings.mint_url).unwrap();
+        let unit = serde_json::to_value(settings.unit).unwrap();
+        let price = Amount::from(1);
+
+        let cases = [
+            (
+                ApiError::InvalidToken("bad base64".to_string()),
+                StatusCode::BAD_REQUEST,
+                json!({
+                    "code": "invalid_token",
+                    "message": "Payment token could not be parsed",
+                    "detail": "bad base64",
+                }),
+            ),
+            (
+                ApiError::InvalidRequest("q is empty".to_string()),
+                StatusCode::BAD_REQUEST,
+                json!({
+                    "code": "invalid_request",
+                    "message": "Invalid request",
+                    "detail": "q is empty",
+                }),
+            ),
+            (
+                settings.payment_required(price, PaymentRequiredReason::MissingToken),
+                StatusCode::PAYMENT_REQUIRED,
+                json!({
+                    "code": "payment_required",
+                    "message": "A payment token is required",
+                    "reason": "missing_token",
+                    "mint": mint,
+                    "amount": 1,
+                    "unit": unit,
+                }),
+            ),
+            (
+                settings.payment_required(price, PaymentRequiredReason::WrongMint),
+                StatusCode::PAYMENT_REQUIRED,
+                json!({
+                    "code": "wrong_mint",
+                    "message": "Token is not from this mint",
+                    "reason": "wrong_mint",
+                    "mint": mint,
+                    "amount": 1,
+                    "unit": unit,
+                }),
+            ),
+            (
+                settings.payment_required(price, PaymentRequiredReason::WrongAmount),
+                StatusCode::PAYMENT_REQUIRED,
+                json!({
+                    "code": "insufficient_amount",
+                    "message": "Token amount is not enough",
+                    "reason": "wrong_amount",
+                    "mint": mint,
+                    "amount": 1,
+                    "unit": unit,
+                }),
+            ),
+            (
+                settings.payment_required(price, PaymentRequiredReason::WrongUnit),
+                StatusCode::PAYMENT_REQUIRED,
+                json!({
+                    "code": "wrong_unit",
+                    "message": "Token is not in the unit requests are priced in",
+                    "reason": "wrong_unit",
+                    "mint": mint,
+                    "amount": 1,
+                    "unit": unit,
+                }),
+            ),
+            (
+                settings.payment_required(price, PaymentRequiredReason::InvalidProof),
+                StatusCode::PAYMENT_REQUIRED,
+                json!({
+                    "code": "invalid_token",
+                    "message": "Token proofs are not valid",
+                    "reason": "invalid_proof",
+                    "mint": mint,
+                    "amount": 1,
+                    "unit": unit,
+                }),
+            ),
+            (
+                settings.payment_required(price, PaymentRequiredReason::TokenSpent),
+                StatusCode::PAYMENT_REQUIRED,
+                json!({
+                    "code": "token_spent",
+                    "message": "Token has already been spent",
+                    "reason": "token_spent",
+                    "mint": mint,
+                    "amount": 1,
+                    "unit": unit,
+                }),
+            ),
+            (
+                settings.payment_required(price, PaymentRequiredReason::SessionNotFound),
+                StatusCode::PAYMENT_REQUIRED,
+                json!({
+                    "code": "session_not_found",
+                    "message": "Session does not exist or has expired",
+                    "reason": "session_not_found",
+                    "mint": mint,
+                    "amount": 1,
+                    "unit": unit,
+                }),
+            ),
+            (
+                ApiError::PaymentRequired(PaymentChallenge {
+                    balance: Some(Amount::ZERO),
+                    ..settings.challenge(price, PaymentRequiredReason::SessionExhausted)
+                }),
+                StatusCode::PAYMENT_REQUIRED,
+                json!({
+                    "code": "session_exhausted",
+                    "message": "Session balance is too low",
+                    "reason": "session_exhausted",
+                    "mint": mint,
+                    "amount": 1,
+                    "unit": unit,
+                    "balance": 0,
+                }),
+            ),
+            (
+                ApiError::RateLimited(30),
+                StatusCode::TOO_MANY_REQUESTS,
+                json!({
+                    "code": "rate_limited",
+                    "message": "Too many requests",
+                    "detail": "Retry after 30 seconds",
+                }),
+            ),
+            (
+                ApiError::UpstreamTimeout("after 15s".to_string()),
+                StatusCode::GATEWAY_TIMEOUT,
+                json!({
+                    "code": "upstream_timeout",
+                    "message": "Upstream provider timed out",
+                    "detail": "after 15s",
+                }),
+            ),
+            (
+                ApiError::Upstream("status 500".to_string()),
+                StatusCode::BAD_GATEWAY,
+                json!({
+                    "code": "upstream_error",
+                    "message": "Upstream provider request failed",
+                    "detail": "status 500",
+                }),
+            ),
+            (
+                ApiError::Unauthorized,
+                StatusCode::UNAUTHORIZED,
+                json!({
+                    "code": "unauthorized",
+                    "message": "Operator token is missing or wrong",
+                }),
+            ),
+            (
+                ApiError::NotFound,
+                StatusCode::NOT_FOUND,
+                json!({
+                    "code": "not_found",
+                    "message": "Not found",
+                }),
+            ),
+            (
+                ApiError::Internal,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({
+                    "code": "internal_error",
+                    "message": "Internal error",
+                }),
+            ),
+            (
+                ApiError::Refunded {
+                    error: Box::new(ApiError::Upstream("status 500".to_string())),
+                    refund: "cashuBrefund".to_string(),
+                    headers: HeaderMap::new(),
+                },
+                StatusCode::BAD_GATEWAY,
+                json!({
+                    "code": "upstream_error",
+                    "message": "Upstream provider request failed",
+                    "detail": "status 500",
+                    "refund": "cashuBrefund",
+                }),
+            ),
+        ];
+
+        for (err, status, expected) in cases {
+            let (actual_status, body) = json_body(err.into_response()).await;
+
+            assert_eq!(actual_status, status, "{}", expected["code"]);
+            assert_eq!(body, expected);
         }
     }
 }
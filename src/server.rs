@@ -0,0 +1,1752 @@
+//! Mint and search api server
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use axum::{middleware, Router};
+use axum_server::tls_rustls::RustlsConfig;
+use axum_server::Handle;
+use bip39::Mnemonic;
+use bitcoin::bip32::{DerivationPath, Xpriv};
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::Network;
+use cdk::cdk_database::MintDatabase;
+use cdk::cdk_lightning::{self, MintLightning};
+use cdk::mint::{FeeReserve, Mint, MintKeySetInfo};
+use cdk::mint_url::MintUrl;
+use cdk::nuts::{
+    nut04, nut05, ContactInfo, CurrencyUnit, Id, MeltMethodSettings, MintInfo, MintKeySet,
+    MintMethodSettings, MintVersion, Nuts, PaymentMethod, State as ProofState,
+};
+use cdk::types::{LnKey, QuoteTTL};
+use cdk::util::unix_time;
+use cdk::Amount;
+use cdk_redb::MintRedbDatabase;
+use fs2::FileExt;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tower_http::cors::CorsLayer;
+
+use crate::alerts;
+use crate::analytics::QueryAnalytics;
+use crate::cln::{Cln, ClnSettings, ClnTransport, SocketTransport};
+use crate::config::{self, Analytics, LnBackend, SearchProviderKind};
+use crate::db::{Compaction, Db, DbDump, KeysetIssuance, SearchStore};
+use crate::dev_lightning::DevLightning;
+use crate::domain_filter::DomainFilter;
+use crate::keyset_accounting::{self, KeysetAudit};
+use crate::landing_page::LandingPage;
+use crate::nostr::{self, Announcer, MintAnnouncement};
+use crate::phoenixd::{Phoenixd, PhoenixdSettings};
+use crate::price::{PriceCache, PriceSource};
+use crate::rate_limit::RateLimiter;
+use crate::runtime_settings::{RuntimeSettings, SharedRuntimeSettings};
+use crate::search_cache::SearchCache;
+use crate::search_provider::{parse_region, BraveProvider, KagiProvider, SearchProvider};
+use crate::search_route_handlers::{
+    search_router, ApiState, Info, LightningBackend, Listeners, Settings, MAX_BATCH_SIZE,
+    PAID_ENDPOINTS, TOKEN_VERSIONS,
+};
+use crate::{expand_path, search_keyset_derivation_path, tls};
+
+const CARGO_PKG_VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
+
+/// Hour of the day, UTC, the first search db compaction check runs at
+const DB_COMPACTION_HOUR_UTC: u64 = 4;
+
+/// Times a compaction skipped for an open transaction is tried again
+const DB_COMPACTION_RETRIES: u32 = 5;
+
+/// Seconds used ys are kept for the cheap replay check, the mint keeps spent
+/// proofs for good
+const USED_YS_RETENTION_SECS: u64 = 30 * 24 * 60 * 60;
+
+pub const DEFAULT_QUOTE_TTL_SECS: u64 = 1800;
+pub const DEFAULT_CACHE_TTL_SECS: u64 = 1800;
+pub const DEFAULT_CACHE_TTI_SECS: u64 = 1800;
+pub const DEFAULT_KAGI_BASE_URL: &str = "https://kagi.com/api/v0";
+pub const DEFAULT_KAGI_TIMEOUT_SECS: u64 = 15;
+pub const DEFAULT_KAGI_MAX_RETRIES: u32 = 2;
+pub const DEFAULT_BRAVE_TIMEOUT_SECS: u64 = 15;
+pub const DEFAULT_ANSWER_PRICE: u64 = 2;
+pub const DEFAULT_SUMMARIZE_PRICE: u64 = 5;
+pub const DEFAULT_MAX_RESULTS: u64 = 20;
+pub const DEFAULT_SEARCH_CACHE_TTL_SECS: u64 = 60;
+pub const DEFAULT_SEARCH_CACHE_SIZE: usize = 1000;
+pub const DEFAULT_SESSION_IDLE_SECS: u64 = 7 * 24 * 60 * 60;
+pub const DEFAULT_SEARCH_COUNT_RETENTION_DAYS: u64 = 400;
+pub const DEFAULT_DB_COMPACTION_INTERVAL_SECS: u64 = 24 * 60 * 60;
+pub const DEFAULT_DB_COMPACTION_FRAGMENTED_PERCENT: u64 = 25;
+pub const DEFAULT_PRICE_CACHE_TTL_SECS: u64 = 60;
+pub const DEFAULT_PRICE_MAX_STALE_SECS: u64 = 10 * 60;
+pub const DEFAULT_MIN_BTC_PRICE: u64 = 1_000;
+pub const DEFAULT_MAX_BTC_PRICE: u64 = 10_000_000;
+pub const DEFAULT_COST_PER_SEARCH_CENTS: u64 = 3;
+pub const DEFAULT_CLN_PAY_TIMEOUT_SECS: u64 = 30;
+pub const DEFAULT_CLN_RPC_TIMEOUT_SECS: u64 = 10;
+pub const DEFAULT_KEYSET_MAX_ORDER: u8 = 1;
+pub const DEFAULT_NOSTR_STATUS_INTERVAL_SECS: u64 = 24 * 60 * 60;
+
+/// Mint and search api serving in the background
+pub struct RunningServer {
+    /// Address the mint api is served on
+    pub mint_addr: SocketAddr,
+    /// Address the search api is served on, the mint address when they share
+    /// a listener
+    pub search_addr: SocketAddr,
+    /// Settings that can be replaced while running
+    pub runtime_settings: SharedRuntimeSettings,
+    pub kagi: Arc<KagiProvider>,
+    task: JoinHandle<anyhow::Result<()>>,
+}
+
+impl RunningServer {
+    /// Wait for the servers to stop
+    pub async fn wait(self) -> anyhow::Result<()> {
+        self.task.await?
+    }
+}
+
+/// Open the databases in `work_dir`, build the mint and start serving
+///
+/// Returns once the listeners are bound, the servers stop when `shutdown`
+/// completes. `settings` are expected to have passed
+/// [`config::Settings::validate`].
+pub async fn run(
+    settings: config::Settings,
+    work_dir: &Path,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<RunningServer> {
+    let work_dir_lock = lock_work_dir(work_dir)?;
+
+    let redb_path = db_path(&settings.info.mint_db_path, work_dir, "cdk-mintd.redb")?;
+    let localstore = Arc::new(MintRedbDatabase::new(&redb_path)?);
+
+    let mint_version = MintVersion::new(
+        "cdk-athenut-mint".to_string(),
+        CARGO_PKG_VERSION.unwrap_or("Unknown").to_string(),
+    );
+
+    let runtime_settings = SharedRuntimeSettings::new(runtime_settings_from(&settings));
+
+    let contact_info: Vec<ContactInfo> = settings
+        .mint_info
+        .all_contacts()
+        .into_iter()
+        .map(|contact| ContactInfo::new(contact.method, contact.info))
+        .collect();
+
+    let relative_ln_fee = settings.ln.fee_percent;
+
+    let absolute_ln_fee_reserve = settings.ln.reserve_fee_min;
+
+    let fee_reserve = FeeReserve {
+        min_fee_reserve: absolute_ln_fee_reserve,
+        percent_fee_reserve: relative_ln_fee,
+    };
+
+    let mut ln_backends: HashMap<
+        LnKey,
+        Arc<dyn MintLightning<Err = cdk_lightning::Error> + Send + Sync>,
+    > = HashMap::new();
+
+    let mut supported_units = HashMap::new();
+
+    // Database for athenmint
+    let athenmint_db = db_path(
+        &settings.info.search_db_path,
+        work_dir,
+        "athenmint_search_api.redb",
+    )?;
+    let db = Db::new(
+        &athenmint_db,
+        settings
+            .search_settings
+            .search_count_retention_days
+            .unwrap_or(DEFAULT_SEARCH_COUNT_RETENTION_DAYS),
+    )?;
+
+    let price_currency = settings.search_settings.price_currency.unwrap_or_default();
+
+    let price_cache = price_cache(&settings)?;
+    // Lightning backends take the price cache, alerts watch the same one
+    let alerting = settings
+        .alerts
+        .enabled
+        .unwrap_or(false)
+        .then(|| (settings.clone(), price_cache.clone()));
+
+    let lightning = match settings.ln.ln_backend {
+        _ if !settings.ln.enable_ln.unwrap_or(true) => {
+            tracing::warn!("DEV MODE: lightning is disabled, mint quotes are paid without payment");
+
+            LightningBackend::Dev(Arc::new(DevLightning::new(
+                MintMethodSettings::default(),
+                MeltMethodSettings::default(),
+            )))
+        }
+        LnBackend::Cln => {
+            let cln_socket = expand_path(
+                settings
+                    .cln
+                    .rpc_path
+                    .to_str()
+                    .ok_or(anyhow!("cln socket not defined"))?,
+            )
+            .ok_or(anyhow!("cln socket not defined"))?;
+
+            let cln = Cln::new(
+                ClnSettings {
+                    rpc_socket: cln_socket,
+                    fee_reserve,
+                    runtime_settings: runtime_settings.clone(),
+                    mint_name: settings.mint_info.name.clone(),
+                    pay_timeout: Duration::from_secs(
+                        settings
+                            .cln
+                            .pay_timeout_secs
+                            .unwrap_or(DEFAULT_CLN_PAY_TIMEOUT_SECS),
+                    ),
+                    rpc_timeout: Duration::from_secs(
+                        settings
+                            .cln
+                            .rpc_timeout_secs
+                            .unwrap_or(DEFAULT_CLN_RPC_TIMEOUT_SECS),
+                    ),
+                    max_fee_percent: settings.cln.max_fee_percent,
+                    retry_for_seconds: settings.cln.retry_for_seconds,
+                    max_delay_blocks: settings.cln.max_delay_blocks,
+                },
+                MintMethodSettings::default(),
+                MeltMethodSettings::default(),
+                price_cache,
+                db.clone(),
+            )
+            .await?;
+
+            LightningBackend::Cln(Arc::new(cln))
+        }
+        LnBackend::Phoenixd => {
+            let phoenixd = Phoenixd::new(
+                PhoenixdSettings {
+                    url: settings.phoenixd.url.clone(),
+                    api_password: settings.phoenixd.api_password.clone(),
+                    fee_reserve,
+                    runtime_settings: runtime_settings.clone(),
+                    mint_name: settings.mint_info.name.clone(),
+                },
+                MintMethodSettings::default(),
+                MeltMethodSettings::default(),
+                price_cache,
+                db.clone(),
+            )?;
+
+            LightningBackend::Phoenixd(Arc::new(phoenixd))
+        }
+    };
+
+    let ln_backend: Arc<dyn MintLightning<Err = cdk_lightning::Error> + Send + Sync> =
+        match &lightning {
+            LightningBackend::Cln(cln) => Arc::clone(cln) as _,
+            LightningBackend::Phoenixd(phoenixd) => Arc::clone(phoenixd) as _,
+            LightningBackend::Dev(dev) => Arc::clone(dev) as _,
+        };
+
+    let search_unit = CurrencyUnit::from_str("XSR")?;
+    ln_backends.insert(LnKey::new(search_unit, PaymentMethod::Bolt11), ln_backend);
+    let input_fee_ppk = settings.info.input_fee_ppk.unwrap_or(0);
+    let keyset_max_order = settings
+        .info
+        .keyset_max_order
+        .unwrap_or(DEFAULT_KEYSET_MAX_ORDER);
+    supported_units.insert(search_unit, (input_fee_ppk, keyset_max_order));
+
+    let nut04_settings = nut04::Settings::new(
+        vec![MintMethodSettings {
+            method: PaymentMethod::Bolt11,
+            unit: search_unit,
+            min_amount: Some(1.into()),
+            max_amount: Some(100.into()),
+            description: true,
+        }],
+        false,
+    );
+
+    let nut05_settings = nut05::Settings::new(
+        vec![MeltMethodSettings {
+            method: PaymentMethod::Bolt11,
+            unit: search_unit,
+            min_amount: None,
+            max_amount: None,
+        }],
+        true,
+    );
+
+    let nuts = Nuts::new()
+        .nut04(nut04_settings)
+        .nut05(nut05_settings)
+        .nut07(true)
+        .nut08(true)
+        .nut09(true)
+        .nut10(true)
+        .nut11(true)
+        .nut12(true)
+        .nut14(true);
+
+    let mint_name = settings.mint_info.name.clone();
+
+    let mint_announcement = MintAnnouncement {
+        url: settings.info.url.clone(),
+        name: settings.mint_info.name.clone(),
+        description: settings.mint_info.description.clone(),
+    };
+
+    let landing_page = settings
+        .mint_info
+        .serve_landing_page
+        .unwrap_or(true)
+        .then(|| LandingPage {
+            name: settings.mint_info.name.clone(),
+            description: settings.mint_info.description.clone(),
+            icon_url: settings.mint_info.icon_url.clone(),
+            link_docs: settings.search_settings.openapi.unwrap_or(false),
+        });
+
+    let mut mint_info = MintInfo::new()
+        .name(settings.mint_info.name)
+        .version(mint_version)
+        .description(settings.mint_info.description)
+        .nuts(nuts);
+
+    if let Some(long_description) = &settings.mint_info.description_long {
+        mint_info = mint_info.long_description(long_description);
+    }
+
+    if !contact_info.is_empty() {
+        mint_info = mint_info.contact_info(contact_info);
+    }
+
+    if let Some(pubkey) = settings.mint_info.pubkey {
+        mint_info = mint_info.pubkey(pubkey);
+    }
+
+    if let Some(icon_url) = &settings.mint_info.icon_url {
+        mint_info = mint_info.icon_url(icon_url);
+    }
+
+    if let Some(motd) = settings.mint_info.motd {
+        mint_info = mint_info.motd(motd);
+    }
+
+    let quote_ttl = QuoteTTL::new(DEFAULT_QUOTE_TTL_SECS, DEFAULT_QUOTE_TTL_SECS);
+
+    let search_der_path = search_keyset_start_path(&localstore, search_unit).await?;
+
+    let mut custom_ders = HashMap::new();
+
+    custom_ders.insert(search_unit, search_der_path);
+
+    let mnemonic = Mnemonic::from_str(&settings.info.mnemonic)?;
+
+    let mint = Mint::new(
+        &settings.info.url,
+        &mnemonic.to_seed_normalized(""),
+        mint_info,
+        quote_ttl,
+        localstore,
+        ln_backends.clone(),
+        supported_units,
+        custom_ders,
+    )
+    .await?;
+
+    let mint = Arc::new(mint);
+
+    let listen_addr: SocketAddr = format!(
+        "{}:{}",
+        settings.info.listen_host, settings.info.listen_port
+    )
+    .parse()?;
+
+    let search_listen_addr: Option<SocketAddr> = match (
+        &settings.info.search_listen_host,
+        settings.info.search_listen_port,
+    ) {
+        (Some(host), Some(port)) => Some(format!("{}:{}", host, port).parse()?),
+        _ => None,
+    };
+
+    // Bound before serving so the addresses are known, port 0 included
+    let mint_listener = bind(listen_addr)?;
+    let search_listener = search_listen_addr.map(bind).transpose()?;
+
+    let listen_addr = mint_listener.local_addr()?;
+    let search_listen_addr = search_listener
+        .as_ref()
+        .map(TcpListener::local_addr)
+        .transpose()?;
+
+    let tls = match (&settings.info.tls_cert_path, &settings.info.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            let expand = |path: &PathBuf| {
+                path.to_str()
+                    .and_then(expand_path)
+                    .ok_or(anyhow!("Invalid tls path {}", path.display()))
+            };
+            let (cert_path, key_path) = (expand(cert_path)?, expand(key_path)?);
+
+            let tls_config = tls::load_tls_config(&cert_path, &key_path)
+                .map_err(|err| anyhow!("Could not load TLS certificate: {}", err))?;
+
+            Some((tls_config, cert_path, key_path))
+        }
+        _ => None,
+    };
+
+    let cache_ttl = settings
+        .info
+        .seconds_to_cache_requests_for
+        .unwrap_or(DEFAULT_CACHE_TTL_SECS);
+    let cache_tti = settings
+        .info
+        .seconds_to_extend_cache_by
+        .unwrap_or(DEFAULT_CACHE_TTI_SECS);
+
+    let v1_service = cdk_axum::create_mint_router(Arc::clone(&mint), cache_ttl, cache_tti).await?;
+
+    // Searches that were paid for but neither served nor refunded before the
+    // last shutdown need to be reconciled by the operator
+    for (payment_id, payment) in db.get_unrefunded_search_payments()? {
+        tracing::warn!(
+            "Search payment {} made at {} was not served or refunded, ys: {:?}",
+            payment_id,
+            payment.created_at,
+            payment.ys
+        );
+    }
+
+    let mint_url = MintUrl::from_str(&settings.info.url)?;
+    let search_price = Amount::from(1);
+    let max_results = settings
+        .search_settings
+        .max_results
+        .unwrap_or(DEFAULT_MAX_RESULTS);
+    let answer_price = Amount::from(
+        settings
+            .search_settings
+            .answer_price
+            .unwrap_or(DEFAULT_ANSWER_PRICE),
+    );
+
+    let summarize_price = Amount::from(
+        settings
+            .search_settings
+            .summarize_price
+            .unwrap_or(DEFAULT_SUMMARIZE_PRICE),
+    );
+
+    let info = Info {
+        mint: mint_url.clone(),
+        name: mint_name,
+        // The mint info of this cdk version has no fields for these, they
+        // are published with the search api info instead
+        tos_url: settings.mint_info.tos_url,
+        privacy_policy_url: settings.mint_info.privacy_policy_url,
+        unit: search_unit.to_string(),
+        search_price,
+        answer_price,
+        summarize_price,
+        cost_per_search_cents: runtime_settings.load().cost_per_search_cents,
+        price_currency: price_currency.to_string(),
+        input_fee_ppk,
+        max_results,
+        max_batch_size: MAX_BATCH_SIZE,
+        token_versions: TOKEN_VERSIONS.iter().map(|v| v.to_string()).collect(),
+        endpoints: PAID_ENDPOINTS.iter().map(|e| e.to_string()).collect(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+    };
+
+    let default_region = match settings.search_settings.default_region {
+        Some(region) => {
+            Some(parse_region(&region).ok_or(anyhow!("Invalid default_region {}", region))?)
+        }
+        None => None,
+    };
+
+    let search_settings = Settings {
+        mint_url,
+        unit: search_unit,
+        search_price,
+        answer_price,
+        summarize_price,
+        max_results,
+        default_region,
+        safesearch: settings.search_settings.safesearch,
+        openapi: settings.search_settings.openapi.unwrap_or(false),
+        strip_html: settings.search_settings.strip_html.unwrap_or(false),
+        dedup_results: settings.search_settings.dedup_results.unwrap_or(true),
+        session_idle_secs: settings
+            .search_settings
+            .session_idle_secs
+            .unwrap_or(DEFAULT_SESSION_IDLE_SECS),
+        operator_token: settings.search_settings.operator_token,
+        kagi_low_balance: settings.search_settings.kagi_low_balance,
+        landing_page,
+        listeners: Listeners {
+            mint: listen_addr.to_string(),
+            search: search_listen_addr.unwrap_or(listen_addr).to_string(),
+        },
+    };
+
+    let kagi_timeout = Duration::from_secs(
+        settings
+            .search_settings
+            .kagi_timeout_secs
+            .unwrap_or(DEFAULT_KAGI_TIMEOUT_SECS),
+    );
+
+    let kagi_max_retries = settings
+        .search_settings
+        .kagi_max_retries
+        .unwrap_or(DEFAULT_KAGI_MAX_RETRIES);
+
+    let kagi = Arc::new(KagiProvider::new(
+        settings
+            .search_settings
+            .kagi_base_url
+            .as_deref()
+            .unwrap_or(DEFAULT_KAGI_BASE_URL),
+        settings.search_settings.kagi_auth_token,
+        kagi_timeout,
+        kagi_max_retries,
+    )?);
+
+    let search_provider: Arc<dyn SearchProvider + Send + Sync> =
+        match settings.search_settings.provider {
+            SearchProviderKind::Kagi => Arc::clone(&kagi) as Arc<dyn SearchProvider + Send + Sync>,
+            SearchProviderKind::Brave => {
+                let brave_auth_token = settings.search_settings.brave_auth_token.ok_or(anyhow!(
+                    "brave_auth_token is required for the brave provider"
+                ))?;
+
+                let brave_timeout = Duration::from_secs(
+                    settings
+                        .search_settings
+                        .brave_timeout_secs
+                        .unwrap_or(DEFAULT_BRAVE_TIMEOUT_SECS),
+                );
+
+                Arc::new(BraveProvider::new(brave_auth_token, brave_timeout)?)
+            }
+        };
+
+    if settings.nostr.announce.unwrap_or(false) {
+        tokio::spawn(nostr::announce_periodically(
+            settings.nostr.clone(),
+            mnemonic.clone(),
+            mint_announcement,
+            db.clone(),
+            Duration::from_secs(
+                settings
+                    .nostr
+                    .status_interval_secs
+                    .unwrap_or(DEFAULT_NOSTR_STATUS_INTERVAL_SECS),
+            ),
+        ));
+    }
+
+    if let Some((alert_settings, alert_price_cache)) = alerting {
+        tokio::spawn(alerts::monitor(
+            alert_settings,
+            mnemonic.clone(),
+            Arc::clone(&kagi),
+            lightning.clone(),
+            alert_price_cache,
+        ));
+    }
+
+    tokio::spawn({
+        let db = db.clone();
+
+        async move {
+            let mut prune_interval = tokio::time::interval(Duration::from_secs(24 * 60 * 60));
+
+            loop {
+                prune_interval.tick().await;
+
+                let before = unix_time().saturating_sub(USED_YS_RETENTION_SECS);
+
+                match db.prune_used_ys(before) {
+                    Ok(pruned) => tracing::debug!("Pruned {} used ys", pruned),
+                    Err(err) => tracing::warn!("Could not prune used ys: {}", err),
+                }
+            }
+        }
+    });
+
+    let analytics = match settings.search_settings.analytics {
+        Analytics::Off => None,
+        Analytics::Hashed => Some(QueryAnalytics::load_or_create(work_dir)?),
+    };
+
+    let compaction_interval_secs = settings
+        .search_settings
+        .db_compaction_interval_secs
+        .unwrap_or(DEFAULT_DB_COMPACTION_INTERVAL_SECS);
+
+    if compaction_interval_secs > 0 {
+        tokio::spawn(compact_periodically(
+            db.clone(),
+            Duration::from_secs(compaction_interval_secs),
+            settings
+                .search_settings
+                .db_compaction_fragmented_percent
+                .unwrap_or(DEFAULT_DB_COMPACTION_FRAGMENTED_PERCENT),
+        ));
+    }
+
+    let api_state = ApiState {
+        info,
+        mint: Arc::clone(&mint),
+        lightning,
+        settings: search_settings,
+        search_provider,
+        kagi,
+        rate_limiter: RateLimiter::new(
+            settings.search_settings.rate_limit_per_minute,
+            settings.search_settings.invalid_rate_limit_per_minute,
+            settings.search_settings.trusted_proxies.clone(),
+        ),
+        search_cache: SearchCache::new(
+            settings
+                .search_settings
+                .search_cache_ttl_secs
+                .unwrap_or(DEFAULT_SEARCH_CACHE_TTL_SECS),
+            settings
+                .search_settings
+                .search_cache_size
+                .unwrap_or(DEFAULT_SEARCH_CACHE_SIZE),
+        ),
+        runtime_settings: runtime_settings.clone(),
+        db: Arc::new(db),
+        analytics,
+        started_at: unix_time(),
+        stats_cache: Arc::new(RwLock::new(None)),
+        search_provider_health_cache: Arc::new(RwLock::new(None)),
+    };
+
+    let kagi = Arc::clone(&api_state.kagi);
+    let accounting_db = Arc::clone(&api_state.db);
+
+    let search_router = search_router(api_state);
+    let v1_service = v1_service
+        .layer(middleware::from_fn_with_state(
+            accounting_db,
+            keyset_accounting::account_mint_api,
+        ))
+        .layer(CorsLayer::permissive());
+
+    let wait_invoice_shutdown = Arc::new(Notify::new());
+
+    tokio::spawn({
+        let shutdown = Arc::clone(&wait_invoice_shutdown);
+        async move { mint.wait_for_paid_invoices(shutdown).await }
+    });
+
+    let tls_config = match tls {
+        Some((tls_config, cert_path, key_path)) => {
+            tokio::spawn({
+                let tls_config = tls_config.clone();
+
+                async move {
+                    if let Err(err) = tls::reload_on_sighup(tls_config, cert_path, key_path).await {
+                        tracing::error!("TLS certificate reload on SIGHUP is unavailable: {}", err);
+                    }
+                }
+            });
+
+            Some(tls_config)
+        }
+        None => None,
+    };
+
+    let stop_servers = CancellationToken::new();
+
+    tokio::spawn({
+        let stop_servers = stop_servers.clone();
+
+        async move {
+            shutdown.await;
+            stop_servers.cancel();
+        }
+    });
+
+    let task = tokio::spawn(async move {
+        let axum_result = match search_listener {
+            Some(search_listener) => {
+                // Whichever server stops first takes the other down with it
+                let stop_on_exit = |server_result: anyhow::Result<()>| {
+                    stop_servers.cancel();
+                    server_result
+                };
+
+                let (mint_result, search_result) = tokio::join!(
+                    async {
+                        stop_on_exit(
+                            serve(
+                                v1_service,
+                                mint_listener,
+                                tls_config.clone(),
+                                stop_servers.clone(),
+                            )
+                            .await,
+                        )
+                    },
+                    async {
+                        stop_on_exit(
+                            serve(
+                                search_router,
+                                search_listener,
+                                tls_config.clone(),
+                                stop_servers.clone(),
+                            )
+                            .await,
+                        )
+                    }
+                );
+
+                mint_result.and(search_result)
+            }
+            None => {
+                let mint_service = Router::new().merge(v1_service).merge(search_router);
+
+                serve(mint_service, mint_listener, tls_config, stop_servers).await
+            }
+        };
+
+        wait_invoice_shutdown.notify_waiters();
+
+        for ln_backend in ln_backends.values() {
+            ln_backend.cancel_wait_invoice();
+        }
+
+        drop(work_dir_lock);
+
+        match axum_result {
+            Ok(_) => {
+                tracing::info!("Axum server stopped with okay status");
+            }
+            Err(err) => {
+                tracing::warn!("Axum server stopped with error");
+                tracing::error!("{}", err);
+
+                bail!("Axum exited with error")
+            }
+        }
+
+        Ok(())
+    });
+
+    Ok(RunningServer {
+        mint_addr: listen_addr,
+        search_addr: search_listen_addr.unwrap_or(listen_addr),
+        runtime_settings,
+        kagi,
+        task,
+    })
+}
+
+/// Settings that are reloaded on SIGHUP
+pub fn runtime_settings_from(settings: &config::Settings) -> RuntimeSettings {
+    RuntimeSettings {
+        cost_per_search_cents: settings
+            .search_settings
+            .cost_per_search_cents
+            .unwrap_or(DEFAULT_COST_PER_SEARCH_CENTS),
+        cors_allowed_origins: settings.search_settings.cors_allowed_origins.clone(),
+        domain_filter: DomainFilter::new(
+            settings.search_settings.blocked_domains.clone(),
+            settings.search_settings.boosted_domains.clone(),
+        ),
+    }
+}
+
+/// Serve `router` on `listener` until it fails or `stop` is cancelled, over TLS
+/// when `tls_config` is set
+async fn serve(
+    router: Router,
+    listener: TcpListener,
+    tls_config: Option<RustlsConfig>,
+    stop: CancellationToken,
+) -> anyhow::Result<()> {
+    let addr = listener.local_addr()?;
+    let make_service = router.into_make_service_with_connect_info::<SocketAddr>();
+
+    match tls_config {
+        Some(tls_config) => {
+            let handle = Handle::new();
+
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    stop.cancelled().await;
+                    handle.graceful_shutdown(None);
+                }
+            });
+
+            tracing::info!("Serving https on {}", addr);
+
+            axum_server::from_tcp_rustls(listener, tls_config)
+                .handle(handle)
+                .serve(make_service)
+                .await?;
+        }
+        None => {
+            tracing::info!("Serving http on {}", addr);
+
+            axum::Server::from_tcp(listener)?
+                .serve(make_service)
+                .with_graceful_shutdown(stop.cancelled())
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Add the next XSR keyset and make it the active one, returning the ids of
+/// the keysets deactivated and of the new keyset
+///
+/// The mint must be stopped. Deactivated keysets stay in the database so
+/// tokens issued under them can still be spent, the mint only stops issuing
+/// under them. The new keyset is made with the configured fee and max order,
+/// so the mint keeps it active when it next starts.
+pub async fn rotate_search_keyset(
+    settings: &config::Settings,
+    work_dir: &Path,
+) -> anyhow::Result<(Vec<Id>, Id)> {
+    let _work_dir_lock = lock_work_dir(work_dir)?;
+
+    let redb_path = db_path(&settings.info.mint_db_path, work_dir, "cdk-mintd.redb")?;
+    let localstore = MintRedbDatabase::new(&redb_path)?;
+
+    let search_unit = CurrencyUnit::from_str("XSR")?;
+
+    let keyset_infos: Vec<MintKeySetInfo> = localstore
+        .get_keyset_infos()
+        .await?
+        .into_iter()
+        .filter(|keyset_info| keyset_info.unit == search_unit)
+        .collect();
+
+    let index = keyset_infos
+        .iter()
+        .filter_map(|keyset_info| keyset_info.derivation_path_index)
+        .max()
+        .ok_or(anyhow!(
+            "There is no XSR keyset yet, start the mint once first"
+        ))?
+        + 1;
+
+    let derivation_path = search_keyset_derivation_path(index)?;
+    let max_order = settings
+        .info
+        .keyset_max_order
+        .unwrap_or(DEFAULT_KEYSET_MAX_ORDER);
+
+    let secp = Secp256k1::new();
+    let mnemonic = Mnemonic::from_str(&settings.info.mnemonic)?;
+    let xpriv = Xpriv::new_master(Network::Bitcoin, &mnemonic.to_seed_normalized(""))?;
+
+    let keyset = MintKeySet::generate_from_xpriv(
+        &secp,
+        xpriv,
+        max_order,
+        search_unit,
+        derivation_path.clone(),
+    );
+
+    let mut old_ids = Vec::new();
+
+    for mut keyset_info in keyset_infos {
+        if keyset_info.active {
+            keyset_info.active = false;
+            old_ids.push(keyset_info.id);
+            localstore.add_keyset_info(keyset_info).await?;
+        }
+    }
+
+    localstore
+        .add_keyset_info(MintKeySetInfo {
+            id: keyset.id,
+            unit: search_unit,
+            active: true,
+            valid_from: unix_time(),
+            valid_to: None,
+            derivation_path,
+            derivation_path_index: Some(index),
+            max_order,
+            input_fee_ppk: settings.info.input_fee_ppk.unwrap_or(0),
+        })
+        .await?;
+
+    localstore.set_active_keyset(search_unit, keyset.id).await?;
+
+    tracing::info!("Rotated XSR keyset to {} at index {}", keyset.id, index);
+
+    Ok((old_ids, keyset.id))
+}
+
+/// Derivation path given to cdk for an XSR keyset it creates on start
+///
+/// `Mint::new` records a new keyset one past the highest derivation path index
+/// of the unit, 0 for the first, but derives its keys from the custom path as
+/// is. Building the path from that same index keeps the keys at the index the
+/// keyset claims, as [`rotate_search_keyset`] does.
+pub(crate) async fn search_keyset_start_path(
+    localstore: &MintRedbDatabase,
+    search_unit: CurrencyUnit,
+) -> anyhow::Result<DerivationPath> {
+    let keyset_infos: Vec<MintKeySetInfo> = localstore
+        .get_keyset_infos()
+        .await?
+        .into_iter()
+        .filter(|keyset_info| keyset_info.unit == search_unit)
+        .collect();
+
+    let index = match keyset_infos
+        .iter()
+        .filter_map(|keyset_info| keyset_info.derivation_path_index)
+        .max()
+    {
+        Some(index) => index + 1,
+        None if keyset_infos.is_empty() => 0,
+        // cdk skips keysets without an index and starts again at 1
+        None => 1,
+    };
+
+    Ok(search_keyset_derivation_path(index)?)
+}
+
+/// Publish the mint announcement and a status note once
+pub async fn announce_now(settings: &config::Settings, work_dir: &Path) -> anyhow::Result<()> {
+    let search_count = {
+        let _work_dir_lock = lock_work_dir(work_dir)?;
+
+        let db = Db::new(
+            &db_path(
+                &settings.info.search_db_path,
+                work_dir,
+                "athenmint_search_api.redb",
+            )?,
+            settings
+                .search_settings
+                .search_count_retention_days
+                .unwrap_or(DEFAULT_SEARCH_COUNT_RETENTION_DAYS),
+        )?;
+
+        db.get_search_count()?.all_time_search_count
+    };
+
+    let announcer = Announcer::new(
+        &settings.nostr,
+        &Mnemonic::from_str(&settings.info.mnemonic)?,
+        MintAnnouncement {
+            url: settings.info.url.clone(),
+            name: settings.mint_info.name.clone(),
+            description: settings.mint_info.description.clone(),
+        },
+    )
+    .await?;
+
+    let result = async {
+        announcer.announce().await?;
+        announcer.publish_status(search_count).await
+    }
+    .await;
+
+    announcer.disconnect().await;
+
+    Ok(result?)
+}
+
+/// Compact the search db when fragmented, first at
+/// [`DB_COMPACTION_HOUR_UTC`] and then every `interval`
+async fn compact_periodically(db: Db, interval: Duration, min_fragmented_percent: u64) {
+    let secs_into_day = unix_time() % (24 * 60 * 60);
+    let until_first =
+        (DB_COMPACTION_HOUR_UTC * 60 * 60 + 24 * 60 * 60 - secs_into_day) % (24 * 60 * 60);
+
+    tokio::time::sleep(Duration::from_secs(until_first)).await;
+
+    let mut compaction_interval = tokio::time::interval(interval);
+
+    loop {
+        compaction_interval.tick().await;
+
+        for _ in 0..=DB_COMPACTION_RETRIES {
+            let compaction = tokio::task::spawn_blocking({
+                let db = db.clone();
+                move || db.compact_if_fragmented(min_fragmented_percent)
+            })
+            .await;
+
+            match compaction {
+                Ok(Ok(Compaction::Compacted {
+                    before_bytes,
+                    after_bytes,
+                })) => {
+                    tracing::info!(
+                        "Compacted search db from {} to {} bytes",
+                        before_bytes,
+                        after_bytes
+                    );
+                    break;
+                }
+                Ok(Ok(Compaction::NotNeeded { fragmented_percent })) => {
+                    tracing::debug!(
+                        "Search db is {}% fragmented, not compacting",
+                        fragmented_percent
+                    );
+                    break;
+                }
+                Ok(Ok(Compaction::Busy)) => {
+                    tracing::debug!("Search db is busy, retrying compaction in a minute");
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                }
+                Ok(Err(err)) => {
+                    tracing::warn!("Could not compact search db: {}", err);
+                    break;
+                }
+                Err(err) => {
+                    tracing::warn!("Search db compaction task failed: {}", err);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Every table of the search api database
+///
+/// Takes the work dir lock, so the mint can not be running.
+pub fn export_search_db(settings: &config::Settings, work_dir: &Path) -> anyhow::Result<DbDump> {
+    let _work_dir_lock = lock_work_dir(work_dir)?;
+
+    open_search_db(settings, work_dir)?.export()
+}
+
+/// Restore `dump` into the search api database
+///
+/// Takes the work dir lock, so the mint can not be running.
+pub fn import_search_db(
+    settings: &config::Settings,
+    work_dir: &Path,
+    dump: &DbDump,
+    merge: bool,
+) -> anyhow::Result<()> {
+    let _work_dir_lock = lock_work_dir(work_dir)?;
+
+    open_search_db(settings, work_dir)?.import(dump, merge)
+}
+
+/// Per keyset totals recorded by the search api next to the totals in the
+/// mint db, for every keyset either knows of
+///
+/// Takes the work dir lock, so the mint can not be running. Neither database
+/// is written to.
+pub async fn audit(
+    settings: &config::Settings,
+    work_dir: &Path,
+) -> anyhow::Result<Vec<KeysetAudit>> {
+    let _work_dir_lock = lock_work_dir(work_dir)?;
+
+    let redb_path = db_path(&settings.info.mint_db_path, work_dir, "cdk-mintd.redb")?;
+    let localstore = MintRedbDatabase::new(&redb_path)?;
+
+    let mut recorded: HashMap<String, KeysetIssuance> = open_search_db(settings, work_dir)?
+        .get_issuance_summary()?
+        .into_iter()
+        .map(|issuance| (issuance.keyset_id.clone(), issuance))
+        .collect();
+
+    let mut keyset_ids: Vec<Id> = localstore
+        .get_keyset_infos()
+        .await?
+        .into_iter()
+        .map(|keyset_info| keyset_info.id)
+        .collect();
+
+    for keyset_id in recorded.keys() {
+        let keyset_id = Id::from_str(keyset_id)?;
+
+        if !keyset_ids.contains(&keyset_id) {
+            keyset_ids.push(keyset_id);
+        }
+    }
+
+    let mut audits = Vec::with_capacity(keyset_ids.len());
+
+    for keyset_id in keyset_ids {
+        let mint_issued = localstore
+            .get_blind_signatures_for_keyset(&keyset_id)
+            .await?
+            .iter()
+            .map(|signature| u64::from(signature.amount))
+            .sum();
+
+        let (proofs, states) = localstore.get_proofs_by_keyset_id(&keyset_id).await?;
+
+        let mint_redeemed = proofs
+            .iter()
+            .zip(states)
+            .filter(|(_, state)| *state == Some(ProofState::Spent))
+            .map(|(proof, _)| u64::from(proof.amount))
+            .sum();
+
+        let recorded = recorded
+            .remove(&keyset_id.to_string())
+            .unwrap_or_else(|| KeysetIssuance {
+                keyset_id: keyset_id.to_string(),
+                ..Default::default()
+            });
+
+        audits.push(KeysetAudit {
+            keyset_id,
+            recorded,
+            mint_issued,
+            mint_redeemed,
+        });
+    }
+
+    Ok(audits)
+}
+
+fn open_search_db(settings: &config::Settings, work_dir: &Path) -> anyhow::Result<Db> {
+    Db::new(
+        &db_path(
+            &settings.info.search_db_path,
+            work_dir,
+            "athenmint_search_api.redb",
+        )?,
+        settings
+            .search_settings
+            .search_count_retention_days
+            .unwrap_or(DEFAULT_SEARCH_COUNT_RETENTION_DAYS),
+    )
+}
+
+/// Check the config, databases, lightning node, price sources and search
+/// provider before serving, logging the outcome of each
+///
+/// An invalid config stops the checks before anything is opened or probed.
+/// A failing price source only warns, as the price can be fetched later.
+/// Every other failure is an error.
+pub async fn self_check(settings: &config::Settings, work_dir: &Path) -> anyhow::Result<()> {
+    // Databases and probes fail in confusing ways on an invalid config
+    match settings.validate() {
+        Ok(()) => tracing::info!("Startup check config: ok"),
+        Err(err) => {
+            tracing::error!("Startup check config: {}", err);
+            bail!("Startup checks failed: config, fix it before starting");
+        }
+    }
+
+    let mut failed = Vec::new();
+
+    let mut report = |check: &str, result: anyhow::Result<()>| match result {
+        Ok(()) => tracing::info!("Startup check {}: ok", check),
+        Err(err) => {
+            tracing::error!("Startup check {}: {}", check, err);
+            failed.push(check.to_string());
+        }
+    };
+
+    // Held while the databases are opened, `run` takes it again
+    let db = match lock_work_dir(work_dir) {
+        Ok(_work_dir_lock) => {
+            report("work dir lock", Ok(()));
+
+            let mint_db = db_path(&settings.info.mint_db_path, work_dir, "cdk-mintd.redb")
+                .and_then(|path| Ok(MintRedbDatabase::new(&path)?));
+            report("mint database", mint_db.map(|_| ()));
+
+            let db = db_path(
+                &settings.info.search_db_path,
+                work_dir,
+                "athenmint_search_api.redb",
+            )
+            .and_then(|path| {
+                Db::new(
+                    &path,
+                    settings
+                        .search_settings
+                        .search_count_retention_days
+                        .unwrap_or(DEFAULT_SEARCH_COUNT_RETENTION_DAYS),
+                )
+            });
+
+            match db {
+                Ok(db) => {
+                    report("search database", db.check());
+                    Some(db)
+                }
+                Err(err) => {
+                    report("search database", Err(err));
+                    None
+                }
+            }
+        }
+        Err(err) => {
+            report("work dir lock", Err(err));
+            None
+        }
+    };
+
+    let price_cache = price_cache(settings)?;
+
+    match (
+        settings.ln.enable_ln.unwrap_or(true),
+        settings.ln.ln_backend,
+        db,
+    ) {
+        (false, _, _) => tracing::info!("Startup check lightning: disabled"),
+        (true, LnBackend::Cln, _) => report("cln", probe_cln(settings).await),
+        (true, LnBackend::Phoenixd, Some(db)) => {
+            let phoenixd = Phoenixd::new(
+                PhoenixdSettings {
+                    url: settings.phoenixd.url.clone(),
+                    api_password: settings.phoenixd.api_password.clone(),
+                    fee_reserve: FeeReserve {
+                        min_fee_reserve: settings.ln.reserve_fee_min,
+                        percent_fee_reserve: settings.ln.fee_percent,
+                    },
+                    runtime_settings: SharedRuntimeSettings::default(),
+                    mint_name: settings.mint_info.name.clone(),
+                },
+                MintMethodSettings::default(),
+                MeltMethodSettings::default(),
+                price_cache.clone(),
+                db,
+            )?;
+
+            report(
+                "phoenixd",
+                phoenixd
+                    .check_connection()
+                    .await
+                    .map_err(anyhow::Error::from),
+            );
+        }
+        (true, LnBackend::Phoenixd, None) => {
+            tracing::warn!("Startup check phoenixd: skipped, the database did not open")
+        }
+    }
+
+    let kagi = KagiProvider::new(
+        settings
+            .search_settings
+            .kagi_base_url
+            .as_deref()
+            .unwrap_or(DEFAULT_KAGI_BASE_URL),
+        settings.search_settings.kagi_auth_token.clone(),
+        Duration::from_secs(
+            settings
+                .search_settings
+                .kagi_timeout_secs
+                .unwrap_or(DEFAULT_KAGI_TIMEOUT_SECS),
+        ),
+        0,
+    )?;
+    report("kagi", kagi.check().await.map_err(anyhow::Error::from));
+
+    if settings.search_settings.provider == SearchProviderKind::Brave {
+        let brave = settings
+            .search_settings
+            .brave_auth_token
+            .clone()
+            .ok_or(anyhow!(
+                "brave_auth_token is required for the brave provider"
+            ))
+            .and_then(|token| {
+                Ok(BraveProvider::new(
+                    token,
+                    Duration::from_secs(
+                        settings
+                            .search_settings
+                            .brave_timeout_secs
+                            .unwrap_or(DEFAULT_BRAVE_TIMEOUT_SECS),
+                    ),
+                )?)
+            });
+
+        let result = match brave {
+            Ok(brave) => brave.check().await.map_err(anyhow::Error::from),
+            Err(err) => Err(err),
+        };
+        report("brave", result);
+    }
+
+    match price_cache.get_price().await {
+        Ok(price) => tracing::info!(
+            "Startup check price: ok, {} {}",
+            price,
+            price_cache.currency()
+        ),
+        Err(err) => tracing::warn!(
+            "Startup check price: {}, continuing as it is fetched again on use",
+            err
+        ),
+    }
+
+    if !failed.is_empty() {
+        bail!(
+            "Startup checks failed: {}, fix them or start with --skip-checks",
+            failed.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Call getinfo on the configured lightningd rpc socket
+pub async fn probe_cln(settings: &config::Settings) -> anyhow::Result<()> {
+    let rpc_socket = settings
+        .cln
+        .rpc_path
+        .to_str()
+        .and_then(expand_path)
+        .ok_or(anyhow!("cln socket not defined"))?;
+
+    let timeout = Duration::from_secs(
+        settings
+            .cln
+            .rpc_timeout_secs
+            .unwrap_or(DEFAULT_CLN_RPC_TIMEOUT_SECS),
+    );
+
+    tokio::time::timeout(timeout, async {
+        let mut transport = SocketTransport::new(rpc_socket).await?;
+
+        transport
+            .call(cln_rpc::Request::Getinfo(
+                cln_rpc::model::requests::GetinfoRequest {},
+            ))
+            .await?;
+
+        anyhow::Ok(())
+    })
+    .await
+    .map_err(|_| anyhow!("timed out"))?
+}
+
+fn price_cache(settings: &config::Settings) -> anyhow::Result<PriceCache> {
+    let price_sources = match &settings.price.sources {
+        Some(sources) if sources.is_empty() => bail!("At least one price source is required"),
+        Some(sources) => sources.clone(),
+        None => PriceSource::DEFAULT.to_vec(),
+    };
+
+    Ok(PriceCache::new(
+        Duration::from_secs(
+            settings
+                .price
+                .cache_ttl_secs
+                .unwrap_or(DEFAULT_PRICE_CACHE_TTL_SECS),
+        ),
+        Duration::from_secs(
+            settings
+                .price
+                .max_stale_secs
+                .unwrap_or(DEFAULT_PRICE_MAX_STALE_SECS),
+        ),
+        settings.search_settings.price_currency.unwrap_or_default(),
+        price_sources,
+        settings.price.min_price.unwrap_or(DEFAULT_MIN_BTC_PRICE),
+        settings.price.max_price.unwrap_or(DEFAULT_MAX_BTC_PRICE),
+    ))
+}
+
+/// Lock the `LOCK` file in `work_dir` for as long as the returned file is
+/// open, so a second instance can not open the same databases
+fn lock_work_dir(work_dir: &Path) -> anyhow::Result<File> {
+    let lock_path = work_dir.join("LOCK");
+
+    let mut lock_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+        .map_err(|err| anyhow!("Could not open {}: {}", lock_path.display(), err))?;
+
+    if let Err(err) = lock_file.try_lock_exclusive() {
+        if err.kind() != fs2::lock_contended_error().kind() {
+            bail!("Could not lock {}: {}", lock_path.display(), err);
+        }
+
+        let mut pid = String::new();
+        let _ = lock_file.read_to_string(&mut pid);
+
+        bail!(
+            "Another instance is running (pid {}) in {}",
+            pid.trim(),
+            work_dir.display()
+        );
+    }
+
+    // Only for the error message of the next instance, the lock is what counts
+    lock_file.set_len(0)?;
+    write!(lock_file, "{}", std::process::id())?;
+
+    Ok(lock_file)
+}
+
+/// `configured` with `~` expanded, `file_name` in `work_dir` when unset
+fn db_path(
+    configured: &Option<PathBuf>,
+    work_dir: &Path,
+    file_name: &str,
+) -> anyhow::Result<PathBuf> {
+    match configured {
+        Some(path) => path
+            .to_str()
+            .and_then(expand_path)
+            .ok_or(anyhow!("Invalid database path {}", path.display())),
+        None => Ok(work_dir.join(file_name)),
+    }
+}
+
+fn bind(addr: SocketAddr) -> anyhow::Result<TcpListener> {
+    let listener =
+        TcpListener::bind(addr).map_err(|err| anyhow!("Could not listen on {}: {}", addr, err))?;
+
+    // The servers take the listener over into tokio
+    listener.set_nonblocking(true)?;
+
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::extract::ConnectInfo;
+    use axum::http::{Request, StatusCode};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test_utils::{
+        mint_token, peer, search_unit, temp_dir, test_mint, test_state_in, MINT_URL, MNEMONIC,
+    };
+
+    async fn search_keysets(work_dir: &Path) -> Vec<MintKeySetInfo> {
+        let localstore = MintRedbDatabase::new(&work_dir.join("cdk-mintd.redb")).unwrap();
+
+        let mut keyset_infos: Vec<MintKeySetInfo> = localstore
+            .get_keyset_infos()
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|keyset_info| keyset_info.unit == search_unit())
+            .collect();
+        keyset_infos.sort_by_key(|keyset_info| keyset_info.derivation_path_index);
+
+        keyset_infos
+    }
+
+    /// Id of the XSR keyset derived at `index` from the test mnemonic
+    fn keyset_id_at(index: u32) -> Id {
+        let mnemonic = Mnemonic::from_str(MNEMONIC).unwrap();
+        let xpriv = Xpriv::new_master(Network::Bitcoin, &mnemonic.to_seed_normalized("")).unwrap();
+
+        MintKeySet::generate_from_xpriv(
+            &Secp256k1::new(),
+            xpriv,
+            32,
+            search_unit(),
+            search_keyset_derivation_path(index).unwrap(),
+        )
+        .id
+    }
+
+    #[tokio::test]
+    async fn keysets_are_derived_at_the_index_they_record() {
+        let work_dir = temp_dir();
+
+        drop(test_mint(&work_dir, &[search_unit()], 0).await);
+        // A fee change makes cdk create the next keyset on start
+        drop(test_mint(&work_dir, &[search_unit()], 100).await);
+
+        let keysets = search_keysets(&work_dir).await;
+        assert_eq!(keysets.len(), 2);
+
+        for (index, keyset_info) in (0..).zip(&keysets) {
+            assert_eq!(keyset_info.derivation_path_index, Some(index));
+            assert_eq!(
+                keyset_info.derivation_path,
+                search_keyset_derivation_path(index).unwrap()
+            );
+            assert_eq!(keyset_info.id, keyset_id_at(index));
+        }
+    }
+
+    #[tokio::test]
+    async fn rotated_keyset_is_kept_active_across_restarts() {
+        let work_dir = temp_dir();
+
+        drop(test_mint(&work_dir, &[search_unit()], 100).await);
+        let first_id = keyset_id_at(0);
+
+        let mut settings = config::Settings::default();
+        settings.info.mnemonic = MNEMONIC.to_string();
+        settings.info.input_fee_ppk = Some(100);
+        settings.info.keyset_max_order = Some(32);
+
+        let (old_ids, new_id) = rotate_search_keyset(&settings, &work_dir).await.unwrap();
+        assert_eq!(old_ids, vec![first_id]);
+        assert_eq!(new_id, keyset_id_at(1));
+
+        let keysets = search_keysets(&work_dir).await;
+        assert_eq!(keysets.len(), 2);
+        assert!(!keysets[0].active);
+        assert!(keysets[1].active);
+
+        // Unchanged fee and max order, so the rotated keyset is reused
+        let mint = test_mint(&work_dir, &[search_unit()], 100).await;
+        assert_eq!(
+            mint.localstore
+                .get_active_keyset_id(&search_unit())
+                .await
+                .unwrap(),
+            Some(new_id)
+        );
+        drop(mint);
+
+        assert_eq!(search_keysets(&work_dir).await.len(), 2);
+
+        // A fee change after a rotation starts past the rotated index
+        drop(test_mint(&work_dir, &[search_unit()], 200).await);
+
+        let keysets = search_keysets(&work_dir).await;
+        assert_eq!(keysets.len(), 3);
+        assert_eq!(keysets[2].id, keyset_id_at(2));
+    }
+
+    #[tokio::test]
+    async fn self_check_stops_at_an_invalid_config() {
+        let work_dir = temp_dir();
+
+        let err = self_check(&config::Settings::default(), &work_dir)
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().contains("config"));
+        // Neither database was opened
+        assert_eq!(std::fs::read_dir(&work_dir).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn tokens_of_a_rotated_keyset_can_still_be_spent() {
+        let work_dir = temp_dir();
+
+        let state = test_state_in(&work_dir, 0).await;
+        assert_eq!(
+            state
+                .mint
+                .localstore
+                .get_active_keyset_id(&search_unit())
+                .await
+                .unwrap(),
+            Some(keyset_id_at(0))
+        );
+        let token = mint_token(&state.mint, search_unit(), 1).await;
+        drop(state);
+
+        let mut settings = config::Settings::default();
+        settings.info.mnemonic = MNEMONIC.to_string();
+        settings.info.input_fee_ppk = Some(0);
+        settings.info.keyset_max_order = Some(32);
+
+        let (old_ids, new_id) = rotate_search_keyset(&settings, &work_dir).await.unwrap();
+        assert_eq!(old_ids, vec![keyset_id_at(0)]);
+
+        // Restarted on the rotated keyset
+        let state = test_state_in(&work_dir, 0).await;
+        assert_eq!(
+            state
+                .mint
+                .localstore
+                .get_active_keyset_id(&search_unit())
+                .await
+                .unwrap(),
+            Some(new_id)
+        );
+
+        let response = search_router(state)
+            .oneshot(
+                Request::builder()
+                    .uri("/search?q=cashu")
+                    .header("X-Cashu", token)
+                    .extension(ConnectInfo(peer()))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    /// Url of a Kagi api answering searches with the recorded fixture
+    fn fake_kagi() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let fixture: serde_json::Value =
+            serde_json::from_str(include_str!("../fixtures/kagi_search.json")).unwrap();
+
+        let kagi = Router::new().route(
+            "/search",
+            axum::routing::get(move || async move { axum::Json(fixture) }),
+        );
+
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(kagi.into_make_service()),
+        );
+
+        format!("http://{}", addr)
+    }
+
+    /// Settings serving on a free port with lightning disabled, the keyset
+    /// matching [`test_mint`] and searches going to `kagi_url`
+    fn dev_settings(kagi_url: String) -> config::Settings {
+        let mut settings = config::Settings::default();
+
+        settings.info.url = MINT_URL.to_string();
+        settings.info.listen_host = "127.0.0.1".to_string();
+        settings.info.listen_port = 0;
+        settings.info.mnemonic = MNEMONIC.to_string();
+        settings.info.input_fee_ppk = Some(0);
+        settings.info.keyset_max_order = Some(32);
+        settings.mint_info.name = "Test Mint".to_string();
+        settings.ln.enable_ln = Some(false);
+        settings.search_settings.kagi_auth_token = "token".to_string();
+        settings.search_settings.kagi_base_url = Some(kagi_url);
+        settings.search_settings.db_compaction_interval_secs = Some(0);
+
+        settings
+    }
+
+    #[tokio::test]
+    async fn server_boots_on_port_zero_and_serves_info_and_search() {
+        let work_dir = temp_dir();
+
+        // Same mnemonic and keyset as the server, so its tokens are accepted
+        let mint = test_mint(&work_dir, &[search_unit()], 0).await;
+        let token = mint_token(&mint, search_unit(), 1).await;
+        drop(mint);
+
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = run(dev_settings(fake_kagi()), &work_dir, async {
+            let _ = stopped.await;
+        })
+        .await
+        .unwrap();
+
+        assert_ne!(server.mint_addr.port(), 0);
+        assert_eq!(server.search_addr, server.mint_addr);
+
+        let base_url = format!("http://{}", server.search_addr);
+        let client = reqwest::Client::new();
+
+        let response = client
+            .get(format!("{}/info", base_url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let info: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(info["name"], "Test Mint");
+        assert_eq!(info["unit"], search_unit().to_string());
+
+        // The mint api shares the listener
+        let response = client
+            .get(format!("{}/v1/keysets", base_url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+        let response = client
+            .get(format!("{}/search?q=cashu", base_url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::PAYMENT_REQUIRED);
+
+        let response = client
+            .get(format!("{}/search?q=cashu", base_url))
+            .header("X-Cashu", &token)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let body = response.text().await.unwrap();
+        assert!(body.contains("https://cashu.space/"), "{}", body);
+
+        stop.send(()).unwrap();
+        server.wait().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn second_run_on_the_same_work_dir_fails_fast() {
+        let work_dir = temp_dir();
+        let settings = dev_settings(fake_kagi());
+
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let first = run(settings.clone(), &work_dir, async {
+            let _ = stopped.await;
+        })
+        .await
+        .unwrap();
+
+        let second = tokio::time::timeout(
+            Duration::from_secs(5),
+            run(settings.clone(), &work_dir, std::future::pending()),
+        )
+        .await
+        .expect("second run does not wait for the lock");
+
+        match second {
+            Ok(_) => panic!("Second instance started on a locked work dir"),
+            Err(err) => {
+                let err = err.to_string();
+                assert!(err.contains("Another instance is running"), "{}", err);
+                assert!(err.contains(&std::process::id().to_string()), "{}", err);
+            }
+        }
+
+        // The first instance is unaffected
+        let response = reqwest::get(format!("http://{}/info", first.search_addr))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+        stop.send(()).unwrap();
+        first.wait().await.unwrap();
+
+        // Released on shutdown
+        lock_work_dir(&work_dir).unwrap();
+    }
+}
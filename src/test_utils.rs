@@ -0,0 +1,326 @@
+//! Fixtures shared by the unit tests
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use axum::http::HeaderMap;
+use bip39::Mnemonic;
+use cdk::amount::SplitTarget;
+use cdk::cdk_lightning::{self, MintLightning};
+use cdk::dhke::construct_proofs;
+use cdk::mint::Mint;
+use cdk::mint_url::MintUrl;
+use cdk::nuts::{
+    CurrencyUnit, MeltMethodSettings, MintInfo, MintMethodSettings, PaymentMethod, PreMintSecrets,
+    Proofs, Token,
+};
+use cdk::types::{LnKey, QuoteTTL};
+use cdk::Amount;
+use cdk_redb::MintRedbDatabase;
+
+use crate::db::Db;
+use crate::dev_lightning::DevLightning;
+use crate::price::{self, PriceFetcher};
+use crate::rate_limit::RateLimiter;
+use crate::runtime_settings::{RuntimeSettings, SharedRuntimeSettings};
+use crate::search_cache::SearchCache;
+use crate::search_provider::{
+    self, KagiProvider, SearchProvider, SearchQuery, SearchResult, SearchResults,
+};
+use crate::search_route_handlers::{ApiState, Info, LightningBackend, Listeners, Settings};
+use crate::server::search_keyset_start_path;
+
+/// Url the test mint is served under
+pub const MINT_URL: &str = "http://127.0.0.1:8085";
+
+/// Mnemonic of the test mint
+pub const MNEMONIC: &str =
+    "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+/// Empty directory of its own for a test
+pub fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("athenut-mint-test-{}", uuid::Uuid::new_v4()));
+
+    std::fs::create_dir_all(&dir).expect("temp dir can be created");
+
+    dir
+}
+
+/// XSR unit searches are priced in
+pub fn search_unit() -> CurrencyUnit {
+    CurrencyUnit::from_str("XSR").expect("XSR is a valid unit")
+}
+
+/// Mint over a redb database in `dir` with a keyset for each of `units`,
+/// all charging `input_fee_ppk`
+pub async fn test_mint(dir: &std::path::Path, units: &[CurrencyUnit], input_fee_ppk: u64) -> Mint {
+    let localstore =
+        Arc::new(MintRedbDatabase::new(&dir.join("cdk-mintd.redb")).expect("mint db opens"));
+
+    let lightning = Arc::new(DevLightning::new(
+        MintMethodSettings::default(),
+        MeltMethodSettings::default(),
+    ));
+
+    let mut ln_backends: HashMap<
+        LnKey,
+        Arc<dyn MintLightning<Err = cdk_lightning::Error> + Send + Sync>,
+    > = HashMap::new();
+    ln_backends.insert(
+        LnKey::new(search_unit(), PaymentMethod::Bolt11),
+        lightning as _,
+    );
+
+    let supported_units = units
+        .iter()
+        .map(|unit| (*unit, (input_fee_ppk, 32)))
+        .collect();
+
+    let mut custom_ders = HashMap::new();
+    custom_ders.insert(
+        search_unit(),
+        search_keyset_start_path(&localstore, search_unit())
+            .await
+            .expect("mint db readable"),
+    );
+
+    let mnemonic = Mnemonic::from_str(MNEMONIC).expect("valid mnemonic");
+
+    Mint::new(
+        MINT_URL,
+        &mnemonic.to_seed_normalized(""),
+        MintInfo::new(),
+        QuoteTTL::new(60, 60),
+        localstore,
+        ln_backends,
+        supported_units,
+        custom_ders,
+    )
+    .await
+    .expect("mint starts")
+}
+
+/// Search api state over a test mint with XSR and sat keysets, answering
+/// searches with [`FakeProvider`]
+pub async fn test_state(input_fee_ppk: u64) -> ApiState {
+    test_state_in(&temp_dir(), input_fee_ppk).await
+}
+
+/// [`test_state`] keeping its mint and search databases in `dir`
+pub async fn test_state_in(dir: &std::path::Path, input_fee_ppk: u64) -> ApiState {
+    let mint = test_mint(dir, &[search_unit(), CurrencyUnit::Sat], input_fee_ppk).await;
+
+    let db = Db::new(&dir.join("athenmint_search_api.redb"), 400).expect("search db opens");
+
+    let mint_url = MintUrl::from_str(MINT_URL).expect("valid mint url");
+
+    let runtime_settings = SharedRuntimeSettings::new(RuntimeSettings {
+        cost_per_search_cents: 3,
+        ..Default::default()
+    });
+
+    ApiState {
+        info: Info {
+            mint: mint_url.clone(),
+            name: "Test Mint".to_string(),
+            tos_url: None,
+            privacy_policy_url: None,
+            unit: search_unit().to_string(),
+            search_price: Amount::from(1),
+            answer_price: Amount::from(2),
+            summarize_price: Amount::from(5),
+            cost_per_search_cents: 3,
+            price_currency: "USD".to_string(),
+            input_fee_ppk,
+            max_results: 10,
+            max_batch_size: 10,
+            token_versions: Vec::new(),
+            endpoints: Vec::new(),
+            version: "test".to_string(),
+        },
+        mint: Arc::new(mint),
+        lightning: LightningBackend::Dev(Arc::new(DevLightning::new(
+            MintMethodSettings::default(),
+            MeltMethodSettings::default(),
+        ))),
+        settings: Settings {
+            mint_url,
+            unit: search_unit(),
+            search_price: Amount::from(1),
+            answer_price: Amount::from(2),
+            summarize_price: Amount::from(5),
+            max_results: 10,
+            default_region: None,
+            safesearch: None,
+            openapi: false,
+            strip_html: false,
+            dedup_results: true,
+            session_idle_secs: 3600,
+            operator_token: None,
+            kagi_low_balance: None,
+            landing_page: None,
+            listeners: Listeners {
+                mint: "127.0.0.1:8085".to_string(),
+                search: "127.0.0.1:8085".to_string(),
+            },
+        },
+        search_provider: Arc::new(FakeProvider),
+        // Never called, searches go to the fake provider
+        kagi: Arc::new(
+            KagiProvider::new(
+                "http://127.0.0.1:9",
+                String::new(),
+                Duration::from_secs(1),
+                0,
+            )
+            .expect("kagi client builds"),
+        ),
+        rate_limiter: RateLimiter::new(None, None, Vec::new()),
+        search_cache: SearchCache::new(60, 0),
+        runtime_settings,
+        db: Arc::new(db),
+        analytics: None,
+        started_at: 0,
+        stats_cache: Arc::new(RwLock::new(None)),
+        search_provider_health_cache: Arc::new(RwLock::new(None)),
+    }
+}
+
+/// Encoded token worth `amount`, signed by the active keyset of `unit`
+pub async fn mint_token(mint: &Mint, unit: CurrencyUnit, amount: u64) -> String {
+    Token::new(
+        MintUrl::from_str(MINT_URL).expect("valid mint url"),
+        mint_proofs(mint, unit, amount).await,
+        None,
+        Some(unit),
+    )
+    .to_string()
+}
+
+/// Proofs worth `amount`, signed by the active keyset of `unit`
+pub async fn mint_proofs(mint: &Mint, unit: CurrencyUnit, amount: u64) -> Proofs {
+    let keyset_id = mint
+        .localstore
+        .get_active_keyset_id(&unit)
+        .await
+        .expect("mint db readable")
+        .expect("unit has an active keyset");
+
+    let keys = mint
+        .keyset_pubkeys(&keyset_id)
+        .await
+        .expect("keyset is known")
+        .keysets
+        .pop()
+        .expect("keyset has keys")
+        .keys;
+
+    let outputs = PreMintSecrets::random(keyset_id, Amount::from(amount), &SplitTarget::default())
+        .expect("outputs can be made");
+
+    let mut signatures = Vec::new();
+
+    for blinded_message in outputs.blinded_messages() {
+        signatures.push(mint.blind_sign(&blinded_message).await.expect("mint signs"));
+    }
+
+    construct_proofs(signatures, outputs.rs(), outputs.secrets(), &keys)
+        .expect("signatures unblind")
+}
+
+/// Headers paying with `token`
+pub fn payment_headers(token: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("X-Cashu", token.parse().expect("token is a valid header"));
+
+    headers
+}
+
+/// Address requests in tests come from
+pub fn peer() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 40000))
+}
+
+/// Provider answering every query with one result
+pub struct FakeProvider;
+
+#[async_trait]
+impl SearchProvider for FakeProvider {
+    async fn search(&self, query: &SearchQuery) -> Result<SearchResults, search_provider::Error> {
+        Ok(SearchResults {
+            results: vec![SearchResult {
+                url: "https://example.com/".to_string(),
+                title: query.q.clone(),
+                description: None,
+                age: None,
+                image: None,
+            }],
+            related: Vec::new(),
+        })
+    }
+
+    async fn check(&self) -> Result<(), search_provider::Error> {
+        Ok(())
+    }
+}
+
+/// Body of a mempool.space prices response
+pub fn mempool_prices(usd: u64, eur: u64) -> String {
+    format!(
+        r#"{{"time":1728000000,"USD":{},"EUR":{},"GBP":48000,"CAD":82000,"CHF":53000,"AUD":92000,"JPY":9000000}}"#,
+        usd, eur
+    )
+}
+
+/// Price fetcher answering every url with the same response
+#[derive(Debug)]
+pub struct FakePriceFetcher {
+    response: Mutex<(u16, String)>,
+    delay: Duration,
+    calls: AtomicU32,
+}
+
+impl FakePriceFetcher {
+    pub fn new(status: u16, body: &str) -> Self {
+        Self {
+            response: Mutex::new((status, body.to_string())),
+            delay: Duration::ZERO,
+            calls: AtomicU32::new(0),
+        }
+    }
+
+    /// Take `delay` to answer
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Answer with `status` and `body` from now on
+    pub fn respond(&self, status: u16, body: &str) {
+        *self.response.lock().unwrap() = (status, body.to_string());
+    }
+
+    /// Requests made so far
+    pub fn calls(&self) -> u32 {
+        self.calls.load(Ordering::SeqCst)
+    }
+}
+
+#[async_trait]
+impl PriceFetcher for FakePriceFetcher {
+    async fn get(&self, _url: &str) -> Result<(u16, String), price::Error> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+
+        if !self.delay.is_zero() {
+            tokio::time::sleep(self.delay).await;
+        }
+
+        Ok(self.response.lock().unwrap().clone())
+    }
+}
@@ -0,0 +1,158 @@
+//! TLS for serving the api without a reverse proxy in front
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::sign::any_supported_type;
+use rustls::{Certificate, PrivateKey, ServerConfig, SignatureScheme};
+use rustls_pemfile::Item;
+use thiserror::Error;
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Message signed with the key and verified against the certificate to
+/// check they belong together
+const KEY_CHECK_MESSAGE: &[u8] = b"athenut-mint tls key check";
+
+/// TLS Error
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Could not read a certificate or key file
+    #[error("Could not read {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    /// Certificate file has no certificates
+    #[error("No certificate found in {0}")]
+    NoCertificate(PathBuf),
+    /// Key file has no private key
+    #[error("No private key found in {0}")]
+    NoPrivateKey(PathBuf),
+    /// Private key type is not supported
+    #[error("Unsupported private key type")]
+    UnsupportedKey,
+    /// Private key does not belong to the certificate
+    #[error("Private key does not match the certificate")]
+    KeyMismatch,
+    /// Rustls Error
+    #[error(transparent)]
+    Rustls(#[from] rustls::Error),
+}
+
+/// Load the certificate chain at `cert_path` and the private key at
+/// `key_path` into a config for the listener
+pub fn load_tls_config(cert_path: &Path, key_path: &Path) -> Result<RustlsConfig, Error> {
+    let server_config = server_config(cert_path, key_path)?;
+
+    Ok(RustlsConfig::from_config(Arc::new(server_config)))
+}
+
+/// Reload the certificate and key into `config` on every SIGHUP, so renewed
+/// certificates are served without a restart
+///
+/// Connections already open keep the certificate they were made with. A
+/// certificate that fails to load is logged and the current one kept.
+pub async fn reload_on_sighup(
+    config: RustlsConfig,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+) -> std::io::Result<()> {
+    let mut sighup = signal(SignalKind::hangup())?;
+
+    while sighup.recv().await.is_some() {
+        match server_config(&cert_path, &key_path) {
+            Ok(server_config) => {
+                config.reload_from_config(Arc::new(server_config));
+                tracing::info!("TLS certificate reloaded");
+            }
+            Err(err) => {
+                tracing::error!("Could not reload TLS certificate, keeping current: {}", err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn server_config(cert_path: &Path, key_path: &Path) -> Result<ServerConfig, Error> {
+    let certs = read_pem(cert_path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(cert) => Some(Certificate(cert)),
+            _ => None,
+        })
+        .collect::<Vec<Certificate>>();
+
+    let leaf = certs
+        .first()
+        .ok_or_else(|| Error::NoCertificate(cert_path.to_path_buf()))?;
+
+    let key = read_pem(key_path)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| Error::NoPrivateKey(key_path.to_path_buf()))?;
+
+    check_key_matches_cert(leaf, &key)?;
+
+    let mut server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(server_config)
+}
+
+fn read_pem(path: &Path) -> Result<Vec<Item>, Error> {
+    let file = File::open(path).map_err(|err| Error::Read(path.to_path_buf(), err))?;
+
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .map_err(|err| Error::Read(path.to_path_buf(), err))
+}
+
+/// Sign with `key` and verify the signature with the public key in `cert`
+///
+/// Rustls only finds a mismatched key on the first handshake, this catches it
+/// at startup instead.
+fn check_key_matches_cert(cert: &Certificate, key: &PrivateKey) -> Result<(), Error> {
+    let signing_key = any_supported_type(key).map_err(|_| Error::UnsupportedKey)?;
+
+    let schemes = [
+        (
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            &webpki::ECDSA_P256_SHA256,
+        ),
+        (
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            &webpki::ECDSA_P384_SHA384,
+        ),
+        (SignatureScheme::ED25519, &webpki::ED25519),
+        (
+            SignatureScheme::RSA_PSS_SHA256,
+            &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+        ),
+    ];
+
+    let offered: Vec<SignatureScheme> = schemes.iter().map(|(scheme, _)| *scheme).collect();
+
+    let signer = signing_key
+        .choose_scheme(&offered)
+        .ok_or(Error::UnsupportedKey)?;
+
+    let algorithm = schemes
+        .iter()
+        .find_map(|(scheme, algorithm)| (*scheme == signer.scheme()).then_some(*algorithm))
+        .ok_or(Error::UnsupportedKey)?;
+
+    let signature = signer.sign(KEY_CHECK_MESSAGE)?;
+
+    let cert =
+        webpki::EndEntityCert::try_from(cert.0.as_slice()).map_err(|_| Error::KeyMismatch)?;
+
+    cert.verify_signature(algorithm, KEY_CHECK_MESSAGE, &signature)
+        .map_err(|_| Error::KeyMismatch)
+}